@@ -0,0 +1,362 @@
+use super::error::{Error, ParseError};
+use super::etree::{sniff_encoding, ETree, ReadState};
+use super::options::ParseOptions;
+use encoding_rs::{Decoder, DecoderResult};
+use std::io::Read;
+
+/// Push-based incremental parser
+///
+/// Bytes can be fed in chunks of any size, complete markups are parsed as soon as they arrive
+/// and only the incomplete rest is kept. The encoding is detected like `ETree::parse_bytes` once the first four bytes,
+/// or the whole XML declaration, have arrived.
+///
+/// ```
+/// use etree::TreeBuilder;
+///
+/// let mut builder = TreeBuilder::new();
+/// builder.feed(b"<root><item>a</it").unwrap();
+/// builder.feed(b"em><item>b</item></root>").unwrap();
+/// let tree = builder.finish().unwrap();
+/// assert_eq!(tree.find_iter("//item").count(), 2);
+/// ```
+pub struct TreeBuilder {
+    options: ParseOptions,
+    tree: Option<ETree>,
+    state: ReadState,
+    decoder: Option<Decoder>,
+    head: Vec<u8>,
+    pending: String,
+    offset: usize,
+    line: usize,
+    column: usize,
+}
+
+impl TreeBuilder {
+    #[allow(dead_code)]
+    pub fn new() -> TreeBuilder {
+        TreeBuilder::with_options(ParseOptions::default())
+    }
+    #[allow(dead_code)]
+    pub fn with_options(options: ParseOptions) -> TreeBuilder {
+        TreeBuilder {
+            options,
+            tree: None,
+            state: ReadState::new(),
+            decoder: None,
+            head: Vec::new(),
+            pending: String::new(),
+            offset: 0,
+            line: 1,
+            column: 1,
+        }
+    }
+    #[allow(dead_code)]
+    /// parse next chunk of the document
+    pub fn feed(&mut self, bytes: &[u8]) -> Result<(), ParseError> {
+        self.decode(bytes, false)?;
+        let len = complete_len(&self.pending);
+        if len > 0 {
+            let text: String = self.pending.drain(..len).collect();
+            self.read(&text)?;
+        }
+        Ok(())
+    }
+    #[allow(dead_code)]
+    /// parse the rest of the document and return the tree
+    pub fn finish(mut self) -> Result<ETree, ParseError> {
+        self.decode(b"", true)?;
+        let text = std::mem::take(&mut self.pending);
+        self.read(&text)?;
+        if let Some(tree) = self.tree.as_ref() {
+            tree.read_end("", &self.state, &mut None).map_err(|e| self.locate(e))?;
+        }
+        let mut tree = self.tree.unwrap_or_else(|| ETree::empty(""));
+        tree.detect_indent();
+        Ok(tree)
+    }
+
+    /// decode bytes into the pending text
+    fn decode(&mut self, bytes: &[u8], last: bool) -> Result<(), ParseError> {
+        if self.decoder.is_none() {
+            // buffer the start of the document until the BOM or the XML declaration can be sniffed
+            self.head.extend_from_slice(bytes);
+            if !last && !sniffable(&self.head) {
+                return Ok(());
+            }
+            let head = std::mem::take(&mut self.head);
+            let (encoding, bom_len) = sniff_encoding(&head);
+            self.decoder = Some(encoding.new_decoder_without_bom_handling());
+            let mut tree = ETree::empty(&String::from_utf8_lossy(&head));
+            tree.set_bom(bom_len > 0);
+            self.tree = Some(tree);
+            return self.decode(&head[bom_len..], last);
+        }
+        let decoder = self.decoder.as_mut().unwrap();
+        let needed = decoder
+            .max_utf8_buffer_length_without_replacement(bytes.len())
+            .unwrap_or(bytes.len());
+        self.pending.reserve(needed);
+        let (result, _) = decoder.decode_to_string_without_replacement(bytes, &mut self.pending, last);
+        match result {
+            DecoderResult::Malformed(_, _) => {
+                let message = format!("invalid {} byte sequence", decoder.encoding().name());
+                Err(self.locate(ParseError::new(&self.pending, self.pending.len(), message)))
+            }
+            _ => Ok(()),
+        }
+    }
+    /// read complete markups and move the position forward
+    fn read(&mut self, text: &str) -> Result<(), ParseError> {
+        if let Some(tree) = self.tree.as_mut() {
+            if let Err(e) = tree.read(text, &self.options, &mut self.state, &mut None, &mut None) {
+                return Err(self.locate(e));
+            }
+        }
+        self.offset += text.len();
+        match text.rfind('\n') {
+            Some(i) => {
+                self.line += text.matches('\n').count();
+                self.column = text[i + 1..].chars().count() + 1;
+            }
+            None => self.column += text.chars().count(),
+        }
+        Ok(())
+    }
+    /// convert position in the current chunk to position in the document
+    fn locate(&self, mut e: ParseError) -> ParseError {
+        if e.line == 1 {
+            e.column += self.column - 1;
+        }
+        e.line += self.line - 1;
+        e.offset += self.offset;
+        e
+    }
+}
+
+/// whether enough of the document start has arrived to detect the encoding
+fn sniffable(head: &[u8]) -> bool {
+    // `sniff_encoding` reads at most 256 bytes of the declaration
+    let decl = head.starts_with(b"<?xml") || b"<?xml".starts_with(head);
+    head.len() >= 4 && (!decl || head.contains(&b'>') || head.len() >= 256)
+}
+
+impl Default for TreeBuilder {
+    fn default() -> Self {
+        TreeBuilder::new()
+    }
+}
+
+/// Iterator over documents concatenated in one stream, created by `ETree::parse_many`
+///
+/// A document ends with the end tag of its root element, whitespace between documents is dropped and anything else
+/// after the root (comments, XML declaration) starts the next document. Each document is parsed by `TreeBuilder`,
+/// so positions of errors and nodes are relative to the start of the document. The stream must use an encoding
+/// compatible with ASCII, such as UTF-8 or ISO-8859-1.
+///
+/// ```
+/// use etree::ETree;
+///
+/// let stream = "<?xml version=\"1.0\"?><log>a</log>\n<?xml version=\"1.0\"?><log>b</log>\n";
+/// let trees: Vec<ETree> = ETree::parse_many(stream.as_bytes()).collect::<Result<_, _>>().unwrap();
+/// assert_eq!(trees.len(), 2);
+/// ```
+pub struct Documents<R> {
+    reader: R,
+    options: ParseOptions,
+    buf: Vec<u8>,
+    eof: bool,
+    // length of scanned part of `buf` and element depth at its end
+    scanned: usize,
+    depth: usize,
+}
+
+impl<R: Read> Documents<R> {
+    pub(crate) fn new(reader: R, options: ParseOptions) -> Documents<R> {
+        Documents {
+            reader,
+            options,
+            buf: Vec::new(),
+            eof: false,
+            scanned: 0,
+            depth: 0,
+        }
+    }
+    /// scan markups of `buf` and return the end of the first document if it is complete
+    fn document_end(&mut self) -> Option<usize> {
+        while let Some(start) = self.buf[self.scanned..].iter().position(|&c| c == b'<').map(|x| x + self.scanned) {
+            let end = start + markup_end(&self.buf[start..])?;
+            self.scanned = end;
+            let markup = &self.buf[start..end];
+            if markup.starts_with(b"<!") || markup.starts_with(b"<?") {
+                continue;
+            }
+            if markup.starts_with(b"</") {
+                self.depth = self.depth.saturating_sub(1);
+            } else if !markup.ends_with(b"/>") {
+                self.depth += 1;
+            }
+            if self.depth == 0 {
+                return Some(end);
+            }
+        }
+        self.scanned = self.buf.len();
+        None
+    }
+    fn parse(&self, bytes: &[u8]) -> Result<ETree, Error> {
+        let mut builder = TreeBuilder::with_options(self.options.clone());
+        builder.feed(bytes)?;
+        Ok(builder.finish()?)
+    }
+}
+
+impl<R: Read> Iterator for Documents<R> {
+    type Item = Result<ETree, Error>;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let end = match self.document_end() {
+                Some(end) => Some(end),
+                None if self.eof && self.buf.iter().all(|c| c.is_ascii_whitespace()) => return None,
+                None if self.eof => Some(self.buf.len()),
+                None => None,
+            };
+            if let Some(end) = end {
+                let document: Vec<u8> = self.buf.drain(..end).collect();
+                let blank = self.buf.iter().take_while(|c| c.is_ascii_whitespace()).count();
+                self.buf.drain(..blank);
+                self.scanned = 0;
+                self.depth = 0;
+                return Some(self.parse(&document));
+            }
+            let mut chunk = [0; 64 * 1024];
+            match self.reader.read(&mut chunk) {
+                Ok(0) => self.eof = true,
+                Ok(len) => self.buf.extend_from_slice(&chunk[..len]),
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    self.eof = true;
+                    self.buf.clear();
+                    return Some(Err(e.into()));
+                }
+            }
+        }
+    }
+}
+
+/// get length of the text which ends with a complete markup, the rest may be continued by the next chunk
+fn complete_len(text: &str) -> usize {
+    let mut complete = 0;
+    while let Some(start) = text[complete..].find('<').map(|x| x + complete) {
+        match markup_end(&text.as_bytes()[start..]) {
+            Some(end) => complete = start + end,
+            None => break,
+        }
+    }
+    complete
+}
+
+/// get length of the markup at the start of `bytes`, `None` if it is incomplete
+pub(crate) fn markup_end(bytes: &[u8]) -> Option<usize> {
+    let find = |pattern: &[u8]| bytes.windows(pattern.len()).position(|x| x == pattern).map(|x| x + pattern.len());
+    if bytes.starts_with(b"<!--") {
+        find(b"-->")
+    } else if bytes.starts_with(b"<![CDATA[") {
+        find(b"]]>")
+    } else if bytes.starts_with(b"<?") {
+        find(b"?>")
+    } else if b"<!--".starts_with(bytes) || b"<![CDATA[".starts_with(bytes) {
+        // too short to know the kind of markup
+        None
+    } else {
+        // tag or DOCTYPE, ">" may appear in quotes or in the internal subset
+        let mut quote = None;
+        let mut depth = 0;
+        for (j, &c) in bytes.iter().enumerate() {
+            match (quote, c) {
+                (Some(q), _) if q == c => quote = None,
+                (Some(_), _) => {}
+                (None, b'"') | (None, b'\'') => quote = Some(c),
+                (None, b'[') => depth += 1,
+                (None, b']') => depth -= 1,
+                (None, b'>') if depth <= 0 => return Some(j + 1),
+                _ => {}
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_complete_len() {
+        assert_eq!(complete_len("<a>text"), 3);
+        assert_eq!(complete_len("<a x='>'>t</a"), 9);
+        assert_eq!(complete_len("<a><!-- > --><!-"), 13);
+        assert_eq!(complete_len("<!DOCTYPE a [<!ENTITY b '>'>]><a>"), 33);
+        assert_eq!(complete_len("<a><![CDATA[ > ]]"), 3);
+    }
+    #[test]
+    fn test_parse_many() {
+        let stream = concat!(
+            "<?xml version=\"1.0\"?>\n<a x='>'><b/><!-- </a> --></a>\n",
+            "<!-- second --><a/>",
+            "<a><![CDATA[</a>]]></a>\n\n",
+            "<?xml version=\"1.0\"?><a></b>"
+        );
+        let results: Vec<Result<ETree, Error>> = Documents::new(stream.as_bytes(), ParseOptions::default()).collect();
+        assert_eq!(results.len(), 4);
+        let trees: Vec<&ETree> = results[..3].iter().map(|x| x.as_ref().unwrap()).collect();
+        assert_eq!(trees[0].descendant(trees[0].root()).len(), 2);
+        assert_eq!(trees[1].prolog().len(), 1);
+        assert!(trees[2].node(1).unwrap().is_cdata());
+        assert!(matches!(results[3], Err(Error::Parse { .. })));
+        // chunks of one byte
+        struct Slow<'a>(&'a [u8]);
+        impl Read for Slow<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let len = self.0.len().min(buf.len()).min(1);
+                buf[..len].copy_from_slice(&self.0[..len]);
+                self.0 = &self.0[len..];
+                Ok(len)
+            }
+        }
+        assert_eq!(Documents::new(Slow(stream.as_bytes()), ParseOptions::default()).count(), 4);
+        assert_eq!(Documents::new(&b" \n"[..], ParseOptions::default()).count(), 0);
+    }
+    #[test]
+    fn test_tree_builder() {
+        let content = "<?xml version=\"1.0\"?>\n<root xmlns:x=\"urn:x\">\n  <x:a k=\"&amp;\">\u{4e2d}</x:a>\n  <!-- c -->\n</root>";
+        let expected = ETree::parse_str(content).unwrap();
+        for size in 1..8 {
+            let mut builder = TreeBuilder::new();
+            for chunk in content.as_bytes().chunks(size) {
+                builder.feed(chunk).unwrap();
+            }
+            let tree = builder.finish().unwrap();
+            assert_eq!(tree.write().unwrap(), expected.write().unwrap());
+            assert_eq!(tree.node(1).unwrap().get_namespace(), "urn:x");
+            assert_eq!(tree.node(2).unwrap().get_offset(), expected.node(2).unwrap().get_offset());
+            assert_eq!(tree.node(2).unwrap().get_line(), Some(4));
+        }
+        let mut builder = TreeBuilder::new();
+        builder.feed(b"<root>\n  <a>").unwrap();
+        let e = builder.feed(b"</b></root>").unwrap_err();
+        assert_eq!((e.offset, e.line, e.column), (14, 2, 8));
+        let mut builder = TreeBuilder::new();
+        builder.feed(b"<root>\n  <a>").unwrap();
+        let e = builder.finish().unwrap_err();
+        assert_eq!((e.offset, e.line, e.column, e.message.as_str()), (12, 2, 6, "element <a> is not closed"));
+        // BOM and encoding split over chunks
+        let content = b"\xef\xbb\xbf<root>a</root>";
+        let latin1 = b"<?xml version=\"1.0\" encoding=\"ISO-8859-1\"?><root>\xe9</root>";
+        for content in [&content[..], &latin1[..]].iter() {
+            let mut builder = TreeBuilder::new();
+            for chunk in content.chunks(1) {
+                builder.feed(chunk).unwrap();
+            }
+            let tree = builder.finish().unwrap();
+            assert_eq!(tree.write().unwrap(), ETree::parse_bytes(content).unwrap().write().unwrap());
+        }
+    }
+}
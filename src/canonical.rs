@@ -0,0 +1,225 @@
+use super::etree::ETree;
+use super::etreenode::NodeKind;
+use super::options::Newline;
+use super::writer::normalize_newlines;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+/// Writer of Canonical XML 1.0 without comments
+///
+/// The XML declaration, DOCTYPE and comments are dropped, empty elements are written as start and end tags,
+/// namespace declarations and attributes are sorted, and only declarations which change the namespace in scope
+/// are written. Texts are taken as stored in the tree, newlines are normalized to "\n".
+pub(crate) struct Canonicalizer<'a, W: Write> {
+    tree: &'a ETree,
+    w: W,
+}
+
+impl<'a, W: Write> Canonicalizer<'a, W> {
+    pub(crate) fn new(tree: &'a ETree, w: W) -> Canonicalizer<'a, W> {
+        Canonicalizer { tree, w }
+    }
+    /// write the whole document, processing instructions outside the root element are kept
+    pub(crate) fn write_document(&mut self) -> io::Result<()> {
+        let root = self.tree.root();
+        for pos in self.tree.prolog() {
+            if self.write_pi(pos)? {
+                self.w.write_all(b"\n")?;
+            }
+        }
+        if self.tree.node(root).is_some() {
+            self.write_element(root, &BTreeMap::new())?;
+        }
+        for pos in self.tree.epilog() {
+            if self.tree.node(pos).map(|x| x.is_pi()).unwrap_or(false) {
+                self.w.write_all(b"\n")?;
+                self.write_pi(pos)?;
+            }
+        }
+        Ok(())
+    }
+    /// write the subtree at `pos` with the namespace declarations in scope
+    pub(crate) fn write_subtree(&mut self, pos: usize) -> io::Result<()> {
+        let node = match self.tree.node(pos) {
+            Some(node) => node,
+            None => return Ok(()),
+        };
+        if node.is_pi() {
+            self.write_pi(pos)?;
+        } else if node.is_cdata() {
+            self.write_text(node.get_text_ref().unwrap_or(""))?;
+        } else if node.is_element() {
+            // declarations on ancestors are rendered by the subtree as if it were the document element
+            let mut ancestors = Vec::new();
+            let mut current = self.tree.parent(pos);
+            while let Some(parent) = current {
+                ancestors.push(parent);
+                current = self.tree.parent(parent);
+            }
+            let mut inherited = BTreeMap::new();
+            for parent in ancestors.into_iter().rev() {
+                inherited.extend(declarations(self.tree, parent));
+            }
+            self.write_element_with(pos, &BTreeMap::new(), inherited)?;
+        }
+        Ok(())
+    }
+    fn write_element(&mut self, pos: usize, rendered: &BTreeMap<String, String>) -> io::Result<()> {
+        self.write_element_with(pos, rendered, BTreeMap::new())
+    }
+    /// write element at `pos`, `rendered` are the declarations in scope of the output, `extra` are declarations
+    /// inherited from ancestors which are not written
+    fn write_element_with(
+        &mut self,
+        pos: usize,
+        rendered: &BTreeMap<String, String>,
+        extra: BTreeMap<String, String>,
+    ) -> io::Result<()> {
+        let tree = self.tree;
+        let node = tree.node(pos).unwrap();
+        let mut decls = extra;
+        decls.extend(declarations(tree, pos));
+        let mut scope = rendered.clone();
+        let mut out_decls = Vec::new();
+        for (prefix, uri) in decls {
+            let current = rendered.get(&prefix).map(|x| x.as_str()).unwrap_or("");
+            if current != uri {
+                out_decls.push((prefix.clone(), uri.clone()));
+                scope.insert(prefix, uri);
+            }
+        }
+        let mut attrs: Vec<(String, String, &str, &str)> = node
+            .get_attr_iter()
+            .filter(|(key, _)| key != "xmlns" && !key.starts_with("xmlns:"))
+            .map(|(key, value)| {
+                let (ns, local) = match key.split_once(':') {
+                    Some((_, local)) => (node.get_attr_namespace(key).unwrap_or_default(), local.to_string()),
+                    None => (String::new(), key.clone()),
+                };
+                (ns, local, key.as_str(), value.as_str())
+            })
+            .collect();
+        attrs.sort_by(|a, b| (&a.0, &a.1).cmp(&(&b.0, &b.1)));
+        let name = node.get_name();
+        write!(self.w, "<{}", name)?;
+        for (prefix, uri) in out_decls.iter() {
+            if prefix.is_empty() {
+                write!(self.w, " xmlns=\"{}\"", escape_attr(uri))?;
+            } else {
+                write!(self.w, " xmlns:{}=\"{}\"", prefix, escape_attr(uri))?;
+            }
+        }
+        for (_, _, key, value) in attrs {
+            write!(self.w, " {}=\"{}\"", key, escape_attr(value))?;
+        }
+        self.w.write_all(b">")?;
+        self.write_text(node.get_text_ref().unwrap_or(""))?;
+        for child in tree.children(pos) {
+            let item = tree.node(child).unwrap();
+            match item.kind() {
+                NodeKind::Comment | NodeKind::DocType => {}
+                NodeKind::ProcessingInstruction => {
+                    self.write_pi(child)?;
+                }
+                NodeKind::CData => self.write_text(item.get_text_ref().unwrap_or(""))?,
+                NodeKind::Element => self.write_element(child, &scope)?,
+            }
+            self.write_text(item.get_tail_ref())?;
+        }
+        write!(self.w, "</{}>", name)
+    }
+    /// write processing instruction at `pos`, return false for other nodes
+    fn write_pi(&mut self, pos: usize) -> io::Result<bool> {
+        match self.tree.node(pos).filter(|x| x.is_pi()) {
+            Some(node) => {
+                let text = node.get_text_ref().unwrap_or("");
+                write!(self.w, "<?{}?>", normalize_newlines(text, Newline::Lf))?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+    fn write_text(&mut self, text: &str) -> io::Result<()> {
+        let text = normalize_newlines(text, Newline::Lf);
+        let text = text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+        self.w.write_all(text.as_bytes())
+    }
+}
+
+/// namespace declarations on the element at `pos`, keyed by prefix ("" for the default namespace)
+fn declarations(tree: &ETree, pos: usize) -> BTreeMap<String, String> {
+    let mut out = BTreeMap::new();
+    if let Some(node) = tree.node(pos) {
+        for (key, value) in node.get_attr_iter() {
+            if key == "xmlns" {
+                out.insert(String::new(), value.clone());
+            } else if let Some(prefix) = key.strip_prefix("xmlns:") {
+                out.insert(prefix.to_string(), value.clone());
+            }
+        }
+    }
+    out
+}
+
+fn escape_attr(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '"' => out.push_str("&quot;"),
+            '\t' => out.push_str("&#x9;"),
+            '\n' => out.push_str("&#xA;"),
+            '\r' => out.push_str("&#xD;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// `io::Write` adaptor which feeds a digest
+pub(crate) struct DigestWriter<D>(pub D);
+
+impl<D: sha2::Digest> Write for DigestWriter<D> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn canonical(tree: &ETree, pos: Option<usize>) -> String {
+        let mut out = Vec::new();
+        let mut c = Canonicalizer::new(tree, &mut out);
+        match pos {
+            Some(pos) => c.write_subtree(pos).unwrap(),
+            None => c.write_document().unwrap(),
+        }
+        String::from_utf8(out).unwrap()
+    }
+    #[test]
+    fn test_canonical() {
+        let content = concat!(
+            "<?xml version=\"1.0\"?>\r\n<!DOCTYPE doc>\n<?pi x?>\n<!-- c -->\n",
+            "<doc xmlns=\"urn:a\" xmlns:b=\"urn:b\" z='1' b:y=\"2&quot;\" a=\"\t\">\r\n",
+            "  <e1 xmlns=\"urn:a\"/><e2 xmlns:b=\"urn:c\">&lt;&gt;<![CDATA[&]]><!-- x --></e2>",
+            "</doc>\n<?end?>"
+        );
+        let tree = ETree::parse_str(content).unwrap();
+        assert_eq!(
+            canonical(&tree, None),
+            concat!(
+                "<?pi x?>\n",
+                "<doc xmlns=\"urn:a\" xmlns:b=\"urn:b\" a=\"&#x9;\" z=\"1\" b:y=\"2&quot;\">\n",
+                "  <e1></e1><e2 xmlns:b=\"urn:c\">&lt;&gt;&amp;</e2></doc>\n",
+                "<?end?>"
+            )
+        );
+        assert_eq!(canonical(&tree, Some(tree.find("//e1").unwrap())), "<e1 xmlns=\"urn:a\" xmlns:b=\"urn:b\"></e1>");
+    }
+}
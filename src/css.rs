@@ -0,0 +1,377 @@
+/// compound selector, e.g. `div.article[lang]:first-child`
+#[derive(Debug, Default)]
+struct Compound {
+    /// element name, None for `*` or no type selector
+    name: Option<String>,
+    /// conditions on attributes and content
+    conditions: Vec<String>,
+    /// conditions on the position among element siblings
+    child: Vec<String>,
+    /// conditions on the position among siblings of the same name
+    of_type: Vec<String>,
+    root: bool,
+    /// number of simple selectors
+    count: usize,
+}
+
+/// string literal of XPath, quotes and backslashes are escaped
+fn quote(text: &str) -> String {
+    format!("'{}'", text.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+/// conditions joined with "and", the predicate parser takes two operands of "and" at a time
+fn and(conditions: &[String]) -> Option<String> {
+    let (first, rest) = conditions.split_first()?;
+    Some(rest.iter().fold(first.clone(), |out, x| format!("({}) and ({})", out, x)))
+}
+
+/// whether a whitespace separated word of the value of `attr` is `word`
+fn word(attr: &str, word: &str) -> String {
+    format!("matches(@{}, {})", attr, quote(&format!(r"(^|\s){}(\s|$)", regex::escape(word))))
+}
+
+/// condition of `an+b` on `position`
+fn nth(position: &str, (a, b): (i64, i64)) -> String {
+    if a == 0 {
+        return format!("{} = {}", position, b);
+    }
+    let offset = match b {
+        b if b < 0 => format!("{} + {}", position, -b),
+        b => format!("{} - {}", position, b),
+    };
+    format!("({}) mod {} = 0 and ({}) div {} >= 0", offset, a, offset, a)
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<char> {
+        self.input[self.pos..].chars().next()
+    }
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += c.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+    fn eat_str(&mut self, text: &str) -> bool {
+        if self.input[self.pos..].starts_with(text) {
+            self.pos += text.len();
+            true
+        } else {
+            false
+        }
+    }
+    /// skip whitespace, return whether there was any
+    fn space(&mut self) -> bool {
+        let start = self.pos;
+        while let Some(c) = self.peek().filter(|c| c.is_whitespace()) {
+            self.pos += c.len_utf8();
+        }
+        self.pos > start
+    }
+    fn expected(&self, what: &str) -> (usize, String) {
+        match self.peek() {
+            Some(c) => (self.pos, format!("unexpected {:?}, expected {}", c, what)),
+            None => (self.pos, format!("unexpected end, expected {}", what)),
+        }
+    }
+    /// identifier, a backslash escapes the next character, e.g. "svg\:rect"
+    fn ident(&mut self) -> Result<String, (usize, String)> {
+        let mut out = String::new();
+        while let Some(c) = self.peek() {
+            if c == '\\' {
+                self.pos += 1;
+                match self.peek() {
+                    Some(c) => out.push(c),
+                    None => return Err(self.expected("a character after '\\'")),
+                }
+            } else if c.is_alphanumeric() || c == '-' || c == '_' || !c.is_ascii() {
+                out.push(c);
+            } else {
+                break;
+            }
+            self.pos += self.peek().map(char::len_utf8).unwrap_or(0);
+        }
+        match out.is_empty() || out.starts_with(|c: char| c.is_ascii_digit()) {
+            true => Err(self.expected("a name")),
+            false => Ok(out),
+        }
+    }
+    /// value of an attribute selector, an identifier or a string in quotes
+    fn value(&mut self) -> Result<String, (usize, String)> {
+        let quote = match self.peek() {
+            Some(c) if c == '"' || c == '\'' => c,
+            _ => return self.ident(),
+        };
+        let start = self.pos;
+        self.pos += 1;
+        let mut out = String::new();
+        loop {
+            match self.peek() {
+                None => return Err((start, "unterminated string".to_string())),
+                Some(c) if c == quote => break,
+                Some('\\') => {
+                    self.pos += 1;
+                    if let Some(c) = self.peek() {
+                        out.push(c);
+                        self.pos += c.len_utf8();
+                    }
+                }
+                Some(c) => {
+                    out.push(c);
+                    self.pos += c.len_utf8();
+                }
+            }
+        }
+        self.pos += 1;
+        Ok(out)
+    }
+    /// argument of `:nth-child()` and the like, `odd`, `even` or `an+b`
+    fn nth(&mut self) -> Result<(i64, i64), (usize, String)> {
+        let start = self.pos;
+        let end = self.input[start..].find(')').map(|x| x + start).ok_or_else(|| self.expected("')'"))?;
+        let arg: String = self.input[start..end].chars().filter(|c| !c.is_whitespace()).collect();
+        let number = |text: &str| match text {
+            "" | "+" => Some(1),
+            "-" => Some(-1),
+            _ => text.parse::<i64>().ok(),
+        };
+        let result = match arg.as_str() {
+            "odd" => Some((2, 1)),
+            "even" => Some((2, 0)),
+            _ => match arg.split_once('n') {
+                Some((a, "")) => number(a).map(|a| (a, 0)),
+                Some((a, b)) if b.starts_with(['+', '-']) => number(a).zip(b.parse::<i64>().ok()),
+                Some(_) => None,
+                None => arg.parse::<i64>().ok().map(|b| (0, b)),
+            },
+        };
+        self.pos = end;
+        result.ok_or((start, format!("invalid argument {:?}, expected odd, even or an+b", arg)))
+    }
+    /// compound selector, `negated` for the argument of `:not()`
+    fn compound(&mut self, negated: bool) -> Result<Compound, (usize, String)> {
+        let mut out = Compound::default();
+        if self.eat('*') {
+            out.count += 1;
+        } else if self.peek().is_some_and(|c| c.is_alphabetic() || c == '_' || c == '\\' || !c.is_ascii()) {
+            out.name = Some(self.ident()?);
+            out.count += 1;
+        }
+        loop {
+            let start = self.pos;
+            if self.eat('#') {
+                out.conditions.push(format!("@id = {}", quote(&self.ident()?)));
+            } else if self.eat('.') {
+                out.conditions.push(word("class", &self.ident()?));
+            } else if self.eat('[') {
+                out.conditions.push(self.attribute()?);
+            } else if self.eat(':') {
+                self.pseudo(&mut out, start, negated)?;
+            } else {
+                break;
+            }
+            out.count += 1;
+        }
+        if out.count == 0 {
+            return Err(self.expected("a selector"));
+        }
+        Ok(out)
+    }
+    /// attribute selector after "["
+    fn attribute(&mut self) -> Result<String, (usize, String)> {
+        self.space();
+        let name = self.ident()?;
+        self.space();
+        let op = ["=", "~=", "|=", "^=", "$=", "*="].iter().copied().find(|x| self.eat_str(x));
+        let condition = match op {
+            None => format!("@{}", name),
+            Some(op) => {
+                self.space();
+                let value = self.value()?;
+                match op {
+                    "=" => format!("@{} = {}", name, quote(&value)),
+                    "~=" => word(&name, &value),
+                    "|=" => {
+                        let prefix = quote(&format!("{}-", value));
+                        format!("@{} = {} or starts-with(@{}, {})", name, quote(&value), name, prefix)
+                    }
+                    "^=" => format!("starts-with(@{}, {})", name, quote(&value)),
+                    "$=" => format!("matches(@{}, {})", name, quote(&format!("{}$", regex::escape(&value)))),
+                    _ => format!("contains(@{}, {})", name, quote(&value)),
+                }
+            }
+        };
+        self.space();
+        if !self.eat(']') {
+            return Err(self.expected("']'"));
+        }
+        Ok(condition)
+    }
+    /// pseudo-class after ":", which starts at `start`
+    fn pseudo(&mut self, out: &mut Compound, start: usize, negated: bool) -> Result<(), (usize, String)> {
+        let name = self.ident()?;
+        let last = "last() - position() + 1";
+        match name.as_str() {
+            "first-child" => out.child.push("position() = 1".to_string()),
+            "last-child" => out.child.push("position() = last()".to_string()),
+            "only-child" => out.child.push("last() = 1".to_string()),
+            "first-of-type" => out.of_type.push("position() = 1".to_string()),
+            "last-of-type" => out.of_type.push("position() = last()".to_string()),
+            "only-of-type" => out.of_type.push("last() = 1".to_string()),
+            "empty" => out.conditions.push("count(*) = 0 and string-length(text()) = 0".to_string()),
+            "root" if !negated => out.root = true,
+            "nth-child" | "nth-last-child" | "nth-of-type" | "nth-last-of-type" | "not" => {
+                if !self.eat('(') {
+                    return Err(self.expected("'('"));
+                }
+                self.space();
+                match name.as_str() {
+                    "nth-child" => out.child.push(nth("position()", self.nth()?)),
+                    "nth-last-child" => out.child.push(nth(last, self.nth()?)),
+                    "nth-of-type" => out.of_type.push(nth("position()", self.nth()?)),
+                    "nth-last-of-type" => out.of_type.push(nth(last, self.nth()?)),
+                    _ => {
+                        let arg_start = self.pos;
+                        if negated {
+                            return Err((start, ":not() cannot be nested".to_string()));
+                        }
+                        let inner = self.compound(true)?;
+                        if inner.count > 1 {
+                            return Err((arg_start, ":not() takes a simple selector".to_string()));
+                        }
+                        let negate = |x: &[String]| and(x).map(|x| format!("not({})", x));
+                        out.conditions.extend(inner.name.map(|x| format!("not(name() = {})", quote(&x))));
+                        out.conditions.extend(negate(&inner.conditions));
+                        out.child.extend(negate(&inner.child));
+                        out.of_type.extend(negate(&inner.of_type));
+                    }
+                }
+                self.space();
+                if !self.eat(')') {
+                    return Err(self.expected("')'"));
+                }
+            }
+            _ => return Err((start, format!("unsupported pseudo-class \":{}\"", name))),
+        }
+        Ok(())
+    }
+}
+
+impl Compound {
+    /// XPath steps of the compound selector after `combinator`, "" for the first compound selector, which may be
+    /// reached in alternative ways
+    fn steps(self, combinator: &str, start: usize) -> Result<Vec<String>, (usize, String)> {
+        let sibling = combinator == "+" || combinator == "~";
+        if !self.child.is_empty() && !self.of_type.is_empty() {
+            return Err((start, "positions among children and among the same type cannot be combined".to_string()));
+        } else if !self.of_type.is_empty() && self.name.is_none() {
+            return Err((start, "positions among the same type need an element name".to_string()));
+        } else if sibling && !(self.child.is_empty() && self.of_type.is_empty()) {
+            return Err((start, "positions are not supported after '+' or '~'".to_string()));
+        } else if self.root && !combinator.is_empty() {
+            return Err((start, ":root is only supported in the first compound selector".to_string()));
+        }
+        let mut conditions = Vec::new();
+        // positions are counted among the nodes passing the node test
+        let test = match self.name {
+            Some(name) if combinator == "+" || !self.child.is_empty() => {
+                conditions.push(format!("name() = {}", quote(&name)));
+                "*".to_string()
+            }
+            Some(name) => name,
+            None => "*".to_string(),
+        };
+        if combinator == "+" {
+            conditions.insert(0, "position() = 1".to_string());
+        }
+        let positional = !(self.child.is_empty() && self.of_type.is_empty());
+        conditions.extend(self.child);
+        conditions.extend(self.of_type);
+        conditions.extend(self.conditions);
+        // positions after "//" are counted among all descendants, so a position among siblings is taken after "/" from
+        // any element, or from the document for the top-level element
+        let axes = match combinator {
+            "" if self.root => vec!["/"],
+            "" if positional => vec!["/", "/descendant-or-self::*/"],
+            " " if positional => vec!["/descendant-or-self::*/"],
+            "" | " " => vec!["//"],
+            ">" => vec!["/"],
+            _ => vec!["/following-sibling::"],
+        };
+        let step = match and(&conditions) {
+            Some(predicate) => format!("{}[{}]", test, predicate),
+            None => test,
+        };
+        Ok(axes.into_iter().map(|x| format!("{}{}", x, step)).collect())
+    }
+}
+
+/// translate a group of CSS selectors to XPath, return the offset and the reason if `selector` is not supported
+pub(crate) fn to_xpath(selector: &str) -> Result<String, (usize, String)> {
+    let mut parser = Parser { input: selector, pos: 0 };
+    let mut paths = Vec::new();
+    loop {
+        parser.space();
+        let mut alternatives = vec![String::new()];
+        let mut combinator = "";
+        loop {
+            let start = parser.pos;
+            let steps = parser.compound(false)?.steps(combinator, start)?;
+            alternatives = alternatives.iter().flat_map(|x| steps.iter().map(move |y| format!("{}{}", x, y))).collect();
+            let space = parser.space();
+            combinator = match parser.peek() {
+                Some(c @ ('>' | '+' | '~')) => {
+                    parser.pos += 1;
+                    parser.space();
+                    match c {
+                        '>' => ">",
+                        '+' => "+",
+                        _ => "~",
+                    }
+                }
+                None | Some(',') => break,
+                Some(_) if space => " ",
+                Some(_) => return Err(parser.expected("a combinator or ','")),
+            };
+        }
+        paths.extend(alternatives);
+        if !parser.eat(',') {
+            return Ok(paths.join(" | "));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_to_xpath() {
+        assert_eq!(to_xpath("div > p").unwrap(), "//div/p");
+        assert_eq!(to_xpath("a b, :root>c").unwrap(), "//a//b | /*/c");
+        assert_eq!(to_xpath("#x").unwrap(), "//*[@id = 'x']");
+        assert_eq!(
+            to_xpath("p:first-child").unwrap(),
+            "/*[(name() = 'p') and (position() = 1)] | /descendant-or-self::*/*[(name() = 'p') and (position() = 1)]"
+        );
+        assert_eq!(to_xpath("h1 + p").unwrap(), "//h1/following-sibling::*[(position() = 1) and (name() = 'p')]");
+        assert_eq!(
+            to_xpath("ul > li:nth-of-type(2n+1)").unwrap(),
+            "//ul/li[(position() - 1) mod 2 = 0 and (position() - 1) div 2 >= 0]"
+        );
+        assert_eq!(to_xpath("a[href$='.pdf']").unwrap(), r"//a[matches(@href, '\\.pdf$')]");
+        assert_eq!(to_xpath("svg\\:rect").unwrap(), "//svg:rect");
+        assert_eq!(to_xpath("a[x").unwrap_err(), (3, "unexpected end, expected ']'".to_string()));
+        assert_eq!(to_xpath("a:hover").unwrap_err(), (1, "unsupported pseudo-class \":hover\"".to_string()));
+        assert_eq!(to_xpath("a >").unwrap_err().0, 3);
+        assert_eq!(to_xpath("a:nth-child(x)").unwrap_err().0, 12);
+        assert!(to_xpath("*:first-of-type").is_err());
+        assert!(to_xpath("a ~ b:last-child").is_err());
+    }
+}
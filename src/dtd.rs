@@ -0,0 +1,770 @@
+/// DTD parser
+///
+/// Only the internal subset stored in the `<DocType>` node is parsed.
+///
+/// Grammar rules:
+/// ```text
+/// doctype:
+///     name
+///     name PUBLIC string string
+///     name SYSTEM string
+/// entity:
+///     <!ENTITY [%] name string >
+///     <!ENTITY [%] name PUBLIC string string [NDATA name] >
+///     <!ENTITY [%] name SYSTEM string [NDATA name] >
+/// notation:
+///     <!NOTATION name PUBLIC string [string] >
+///     <!NOTATION name SYSTEM string >
+/// attlist:
+///     <!ATTLIST name attdef* >
+/// attdef:
+///     name atttype default
+/// atttype:
+///     ( name | name )
+///     NOTATION ( name | name )
+///     name
+/// default:
+///     #REQUIRED
+///     #IMPLIED
+///     #FIXED string
+///     string
+/// ```
+use super::options::{AttrNormalization, ParseOptions};
+use super::warning::ParseWarningKind;
+use nom::{
+    branch::alt,
+    bytes::complete::{is_not, tag, take_until},
+    character::complete::{multispace0, multispace1},
+    combinator::{map, opt, recognize},
+    multi::many0,
+    sequence::{delimited, preceded, tuple},
+    IResult,
+};
+use std::collections::HashMap;
+
+/// Default value of an attribute declared by `<!ATTLIST>`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AttrDefault {
+    Required,
+    Implied,
+    Fixed(String),
+    Value(String),
+}
+
+impl AttrDefault {
+    #[allow(dead_code)]
+    /// value which is used when the attribute is missing
+    pub fn value(&self) -> Option<&str> {
+        match self {
+            AttrDefault::Fixed(v) | AttrDefault::Value(v) => Some(v.as_str()),
+            _ => None,
+        }
+    }
+}
+
+/// Attribute declaration of `<!ATTLIST>`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AttlistDecl {
+    pub element: String,
+    pub name: String,
+    pub attr_type: String,
+    pub default: AttrDefault,
+}
+
+/// Entity declaration `<!ENTITY name "value">` or `<!ENTITY name SYSTEM "system_id" NDATA notation>`
+///
+/// `value` is `None` for external entities, `parameter` is true for `<!ENTITY % name ...>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntityDecl {
+    pub name: String,
+    pub parameter: bool,
+    pub value: Option<String>,
+    pub public_id: Option<String>,
+    pub system_id: Option<String>,
+    pub notation: Option<String>,
+}
+
+impl std::fmt::Display for EntityDecl {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "<!ENTITY {}{}", if self.parameter { "% " } else { "" }, self.name)?;
+        match &self.value {
+            Some(value) => write!(f, " {}", quote(value))?,
+            None => write_external_id(f, &self.public_id, &self.system_id)?,
+        }
+        if let Some(notation) = &self.notation {
+            write!(f, " NDATA {}", notation)?;
+        }
+        write!(f, ">")
+    }
+}
+
+/// Notation declaration `<!NOTATION name PUBLIC "public_id" "system_id">`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NotationDecl {
+    pub name: String,
+    pub public_id: Option<String>,
+    pub system_id: Option<String>,
+}
+
+impl std::fmt::Display for NotationDecl {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "<!NOTATION {}", self.name)?;
+        write_external_id(f, &self.public_id, &self.system_id)?;
+        write!(f, ">")
+    }
+}
+
+/// Document type declaration `<!DOCTYPE name PUBLIC "public_id" "system_id" [internal_subset]>`
+///
+/// `entities` and `notations` are the declarations found in `internal_subset`.
+/// Use the builder methods to construct a DOCTYPE, they keep `internal_subset` in sync:
+///
+/// ```
+/// use etree::Doctype;
+///
+/// let doctype = Doctype::new("html")
+///     .with_public_id("-//W3C//DTD XHTML 1.0 Strict//EN", "http://www.w3.org/TR/xhtml1/DTD/xhtml1-strict.dtd")
+///     .with_entity("copy", "&#169;");
+/// assert_eq!(
+///     doctype.to_string(),
+///     concat!(
+///         r#"<!DOCTYPE html PUBLIC "-//W3C//DTD XHTML 1.0 Strict//EN" "#,
+///         r#""http://www.w3.org/TR/xhtml1/DTD/xhtml1-strict.dtd" [<!ENTITY copy "&#169;">]>"#
+///     )
+/// );
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Doctype {
+    pub name: String,
+    pub public_id: Option<String>,
+    pub system_id: Option<String>,
+    pub internal_subset: Option<String>,
+    pub entities: Vec<EntityDecl>,
+    pub notations: Vec<NotationDecl>,
+}
+
+impl Doctype {
+    #[allow(dead_code)]
+    pub fn new(name: &str) -> Doctype {
+        Doctype {
+            name: name.to_string(),
+            public_id: None,
+            system_id: None,
+            internal_subset: None,
+            entities: Vec::new(),
+            notations: Vec::new(),
+        }
+    }
+    #[allow(dead_code)]
+    pub fn with_public_id(mut self, public_id: &str, system_id: &str) -> Doctype {
+        self.public_id = Some(public_id.to_string());
+        self.system_id = Some(system_id.to_string());
+        self
+    }
+    #[allow(dead_code)]
+    pub fn with_system_id(mut self, system_id: &str) -> Doctype {
+        self.public_id = None;
+        self.system_id = Some(system_id.to_string());
+        self
+    }
+    #[allow(dead_code)]
+    /// declare an internal entity, `value` is kept as written (references in it are expanded on use)
+    pub fn with_entity(self, name: &str, value: &str) -> Doctype {
+        self.with_entity_decl(EntityDecl {
+            name: name.to_string(),
+            parameter: false,
+            value: Some(value.to_string()),
+            public_id: None,
+            system_id: None,
+            notation: None,
+        })
+    }
+    #[allow(dead_code)]
+    /// declare an entity
+    pub fn with_entity_decl(mut self, entity: EntityDecl) -> Doctype {
+        self.append_subset(&entity.to_string());
+        self.entities.push(entity);
+        self
+    }
+    #[allow(dead_code)]
+    /// declare a notation
+    pub fn with_notation(mut self, notation: NotationDecl) -> Doctype {
+        self.append_subset(&notation.to_string());
+        self.notations.push(notation);
+        self
+    }
+    /// text between "<!DOCTYPE" and ">" as stored in the `<DocType>` node
+    pub(crate) fn text(&self) -> String {
+        let mut out = format!(" {}", self.name);
+        match (&self.public_id, &self.system_id) {
+            (Some(public_id), Some(system_id)) => out += &format!(" PUBLIC {} {}", quote(public_id), quote(system_id)),
+            (_, Some(system_id)) => out += &format!(" SYSTEM {}", quote(system_id)),
+            _ => {}
+        }
+        if let Some(subset) = &self.internal_subset {
+            out += &format!(" [{}]", subset);
+        }
+        out
+    }
+    fn append_subset(&mut self, decl: &str) {
+        self.internal_subset.get_or_insert_with(String::new).push_str(decl);
+    }
+}
+
+impl std::fmt::Display for Doctype {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "<!DOCTYPE{}>", self.text())
+    }
+}
+
+/// quote a literal with `"` unless it contains `"`
+fn quote(value: &str) -> String {
+    if value.contains('"') {
+        format!("'{}'", value)
+    } else {
+        format!("\"{}\"", value)
+    }
+}
+
+fn write_external_id(
+    f: &mut std::fmt::Formatter,
+    public_id: &Option<String>,
+    system_id: &Option<String>,
+) -> std::fmt::Result {
+    if let Some(public_id) = public_id {
+        write!(f, " PUBLIC {}", quote(public_id))?;
+        if let Some(system_id) = system_id {
+            write!(f, " {}", quote(system_id))?;
+        }
+    } else if let Some(system_id) = system_id {
+        write!(f, " SYSTEM {}", quote(system_id))?;
+    }
+    Ok(())
+}
+
+/// get internal subset (text between "[" and "]") of DOCTYPE text
+pub(crate) fn internal_subset(doctype: &str) -> Option<&str> {
+    let mut quote = None;
+    let mut start = None;
+    for (i, c) in doctype.char_indices() {
+        match (quote, c) {
+            (Some(q), _) if q == c => quote = None,
+            (Some(_), _) => {}
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (None, '[') if start.is_none() => start = Some(i + 1),
+            _ => {}
+        }
+    }
+    let start = start?;
+    let end = doctype.rfind(']').filter(|x| *x >= start)?;
+    doctype.get(start..end)
+}
+
+/// split internal subset into markup declarations (`<!...>`), comments and PIs are skipped
+pub(crate) fn declarations(subset: &str) -> Vec<&str> {
+    let mut out = Vec::new();
+    let mut rest = subset;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start..];
+        if rest.starts_with("<!--") {
+            rest = rest.find("-->").map(|x| &rest[x + 3..]).unwrap_or("");
+        } else if rest.starts_with("<?") {
+            rest = rest.find("?>").map(|x| &rest[x + 2..]).unwrap_or("");
+        } else {
+            let mut quote = None;
+            let mut end = rest.len();
+            for (i, c) in rest.char_indices() {
+                match (quote, c) {
+                    (Some(q), _) if q == c => quote = None,
+                    (Some(_), _) => {}
+                    (None, '"') | (None, '\'') => quote = Some(c),
+                    (None, '>') => {
+                        end = i + 1;
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+            out.push(&rest[..end]);
+            rest = &rest[end..];
+        }
+    }
+    out
+}
+
+fn name(input: &str) -> IResult<&str, &str> {
+    is_not(" \t\r\n>()|\"'")(input)
+}
+
+pub(crate) fn string(input: &str) -> IResult<&str, String> {
+    map(
+        alt((
+            delimited(tag("\""), take_until("\""), tag("\"")),
+            delimited(tag("'"), take_until("'"), tag("'")),
+        )),
+        |t: &str| t.to_string(),
+    )(input)
+}
+
+fn atttype(input: &str) -> IResult<&str, &str> {
+    alt((
+        recognize(tuple((tag("NOTATION"), multispace1, tag("("), is_not(")"), tag(")")))),
+        recognize(tuple((tag("("), is_not(")"), tag(")")))),
+        name,
+    ))(input)
+}
+
+fn default(input: &str) -> IResult<&str, AttrDefault> {
+    alt((
+        map(tag("#REQUIRED"), |_| AttrDefault::Required),
+        map(tag("#IMPLIED"), |_| AttrDefault::Implied),
+        map(preceded(tuple((tag("#FIXED"), multispace1)), string), AttrDefault::Fixed),
+        map(string, AttrDefault::Value),
+    ))(input)
+}
+
+fn attdef(input: &str) -> IResult<&str, (&str, &str, AttrDefault)> {
+    map(
+        tuple((multispace1, name, multispace1, atttype, multispace1, default)),
+        |t| (t.1, t.3, t.5),
+    )(input)
+}
+
+fn attlist(input: &str) -> IResult<&str, Vec<AttlistDecl>> {
+    map(
+        tuple((tag("<!ATTLIST"), multispace1, name, many0(attdef), multispace0, tag(">"))),
+        |(_, _, element, attdefs, _, _)| {
+            attdefs
+                .into_iter()
+                .map(|x| AttlistDecl {
+                    element: element.to_string(),
+                    name: x.0.to_string(),
+                    attr_type: x.1.to_string(),
+                    default: x.2,
+                })
+                .collect()
+        },
+    )(input)
+}
+
+fn external_id(input: &str) -> IResult<&str, (Option<String>, Option<String>)> {
+    alt((
+        map(
+            tuple((tag("PUBLIC"), multispace1, string, multispace1, string)),
+            |t| (Some(t.2), Some(t.4)),
+        ),
+        map(tuple((tag("SYSTEM"), multispace1, string)), |t| (None, Some(t.2))),
+    ))(input)
+}
+
+/// parse DOCTYPE text (between "<!DOCTYPE" and ">")
+pub(crate) fn doctype(text: &str) -> Option<Doctype> {
+    let (_, (_, name, external)) =
+        tuple((multispace0, is_not(" \t\r\n["), opt(preceded(multispace1, external_id))))(text).ok()?;
+    let (public_id, system_id) = external.unwrap_or((None, None));
+    let subset = internal_subset(text);
+    let decls = subset.map(declarations).unwrap_or_default();
+    Some(Doctype {
+        name: name.to_string(),
+        public_id,
+        system_id,
+        internal_subset: subset.map(|x| x.to_string()),
+        entities: decls.iter().filter_map(|x| entity_decl(x).ok()).map(|x| x.1).collect(),
+        notations: decls.iter().filter_map(|x| notation_decl(x).ok()).map(|x| x.1).collect(),
+    })
+}
+
+fn entity_decl(input: &str) -> IResult<&str, EntityDecl> {
+    let value = map(string, |x| (Some(x), (None, None), None));
+    let external = map(
+        tuple((external_id, opt(preceded(tuple((multispace1, tag("NDATA"), multispace1)), name)))),
+        |(id, notation)| (None, id, notation.map(|x| x.to_string())),
+    );
+    map(
+        tuple((
+            tag("<!ENTITY"),
+            multispace1,
+            opt(tuple((tag("%"), multispace1))),
+            name,
+            multispace1,
+            alt((value, external)),
+            multispace0,
+            tag(">"),
+        )),
+        |t| EntityDecl {
+            name: t.3.to_string(),
+            parameter: t.2.is_some(),
+            value: (t.5).0,
+            public_id: ((t.5).1).0,
+            system_id: ((t.5).1).1,
+            notation: (t.5).2,
+        },
+    )(input)
+}
+
+fn notation_decl(input: &str) -> IResult<&str, NotationDecl> {
+    let public_only = map(tuple((tag("PUBLIC"), multispace1, string)), |t| (Some(t.2), None));
+    map(
+        tuple((
+            tag("<!NOTATION"),
+            multispace1,
+            name,
+            multispace1,
+            alt((external_id, public_only)),
+            multispace0,
+            tag(">"),
+        )),
+        |t| NotationDecl {
+            name: t.2.to_string(),
+            public_id: (t.4).0,
+            system_id: (t.4).1,
+        },
+    )(input)
+}
+
+fn entity(input: &str) -> IResult<&str, (String, Option<String>)> {
+    map(
+        tuple((
+            tag("<!ENTITY"),
+            multispace1,
+            name,
+            multispace1,
+            alt((map(string, Some), map(external_id, |_| None))),
+            take_until(">"),
+            tag(">"),
+        )),
+        |t| (t.2.to_string(), t.4),
+    )(input)
+}
+
+/// parse general entities in DOCTYPE text, the first declaration wins
+///
+/// Values of external entities (`SYSTEM` or `PUBLIC`) are `None`, parameter entities are skipped.
+pub(crate) fn entities(doctype: &str) -> HashMap<String, Option<String>> {
+    let mut out = HashMap::new();
+    if let Some(subset) = internal_subset(doctype) {
+        for decl in declarations(subset) {
+            if let Ok((_, (name, value))) = entity(decl) {
+                out.entry(name).or_insert(value);
+            }
+        }
+    }
+    out
+}
+
+/// Entity references replacer which keeps count of expanded bytes in a document
+pub(crate) struct Unescaper<'a> {
+    options: &'a ParseOptions,
+    expanded: usize,
+    xml11: bool,
+    warnings: Option<Vec<ParseWarningKind>>,
+}
+
+impl<'a> Unescaper<'a> {
+    pub(crate) fn new(options: &'a ParseOptions) -> Unescaper<'a> {
+        Unescaper {
+            options,
+            expanded: 0,
+            xml11: false,
+            warnings: None,
+        }
+    }
+    /// keep unknown entities and replace invalid character references instead of failing, see `take_warnings`
+    pub(crate) fn set_lenient(&mut self, lenient: bool) {
+        self.warnings = if lenient { Some(Vec::new()) } else { None };
+    }
+    /// take the warnings about references tolerated since the last call
+    pub(crate) fn take_warnings(&mut self) -> Vec<ParseWarningKind> {
+        self.warnings.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+    pub(crate) fn options(&self) -> &ParseOptions {
+        self.options
+    }
+    pub(crate) fn expanded(&self) -> usize {
+        self.expanded
+    }
+    /// continue counting from a previous chunk of the document
+    pub(crate) fn set_expanded(&mut self, expanded: usize) {
+        self.expanded = expanded;
+    }
+    /// check character references with XML 1.1 rules, which allow control characters
+    pub(crate) fn set_xml11(&mut self, xml11: bool) {
+        self.xml11 = xml11;
+    }
+    /// replace entity and character references in raw text
+    ///
+    /// References to declared entities are kept as-is if `expand_entities` is false, all references are kept
+    /// if `preserve_references` is true. Unknown entities are errors.
+    pub(crate) fn unescape(&mut self, raw: &str, entities: &HashMap<String, Option<String>>) -> Result<String, String> {
+        self.unescape_nested(raw, entities, 0, false)
+    }
+    /// unescape raw attribute value, whitespaces are normalized according to `attr_normalization`
+    pub(crate) fn unescape_attr(
+        &mut self,
+        raw: &str,
+        entities: &HashMap<String, Option<String>>,
+    ) -> Result<String, String> {
+        match self.options.attr_normalization {
+            AttrNormalization::Unescape => self.unescape_nested(raw, entities, 0, false),
+            AttrNormalization::Normalize => self.unescape_nested(&normalize_space(raw), entities, 0, true),
+            AttrNormalization::Raw => Ok(raw.to_string()),
+        }
+    }
+    fn unescape_nested(
+        &mut self,
+        raw: &str,
+        entities: &HashMap<String, Option<String>>,
+        depth: usize,
+        normalize: bool,
+    ) -> Result<String, String> {
+        if depth > self.options.max_entity_depth {
+            return Err(format!("entity references nested deeper than {}", self.options.max_entity_depth));
+        }
+        let mut out = String::with_capacity(raw.len());
+        let mut rest = raw;
+        while let Some(start) = rest.find('&') {
+            out.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let end = rest.find(';').ok_or_else(|| "unterminated entity reference".to_string())?;
+            let name = &rest[1..end];
+            match name {
+                _ if self.options.preserve_references => {
+                    check_reference(name, entities, self.xml11)?;
+                    out.push_str(&rest[..=end]);
+                }
+                "lt" => out.push('<'),
+                "gt" => out.push('>'),
+                "amp" => out.push('&'),
+                "apos" => out.push('\''),
+                "quot" => out.push('"'),
+                _ if name.starts_with('#') => {
+                    let code = match name.strip_prefix("#x") {
+                        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                        None => name[1..].parse().ok(),
+                    };
+                    match (code.and_then(std::char::from_u32).filter(|&c| is_char(c, self.xml11)), &mut self.warnings) {
+                        (Some(c), _) => out.push(c),
+                        (None, Some(warnings)) => {
+                            warnings.push(ParseWarningKind::InvalidCharacter(rest[..=end].to_string()));
+                            out.push('\u{fffd}');
+                        }
+                        (None, None) => return Err(format!("invalid character reference &{};", name)),
+                    }
+                }
+                _ => match entities.get(name) {
+                    Some(_) if !self.options.expand_entities => out.push_str(&rest[..=end]),
+                    Some(Some(value)) => {
+                        self.expanded += value.len().max(1);
+                        if self.expanded > self.options.max_entity_expansion {
+                            return Err(format!(
+                                "entity expansion exceeds {} bytes",
+                                self.options.max_entity_expansion
+                            ));
+                        }
+                        let value = if normalize { normalize_space(value) } else { value.clone() };
+                        out.push_str(&self.unescape_nested(&value, entities, depth + 1, normalize)?);
+                    }
+                    Some(None) => return Err(format!("external entity &{}; is not expanded", name)),
+                    None => match &mut self.warnings {
+                        Some(warnings) => {
+                            warnings.push(ParseWarningKind::UnknownEntity(name.to_string()));
+                            out.push_str(&rest[..=end]);
+                        }
+                        None => return Err(format!("unknown entity &{};", name)),
+                    },
+                },
+            }
+            rest = &rest[end + 1..];
+        }
+        out.push_str(rest);
+        Ok(out)
+    }
+}
+
+/// replace literal line ends and tabs by spaces, a "\r\n" pair becomes one space
+fn normalize_space(raw: &str) -> String {
+    raw.replace("\r\n", " ").replace(['\t', '\n', '\r'], " ")
+}
+
+/// trim and collapse runs of spaces in a normalized value of a tokenized attribute type
+pub(crate) fn collapse_space(value: &str) -> String {
+    value.split(' ').filter(|x| !x.is_empty()).collect::<Vec<_>>().join(" ")
+}
+
+/// check whether `c` is allowed in a document, XML 1.1 also allows control characters except NUL
+pub(crate) fn is_char(c: char, xml11: bool) -> bool {
+    match c {
+        '\t' | '\n' | '\r' => true,
+        '\u{0}' => false,
+        '\u{1}'..='\u{1f}' => xml11,
+        '\u{fffe}' | '\u{ffff}' => false,
+        _ => true,
+    }
+}
+
+/// fail if `name` of a reference is neither a valid character reference nor a known entity
+fn check_reference(name: &str, entities: &HashMap<String, Option<String>>, xml11: bool) -> Result<(), String> {
+    if let Some(code) = name.strip_prefix('#') {
+        let code = match code.strip_prefix('x') {
+            Some(hex) => u32::from_str_radix(hex, 16).ok(),
+            None => code.parse().ok(),
+        };
+        match code.and_then(std::char::from_u32).filter(|&c| is_char(c, xml11)) {
+            Some(_) => Ok(()),
+            None => Err(format!("invalid character reference &{};", name)),
+        }
+    } else if ["lt", "gt", "amp", "apos", "quot"].contains(&name) || entities.contains_key(name) {
+        Ok(())
+    } else {
+        Err(format!("unknown entity &{};", name))
+    }
+}
+
+/// parse all `<!ATTLIST>` declarations in DOCTYPE text
+pub(crate) fn attlists(doctype: &str) -> Vec<AttlistDecl> {
+    let mut out = Vec::new();
+    if let Some(subset) = internal_subset(doctype) {
+        for decl in declarations(subset) {
+            if let Ok((_, mut items)) = attlist(decl) {
+                out.append(&mut items);
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_internal_subset() {
+        assert_eq!(internal_subset(" root [ <!ENTITY a \"]\"> ]"), Some(" <!ENTITY a \"]\"> "));
+        assert_eq!(internal_subset(" html PUBLIC \"-//W3C//DTD XHTML 1.0 Strict//EN\""), None);
+    }
+    #[test]
+    fn test_doctype() {
+        assert_eq!(
+            doctype(" html PUBLIC \"-//W3C//DTD XHTML 1.0 Strict//EN\"\n  'http://www.w3.org/TR/xhtml1/DTD/xhtml1-strict.dtd'"),
+            Some(Doctype {
+                name: "html".to_string(),
+                public_id: Some("-//W3C//DTD XHTML 1.0 Strict//EN".to_string()),
+                system_id: Some("http://www.w3.org/TR/xhtml1/DTD/xhtml1-strict.dtd".to_string()),
+                internal_subset: None,
+                entities: Vec::new(),
+                notations: Vec::new(),
+            })
+        );
+        assert_eq!(
+            doctype(" note SYSTEM \"note.dtd\" [<!ENTITY a \"b\">]"),
+            Some(Doctype {
+                name: "note".to_string(),
+                public_id: None,
+                system_id: Some("note.dtd".to_string()),
+                internal_subset: Some("<!ENTITY a \"b\">".to_string()),
+                entities: vec![EntityDecl {
+                    name: "a".to_string(),
+                    parameter: false,
+                    value: Some("b".to_string()),
+                    public_id: None,
+                    system_id: None,
+                    notation: None,
+                }],
+                notations: Vec::new(),
+            })
+        );
+        assert_eq!(doctype(" root[]").map(|x| x.name), Some("root".to_string()));
+        assert_eq!(doctype(""), None);
+    }
+    #[test]
+    fn test_doctype_decls() {
+        let text = concat!(
+            " doc [\n",
+            "  <!NOTATION gif PUBLIC \"image/gif\">\n",
+            "  <!ENTITY % p \"x\">\n",
+            "  <!ENTITY logo SYSTEM \"logo.gif\" NDATA gif>\n",
+            "]"
+        );
+        let parsed = doctype(text).unwrap();
+        assert_eq!(parsed.notations.len(), 1);
+        assert_eq!(parsed.entities.len(), 2);
+        assert!(parsed.entities[0].parameter);
+        assert_eq!(parsed.entities[1].notation.as_deref(), Some("gif"));
+        assert_eq!(parsed.notations[0].to_string(), "<!NOTATION gif PUBLIC \"image/gif\">");
+        assert_eq!(parsed.entities[1].to_string(), "<!ENTITY logo SYSTEM \"logo.gif\" NDATA gif>");
+        let built = Doctype::new("doc")
+            .with_system_id("doc.dtd")
+            .with_notation(parsed.notations[0].clone())
+            .with_entity("q", "say \"hi\"");
+        assert_eq!(
+            built.to_string(),
+            "<!DOCTYPE doc SYSTEM \"doc.dtd\" [<!NOTATION gif PUBLIC \"image/gif\"><!ENTITY q 'say \"hi\"'>]>"
+        );
+        assert_eq!(doctype(&built.text()), Some(built));
+    }
+    #[test]
+    fn test_entities() {
+        let entities = entities(" doc [<!ENTITY a \"A&b;\"> <!ENTITY b 'B'> <!ENTITY % p \"P\"> <!ENTITY e SYSTEM \"e.xml\">]");
+        assert_eq!(entities.len(), 3);
+        assert_eq!(entities.get("e"), Some(&None));
+        let options = ParseOptions::new();
+        let mut unescaper = Unescaper::new(&options);
+        assert_eq!(unescaper.unescape("&a;&#65;&#x42;&lt;", &entities), Ok("ABAB<".to_string()));
+        assert_eq!(
+            unescaper.unescape("&e;", &entities),
+            Err("external entity &e; is not expanded".to_string())
+        );
+        let options = ParseOptions::new().with_expand_entities(false);
+        let mut unescaper = Unescaper::new(&options);
+        assert_eq!(unescaper.unescape("&a;&e;&amp;", &entities), Ok("&a;&e;&".to_string()));
+        let looped = super::entities(" x [<!ENTITY x \"&x;\">]");
+        let options = ParseOptions::new();
+        assert!(Unescaper::new(&options).unescape("&x;", &looped).is_err());
+    }
+    #[test]
+    fn test_entity_bomb() {
+        let mut subset = "<!ENTITY lol0 \"lol\">".to_string();
+        for i in 1..10 {
+            subset += &format!("<!ENTITY lol{} \"{}\">", i, format!("&lol{};", i - 1).repeat(10));
+        }
+        let entities = entities(&format!(" lolz [{}]", subset));
+        let options = ParseOptions::new();
+        let e = Unescaper::new(&options).unescape("&lol9;", &entities).unwrap_err();
+        assert_eq!(e, "entity expansion exceeds 1048576 bytes");
+        let options = ParseOptions::new().with_max_entity_depth(4);
+        let e = Unescaper::new(&options).unescape("&lol9;", &entities).unwrap_err();
+        assert_eq!(e, "entity references nested deeper than 4");
+    }
+    #[test]
+    fn test_declarations() {
+        assert_eq!(
+            declarations("<!-- <!x> --> <!ATTLIST a b CDATA '>'> <?pi?> <!ELEMENT a ANY>"),
+            vec!["<!ATTLIST a b CDATA '>'>", "<!ELEMENT a ANY>"]
+        );
+    }
+    #[test]
+    fn test_attlist() {
+        assert_eq!(
+            attlists(" root [<!ATTLIST a b CDATA \"x&y\"\n  c (on|off) #FIXED 'on' d ID #REQUIRED>]"),
+            vec![
+                AttlistDecl {
+                    element: "a".to_string(),
+                    name: "b".to_string(),
+                    attr_type: "CDATA".to_string(),
+                    default: AttrDefault::Value("x&y".to_string()),
+                },
+                AttlistDecl {
+                    element: "a".to_string(),
+                    name: "c".to_string(),
+                    attr_type: "(on|off)".to_string(),
+                    default: AttrDefault::Fixed("on".to_string()),
+                },
+                AttlistDecl {
+                    element: "a".to_string(),
+                    name: "d".to_string(),
+                    attr_type: "ID".to_string(),
+                    default: AttrDefault::Required,
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,261 @@
+use super::namespace::NamespaceError;
+use super::schema::ValidationError;
+use super::wellformed::WellFormednessError;
+use std::path::PathBuf;
+
+/// Error of etree
+///
+/// - `Io`: reading or writing a file failed, `path` is the file if known
+/// - `Parse`: the document is malformed, see `ParseError`
+/// - `Xml`: quick-xml failed to serialize the document
+/// - `InvalidState`: an API was called in a wrong order (e.g. `XmlWriter::end_element` without open element) or
+///   with content that can't be written (e.g. a comment containing "--")
+/// - `Tree`: internal structure of the node at position `pos` is broken
+/// - `XPath`: the expression is invalid from byte `position` to `end`
+/// - `Selector`: the CSS selector is invalid or not supported at byte `position`
+/// - `Value`: the text of element `name`, or its attribute `attr`, is missing or can't be parsed (e.g. by
+///   `ETreeNode::get_text_as`)
+/// - `Validation`: `ETree::validate_with` found errors
+/// - `Namespace`: `ETree::check_namespaces` found errors
+/// - `WellFormedness`: `ETree::parse_strict` found errors
+#[derive(Debug)]
+pub enum Error {
+    Io {
+        path: Option<PathBuf>,
+        source: std::io::Error,
+    },
+    Parse {
+        path: Option<PathBuf>,
+        source: ParseError,
+    },
+    Xml(quick_xml::Error),
+    InvalidState(&'static str),
+    Tree {
+        pos: usize,
+        message: String,
+    },
+    XPath {
+        expr: String,
+        position: usize,
+        end: usize,
+        message: String,
+    },
+    Selector {
+        selector: String,
+        position: usize,
+        message: String,
+    },
+    Value {
+        name: String,
+        attr: Option<String>,
+        value: Option<String>,
+        message: String,
+    },
+    Validation(Vec<ValidationError>),
+    Namespace(Vec<NamespaceError>),
+    WellFormedness(Vec<WellFormednessError>),
+}
+
+/// Error of parsing, located by byte `offset` and 1-based `line` and `column` (in characters)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl ParseError {
+    /// locate byte `offset` in `content`
+    pub(crate) fn new(content: &str, offset: usize, message: String) -> ParseError {
+        let mut offset = offset.min(content.len());
+        while !content.is_char_boundary(offset) {
+            offset -= 1;
+        }
+        let before = &content[..offset];
+        let line_start = before.rfind('\n').map(|x| x + 1).unwrap_or(0);
+        ParseError {
+            offset,
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+            message,
+        }
+    }
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "malformed XML at line {}, column {} (byte {}): {}",
+            self.line, self.column, self.offset, self.message
+        )
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Error of writing
+///
+/// Kept for compatibility, all errors are reported by `Error`.
+pub type WriteError = Error;
+
+/// Error of XPath expressions
+///
+/// Reported as `Error::XPath` with the byte range of the offending part of the expression.
+pub type XPathError = Error;
+
+impl Error {
+    /// attach file path to the error
+    pub(crate) fn with_path(self, file: PathBuf) -> Error {
+        match self {
+            Error::Io { path: None, source } => Error::Io {
+                path: Some(file),
+                source,
+            },
+            Error::Parse { path: None, source } => Error::Parse {
+                path: Some(file),
+                source,
+            },
+            other => other,
+        }
+    }
+}
+
+fn write_path(f: &mut std::fmt::Formatter, path: &Option<PathBuf>) -> std::fmt::Result {
+    match path {
+        Some(path) => write!(f, "{}: ", path.display()),
+        None => Ok(()),
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::Io { path, source } => {
+                write_path(f, path)?;
+                write!(f, "{}", source)
+            }
+            Error::Parse { path, source } => {
+                write_path(f, path)?;
+                write!(f, "{}", source)
+            }
+            Error::Xml(e) => write!(f, "XML error: {}", e),
+            Error::InvalidState(message) => write!(f, "invalid state: {}", message),
+            Error::Tree { pos, message } => write!(f, "broken tree at node [{}]: {}", pos, message),
+            Error::XPath { expr, position, message, .. } => {
+                write!(f, "invalid XPath {:?}: {} at offset {}", expr, message, position)
+            }
+            Error::Selector {
+                selector,
+                position,
+                message,
+            } => write!(f, "invalid CSS selector {:?}: {} at offset {}", selector, message, position),
+            Error::Value {
+                name,
+                attr,
+                value,
+                message,
+            } => {
+                match attr {
+                    Some(attr) => write!(f, "attribute {:?} of <{}>", attr, name)?,
+                    None => write!(f, "text of <{}>", name)?,
+                }
+                match value {
+                    Some(value) => write!(f, " has invalid value {:?}: {}", value, message),
+                    None => write!(f, ": {}", message),
+                }
+            }
+            Error::Validation(errors) => {
+                write!(f, "{} validation error(s)", errors.len())?;
+                for e in errors.iter() {
+                    write!(f, "\n  {}", e)?;
+                }
+                Ok(())
+            }
+            Error::Namespace(errors) => {
+                write!(f, "{} namespace error(s)", errors.len())?;
+                for e in errors.iter() {
+                    write!(f, "\n  {}", e)?;
+                }
+                Ok(())
+            }
+            Error::WellFormedness(errors) => {
+                write!(f, "{} well-formedness error(s)", errors.len())?;
+                for e in errors.iter() {
+                    write!(f, "\n  {}", e)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            Error::Parse { source, .. } => Some(source),
+            Error::Xml(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(value: std::io::Error) -> Self {
+        Error::Io {
+            path: None,
+            source: value,
+        }
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(value: ParseError) -> Self {
+        Error::Parse {
+            path: None,
+            source: value,
+        }
+    }
+}
+
+impl From<quick_xml::Error> for Error {
+    fn from(value: quick_xml::Error) -> Self {
+        match value {
+            quick_xml::Error::Io(e) => Error::from(e),
+            other => Error::Xml(other),
+        }
+    }
+}
+
+impl From<Vec<ValidationError>> for Error {
+    fn from(value: Vec<ValidationError>) -> Self {
+        Error::Validation(value)
+    }
+}
+
+impl From<Vec<NamespaceError>> for Error {
+    fn from(value: Vec<NamespaceError>) -> Self {
+        Error::Namespace(value)
+    }
+}
+
+impl From<Vec<WellFormednessError>> for Error {
+    fn from(value: Vec<WellFormednessError>) -> Self {
+        Error::WellFormedness(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_display() {
+        let e = Error::from(std::io::Error::new(std::io::ErrorKind::NotFound, "not found")).with_path(PathBuf::from("a.xml"));
+        assert_eq!(e.to_string(), "a.xml: not found");
+        assert!(std::error::Error::source(&e).is_some());
+        let e = Error::from(ParseError::new("<a>\n  <b>", 7, "unexpected end".to_string()));
+        assert_eq!(e.to_string(), "malformed XML at line 2, column 4 (byte 7): unexpected end");
+    }
+}
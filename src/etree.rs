@@ -1,13 +1,38 @@
-use super::etreenode::ETreeNode;
+#[cfg(feature = "async")]
+use super::builder::TreeBuilder;
+use super::builder::Documents;
+use super::canonical::{Canonicalizer, DigestWriter};
+use super::css;
+use super::dtd::{self, AttlistDecl, Doctype};
+use super::error::{Error, ParseError, WriteError, XPathError};
+use super::etreenode::{ETreeNode, NodeKind, XML_NAMESPACE, XSI_NAMESPACE};
+use super::namespace::Declarations;
+use super::handler::{self, Control, ParseHandler};
+use super::stream::StreamMatches;
+use super::view::SubtreeView;
+use super::options::{
+    AttrNormalization, AttrOrder, AttrStyle, CompareOptions, Newline, ParseOptions, PrettyOptions, QuoteChar,
+};
+use super::warning::{ParseWarning, ParseWarningKind};
+use super::writer::{self, EscapePolicy};
 use super::xpath;
+use encoding_rs::{DecoderResult, Encoding, UTF_16BE, UTF_16LE, UTF_8};
 use quick_xml::events::{BytesDecl, BytesEnd, BytesStart, BytesText, Event};
 use quick_xml::{Reader, Writer};
 use regex::Regex;
-use std::collections::HashMap;
-use std::fs;
+use std::any::{Any, TypeId};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File};
 use std::io::prelude::*;
-use std::io::Cursor;
+use std::io::BufWriter;
+use std::ops::Range;
+use std::panic::{RefUnwindSafe, UnwindSafe};
 use std::path::Path;
+use std::sync::{Arc, OnceLock};
+
+/// message of the error returned when a `ParseHandler` aborts parsing
+const ABORTED: &str = "parsing aborted by handler";
 
 /// Element tree
 ///
@@ -23,20 +48,252 @@ pub struct ETree {
     crlf: String,
     enable_index: bool,
     index: HashMap<usize, usize>,
+    values: OnceLock<ValueIndex>,
+    enable_hash: bool,
+    hashes: HashMap<usize, u64>,
+    user_data: UserData,
+    attr_style: AttrStyle,
+    attr_order: AttrOrder,
+    escape_policy: EscapePolicy,
+    quote_char: QuoteChar,
+    max_width: Option<usize>,
+    attrs_per_line: Option<usize>,
+    write_doctype: bool,
+    write_declaration: bool,
+    strict_namespaces: bool,
+    cdata_elements: Vec<String>,
+    output_newline: Option<Newline>,
+    round_trip: bool,
+    source_decl: Option<String>,
+    bom: bool,
+    entities: HashMap<String, Option<String>>,
+    references: bool,
+    raw_attributes: bool,
+    fragment: bool,
+    head: Option<String>,
+    xpath_namespaces: HashMap<String, String>,
+    /// options the document was parsed with, used by `reparse`
+    options: ParseOptions,
+}
+
+/// user data of nodes, keyed by idx and type, values are shared between clones until changed
+#[derive(Clone, Default)]
+struct UserData(HashMap<usize, HashMap<TypeId, Arc<dyn UserValue>>>);
+
+/// value of user data, which can be copied when a shared value is changed
+trait UserValue: Any + Send + Sync + UnwindSafe + RefUnwindSafe {
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+    fn clone_arc(&self) -> Arc<dyn UserValue>;
+}
+
+impl<T: Any + Clone + Send + Sync + UnwindSafe + RefUnwindSafe> UserValue for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+    fn clone_arc(&self) -> Arc<dyn UserValue> {
+        Arc::new(self.clone())
+    }
+}
+
+/// positions of elements by name and by attribute (name, value), built on first use after a modification
+#[derive(Debug, Clone, Default)]
+struct ValueIndex {
+    names: HashMap<String, Vec<usize>>,
+    attrs: HashMap<(String, String), Vec<usize>>,
+}
+
+/// nodes written by `ETree::write_nodes`, which are top-level nodes and their descendants
+struct WriteScope {
+    range: Range<usize>,
+    skip_tail: Option<usize>,
+    close_tag: Regex,
+    /// position by idx of nodes in the range
+    idxmap: HashMap<String, usize>,
+}
+
+impl WriteScope {
+    fn new(tree: &ETree, range: Range<usize>, skip_tail: Option<usize>) -> WriteScope {
+        let idxmap = range.clone().map(|idx| (tree.data[idx].get_idx().to_string(), idx)).collect();
+        WriteScope {
+            range,
+            skip_tail,
+            close_tag: Regex::new(r"^(?P<parent>#.*?)(?P<current>\d+)#$").unwrap(),
+            idxmap,
+        }
+    }
+    /// tail of the node at `idx`, empty for the node at `skip_tail`
+    fn tail<'t>(&self, tree: &'t ETree, idx: usize) -> &'t str {
+        if Some(idx) == self.skip_tail {
+            ""
+        } else {
+            tree.data[idx].get_tail_ref()
+        }
+    }
+}
+
+/// 64-bit FNV-1a hash of subtrees, which doesn't depend on the process or the Rust release unlike `DefaultHasher`
+struct Fnv(u64);
+
+impl Fnv {
+    fn new() -> Fnv {
+        Fnv(0xcbf2_9ce4_8422_2325)
+    }
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 = (self.0 ^ b as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    /// write `text` with its length, so adjacent strings can't be shifted into each other
+    fn write_str(&mut self, text: &str) {
+        self.write(&(text.len() as u64).to_le_bytes());
+        self.write(text.as_bytes());
+    }
+}
+
+impl std::fmt::Debug for UserData {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "UserData({} nodes)", self.0.len())
+    }
 }
 
 impl ETree {
     #[allow(dead_code)]
-    pub fn parse_file<P: AsRef<Path>>(path: P) -> Result<ETree, std::io::Error> {
-        let mut fh = fs::OpenOptions::new().read(true).open(path)?;
-        let mut buf = String::new();
-        fh.read_to_string(&mut buf)?;
-        Ok(ETree::parse_str(buf.as_str()))
+    /// parse XML file, gzip (`.gz`) and zstd (`.zst`) files are decompressed with features `gzip` and `zstd`
+    ///
+    /// Compression is detected by the magic bytes at the start of the file, not by the extension.
+    pub fn parse_file<P: AsRef<Path>>(path: P) -> Result<ETree, Error> {
+        let read = || -> Result<ETree, Error> {
+            let mut fh = fs::OpenOptions::new().read(true).open(path.as_ref())?;
+            let mut buf = Vec::new();
+            fh.read_to_end(&mut buf)?;
+            let buf = decompress(buf)?;
+            Ok(ETree::parse_bytes(&buf)?)
+        };
+        read().map_err(|e| e.with_path(path.as_ref().to_path_buf()))
+    }
+    #[cfg(feature = "async")]
+    #[allow(dead_code)]
+    /// parse XML from an async reader, chunks are parsed by `TreeBuilder` as they arrive
+    pub async fn parse_async<R: tokio::io::AsyncRead>(reader: R) -> Result<ETree, Error> {
+        use tokio::io::AsyncReadExt;
+        tokio::pin!(reader);
+        let mut builder = TreeBuilder::new();
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let len = reader.read(&mut buf).await?;
+            if len == 0 {
+                break;
+            }
+            builder.feed(&buf[..len])?;
+        }
+        Ok(builder.finish()?)
+    }
+    #[allow(dead_code)]
+    /// parse XML bytes, the encoding is detected from BOM or the encoding of XML declaration (UTF-8 by default)
+    ///
+    /// The text is transcoded to UTF-8 before parsing, so offsets of parse errors refer to the transcoded text.
+    /// `write()` always produces UTF-8.
+    pub fn parse_bytes(content: &[u8]) -> Result<ETree, ParseError> {
+        ETree::parse_str(&decode(content)?)
+    }
+    #[allow(dead_code)]
+    /// parse XML text, the error tells where the text is malformed
+    pub fn parse_str(content: &str) -> Result<ETree, ParseError> {
+        ETree::parse_with(content, &ParseOptions::default())
+    }
+    #[allow(dead_code)]
+    /// parse XML text with options
+    pub fn parse_with(content: &str, options: &ParseOptions) -> Result<ETree, ParseError> {
+        let mut out = ETree::empty(content);
+        let content = content.strip_prefix('\u{feff}').unwrap_or(content);
+        let mut state = ReadState::new();
+        out.read(content, options, &mut state, &mut None, &mut None)?;
+        out.read_end(content, &state, &mut None)?;
+        out.detect_indent();
+        Ok(out)
+    }
+    #[allow(dead_code)]
+    /// parse XML text with options, `handler` receives events while the tree is built
+    ///
+    /// The handler can skip subtrees, texts and comments, which are then left out of the tree,
+    /// or abort parsing, which returns an error.
+    pub fn parse_with_handler(
+        content: &str,
+        options: &ParseOptions,
+        handler: &mut dyn ParseHandler,
+    ) -> Result<ETree, ParseError> {
+        let mut out = ETree::empty(content);
+        let content = content.strip_prefix('\u{feff}').unwrap_or(content);
+        let mut state = ReadState::new();
+        out.read(content, options, &mut state, &mut None, &mut Some(handler))?;
+        out.read_end(content, &state, &mut None)?;
+        out.detect_indent();
+        Ok(out)
+    }
+    #[allow(dead_code)]
+    /// parse malformed XML text as far as possible and return the tree with the errors recovered from
+    ///
+    /// Mismatched end tags close the matching open element, unclosed elements are closed at the end,
+    /// broken attributes are dropped and unknown entity references are kept as text.
+    pub fn parse_recover(content: &str, options: &ParseOptions) -> (ETree, Vec<ParseError>) {
+        let mut out = ETree::empty(content);
+        let mut errors = Some(Vec::new());
+        let content = content.strip_prefix('\u{feff}').unwrap_or(content);
+        let mut state = ReadState::new();
+        let result = out
+            .read(content, options, &mut state, &mut errors, &mut None)
+            .and_then(|_| out.read_end(content, &state, &mut errors));
+        let mut errors = errors.unwrap_or_default();
+        if let Err(e) = result {
+            errors.push(e);
+        }
+        out.detect_indent();
+        (out, errors)
+    }
+    #[allow(dead_code)]
+    /// parse several XML documents concatenated in one stream, one tree is yielded per document
+    pub fn parse_many<R: Read>(reader: R) -> Documents<R> {
+        ETree::parse_many_with(reader, &ParseOptions::default())
+    }
+    #[allow(dead_code)]
+    /// parse several XML documents concatenated in one stream with options
+    pub fn parse_many_with<R: Read>(reader: R, options: &ParseOptions) -> Documents<R> {
+        Documents::new(reader, options.clone())
+    }
+    #[allow(dead_code)]
+    /// find subtrees matching `path` while XML is read from `reader`, the whole document is never built
+    ///
+    /// The path is restricted to what is known at the start tag of a match, see `StreamMatches`.
+    pub fn stream_find<R: Read>(reader: R, path: &str) -> Result<StreamMatches<R>, XPathError> {
+        StreamMatches::new(reader, path)
     }
     #[allow(dead_code)]
-    pub fn parse_str(content: &str) -> ETree {
+    /// parse XML fragment which may have zero or multiple top-level nodes and no XML declaration
+    ///
+    /// Text before the first node is kept, `write()` omits the XML declaration. Use `roots()` to get top-level elements.
+    pub fn parse_fragment(content: &str) -> Result<ETree, ParseError> {
+        ETree::parse_fragment_with(content, &ParseOptions::default())
+    }
+    /// parse XML fragment with options
+    fn parse_fragment_with(content: &str, options: &ParseOptions) -> Result<ETree, ParseError> {
+        let mut out = ETree::empty(content);
+        out.fragment = true;
+        let content = content.strip_prefix('\u{feff}').unwrap_or(content);
+        let mut state = ReadState::new();
+        out.read(content, options, &mut state, &mut None, &mut None)?;
+        out.read_end(content, &state, &mut None)?;
+        out.detect_indent();
+        Ok(out)
+    }
+    /// empty tree with the line ending and BOM of `content`
+    pub(crate) fn empty(content: &str) -> ETree {
         let fileformat = if content.contains("\r\n") { "\r\n" } else { "\n" };
-        let mut out = ETree {
+        let bom = content.starts_with('\u{feff}');
+        ETree {
             indent: "".to_string(),
             count: 0,
             version: Vec::new(),
@@ -46,15 +303,42 @@ impl ETree {
             crlf: fileformat.to_string(),
             enable_index: false,
             index: HashMap::new(),
-        };
-        out.read(content);
-        out.detect_indent();
-        out
+            values: OnceLock::new(),
+            enable_hash: false,
+            hashes: HashMap::new(),
+            user_data: UserData::default(),
+            attr_style: AttrStyle::Inline,
+            attr_order: AttrOrder::Document,
+            escape_policy: EscapePolicy::new(),
+            quote_char: QuoteChar::Double,
+            max_width: None,
+            attrs_per_line: None,
+            write_doctype: true,
+            write_declaration: true,
+            strict_namespaces: false,
+            cdata_elements: Vec::new(),
+            output_newline: None,
+            round_trip: false,
+            source_decl: None,
+            bom,
+            entities: HashMap::new(),
+            references: false,
+            raw_attributes: false,
+            fragment: false,
+            head: Some(String::new()),
+            xpath_namespaces: HashMap::new(),
+            options: ParseOptions::default(),
+        }
     }
     #[allow(dead_code)]
-    pub fn write_file<P: AsRef<Path>>(&self, path: P) -> Result<(), WriteError> {
-        fs::write(path, self.write()?)?;
-        Ok(())
+    /// write the document to a file through a buffer, the whole document is never held in memory
+    pub fn write_file<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let path = path.as_ref();
+        let file = File::create(path).map_err(|e| Error::from(e).with_path(path.to_path_buf()))?;
+        let mut w = BufWriter::new(file);
+        self.write_to(&mut w)
+            .and_then(|_| w.flush().map_err(Error::from))
+            .map_err(|e| e.with_path(path.to_path_buf()))
     }
     #[allow(dead_code)]
     /// get whether index feature is enabled
@@ -63,19 +347,171 @@ impl ETree {
     }
     #[allow(dead_code)]
     /// set whether index feature is enabled (usable for function `pos()`)
+    ///
+    /// XPath also looks up descendants by element name and by `[@attrname='value']` in an index, which is built by
+    /// the first query after a modification, so repeated queries on a large tree do not scan all descendants.
     pub fn set_enable_index(&mut self, enable_index: bool) {
         self.enable_index = enable_index;
         self.generate_index();
     }
     #[allow(dead_code)]
+    /// get whether subtree hash feature is enabled
+    pub fn get_enable_hash(&self) -> bool {
+        self.enable_hash
+    }
+    #[allow(dead_code)]
+    /// set whether subtree hash feature is enabled (usable for function `subtree_hash()`)
+    pub fn set_enable_hash(&mut self, enable_hash: bool) {
+        self.enable_hash = enable_hash;
+        self.hashes = HashMap::new();
+        if enable_hash {
+            for pos in 0..self.data.len() {
+                if self.data[pos].get_route() == "#" {
+                    self.generate_hash(pos);
+                }
+            }
+        }
+    }
+    #[allow(dead_code)]
+    /// get hash of the subtree rooted at the node of specified position
+    ///
+    /// The hash combines tag, attributes and text of the node with hashes and tails of its children,
+    /// so two subtrees with the same hash can be treated as identical without traversing them.
+    /// Hashes are cached only when subtree hash feature is enabled, and are kept up to date by the
+    /// modification functions of `ETree`. The hash is 64-bit FNV-1a over the contents, so it is the same across
+    /// processes and Rust releases and may be persisted, but it is not a cryptographic digest (see `canonical_digest`).
+    pub fn subtree_hash(&self, pos: usize) -> Option<u64> {
+        if pos < self.data.len() {
+            Some(self.calc_hash(pos))
+        } else {
+            None
+        }
+    }
+    #[allow(dead_code)]
+    /// attach user data to the node of specified position, data is keyed by idx and type `T`
+    ///
+    /// Return false if pos is out of range
+    pub fn set_user_data<T>(&mut self, pos: usize, value: T) -> bool
+    where
+        T: Any + Clone + Send + Sync + UnwindSafe + RefUnwindSafe,
+    {
+        if let Some(node) = self.data.get(pos) {
+            self.user_data
+                .0
+                .entry(node.get_idx())
+                .or_default()
+                .insert(TypeId::of::<T>(), Arc::new(value));
+            true
+        } else {
+            false
+        }
+    }
+    #[allow(dead_code)]
+    /// get user data of type `T` attached to the node of specified position
+    pub fn get_user_data<T: Any + Send + Sync>(&self, pos: usize) -> Option<&T> {
+        let idx = self.data.get(pos)?.get_idx();
+        let data: &dyn UserValue = self.user_data.0.get(&idx)?.get(&TypeId::of::<T>())?.as_ref();
+        data.as_any().downcast_ref::<T>()
+    }
+    #[allow(dead_code)]
+    /// get mut user data of type `T` attached to the node of specified position
+    ///
+    /// Data shared with a clone of this tree is copied first, so the clone keeps its value.
+    pub fn get_user_data_mut<T: Any + Send + Sync>(&mut self, pos: usize) -> Option<&mut T> {
+        let idx = self.data.get(pos)?.get_idx();
+        let data = self.user_data.0.get_mut(&idx)?.get_mut(&TypeId::of::<T>())?;
+        if Arc::get_mut(data).is_none() {
+            *data = data.as_ref().clone_arc();
+        }
+        Arc::get_mut(data)?.as_any_mut().downcast_mut::<T>()
+    }
+    #[allow(dead_code)]
+    /// remove user data of type `T` from the node of specified position and return whether it existed
+    pub fn remove_user_data<T: Any + Send + Sync>(&mut self, pos: usize) -> bool {
+        let idx = match self.data.get(pos) {
+            Some(node) => node.get_idx(),
+            None => return false,
+        };
+        let mut removed = false;
+        if let Some(items) = self.user_data.0.get_mut(&idx) {
+            removed = items.remove(&TypeId::of::<T>()).is_some();
+            if items.is_empty() {
+                self.user_data.0.remove(&idx);
+            }
+        }
+        removed
+    }
+    #[allow(dead_code)]
+    /// get layout of attributes in start tags
+    pub fn get_attr_style(&self) -> AttrStyle {
+        self.attr_style
+    }
+    #[allow(dead_code)]
+    /// set layout of attributes in start tags
+    pub fn set_attr_style(&mut self, attr_style: AttrStyle) {
+        self.attr_style = attr_style;
+    }
+    #[allow(dead_code)]
+    /// get order of attributes in start tags
+    pub fn get_attr_order(&self) -> AttrOrder {
+        self.attr_order
+    }
+    #[allow(dead_code)]
+    /// set order of attributes in start tags, the order stored in nodes is not changed
+    pub fn set_attr_order(&mut self, attr_order: AttrOrder) {
+        self.attr_order = attr_order;
+    }
+    #[allow(dead_code)]
+    /// get optional escapes of texts and attribute values
+    pub fn get_escape_policy(&self) -> EscapePolicy {
+        self.escape_policy
+    }
+    #[allow(dead_code)]
+    /// set optional escapes of texts and attribute values
+    pub fn set_escape_policy(&mut self, escape_policy: EscapePolicy) {
+        self.escape_policy = escape_policy;
+    }
+    #[allow(dead_code)]
+    /// get quote character around attribute values
+    pub fn get_quote_char(&self) -> QuoteChar {
+        self.quote_char
+    }
+    #[allow(dead_code)]
+    /// set quote character around attribute values, start tags kept by `ParseOptions::round_trip` use their own
+    pub fn set_quote_char(&mut self, quote_char: QuoteChar) {
+        self.quote_char = quote_char;
+    }
+    #[allow(dead_code)]
+    /// hash Canonical XML 1.0 (without comments) of the document, or of the subtree at `pos`, with digest `D`
+    ///
+    /// The canonical form is fed to the digest as it is produced, so documents which differ only in formatting
+    /// of tags (attribute order, quotes, empty elements, DOCTYPE, comments) get the same digest.
+    /// Any digest of the RustCrypto family can be used, e.g. `sha2::Sha256`.
+    pub fn canonical_digest<D: sha2::Digest>(&self, pos: Option<usize>) -> Vec<u8> {
+        let mut w = DigestWriter(D::new());
+        let mut canonicalizer = Canonicalizer::new(self, &mut w);
+        // the digest never fails to write
+        let _ = match pos {
+            Some(pos) => canonicalizer.write_subtree(pos),
+            None => canonicalizer.write_document(),
+        };
+        w.0.finalize().to_vec()
+    }
+    #[allow(dead_code)]
+    /// SHA-256 of Canonical XML of the document or the subtree at `pos` in lowercase hex, see `canonical_digest`
+    pub fn canonical_sha256(&self, pos: Option<usize>) -> String {
+        self.canonical_digest::<sha2::Sha256>(pos).iter().map(|x| format!("{:02x}", x)).collect()
+    }
+    #[allow(dead_code)]
     /// get XML version
     pub fn get_version(&self) -> Option<String> {
         String::from_utf8(self.version.clone()).ok()
     }
     #[allow(dead_code)]
-    /// set XML version
+    /// set XML version, control characters are written as character references in version "1.1"
     pub fn set_version(&mut self, version: &str) {
         self.version = version.to_string().into_bytes();
+        self.source_decl = None;
     }
     #[allow(dead_code)]
     /// get XML encoding
@@ -86,6 +522,7 @@ impl ETree {
     /// set XML encoding
     pub fn set_encoding(&mut self, encoding: &str) {
         self.encoding = Some(encoding.to_string().into_bytes());
+        self.source_decl = None;
     }
     #[allow(dead_code)]
     /// get XML standalone
@@ -98,14 +535,323 @@ impl ETree {
     /// set XML standalone
     pub fn set_standalone(&mut self, standalone: &str) {
         self.standalone = Some(standalone.to_string().into_bytes());
+        self.source_decl = None;
+    }
+    #[allow(dead_code)]
+    /// get whether the document starts with a byte order mark
+    pub fn get_bom(&self) -> bool {
+        self.bom
+    }
+    #[allow(dead_code)]
+    /// set whether `write()` emits a UTF-8 byte order mark
+    pub fn set_bom(&mut self, bom: bool) {
+        self.bom = bom;
+    }
+    #[allow(dead_code)]
+    /// get DOCTYPE with name, PUBLIC and SYSTEM identifiers
+    pub fn get_doctype(&self) -> Option<Doctype> {
+        self.data
+            .iter()
+            .find(|x| x.kind() == NodeKind::DocType)
+            .and_then(|x| dtd::doctype(x.get_text().as_deref().unwrap_or("")))
+    }
+    #[allow(dead_code)]
+    /// set DOCTYPE and return its position, `public_id` is only written along with `system_id`
+    ///
+    /// An existing DOCTYPE is replaced, otherwise DOCTYPE is inserted before the first node of the document.
+    ///
+    /// *Warning*: position which is larger than return value and obtained before this function all should be re-obtained
+    pub fn set_doctype(
+        &mut self,
+        name: &str,
+        public_id: Option<&str>,
+        system_id: Option<&str>,
+        internal_subset: Option<&str>,
+    ) -> usize {
+        let doctype = Doctype {
+            name: name.to_string(),
+            public_id: public_id.map(|x| x.to_string()),
+            system_id: system_id.map(|x| x.to_string()),
+            internal_subset: internal_subset.map(|x| x.to_string()),
+            entities: Vec::new(),
+            notations: Vec::new(),
+        };
+        self.set_doctype_decl(&doctype)
+    }
+    #[allow(dead_code)]
+    /// set DOCTYPE built with the builder methods of `Doctype` and return its position, see `set_doctype`
+    pub fn set_doctype_decl(&mut self, doctype: &Doctype) -> usize {
+        let text = doctype.text();
+        self.entities = dtd::entities(&text);
+        if let Some(pos) = self.data.iter().position(|x| x.kind() == NodeKind::DocType) {
+            self.data[pos].set_text(&text);
+            self.update_hash(pos);
+            return pos;
+        }
+        let mut node = ETreeNode::new(NodeKind::DocType.sentinel().unwrap());
+        node.set_text(&text);
+        node.set_tail(if self.crlf.is_empty() { "\n" } else { &self.crlf });
+        node.set_idx(self.count);
+        node.set_route("#");
+        self.data.insert(0, node);
+        self.index.insert(self.count, 0);
+        self.update_index(1);
+        self.update_hash(0);
+        self.count += 1;
+        0
+    }
+    #[allow(dead_code)]
+    /// get whether DOCTYPE is written by `write()`
+    pub fn get_write_doctype(&self) -> bool {
+        self.write_doctype
+    }
+    #[allow(dead_code)]
+    /// set whether DOCTYPE is written by `write()`, the DOCTYPE node is kept in the tree either way
+    pub fn set_write_doctype(&mut self, write_doctype: bool) {
+        self.write_doctype = write_doctype;
+    }
+    #[allow(dead_code)]
+    /// get whether the XML declaration is written by `write()`
+    pub fn get_write_declaration(&self) -> bool {
+        self.write_declaration
+    }
+    #[allow(dead_code)]
+    /// set whether the XML declaration is written by `write()`, the output then starts with the first node
+    pub fn set_write_declaration(&mut self, write_declaration: bool) {
+        self.write_declaration = write_declaration;
+    }
+    #[allow(dead_code)]
+    /// get whether `write()` fails on prefixes which are neither declared nor resolvable
+    pub fn get_strict_namespaces(&self) -> bool {
+        self.strict_namespaces
+    }
+    #[allow(dead_code)]
+    /// set whether `write()` fails on prefixes which are neither declared nor resolvable
+    ///
+    /// Unbound prefixes with a known namespace are declared either way, other prefixes are written as they are
+    /// unless this is set.
+    pub fn set_strict_namespaces(&mut self, strict_namespaces: bool) {
+        self.strict_namespaces = strict_namespaces;
+    }
+    #[allow(dead_code)]
+    /// get names of elements whose text is written as CDATA section
+    pub fn get_cdata_elements(&self) -> Vec<String> {
+        self.cdata_elements.clone()
+    }
+    #[allow(dead_code)]
+    /// set names (`prefix:localname` or `{namespace}localname`) of elements whose text is written as CDATA section
+    ///
+    /// Text before the first child is wrapped unless it is only whitespace, tails of children are escaped as usual.
+    pub fn set_cdata_elements(&mut self, names: &[&str]) {
+        self.cdata_elements = names.iter().map(|x| x.to_string()).collect();
+    }
+    #[allow(dead_code)]
+    /// get prefixes bound to namespaces for XPath queries
+    pub fn get_xpath_namespaces(&self) -> HashMap<String, String> {
+        self.xpath_namespaces.clone()
+    }
+    #[allow(dead_code)]
+    /// bind `prefix` to namespace `uri` for XPath queries
+    ///
+    /// Names with the prefix, e.g. `//dc:title` or `[@xlink:href]`, match nodes in the namespace whatever prefix the
+    /// document uses. Names with other prefixes are matched literally.
+    pub fn register_xpath_namespace(&mut self, prefix: &str, uri: &str) {
+        self.xpath_namespaces.insert(prefix.to_string(), uri.to_string());
+    }
+    #[allow(dead_code)]
+    /// get newline style of output, `None` if newlines are written as stored
+    pub fn get_output_newline(&self) -> Option<Newline> {
+        self.output_newline
+    }
+    #[allow(dead_code)]
+    /// set newline style of texts, tails, comments and CDATA sections written by `write()`, nodes are not changed
+    pub fn set_output_newline(&mut self, output_newline: Option<Newline>) {
+        self.output_newline = output_newline;
+    }
+    #[allow(dead_code)]
+    /// get whether references in texts and attribute values are kept verbatim, see `ParseOptions::preserve_references`
+    pub fn get_preserve_references(&self) -> bool {
+        self.references
+    }
+    #[allow(dead_code)]
+    /// set whether `write()` keeps "&" which starts a reference instead of escaping it
+    pub fn set_preserve_references(&mut self, preserve_references: bool) {
+        self.references = preserve_references;
+    }
+    #[allow(dead_code)]
+    /// get whether attribute values are kept as written, see `AttrNormalization::Raw`
+    pub fn get_raw_attributes(&self) -> bool {
+        self.raw_attributes
+    }
+    #[allow(dead_code)]
+    /// set whether `write()` outputs attribute values unchanged instead of escaping them
+    pub fn set_raw_attributes(&mut self, raw_attributes: bool) {
+        self.raw_attributes = raw_attributes;
+    }
+    #[allow(dead_code)]
+    /// get general entities declared in the internal subset of DOCTYPE, values of external entities are `None`
+    pub fn get_entities(&self) -> HashMap<String, Option<String>> {
+        self.entities.clone()
+    }
+    #[allow(dead_code)]
+    /// get attribute declarations in the internal subset of DOCTYPE
+    pub fn attlist_decls(&self) -> Vec<AttlistDecl> {
+        match self.data.iter().find(|x| x.kind() == NodeKind::DocType) {
+            Some(node) => dtd::attlists(node.get_text().as_deref().unwrap_or("")),
+            None => Vec::new(),
+        }
+    }
+    #[allow(dead_code)]
+    /// add missing attributes which have default values in DOCTYPE and return the count of added attributes
+    ///
+    /// Added attributes are flagged (see `ETreeNode::is_attr_defaulted`) and skipped on writing
+    /// unless they are set again by `ETreeNode::set_attr`.
+    pub fn apply_attlist_defaults(&mut self) -> usize {
+        let decls = self.attlist_decls();
+        let mut count = 0;
+        if decls.is_empty() {
+            return count;
+        }
+        for pos in 0..self.data.len() {
+            let name = self.data[pos].get_name();
+            for decl in decls.iter().filter(|x| x.element == name) {
+                if let Some(value) = decl.default.value() {
+                    if self.data[pos].get_attr(&decl.name).is_none() {
+                        let options = ParseOptions::default().with_preserve_references(self.references);
+                        let value = dtd::Unescaper::new(&options)
+                            .unescape(value, &self.entities)
+                            .unwrap_or_else(|_| value.to_string());
+                        self.invalidate_hash(pos);
+                        self.data[pos].set_default_attr(&decl.name, &value);
+                        count += 1;
+                    }
+                }
+            }
+        }
+        count
+    }
+    #[allow(dead_code)]
+    /// get namespace bound to `prefix` ("" for default namespace) in the scope of the node of specified position
+    pub fn lookup_namespace(&self, pos: usize, prefix: &str) -> Option<String> {
+        if prefix == "xml" {
+            return Some("http://www.w3.org/XML/1998/namespace".to_string());
+        }
+        let key = if prefix.is_empty() {
+            "xmlns".to_string()
+        } else {
+            format!("xmlns:{}", prefix)
+        };
+        let mut cur = if pos < self.data.len() { Some(pos) } else { None };
+        while let Some(p) = cur {
+            if let Some(ns) = self.data[p].get_attr(&key) {
+                return if ns.is_empty() { None } else { Some(ns) };
+            }
+            cur = self.parent(p);
+        }
+        None
+    }
+    #[allow(dead_code)]
+    /// get prefix bound to `namespace` in the scope of the node of specified position
+    pub fn lookup_prefix(&self, pos: usize, namespace: &str) -> Option<String> {
+        let mut cur = if pos < self.data.len() { Some(pos) } else { None };
+        while let Some(p) = cur {
+            for (key, value) in self.data[p].get_attr_iter() {
+                if value == namespace {
+                    let prefix = if key == "xmlns" { Some("") } else { key.strip_prefix("xmlns:") };
+                    if let Some(prefix) = prefix {
+                        // the prefix may be re-bound by a descendant
+                        if self.lookup_namespace(pos, prefix).as_deref() == Some(namespace) {
+                            return Some(prefix.to_string());
+                        }
+                    }
+                }
+            }
+            cur = self.parent(p);
+        }
+        None
+    }
+    fn xsi_prefix(&self, pos: usize) -> String {
+        self.lookup_prefix(pos, XSI_NAMESPACE)
+            .filter(|x| !x.is_empty())
+            .unwrap_or_else(|| "xsi".to_string())
+    }
+    /// prefix bound to XML Schema instance namespace in scope, declared on the node if there is none
+    fn declare_xsi_prefix(&mut self, pos: usize) -> String {
+        if let Some(prefix) = self.lookup_prefix(pos, XSI_NAMESPACE).filter(|x| !x.is_empty()) {
+            return prefix;
+        }
+        let mut prefix = "xsi".to_string();
+        let mut i = 0;
+        while self.lookup_namespace(pos, &prefix).is_some() {
+            i += 1;
+            prefix = format!("xsi{}", i);
+        }
+        self.data[pos].add_namespace_decl(&prefix, XSI_NAMESPACE);
+        prefix
+    }
+    #[allow(dead_code)]
+    /// set `xsi:nil="true"` or remove `xsi:nil` of the node of specified position
+    ///
+    /// The prefix bound to XML Schema instance in scope is used, `xmlns:xsi` is declared on the node if there is none.
+    /// Return false if pos is out of range
+    pub fn set_nil(&mut self, pos: usize, nil: bool) -> bool {
+        if pos >= self.data.len() {
+            return false;
+        }
+        self.invalidate_hash(pos);
+        if nil {
+            let key = format!("{}:nil", self.declare_xsi_prefix(pos));
+            self.data[pos].set_attr_ns(XSI_NAMESPACE, &key, "true");
+        } else {
+            let key = format!("{}:nil", self.xsi_prefix(pos));
+            self.data[pos].remove_attr(&key);
+        }
+        true
+    }
+    #[allow(dead_code)]
+    /// set QName in `xsi:type` of the node of specified position, prefix of `qname` should be bound to the type's
+    /// namespace
+    ///
+    /// The prefix bound to XML Schema instance in scope is used, `xmlns:xsi` is declared on the node if there is none.
+    /// Return false if pos is out of range
+    pub fn set_xsi_type(&mut self, pos: usize, qname: &str) -> bool {
+        if pos >= self.data.len() {
+            return false;
+        }
+        self.invalidate_hash(pos);
+        let key = format!("{}:type", self.declare_xsi_prefix(pos));
+        self.data[pos].set_attr_ns(XSI_NAMESPACE, &key, qname);
+        true
+    }
+    #[allow(dead_code)]
+    /// whether `xsi:nil` of the node of specified position is true, prefix of XML Schema instance is resolved in scope
+    pub fn is_nil(&self, pos: usize) -> bool {
+        let prefix = self.xsi_prefix(pos);
+        self.data
+            .get(pos)
+            .and_then(|x| x.get_attr(&format!("{}:nil", prefix)))
+            .map(|x| x.trim() == "true" || x.trim() == "1")
+            .unwrap_or(false)
+    }
+    #[allow(dead_code)]
+    /// get `xsi:type` of the node of specified position as (namespace, localname)
+    pub fn resolve_xsi_type(&self, pos: usize) -> Option<(String, String)> {
+        let prefix = self.xsi_prefix(pos);
+        let qname = self.data.get(pos)?.get_attr(&format!("{}:type", prefix))?;
+        let qname = qname.trim();
+        let (prefix, localname) = match qname.find(':') {
+            Some(i) => (&qname[..i], &qname[i + 1..]),
+            None => ("", qname),
+        };
+        let ns = self.lookup_namespace(pos, prefix).unwrap_or_default();
+        Some((ns, localname.to_string()))
     }
     #[allow(dead_code)]
     /// get position of root node
     pub fn root(&self) -> usize {
         let mut idx = 0;
         while idx < self.data.len() {
-            if !(self.data[idx].get_localname().starts_with("<") && self.data[idx].get_localname().ends_with(">"))
-            {
+            if self.data[idx].is_element() {
                 break;
             }
             idx += 1;
@@ -113,9 +859,36 @@ impl ETree {
         idx
     }
     #[allow(dead_code)]
+    /// get positions of top-level elements, a fragment may have more than one
+    pub fn roots(&self) -> Vec<usize> {
+        (0..self.data.len())
+            .filter(|&x| self.data[x].get_route() == "#" && self.data[x].is_element())
+            .collect()
+    }
+    #[allow(dead_code)]
+    /// get positions of comments, processing instructions and DOCTYPE before the root element
+    pub fn prolog(&self) -> Vec<usize> {
+        (0..self.root().min(self.data.len())).collect()
+    }
+    #[allow(dead_code)]
+    /// get positions of comments and processing instructions after the root element
+    pub fn epilog(&self) -> Vec<usize> {
+        let root = self.root();
+        if root >= self.data.len() {
+            return Vec::new();
+        }
+        let end = self.descendant(root).last().map(|x| x + 1).unwrap_or(root + 1);
+        (end..self.data.len()).filter(|&x| self.data[x].get_route() == "#").collect()
+    }
+    #[allow(dead_code)]
+    /// get whether the tree is a fragment parsed by `parse_fragment()`
+    pub fn is_fragment(&self) -> bool {
+        self.fragment
+    }
+    #[allow(dead_code)]
     /// get position of parent node
     pub fn parent(&self, pos: usize) -> Option<usize> {
-        if pos <= 0 || pos >= self.data.len() {
+        if pos == 0 || pos >= self.data.len() {
             None
         } else {
             let close_tag = Regex::new(r"^(?P<parent>#.*?)(?P<current>\d+)#$").unwrap();
@@ -150,11 +923,16 @@ impl ETree {
         out
     }
     #[allow(dead_code)]
-    /// get positions of children node with specified name
+    /// get a view of the subtree rooted at the node of specified position, None if pos is out of range
+    pub fn view(&self, pos: usize) -> Option<SubtreeView<'_>> {
+        SubtreeView::new(self, pos)
+    }
+    #[allow(dead_code)]
+    /// get positions of children elements with specified name
     pub fn children_by_name(&self, pos: usize, tagname: &str) -> Vec<usize> {
         let mut out: Vec<usize> = Vec::new();
         for i in self.children(pos) {
-            if self.data[i].get_name() == tagname {
+            if self.data[i].is_element() && self.data[i].get_name() == tagname {
                 out.push(i);
             }
         }
@@ -179,7 +957,7 @@ impl ETree {
     #[allow(dead_code)]
     /// get position of previous sibling node
     pub fn previous(&self, pos: usize) -> Option<usize> {
-        if pos <= 0 || pos >= self.data.len() {
+        if pos == 0 || pos >= self.data.len() {
             None
         } else {
             let mut pos2 = pos;
@@ -240,6 +1018,7 @@ impl ETree {
     #[allow(dead_code)]
     /// get mut node by position
     pub fn node_mut(&mut self, pos: usize) -> Option<&mut ETreeNode> {
+        self.invalidate_hash(pos);
         self.data.get_mut(pos)
     }
     #[allow(dead_code)]
@@ -259,6 +1038,31 @@ impl ETree {
             crlf: self.crlf.clone(),
             enable_index: false,
             index: HashMap::new(),
+            values: OnceLock::new(),
+            enable_hash: false,
+            hashes: HashMap::new(),
+            user_data: UserData::default(),
+            attr_style: AttrStyle::Inline,
+            attr_order: AttrOrder::Document,
+            escape_policy: EscapePolicy::new(),
+            quote_char: QuoteChar::Double,
+            max_width: None,
+            attrs_per_line: None,
+            write_doctype: true,
+            write_declaration: true,
+            strict_namespaces: false,
+            cdata_elements: Vec::new(),
+            output_newline: None,
+            round_trip: false,
+            source_decl: None,
+            bom: self.bom,
+            entities: self.entities.clone(),
+            references: self.references,
+            raw_attributes: self.raw_attributes,
+            fragment: false,
+            head: None,
+            xpath_namespaces: self.xpath_namespaces.clone(),
+            options: self.options.clone(),
         };
         let offspring = self.descendant(pos);
         let mut node = self.data[pos].clone();
@@ -277,6 +1081,7 @@ impl ETree {
     ///
     /// *Warning*: position which is larger than return value and obtained before this function all should be re-obtained
     pub fn append_previous_node(&mut self, pos: usize, mut node: ETreeNode) -> Option<usize> {
+        self.inherit_default_namespace(&mut node, self.parent(pos));
         if let Some(cell) = self.prepare_append_previous(pos) {
             node.set_idx(self.count);
             node.set_tail(&cell.get_tail());
@@ -284,6 +1089,7 @@ impl ETree {
             self.data.insert(cell.get_idx(), node);
             self.index.insert(self.count, cell.get_idx());
             self.update_index(cell.get_idx() + 1);
+            self.update_hash(cell.get_idx());
             self.count += 1;
             Some(cell.get_idx())
         } else {
@@ -295,6 +1101,7 @@ impl ETree {
     ///
     /// *Warning*: position which is larger than return value and obtained before this function all should be re-obtained
     pub fn append_next_node(&mut self, pos: usize, mut node: ETreeNode) -> Option<usize> {
+        self.inherit_default_namespace(&mut node, self.parent(pos));
         if let Some(cell) = self.prepare_append_next(pos) {
             node.set_idx(self.count);
             node.set_tail(&cell.get_tail());
@@ -302,6 +1109,7 @@ impl ETree {
             self.data.insert(cell.get_idx(), node);
             self.index.insert(self.count, cell.get_idx());
             self.update_index(cell.get_idx() + 1);
+            self.update_hash(cell.get_idx());
             self.count += 1;
             Some(cell.get_idx())
         } else {
@@ -313,6 +1121,7 @@ impl ETree {
     ///
     /// *Warning*: position which is larger than return value and obtained before this function all should be re-obtained
     pub fn append_child_node(&mut self, pos: usize, mut node: ETreeNode) -> Option<usize> {
+        self.inherit_default_namespace(&mut node, Some(pos));
         if let Some(cell) = self.prepare_append_child(pos) {
             node.set_idx(self.count);
             node.set_tail(&cell.get_tail());
@@ -320,6 +1129,7 @@ impl ETree {
             self.data.insert(cell.get_idx(), node);
             self.index.insert(self.count, cell.get_idx());
             self.update_index(cell.get_idx() + 1);
+            self.update_hash(cell.get_idx());
             self.count += 1;
             Some(cell.get_idx())
         } else {
@@ -349,15 +1159,19 @@ impl ETree {
                 self.index.insert(tree.data[i].get_idx(), cell.get_idx() + i);
             }
             self.update_index(cell.get_idx() + tree.data.len());
-            if self.indent.len() > 0 {
+            if !self.indent.is_empty() {
                 let lines: Vec<&str> = tail.lines().collect();
                 let mut level = lines[lines.len() - 1].len() / self.indent.len();
                 if self.next(cell.get_idx()).is_none() {
                     level += 1;
                 }
-                self.pretty_tree(cell.get_idx(), level);
+                self.pretty_tree(cell.get_idx(), level, true);
                 self.data[cell.get_idx()].set_tail(&tail);
             }
+            if self.enable_hash {
+                self.generate_hash(cell.get_idx());
+                self.update_hash(cell.get_idx());
+            }
             Some(cell.get_idx())
         } else {
             None
@@ -386,15 +1200,19 @@ impl ETree {
                 self.index.insert(tree.data[i].get_idx(), cell.get_idx() + i);
             }
             self.update_index(cell.get_idx() + tree.data.len());
-            if self.indent.len() > 0 {
+            if !self.indent.is_empty() {
                 let lines: Vec<&str> = tail.lines().collect();
                 let mut level = lines[lines.len() - 1].len() / self.indent.len();
                 if self.next(cell.get_idx()).is_none() {
                     level += 1;
                 }
-                self.pretty_tree(cell.get_idx(), level);
+                self.pretty_tree(cell.get_idx(), level, true);
                 self.data[cell.get_idx()].set_tail(&tail);
             }
+            if self.enable_hash {
+                self.generate_hash(cell.get_idx());
+                self.update_hash(cell.get_idx());
+            }
             Some(cell.get_idx())
         } else {
             None
@@ -423,15 +1241,19 @@ impl ETree {
                 self.index.insert(tree.data[i].get_idx(), cell.get_idx() + i);
             }
             self.update_index(cell.get_idx() + tree.data.len());
-            if self.indent.len() > 0 {
+            if !self.indent.is_empty() {
                 let lines: Vec<&str> = tail.lines().collect();
                 let mut level = lines[lines.len() - 1].len() / self.indent.len();
                 if self.next(cell.get_idx()).is_none() {
                     level += 1;
                 }
-                self.pretty_tree(cell.get_idx(), level);
+                self.pretty_tree(cell.get_idx(), level, true);
                 self.data[cell.get_idx()].set_tail(&tail);
             }
+            if self.enable_hash {
+                self.generate_hash(cell.get_idx());
+                self.update_hash(cell.get_idx());
+            }
             Some(cell.get_idx())
         } else {
             None
@@ -442,339 +1264,984 @@ impl ETree {
     ///
     /// *Warning*: position which is larger than specified value and obtained before this function all should be re-obtained
     pub fn remove(&mut self, pos: usize) {
-        if let Some(previous) = self.previous(pos) {
-            let tail = self.data[pos].get_tail();
-            self.data[previous].set_tail(&tail);
-        } else if let Some(_next) = self.next(pos) {
-        } else if let Some(parent) = self.parent(pos) {
-            let mut text = String::from(self.data[parent].get_text().as_deref().unwrap());
-            if text.ends_with(&self.indent) {
-                let retain = text.len() - self.indent.len();
-                text.truncate(retain);
-                self.data[parent].set_text(&text);
-            }
+        for node in self.take_subtree(pos) {
+            self.user_data.0.remove(&node.get_idx());
         }
-        let offspring = self.descendant(pos);
-        let mut i = offspring.len();
-        while i > 0 {
-            i -= 1;
-            self.index.remove(&self.data[offspring[i]].get_idx());
-            self.data.remove(offspring[i]);
-        }
-        self.index.remove(&self.data[pos].get_idx());
-        self.data.remove(pos);
-        self.update_index(pos);
     }
     #[allow(dead_code)]
-    /// clear indent and return old indent
-    pub fn noindent(&mut self) -> String {
-        let oldindent = format!("{}{}", self.crlf, self.indent);
-        self.indent = "".to_string();
+    /// remove a subtree rooted at the node of specified position and return it as a tree
+    /// Will return None if pos is out of range
+    ///
+    /// *Warning*: position which is larger than specified value and obtained before this function all should be re-obtained
+    pub fn detach(&mut self, pos: usize) -> Option<ETree> {
+        if pos >= self.data.len() {
+            return None;
+        }
+        let mut tree = ETree {
+            indent: self.indent.clone(),
+            count: 0,
+            version: self.version.clone(),
+            encoding: self.encoding.clone(),
+            standalone: self.standalone.clone(),
+            data: self.take_subtree(pos),
+            crlf: self.crlf.clone(),
+            enable_index: false,
+            index: HashMap::new(),
+            values: OnceLock::new(),
+            enable_hash: false,
+            hashes: HashMap::new(),
+            user_data: UserData::default(),
+            attr_style: self.attr_style,
+            attr_order: self.attr_order,
+            escape_policy: self.escape_policy,
+            quote_char: self.quote_char,
+            max_width: self.max_width,
+            attrs_per_line: self.attrs_per_line,
+            write_doctype: self.write_doctype,
+            write_declaration: self.write_declaration,
+            strict_namespaces: self.strict_namespaces,
+            cdata_elements: self.cdata_elements.clone(),
+            output_newline: self.output_newline,
+            round_trip: self.round_trip,
+            source_decl: self.source_decl.clone(),
+            bom: self.bom,
+            entities: self.entities.clone(),
+            references: self.references,
+            raw_attributes: self.raw_attributes,
+            fragment: false,
+            head: None,
+            xpath_namespaces: self.xpath_namespaces.clone(),
+            options: self.options.clone(),
+        };
+        let base_root_len = tree.data[0].get_route().len() - 1;
+        for node in tree.data.iter_mut() {
+            node.set_route(node.get_route().get(base_root_len..).unwrap());
+            tree.count = tree.count.max(node.get_idx() + 1);
+            if let Some(items) = self.user_data.0.remove(&node.get_idx()) {
+                tree.user_data.0.insert(node.get_idx(), items);
+            }
+        }
+        Some(tree)
+    }
+    #[allow(dead_code)]
+    /// renumber idx of all nodes densely in document order, shrink internal storage and return the map from old idx to new idx
+    ///
+    /// *Warning*: idx obtained before this function all should be converted by the returned map
+    pub fn compact(&mut self) -> HashMap<usize, usize> {
+        let mut idxmap: HashMap<usize, usize> = HashMap::with_capacity(self.data.len());
+        for (i, node) in self.data.iter().enumerate() {
+            idxmap.insert(node.get_idx(), i);
+        }
+        for node in self.data.iter_mut() {
+            let route: Vec<String> = node
+                .get_route()
+                .split('#')
+                .filter(|x| !x.is_empty())
+                .map(|x| idxmap[&x.parse::<usize>().unwrap()].to_string())
+                .collect();
+            if route.is_empty() {
+                node.set_route("#");
+            } else {
+                node.set_route(&format!("#{}#", route.join("#")));
+            }
+            node.set_idx(idxmap[&node.get_idx()]);
+        }
+        self.count = self.data.len();
+        self.data.shrink_to_fit();
+        self.hashes = self
+            .hashes
+            .drain()
+            .filter_map(|(k, v)| idxmap.get(&k).map(|x| (*x, v)))
+            .collect();
+        self.hashes.shrink_to_fit();
+        self.user_data.0 = self
+            .user_data
+            .0
+            .drain()
+            .filter_map(|(k, v)| idxmap.get(&k).map(|x| (*x, v)))
+            .collect();
+        self.index = HashMap::new();
+        self.generate_index();
+        idxmap
+    }
+    #[allow(dead_code)]
+    /// re-read the document from `content` with the options it was parsed with, nodes which are structurally
+    /// unchanged keep their idx
+    ///
+    /// Starting from the top-level nodes, children of matched nodes are matched by name in order
+    /// (see `match_siblings`), so inserted or removed siblings don't change the idx of the others.
+    /// Texts and attributes may differ. Other nodes get new idx and user data of removed nodes is dropped.
+    /// The tree is unchanged if `content` is malformed.
+    pub fn reparse(&mut self, content: &str) -> Result<(), ParseError> {
+        let mut tree = if self.fragment {
+            ETree::parse_fragment_with(content, &self.options)?
+        } else {
+            ETree::parse_with(content, &self.options)?
+        };
+        let mut idxs: Vec<Option<usize>> = vec![None; tree.data.len()];
+        let top = |t: &ETree| (0..t.data.len()).filter(|&x| t.data[x].get_route() == "#").collect::<Vec<usize>>();
+        let mut todo = vec![(top(self), top(&tree))];
+        while let Some((old, new)) = todo.pop() {
+            for (o, n) in match_siblings(&self.data, &old, &tree.data, &new) {
+                idxs[n] = Some(self.data[o].get_idx());
+                todo.push((self.children(o), tree.children(n)));
+            }
+        }
+        // parents come before children, so their routes are already rebuilt
+        let mut routes: HashMap<String, usize> = HashMap::new();
+        let mut parents: Vec<Option<usize>> = Vec::with_capacity(tree.data.len());
+        for (pos, node) in tree.data.iter().enumerate() {
+            parents.push(routes.get(&node.get_route()).copied());
+            routes.insert(format!("{}{}#", node.get_route(), node.get_idx()), pos);
+        }
+        let mut count = self.count;
+        for pos in 0..tree.data.len() {
+            let idx = idxs[pos].unwrap_or_else(|| {
+                count += 1;
+                count - 1
+            });
+            let route = match parents[pos] {
+                Some(p) => format!("{}{}#", tree.data[p].get_route(), tree.data[p].get_idx()),
+                None => "#".to_string(),
+            };
+            tree.data[pos].set_idx(idx);
+            tree.data[pos].set_route(&route);
+        }
+        tree.count = count;
+        tree.attr_style = self.attr_style;
+        tree.attr_order = self.attr_order;
+        tree.escape_policy = self.escape_policy;
+        tree.quote_char = self.quote_char;
+        tree.max_width = self.max_width;
+        tree.attrs_per_line = self.attrs_per_line;
+        tree.write_doctype = self.write_doctype;
+        tree.write_declaration = self.write_declaration;
+        tree.strict_namespaces = self.strict_namespaces;
+        tree.cdata_elements = self.cdata_elements.clone();
+        tree.output_newline = self.output_newline;
+        tree.round_trip = self.round_trip;
+        tree.source_decl = self.source_decl.clone();
+        tree.enable_index = self.enable_index;
+        tree.xpath_namespaces = self.xpath_namespaces.clone();
+        let kept: HashSet<usize> = idxs.iter().flatten().copied().collect();
+        tree.user_data.0 = std::mem::take(&mut self.user_data.0)
+            .into_iter()
+            .filter(|(k, _)| kept.contains(k))
+            .collect();
+        tree.generate_index();
+        let enable_hash = self.enable_hash;
+        *self = tree;
+        self.set_enable_hash(enable_hash);
+        Ok(())
+    }
+    #[allow(dead_code)]
+    /// clear indent and return old indent, texts inside elements with xml:space="preserve" are kept
+    pub fn noindent(&mut self) -> String {
+        let oldindent = format!("{}{}", self.crlf, self.indent);
+        self.indent = "".to_string();
         self.crlf = "".to_string();
-        for item in self.data.iter_mut() {
-            item.set_tail(item.get_tail().trim());
-            if let Some(text) = item.get_text() {
+        if let Some(head) = self.head.as_mut() {
+            *head = head.trim().to_string();
+        }
+        let preserved = self.preserved_spaces();
+        for (item, (text_preserved, tail_preserved)) in self.data.iter_mut().zip(preserved) {
+            if !tail_preserved {
+                item.set_tail(item.get_tail().trim());
+            }
+            if let Some(text) = item.get_text().filter(|_| !text_preserved) {
                 item.set_text(text.trim());
             }
         }
+        self.set_enable_hash(self.enable_hash);
         oldindent
     }
     #[allow(dead_code)]
-    /// format nodes according to indent
+    /// whether the subtree at `pos` has the same content as the subtree at `other_pos` of `other`
+    pub fn deep_equal(&self, pos: usize, other: &ETree, other_pos: usize) -> bool {
+        self.deep_equal_with(pos, other, other_pos, &CompareOptions::new())
+    }
+    #[allow(dead_code)]
+    /// compare subtrees according to compare options
+    ///
+    /// Nodes are compared by `ETreeNode::content_eq`, and children in order along with their tails.
+    pub fn deep_equal_with(&self, pos: usize, other: &ETree, other_pos: usize, options: &CompareOptions) -> bool {
+        let (node, other_node) = match (self.node(pos), other.node(other_pos)) {
+            (Some(node), Some(other_node)) => (node, other_node),
+            _ => return false,
+        };
+        let children = self.children(pos);
+        let other_children = other.children(other_pos);
+        node.content_eq(other_node, options)
+            && children.len() == other_children.len()
+            && children.iter().zip(other_children.iter()).all(|(&x, &y)| {
+                options.significant(self.data[x].get_tail_ref()) == options.significant(other.data[y].get_tail_ref())
+                    && self.deep_equal_with(x, other, y, options)
+            })
+    }
+    #[allow(dead_code)]
+    /// format nodes according to indent, elements with xml:space="preserve" are kept as they are
     pub fn pretty(&mut self, indent: &str) {
-        self.set_indent(indent);
+        self.pretty_with(&PrettyOptions::new(indent));
+    }
+    #[allow(dead_code)]
+    /// format nodes according to pretty-printing options
+    pub fn pretty_with(&mut self, options: &PrettyOptions) {
+        self.attr_style = options.attr_style;
+        self.max_width = options.max_width;
+        self.attrs_per_line = options.attrs_per_line;
+        self.crlf = options.newline.as_str().to_string();
+        self.indent = options.indent.clone();
+        if !self.fragment {
+            self.head = None;
+        }
         let nodecnt = self.data.len();
         let mut idx = 0;
         while idx < nodecnt {
-            if self.data[idx].get_localname().starts_with("<") && self.data[idx].get_localname().ends_with(">") {
+            if !self.data[idx].is_element() {
                 self.data[idx].set_tail(&self.crlf);
             } else {
                 break;
             }
             idx += 1;
         }
-        self.pretty_tree(idx, 0);
+        self.pretty_tree(idx, 0, options.indent_misc);
+        self.set_enable_hash(self.enable_hash);
     }
 
-    fn read(&mut self, data: &str) {
+    /// build nodes from XML text, recoverable errors are collected if `errors` is `Some`
+    ///
+    /// `state` keeps open elements between calls, so a document can be read in chunks split between markups.
+    /// `handler` is notified of nodes as they are read.
+    pub(crate) fn read(
+        &mut self,
+        data: &str,
+        options: &ParseOptions,
+        state: &mut ReadState,
+        errors: &mut Option<Vec<ParseError>>,
+        handler: &mut Option<&mut dyn ParseHandler>,
+    ) -> Result<(), ParseError> {
+        self.options = options.clone();
+        let round_trip;
+        let options = if options.round_trip {
+            round_trip = ParseOptions {
+                preserve_references: true,
+                attr_normalization: AttrNormalization::Raw,
+                ..options.clone()
+            };
+            self.round_trip = true;
+            self.escape_policy = EscapePolicy::minimal();
+            &round_trip
+        } else {
+            options
+        };
         let mut reader = Reader::from_str(data);
+        reader.check_end_names(false);
+        let mut error_pos = None;
         let mut buf = Vec::new();
-        let mut ns_buf = Vec::new();
-        let mut status = 0;
-        let mut route = "#".to_string();
-        let close_tag = Regex::new(r"^(?P<parent>#.*?)(?P<current>\d+)#$").unwrap();
-        let mut closeidx = 0;
+        let mut unescaper = dtd::Unescaper::new(options);
+        unescaper.set_expanded(state.expanded);
+        unescaper.set_xml11(self.version == b"1.1");
+        unescaper.set_lenient(state.warnings.is_some());
+        // unexpanded entity references are written as they are
+        self.references = options.preserve_references || !options.expand_entities;
+        self.raw_attributes = options.attr_normalization == AttrNormalization::Raw;
         loop {
-            match reader.read_namespaced_event(&mut buf, &mut ns_buf) {
-                Ok((ref ns, Event::Start(ref e))) => {
-                    status = 1;
-                    let fulltag = String::from_utf8(e.name().to_vec()).unwrap();
-                    let shorttag = String::from_utf8(e.local_name().to_vec()).unwrap();
-                    let prefixlen = fulltag.len() - shorttag.len();
-                    let prefix = if prefixlen > 0 {
-                        fulltag.get(..prefixlen - 1).unwrap().to_string()
-                    } else {
-                        "".to_string()
-                    };
-                    let mut node = ETreeNode::new(&shorttag);
-                    node.set_idx(self.count);
-                    if ns.is_some() {
-                        node.set_namespace(&String::from_utf8(ns.unwrap().to_vec()).unwrap());
+            let start = reader.buffer_position();
+            let position = state.locate(data, start);
+            match reader.read_event(&mut buf) {
+                Ok(Event::Start(ref e)) => {
+                    let (mut node, bindings) = self.read_elem(e, &reader, data, &mut unescaper, state, errors)?;
+                    state.warn_redundant(data, start, &bindings);
+                    node.set_source_position(Some(position));
+                    if options.round_trip {
+                        node.set_source_start(&data[start..reader.buffer_position()]);
                     }
-                    node.set_namespace_abbrev(&prefix);
                     node.set_text("");
-                    node.set_route(&route);
-                    for item in e.attributes() {
-                        if let Ok(attr) = item {
-                            node.set_attr(
-                                &String::from_utf8(attr.key.to_vec()).unwrap(),
-                                &attr.unescape_and_decode_value(&reader).unwrap(),
-                            );
+                    node.set_self_closing(Some(false));
+                    self.data.push(node);
+                    match handler::notify(handler, |h| h.start_element(self, self.count)) {
+                        Control::Continue => {}
+                        Control::Skip => {
+                            let name = e.name().to_vec();
+                            self.data.pop();
+                            reader.read_to_end(name, &mut Vec::new()).map_err(|x| parse_err(data, &reader, x))?;
+                            continue;
                         }
+                        Control::Abort => return Err(ParseError::new(data, start, ABORTED.to_string())),
                     }
-                    self.data.push(node);
-                    route = format!("{}{}#", route, self.count);
+                    state.status = 1;
+                    state.route = format!("{}{}#", state.route, self.count);
+                    state.scopes.push(bindings);
                     self.count += 1;
                 }
-                Ok((_, Event::End(_))) => {
-                    status = 2;
-                    if let Some(c) = close_tag.captures(route.clone().as_str()) {
-                        route = c.name("parent").unwrap().as_str().to_string();
-                        let current = c.name("current").unwrap().as_str();
-                        closeidx = current.parse().unwrap();
+                Ok(Event::End(ref e)) => {
+                    let name = reader.decode(e.name()).map_err(|x| parse_err(data, &reader, x))?;
+                    let open: Vec<usize> = state.route.split('#').filter_map(|x| x.parse().ok()).collect();
+                    let matched = match open.last() {
+                        Some(&x) if self.data[x].get_name() == name => Some(open.len() - 1),
+                        _ if errors.is_some() => open.iter().rposition(|&x| self.data[x].get_name() == name),
+                        Some(&x) => {
+                            let message = format!("expecting </{}> but found </{}>", self.data[x].get_name(), name);
+                            return Err(ParseError::new(data, start + 2, message));
+                        }
+                        None => None,
+                    };
+                    match matched {
+                        Some(k) => {
+                            for &x in open[k + 1..].iter().rev() {
+                                let message = format!("element <{}> is not closed", self.data[x].get_name());
+                                recoverable(errors, ParseError::new(data, start, message))?;
+                            }
+                            if options.round_trip {
+                                self.data[open[k]].set_source_end(&data[start..reader.buffer_position()]);
+                            }
+                            if handler::notify(handler, |h| h.end_element(self, open[k])) == Control::Abort {
+                                return Err(ParseError::new(data, start, ABORTED.to_string()));
+                            }
+                            state.status = 2;
+                            state.closeidx = open[k];
+                            state.route = open[..k].iter().fold("#".to_string(), |r, x| format!("{}{}#", r, x));
+                            state.scopes.truncate(k);
+                        }
+                        None => {
+                            let message = format!("unexpected end tag </{}>", name);
+                            recoverable(errors, ParseError::new(data, start, message))?;
+                        }
                     }
                 }
-                Ok((ref ns, Event::Empty(ref e))) => {
-                    status = 2;
-                    let fulltag = String::from_utf8(e.name().to_vec()).unwrap();
-                    let shorttag = String::from_utf8(e.local_name().to_vec()).unwrap();
-                    let prefixlen = fulltag.len() - shorttag.len();
-                    let prefix = if prefixlen > 0 {
-                        fulltag.get(..prefixlen - 1).unwrap().to_string()
-                    } else {
-                        "".to_string()
-                    };
-                    let mut node = ETreeNode::new(&shorttag);
-                    node.set_idx(self.count);
-                    if ns.is_some() {
-                        node.set_namespace(&String::from_utf8(ns.unwrap().to_vec()).unwrap());
+                Ok(Event::Empty(ref e)) => {
+                    let (mut node, bindings) = self.read_elem(e, &reader, data, &mut unescaper, state, errors)?;
+                    state.warn_redundant(data, start, &bindings);
+                    node.set_source_position(Some(position));
+                    if options.round_trip {
+                        node.set_source_start(&data[start..reader.buffer_position()]);
                     }
-                    node.set_namespace_abbrev(&prefix);
-                    node.set_route(&route);
-                    for item in e.attributes() {
-                        if let Ok(attr) = item {
-                            node.set_attr(
-                                &String::from_utf8(attr.key.to_vec()).unwrap(),
-                                &attr.unescape_and_decode_value(&reader).unwrap(),
-                            );
+                    node.set_self_closing(Some(true));
+                    self.data.push(node);
+                    let control = match handler::notify(handler, |h| h.start_element(self, self.count)) {
+                        Control::Continue => handler::notify(handler, |h| h.end_element(self, self.count)),
+                        control => control,
+                    };
+                    match control {
+                        Control::Abort => return Err(ParseError::new(data, start, ABORTED.to_string())),
+                        Control::Skip => {
+                            self.data.pop();
+                            continue;
                         }
+                        Control::Continue => {}
                     }
-                    self.data.push(node);
-                    closeidx = self.count;
+                    state.status = 2;
+                    state.closeidx = self.count;
                     self.count += 1;
                 }
-                Ok((_, Event::Text(e))) => {
-                    if status == 1 {
-                        if let Some(node) = self.data.get_mut(self.count - 1) {
-                            node.set_text(&e.unescape_and_decode(&reader).unwrap());
-                        }
-                    } else if status == 2 {
-                        if let Some(node) = self.data.get_mut(closeidx) {
-                            node.set_tail(&e.unescape_and_decode(&reader).unwrap());
+                Ok(Event::Text(e)) => {
+                    let raw = reader.decode(e.escaped()).map_err(|x| parse_err(data, &reader, x))?;
+                    let text = match unescaper.unescape(raw, &self.entities) {
+                        Ok(text) => text,
+                        Err(x) => {
+                            recoverable(errors, parse_err(data, &reader, x))?;
+                            raw.to_string()
                         }
+                    };
+                    let text = if options.trim_text { text.trim() } else { &text };
+                    let control = if text.is_empty() {
+                        Control::Continue
+                    } else {
+                        handler::notify(handler, |h| h.text(text))
+                    };
+                    match control {
+                        Control::Continue => self.push_text(state, text, options.shared_text_threshold),
+                        Control::Skip => {}
+                        Control::Abort => return Err(ParseError::new(data, start, ABORTED.to_string())),
                     }
                 }
-                Ok((_, Event::Comment(e))) => {
-                    status = 2;
-                    let mut node = ETreeNode::new("<Comment>");
-                    node.set_idx(self.count);
-                    node.set_text(&e.unescape_and_decode(&reader).unwrap());
-                    node.set_route(&route);
-                    self.data.push(node);
-                    closeidx = self.count;
-                    self.count += 1;
+                Ok(Event::Comment(_)) if options.ignore_comments => {}
+                Ok(Event::Comment(e)) => {
+                    let text = if options.round_trip {
+                        reader.decode(&e).map_err(|x| parse_err(data, &reader, x))?.to_string()
+                    } else {
+                        e.unescape_and_decode(&reader).map_err(|x| parse_err(data, &reader, x))?
+                    };
+                    match handler::notify(handler, |h| h.comment(&text)) {
+                        Control::Continue => self.push_leaf(NodeKind::Comment, &text, position, state),
+                        Control::Skip => {}
+                        Control::Abort => return Err(ParseError::new(data, start, ABORTED.to_string())),
+                    }
                 }
-                Ok((_, Event::CData(e))) => {
-                    status = 2;
-                    let mut node = ETreeNode::new("<CData>");
-                    node.set_idx(self.count);
-                    node.set_text(&e.unescape_and_decode(&reader).unwrap());
-                    node.set_route(&route);
-                    self.data.push(node);
-                    closeidx = self.count;
-                    self.count += 1;
+                Ok(Event::CData(e)) => {
+                    // CDATA content is literal, references in it are not expanded
+                    let text = reader.decode(&e).map_err(|x| parse_err(data, &reader, x))?;
+                    match handler::notify(handler, |h| h.text(text)) {
+                        Control::Continue if options.coalesce_cdata => {
+                            self.push_text(state, text, options.shared_text_threshold)
+                        }
+                        Control::Continue => self.push_leaf(NodeKind::CData, text, position, state),
+                        Control::Skip => {}
+                        Control::Abort => return Err(ParseError::new(data, start, ABORTED.to_string())),
+                    }
                 }
-                Ok((_, Event::Decl(ref e))) => {
-                    self.version = e.version().unwrap().into_owned();
+                Ok(Event::Decl(ref e)) => {
+                    self.version = e.version().map_err(|x| parse_err(data, &reader, x))?.into_owned();
+                    unescaper.set_xml11(self.version == b"1.1");
                     if let Some(x) = e.encoding() {
-                        self.encoding = Some(x.unwrap().into_owned());
+                        self.encoding = Some(x.map_err(|x| parse_err(data, &reader, x))?.into_owned());
                     }
                     if let Some(x) = e.standalone() {
-                        self.standalone = Some(x.unwrap().into_owned());
+                        self.standalone = Some(x.map_err(|x| parse_err(data, &reader, x))?.into_owned());
+                    }
+                    if options.round_trip {
+                        let decl = data[start..reader.buffer_position()].trim_start_matches('\u{feff}');
+                        self.source_decl = Some(decl.to_string());
                     }
                 }
-                Ok((_, Event::PI(e))) => {
-                    status = 2;
-                    let mut node = ETreeNode::new("<PI>");
-                    node.set_idx(self.count);
-                    node.set_text(&e.unescape_and_decode(&reader).unwrap());
-                    node.set_route(&route);
-                    self.data.push(node);
-                    closeidx = self.count;
-                    self.count += 1;
+                Ok(Event::PI(_)) if options.ignore_processing_instructions => {}
+                Ok(Event::PI(e)) => {
+                    // PI content is literal like CDATA
+                    let text = reader.decode(&e).map_err(|x| parse_err(data, &reader, x))?;
+                    self.push_leaf(NodeKind::ProcessingInstruction, text, position, state);
                 }
-                Ok((_, Event::DocType(e))) => {
-                    status = 2;
-                    let mut node = ETreeNode::new("<DocType>");
-                    node.set_idx(self.count);
-                    node.set_text(&e.unescape_and_decode(&reader).unwrap());
-                    node.set_route(&route);
-                    self.data.push(node);
-                    closeidx = self.count;
-                    self.count += 1;
+                Ok(Event::DocType(e)) => {
+                    let text = reader.decode(e.escaped()).map_err(|x| parse_err(data, &reader, x))?;
+                    self.entities = dtd::entities(text);
+                    if options.attr_normalization == AttrNormalization::Normalize {
+                        state.attlists = dtd::attlists(text);
+                    }
+                    self.push_leaf(NodeKind::DocType, text, position, state);
+                }
+                Ok(Event::Eof) => break,
+                Err(e) => {
+                    recoverable(errors, parse_err(data, &reader, e))?;
+                    // stop if the reader can't move on
+                    if error_pos == Some(reader.buffer_position()) {
+                        break;
+                    }
+                    error_pos = Some(reader.buffer_position());
                 }
-                Ok((_, Event::Eof)) => break,
-                Err(e) => panic!("Error at position {}: {:?}", reader.buffer_position(), e),
+            }
+            check_limit(data, &reader, "node count", self.data.len(), options.max_nodes)?;
+            for kind in unescaper.take_warnings() {
+                state.warn(data, start, kind);
             }
         }
+        state.expanded = unescaper.expanded();
+        state.locate(data, data.len());
+        state.consumed = 0;
+        Ok(())
     }
-    fn write(&self) -> Result<Vec<u8>, quick_xml::Error> {
-        let close_tag = Regex::new(r"^(?P<parent>#.*?)(?P<current>\d+)#$").unwrap();
-        let mut idxmap: HashMap<String, usize> = HashMap::new();
-        for idx in 0..self.data.len() {
-            idxmap.insert(self.data[idx].get_idx().to_string(), idx);
+    /// report elements which are still open at the end of reading, fail on the innermost one unless recovering
+    pub(crate) fn read_end(
+        &self,
+        data: &str,
+        state: &ReadState,
+        errors: &mut Option<Vec<ParseError>>,
+    ) -> Result<(), ParseError> {
+        for x in state.route.split('#').filter_map(|x| x.parse::<usize>().ok()).rev() {
+            let message = format!("element <{}> is not closed", self.data[x].get_name());
+            recoverable(errors, ParseError::new(data, data.len(), message))?;
         }
-        let mut writer = Writer::new(Cursor::new(Vec::new()));
-        let elem = BytesDecl::new(
-            self.version.as_slice(),
-            self.encoding.as_deref(),
-            self.standalone.as_deref(),
-        );
-        let _ = writer.write_event(Event::Decl(elem));
-        let _ = writer.write(self.crlf.as_bytes());
+        Ok(())
+    }
+    /// build element node from a start tag, return the node and namespace bindings declared by it
+    fn read_elem(
+        &self,
+        e: &BytesStart,
+        reader: &Reader<&[u8]>,
+        data: &str,
+        unescaper: &mut dtd::Unescaper,
+        state: &ReadState,
+        errors: &mut Option<Vec<ParseError>>,
+    ) -> Result<(ETreeNode, HashMap<String, String>), ParseError> {
+        let depth = state.route.matches('#').count();
+        check_limit(data, reader, "nesting depth", depth, unescaper.options().max_depth)?;
+        let fulltag = String::from_utf8(e.name().to_vec()).map_err(|x| parse_err(data, reader, x))?;
+        let shorttag = String::from_utf8(e.local_name().to_vec()).map_err(|x| parse_err(data, reader, x))?;
+        let prefixlen = fulltag.len() - shorttag.len();
+        let prefix = if prefixlen > 0 {
+            fulltag.get(..prefixlen - 1).unwrap().to_string()
+        } else {
+            "".to_string()
+        };
+        let mut node = ETreeNode::new(&shorttag);
+        node.set_idx(self.count);
+        node.set_namespace_abbrev(&prefix);
+        node.set_route(&state.route);
+        self.read_attrs(&mut node, e, reader, data, unescaper, &state.attlists, errors)?;
+        let mut bindings = HashMap::new();
+        for (key, value) in node.get_attr_iter() {
+            if key == "xmlns" {
+                bindings.insert("".to_string(), value.clone());
+            } else if let Some(x) = key.strip_prefix("xmlns:") {
+                bindings.insert(x.to_string(), value.clone());
+            }
+        }
+        let lookup = |prefix: &str| -> Option<String> {
+            if prefix == "xml" {
+                Some(XML_NAMESPACE.to_string())
+            } else {
+                bindings
+                    .get(prefix)
+                    .or_else(|| state.scopes.iter().rev().find_map(|x| x.get(prefix)))
+                    .filter(|x| !x.is_empty())
+                    .cloned()
+            }
+        };
+        if let Some(ns) = lookup(&prefix) {
+            node.set_namespace(&ns);
+        }
+        node.set_default_namespace(&lookup("").unwrap_or_default());
+        // unprefixed attributes are in no namespace
+        let attr_ns: Vec<(String, String)> = node
+            .get_attr_iter()
+            .filter_map(|(key, _)| key.split_once(':'))
+            .filter(|(prefix, _)| *prefix != "xmlns")
+            .filter_map(|(prefix, name)| lookup(prefix).map(|ns| (format!("{}:{}", prefix, name), ns)))
+            .collect();
+        for (key, ns) in attr_ns {
+            node.set_attr_namespace(&key, &ns);
+        }
+        Ok((node, bindings))
+    }
+    /// read attributes of a start tag into `node`, `attlists` tells the declared types of attributes
+    #[allow(clippy::too_many_arguments)]
+    fn read_attrs(
+        &self,
+        node: &mut ETreeNode,
+        e: &BytesStart,
+        reader: &Reader<&[u8]>,
+        data: &str,
+        unescaper: &mut dtd::Unescaper,
+        attlists: &[AttlistDecl],
+        errors: &mut Option<Vec<ParseError>>,
+    ) -> Result<(), ParseError> {
+        let tag = node.get_name();
+        for (i, attr) in e.attributes().enumerate() {
+            check_limit(data, reader, "attribute count", i + 1, unescaper.options().max_attributes)?;
+            let attr = match attr {
+                Ok(attr) => attr,
+                Err(x) => {
+                    // the rest of the tag can't be split into attributes
+                    return recoverable(errors, parse_err(data, reader, x));
+                }
+            };
+            let key = std::str::from_utf8(attr.key).map_err(|x| parse_err(data, reader, x))?;
+            let raw = reader.decode(&attr.value).map_err(|x| parse_err(data, reader, x))?;
+            let mut value = match unescaper.unescape_attr(raw, &self.entities) {
+                Ok(value) => value,
+                Err(x) => {
+                    recoverable(errors, parse_err(data, reader, x))?;
+                    raw.to_string()
+                }
+            };
+            if attlists.iter().any(|x| x.element == tag && x.name == key && x.attr_type != "CDATA") {
+                value = dtd::collapse_space(&value);
+            }
+            node.set_attr(key, &value);
+        }
+        Ok(())
+    }
+    /// append text to the text of the open element (`status` 1) or the tail of the last closed node (`status` 2)
+    ///
+    /// Texts of at least `threshold` bytes are stored as shared strings.
+    fn push_text(&mut self, state: &ReadState, text: &str, threshold: Option<usize>) {
+        if state.status == 1 {
+            if let Some(node) = self.data.get_mut(self.count - 1) {
+                let text = match node.get_text_ref() {
+                    Some(old) if !old.is_empty() => Cow::Owned(old.to_string() + text),
+                    _ => Cow::Borrowed(text),
+                };
+                match threshold {
+                    Some(threshold) if text.len() >= threshold => node.set_text_shared(Arc::from(text.as_ref())),
+                    _ => node.set_text(&text),
+                }
+            }
+        } else if state.status == 2 {
+            if let Some(node) = self.data.get_mut(state.closeidx) {
+                node.set_tail(&(node.get_tail() + text));
+            }
+        } else {
+            self.head.get_or_insert_with(String::new).push_str(text);
+        }
+    }
+    /// add a comment, CDATA, PI or DOCTYPE node
+    fn push_leaf(&mut self, kind: NodeKind, text: &str, position: (usize, usize, usize), state: &mut ReadState) {
+        let mut node = ETreeNode::new(kind.sentinel().unwrap_or_default());
+        node.set_source_position(Some(position));
+        node.set_idx(self.count);
+        node.set_text(text);
+        node.set_route(&state.route);
+        self.data.push(node);
+        state.status = 2;
+        state.closeidx = self.count;
+        self.count += 1;
+    }
+    pub(crate) fn write(&self) -> Result<Vec<u8>, Error> {
+        let mut buf = Vec::new();
+        self.write_to(&mut buf)?;
+        Ok(buf)
+    }
+    #[allow(dead_code)]
+    /// serialize the node at `pos` with its descendants, without XML declaration and the tail of the node
+    ///
+    /// Namespace declarations of ancestors are not repeated. Return an empty string if pos is out of range.
+    pub fn outer_xml(&self, pos: usize) -> String {
+        if pos >= self.data.len() {
+            return String::new();
+        }
+        let mut writer = Writer::new(Vec::new());
+        let end = pos + 1 + self.descendant(pos).len();
+        match self.write_nodes(&mut writer, pos..end, Some(pos), &HashMap::new()) {
+            Ok(()) => String::from_utf8(writer.into_inner()).expect("serialized XML is UTF-8"),
+            Err(_) => String::new(),
+        }
+    }
+    #[allow(dead_code)]
+    /// write the sibling nodes from `start_pos` to `end_pos` (inclusive) with their descendants to `w` as a fragment
+    ///
+    /// Tails between the nodes are kept, the tail of the last node is not written. Namespace declarations of
+    /// ancestors are not repeated.
+    pub fn write_range<W: Write>(&self, start_pos: usize, end_pos: usize, w: W) -> Result<(), WriteError> {
+        if end_pos >= self.data.len()
+            || start_pos > end_pos
+            || self.data[start_pos].get_route() != self.data[end_pos].get_route()
+        {
+            return Err(Error::InvalidState("write_range needs a sibling of start_pos as end_pos"));
+        }
+        let mut writer = Writer::new(w);
+        let end = end_pos + 1 + self.descendant(end_pos).len();
+        self.write_nodes(&mut writer, start_pos..end, Some(end_pos), &HashMap::new())
+    }
+    #[allow(dead_code)]
+    /// serialize the document to a string, the same text as `write_file` writes
+    pub fn to_xml_string(&self) -> Result<String, WriteError> {
+        let buf = self.write()?;
+        // the writer only gets UTF-8 text
+        Ok(String::from_utf8(buf).expect("serialized XML is UTF-8"))
+    }
+    #[allow(dead_code)]
+    /// write the document to `w`, e.g. a socket or an entry of an archive, without an intermediate buffer
+    pub fn write_to<W: Write>(&self, w: W) -> Result<(), WriteError> {
+        let decls = self.missing_declarations(self.strict_namespaces)?;
+        let mut writer = Writer::new(w);
+        self.write_prolog(&mut writer)?;
+        for range in self.write_ranges() {
+            self.write_nodes(&mut writer, range, None, &decls)?;
+        }
+        Ok(())
+    }
+    /// write BOM, XML declaration and the text before the first node
+    fn write_prolog<W: Write>(&self, writer: &mut Writer<W>) -> Result<(), Error> {
+        if self.bom {
+            writer.write("\u{feff}".as_bytes())?;
+        }
+        if self.fragment || self.write_declaration {
+            if let Some(decl) = self.source_decl.as_ref().filter(|_| !self.fragment) {
+                writer.write(decl.as_bytes())?;
+            } else if !self.fragment {
+                let elem = BytesDecl::new(
+                    self.version.as_slice(),
+                    self.encoding.as_deref(),
+                    self.standalone.as_deref(),
+                );
+                let _ = writer.write_event(Event::Decl(elem));
+            }
+            match &self.head {
+                Some(head) => writer.write_event(Event::Text(self.text_event(head)))?,
+                None if !self.fragment => writer.write(self.newlines(&self.crlf).as_bytes())?,
+                None => {}
+            }
+        }
+        Ok(())
+    }
+    /// ranges of nodes written by `write()`, the second one is empty unless DOCTYPE is skipped
+    fn write_ranges(&self) -> [Range<usize>; 2] {
+        // DOCTYPE is a top-level node, so nodes before and after it are written separately with its tail
         let nodelen = self.data.len();
-        for idx in 0..nodelen {
-            if idx > 0 {
-                if self.data[idx].get_route() == self.data[idx - 1].get_route() {
-                    // Sibling node for last node
-                    if self.data[idx - 1].get_text().is_some() {
-                        if !(self.data[idx - 1].get_localname().starts_with("<")
-                            && self.data[idx - 1].get_localname().ends_with(">"))
-                        {
-                            let elem = BytesEnd::owned(Vec::<u8>::from(self.data[idx - 1].get_name()));
-                            writer.write_event(Event::End(elem))?;
-                        }
-                        let elem = BytesText::from_plain_str(self.data[idx - 1].get_tail().as_str()).into_owned();
-                        writer.write_event(Event::Text(elem))?;
+        match self.data.iter().position(|x| x.kind() == NodeKind::DocType).filter(|_| !self.write_doctype) {
+            Some(pos) => [0..pos, pos + 1..nodelen],
+            None => [0..nodelen, nodelen..nodelen],
+        }
+    }
+    #[cfg(feature = "async")]
+    #[allow(dead_code)]
+    /// write the document to an async writer such as `tokio::fs::File` or a socket, then flush it
+    ///
+    /// Nodes are serialized into a buffer which is written whenever it reaches 64 KiB, so the document is never
+    /// held in memory as a whole.
+    pub async fn write_async<W: tokio::io::AsyncWrite>(&self, writer: W) -> Result<(), WriteError> {
+        use tokio::io::AsyncWriteExt;
+        const CHUNK: usize = 64 * 1024;
+        tokio::pin!(writer);
+        let decls = self.missing_declarations(self.strict_namespaces)?;
+        let mut out = Writer::new(Vec::with_capacity(CHUNK));
+        self.write_prolog(&mut out)?;
+        for range in self.write_ranges() {
+            if range.is_empty() {
+                continue;
+            }
+            let scope = WriteScope::new(self, range.clone(), None);
+            for idx in range {
+                self.write_node(&mut out, idx, &scope, &decls)?;
+                if out.inner().len() >= CHUNK {
+                    writer.write_all(out.inner()).await?;
+                    out.inner().clear();
+                }
+            }
+            self.write_close(&mut out, &scope)?;
+        }
+        writer.write_all(out.inner()).await?;
+        writer.flush().await?;
+        Ok(())
+    }
+    /// write nodes in `range`, which are top-level nodes and their descendants, closing all of them at the end
+    ///
+    /// The tail of the node at `skip_tail` is not written, `decls` are namespace declarations added to start tags by
+    /// position.
+    fn write_nodes<W: Write>(
+        &self,
+        writer: &mut Writer<W>,
+        range: Range<usize>,
+        skip_tail: Option<usize>,
+        decls: &Declarations,
+    ) -> Result<(), Error> {
+        if range.is_empty() {
+            return Ok(());
+        }
+        let scope = WriteScope::new(self, range.clone(), skip_tail);
+        for idx in range {
+            self.write_node(writer, idx, &scope, decls)?;
+        }
+        self.write_close(writer, &scope)
+    }
+    /// write the node at `idx` of `scope`, closing the previous nodes which end before it
+    fn write_node<W: Write>(
+        &self,
+        writer: &mut Writer<W>,
+        idx: usize,
+        scope: &WriteScope,
+        decls: &Declarations,
+    ) -> Result<(), Error> {
+        let tail = |idx: usize| scope.tail(self, idx);
+        if idx > scope.range.start {
+            if self.data[idx].get_route() == self.data[idx - 1].get_route() {
+                // Sibling node for last node
+                if !self.is_empty_elem(idx - 1) {
+                    if self.data[idx - 1].is_element() {
+                        self.write_end(writer, idx - 1)?;
                     }
-                } else if self.data[idx].get_route().starts_with(&self.data[idx - 1].get_route()) {
-                    // Child node for last node
-                } else if self.data[idx - 1].get_route().starts_with(&self.data[idx].get_route()) {
-                    // Close tag
-                    if self.data[idx - 1].get_text().is_some() {
-                        if !(self.data[idx - 1].get_localname().starts_with("<")
-                            && self.data[idx - 1].get_localname().ends_with(">"))
-                        {
-                            let elem = BytesEnd::owned(Vec::<u8>::from(self.data[idx - 1].get_name()));
-                            writer.write_event(Event::End(elem))?;
-                        }
-                        let elem = BytesText::from_plain_str(self.data[idx - 1].get_tail().as_str()).into_owned();
-                        writer.write_event(Event::Text(elem))?;
+                    let elem = self.text_event(tail(idx - 1));
+                    writer.write_event(Event::Text(elem))?;
+                }
+            } else if self.data[idx].get_route().starts_with(&self.data[idx - 1].get_route()) {
+                // Child node for last node
+            } else if self.data[idx - 1].get_route().starts_with(&self.data[idx].get_route()) {
+                // Close tag
+                if !self.is_empty_elem(idx - 1) {
+                    if self.data[idx - 1].is_element() {
+                        self.write_end(writer, idx - 1)?;
                     }
-                    let mut route = self.data[idx - 1].get_route();
-                    while let Some(c) = close_tag.captures(&route.clone()) {
-                        route = c.name("parent").unwrap().as_str().to_string();
-                        let current = c.name("current").unwrap().as_str().to_string();
-                        let closeidx = idxmap.get(&current).unwrap();
-                        if !(self.data[*closeidx].get_localname().starts_with("<")
-                            && self.data[*closeidx].get_localname().ends_with(">"))
-                        {
-                            let elem = BytesEnd::owned(Vec::<u8>::from(self.data[*closeidx].get_name()));
-                            writer.write_event(Event::End(elem))?;
-                        }
-                        let elem =
-                            BytesText::from_plain_str(self.data[*closeidx].get_tail().as_str()).into_owned();
-                        writer.write_event(Event::Text(elem))?;
-                        if route == self.data[idx].get_route() {
-                            break;
-                        }
+                    let elem = self.text_event(tail(idx - 1));
+                    writer.write_event(Event::Text(elem))?;
+                }
+                let mut route = self.data[idx - 1].get_route();
+                while let Some(c) = scope.close_tag.captures(&route.clone()) {
+                    route = c.name("parent").unwrap().as_str().to_string();
+                    let current = c.name("current").unwrap().as_str().to_string();
+                    let closeidx = scope.idxmap.get(&current).unwrap();
+                    if self.data[*closeidx].is_element() {
+                        self.write_end(writer, *closeidx)?;
+                    }
+                    let elem = self.text_event(tail(*closeidx));
+                    writer.write_event(Event::Text(elem))?;
+                    if route == self.data[idx].get_route() {
+                        break;
                     }
-                } else {
-                    panic!(
-                        "Error route: {}[{}] {}[{}]",
-                        idx - 1,
-                        self.data[idx - 1].get_route(),
-                        idx,
-                        self.data[idx].get_route()
-                    );
                 }
+            } else {
+                return Err(Error::Tree {
+                    pos: idx,
+                    message: format!(
+                        "route {} doesn't follow route {} of node [{}]",
+                        self.data[idx].get_route(),
+                        self.data[idx - 1].get_route(),
+                        idx - 1
+                    ),
+                });
             }
-            if self.data[idx].get_localname() == "<Comment>" {
-                let elem = BytesText::from_plain_str(self.data[idx].get_text().as_deref().unwrap()).into_owned();
-                writer.write_event(Event::Comment(elem))?;
-            } else if self.data[idx].get_localname() == "<CData>" {
-                let elem = BytesText::from_plain_str(self.data[idx].get_text().as_deref().unwrap()).into_owned();
-                writer.write_event(Event::CData(elem))?;
-            } else if self.data[idx].get_localname() == "<PI>" {
-                let elem = BytesText::from_plain_str(self.data[idx].get_text().as_deref().unwrap()).into_owned();
-                writer.write_event(Event::PI(elem))?;
-            } else if self.data[idx].get_localname() == "<DocType>" {
-                let elem = BytesText::from_plain_str(self.data[idx].get_text().as_deref().unwrap()).into_owned();
-                writer.write_event(Event::DocType(elem))?;
+        }
+        if self.data[idx].kind() == NodeKind::Comment {
+            let text = self.newlines(self.data[idx].get_text_ref().unwrap());
+            if self.round_trip {
+                // comments are kept as written, see `ParseOptions::round_trip`
+                writer.write_event(Event::Comment(BytesText::from_escaped_str(text)))?;
             } else {
-                let name = self.data[idx].get_name();
-                let mut elem = BytesStart::borrowed(name.as_bytes(), name.len());
-                for attr in self.data[idx].get_attr_iter() {
-                    elem.push_attribute((attr.0.as_str(), attr.1.as_str()));
-                }
-                if self.data[idx].get_text().is_some() {
-                    writer.write_event(Event::Start(elem))?;
-                    let elem =
-                        BytesText::from_plain_str(self.data[idx].get_text().as_deref().unwrap()).into_owned();
-                    writer.write_event(Event::Text(elem))?;
+                writer.write_event(Event::Comment(BytesText::from_plain_str(&text)))?;
+            }
+        } else if self.data[idx].kind() == NodeKind::CData {
+            writer.write_event(Event::CData(cdata_event(&self.newlines(self.data[idx].get_text_ref().unwrap()))))?;
+        } else if self.data[idx].kind() == NodeKind::ProcessingInstruction {
+            let elem = BytesText::from_escaped_str(self.data[idx].get_text_ref().unwrap());
+            writer.write_event(Event::PI(elem))?;
+        } else if self.data[idx].kind() == NodeKind::DocType {
+            let elem = BytesText::from_escaped_str(self.data[idx].get_text_ref().unwrap());
+            writer.write_event(Event::DocType(elem))?;
+        } else {
+            let empty = self.is_empty_elem(idx);
+            let extra = decls.get(&idx).map(|x| x.as_slice()).unwrap_or(&[]);
+            match self.data[idx].get_source_start().filter(|x| x.ends_with("/>") == empty && extra.is_empty()) {
+                Some(start) => writer.write(start.as_bytes())?,
+                None if empty => writer.write_event(Event::Empty(self.start_elem(idx, extra)))?,
+                None => writer.write_event(Event::Start(self.start_elem(idx, extra)))?,
+            }
+            if !empty {
+                let text = self.data[idx].get_text_ref().unwrap();
+                if self.is_cdata_elem(idx) && !text.trim().is_empty() {
+                    writer.write_event(Event::CData(cdata_event(&self.newlines(text))))?;
                 } else {
-                    writer.write_event(Event::Empty(elem))?;
-                    let elem = BytesText::from_plain_str(self.data[idx].get_tail().as_str()).into_owned();
-                    writer.write_event(Event::Text(elem))?;
+                    writer.write_event(Event::Text(self.text_event(text)))?;
                 }
+            } else {
+                let elem = self.text_event(tail(idx));
+                writer.write_event(Event::Text(elem))?;
             }
         }
+        Ok(())
+    }
+    /// close the nodes of `scope` which are still open after its last node
+    fn write_close<W: Write>(&self, writer: &mut Writer<W>, scope: &WriteScope) -> Result<(), Error> {
+        let tail = |idx: usize| scope.tail(self, idx);
+        let base = self.data[scope.range.start].get_route();
+        let nodelen = scope.range.end;
         // Close all remaining tags
-        if self.data[nodelen - 1].get_text().is_some() {
-            if !(self.data[nodelen - 1].get_localname().starts_with("<")
-                && self.data[nodelen - 1].get_localname().ends_with(">"))
-            {
-                let elem = BytesEnd::owned(Vec::<u8>::from(self.data[nodelen - 1].get_name()));
-                writer.write_event(Event::End(elem))?;
-            }
-            let elem = BytesText::from_plain_str(self.data[nodelen - 1].get_tail().as_str()).into_owned();
+        if !self.is_empty_elem(nodelen - 1) {
+            if self.data[nodelen - 1].is_element() {
+                self.write_end(writer, nodelen - 1)?;
+            }
+            let elem = self.text_event(tail(nodelen - 1));
             writer.write_event(Event::Text(elem))?;
         }
         let mut route = self.data[nodelen - 1].get_route();
-        while let Some(c) = close_tag.captures(&route.clone()) {
+        while let Some(c) = scope.close_tag.captures(&route.clone()).filter(|_| route != base) {
             route = c.name("parent").unwrap().as_str().to_string();
             let current = c.name("current").unwrap().as_str().to_string();
-            let closeidx = idxmap.get(&current).unwrap();
-            if !(self.data[*closeidx].get_localname().starts_with("<")
-                && self.data[*closeidx].get_localname().ends_with(">"))
-            {
-                let elem = BytesEnd::owned(Vec::<u8>::from(self.data[*closeidx].get_name()));
-                writer.write_event(Event::End(elem))?;
-            }
-            let elem = BytesText::from_plain_str(self.data[*closeidx].get_tail().as_str()).into_owned();
+            let closeidx = scope.idxmap.get(&current).unwrap();
+            if self.data[*closeidx].is_element() {
+                self.write_end(writer, *closeidx)?;
+            }
+            let elem = self.text_event(tail(*closeidx));
             writer.write_event(Event::Text(elem))?;
-            if route == "#" {
+            if route == base {
                 break;
             }
         }
-        Ok(writer.into_inner().into_inner())
+        Ok(())
+    }
+    /// text or tail event, escaped according to the version and `references` of the tree
+    ///
+    /// The text is borrowed if nothing needs escaping, so large texts are written without copies.
+    fn text_event<'a>(&self, text: &'a str) -> BytesText<'a> {
+        match self.newlines(text) {
+            Cow::Borrowed(text) => BytesText::from_escaped_str(self.escape().escape(text)),
+            Cow::Owned(text) => BytesText::from_escaped_str(self.escape().escape(&text).into_owned()),
+        }
+    }
+    /// newlines of `text` in the style of `output_newline`
+    fn newlines<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        match self.output_newline {
+            Some(newline) => writer::normalize_newlines(text, newline),
+            None => Cow::Borrowed(text),
+        }
+    }
+    fn escape(&self) -> writer::Escape {
+        writer::Escape {
+            keep_references: self.references,
+            xml11: self.version == b"1.1",
+            attr: false,
+            quote: self.quote_char,
+            policy: self.escape_policy,
+        }
+    }
+    /// write end tag of the element at `pos`, as written in the source if kept
+    fn write_end<W: Write>(&self, writer: &mut Writer<W>, pos: usize) -> Result<(), Error> {
+        match self.data[pos].get_source_end() {
+            Some(end) => writer.write(end.as_bytes())?,
+            None => writer.write_event(Event::End(BytesEnd::owned(Vec::<u8>::from(self.data[pos].get_name()))))?,
+        }
+        Ok(())
+    }
+    fn is_cdata_elem(&self, pos: usize) -> bool {
+        let node = &self.data[pos];
+        !self.cdata_elements.is_empty()
+            && self.cdata_elements.iter().any(|x| x == &node.get_name() || x == &node.get_tag())
+    }
+    fn is_empty_elem(&self, pos: usize) -> bool {
+        let node = &self.data[pos];
+        if !node.is_element() {
+            return false;
+        }
+        if pos + 1 < self.data.len()
+            && self.data[pos + 1].get_route() == format!("{}{}#", node.get_route(), node.get_idx())
+        {
+            return false;
+        }
+        match node.get_self_closing() {
+            Some(true) => node.get_text().map(|x| x.is_empty()).unwrap_or(true),
+            Some(false) => false,
+            None => node.get_text().is_none(),
+        }
+    }
+    /// start tag of the element at `pos`, namespace declarations `decls` (prefix, namespace) are written first
+    fn start_elem(&self, pos: usize, decls: &[(String, String)]) -> BytesStart<'static> {
+        // margin is the last line of whitespace before the node
+        let wrap = self.max_width.is_some() || self.attrs_per_line.is_some();
+        let before = if self.attr_style == AttrStyle::Inline && !wrap {
+            "".to_string()
+        } else if let Some(previous) = self.previous(pos) {
+            self.data[previous].get_tail()
+        } else if let Some(parent) = self.parent(pos) {
+            self.data[parent].get_text().unwrap_or_default()
+        } else if pos > 0 {
+            self.data[pos - 1].get_tail()
+        } else {
+            "".to_string()
+        };
+        let margin = before.rsplit(['\n', '\r']).next().unwrap_or("");
+        let node = &self.data[pos];
+        let decls: Vec<(String, String)> = decls.iter().map(|(prefix, ns)| (format!("xmlns:{}", prefix), ns.clone())).collect();
+        let mut attrs: Vec<&(String, String)> =
+            decls.iter().chain(node.get_attr_iter().filter(|x| !node.is_attr_defaulted(&x.0))).collect();
+        if self.attr_order == AttrOrder::Sorted {
+            attrs.sort_by(|a, b| a.0.cmp(&b.0));
+        }
+        let escape = writer::Escape {
+            keep_references: self.references || self.raw_attributes,
+            attr: true,
+            quote: match node.get_source_quote() {
+                Some('\'') => QuoteChar::Single,
+                Some(_) => QuoteChar::Double,
+                None => self.quote_char,
+            },
+            ..self.escape()
+        };
+        let mut attr_style = self.attr_style;
+        if self.attrs_per_line.map(|x| attrs.len() >= x).unwrap_or(false) {
+            attr_style = AttrStyle::Aligned;
+        }
+        if attr_style == AttrStyle::Inline {
+            let elem = writer::start_tag(&node.get_name(), attrs.iter().copied(), attr_style, margin, &self.crlf, escape);
+            // the tag between "<" and ">"
+            let width = margin.chars().count() + String::from_utf8_lossy(&elem).chars().count() + 2;
+            if self.max_width.map(|x| width <= x).unwrap_or(true) {
+                return elem;
+            }
+            attr_style = AttrStyle::Aligned;
+        }
+        writer::start_tag(&node.get_name(), attrs.into_iter(), attr_style, margin, &self.crlf, escape)
     }
-    fn detect_indent(&mut self) {
+    pub(crate) fn detect_indent(&mut self) {
         let mut idx = self.data.len();
         while idx > 0 {
             idx -= 1;
-            if !(self.data[idx].get_localname().starts_with("<") && self.data[idx].get_localname().ends_with(">"))
-            {
+            if self.data[idx].is_element() {
                 break;
             }
         }
@@ -830,7 +2297,7 @@ impl ETree {
                 self.data[pos].set_tail(&tail);
             }
             let offspring = self.descendant(pos);
-            let newpos = if offspring.len() == 0 {
+            let newpos = if offspring.is_empty() {
                 pos + 1
             } else {
                 offspring[offspring.len() - 1] + 1
@@ -850,21 +2317,16 @@ impl ETree {
             0 => {
                 // No child exists
                 let previous = self.previous(pos);
-                let tail = if previous.is_some() {
-                    format!("{}", self.data[previous.unwrap()].get_tail())
+                let tail = if let Some(previous) = previous {
+                    self.data[previous].get_tail()
+                } else if let Some(parent) = self.parent(pos) {
+                    self.data[parent].get_text().unwrap_or("".to_string())
                 } else {
-                    let parent = self.parent(pos);
-                    if parent.is_some() {
-                        format!("{}", self.data[parent.unwrap()].get_text().unwrap_or("".to_string()))
-                    } else {
-                        self.crlf.clone()
-                    }
+                    self.crlf.clone()
                 };
                 let text = format!("{}{}", tail, self.indent);
                 node.set_tail(&tail);
-                if self.data[pos].get_text().is_none() {
-                    self.data[pos].set_text(&text);
-                } else if self.data[pos].get_text().as_deref() == Some("") {
+                if self.data[pos].get_text().is_none() || self.data[pos].get_text().as_deref() == Some("") {
                     self.data[pos].set_text(&text);
                 }
                 node.set_idx(pos + 1);
@@ -886,6 +2348,49 @@ impl ETree {
         }
         Some(node)
     }
+    /// let a node appended below `parent` take the default namespace in scope there
+    ///
+    /// An unprefixed element without namespace is put in that namespace, unless it declares its own `xmlns`.
+    fn inherit_default_namespace(&self, node: &mut ETreeNode, parent: Option<usize>) {
+        if !node.is_element() || parent.map(|x| x >= self.data.len()).unwrap_or(false) {
+            return;
+        }
+        let default_ns = match node.get_attr("xmlns") {
+            Some(ns) => ns,
+            None => parent.map(|x| self.data[x].get_default_namespace()).unwrap_or_default(),
+        };
+        node.set_default_namespace(&default_ns);
+        if node.get_namespace_abbrev().is_empty() && node.get_namespace().is_empty() {
+            node.set_namespace(&default_ns);
+        }
+    }
+    /// unlink a subtree from the tree and return its nodes
+    fn take_subtree(&mut self, pos: usize) -> Vec<ETreeNode> {
+        let parent = self.parent(pos);
+        if let Some(previous) = self.previous(pos) {
+            let tail = self.data[pos].get_tail();
+            self.data[previous].set_tail(&tail);
+        } else if let Some(_next) = self.next(pos) {
+        } else if let Some(parent) = parent {
+            let mut text = String::from(self.data[parent].get_text().as_deref().unwrap());
+            if text.ends_with(&self.indent) {
+                let retain = text.len() - self.indent.len();
+                text.truncate(retain);
+                self.data[parent].set_text(&text);
+            }
+        }
+        let end = pos + 1 + self.descendant(pos).len();
+        let nodes: Vec<ETreeNode> = self.data.drain(pos..end).collect();
+        for node in nodes.iter() {
+            self.index.remove(&node.get_idx());
+            self.hashes.remove(&node.get_idx());
+        }
+        self.update_index(pos);
+        if let Some(parent) = parent {
+            self.update_hash(parent);
+        }
+        nodes
+    }
     fn subtree_reindex(&mut self, start_idx: usize) -> (usize, usize) {
         let datacnt = self.data.len();
         if datacnt > 0 {
@@ -922,26 +2427,14 @@ impl ETree {
             (0, 0)
         }
     }
-    fn set_indent(&mut self, indent: &str) {
-        let lines: Vec<&str> = indent.lines().collect();
-        if lines.len() >= 2 && lines[lines.len() - 1].len() > 0 {
-            if indent.contains("\r\n") {
-                self.crlf = "\r\n".to_string();
-            } else if indent.contains("\n") {
-                self.crlf = "\n".to_string();
-            } else {
-                self.crlf = "\r".to_string();
-            }
-        } else {
-            self.crlf = "\n".to_string();
-        }
-        self.indent = lines[lines.len() - 1].to_string();
-    }
-    fn pretty_tree(&mut self, pos: usize, level: usize) {
+    fn pretty_tree(&mut self, pos: usize, level: usize, indent_misc: bool) {
         let tail = format!("{}{}", self.crlf, self.indent.repeat(level));
         self.data[pos].set_tail(&tail);
+        if self.data[pos].get_attr("xml:space").as_deref() == Some("preserve") {
+            return;
+        }
         let children = self.children(pos);
-        if children.len() > 0 {
+        if !children.is_empty() {
             let text = format!(
                 "{}{}{}",
                 self.data[pos].get_text().as_deref().unwrap().trim(),
@@ -950,27 +2443,128 @@ impl ETree {
             );
             self.data[pos].set_text(&text);
             for subpos in children.iter() {
-                self.pretty_tree(*subpos, level + 1);
+                self.pretty_tree(*subpos, level + 1, indent_misc);
             }
             self.data[children[children.len() - 1]].set_tail(&tail);
+            if !indent_misc {
+                // comments and processing instructions start at column 0
+                for (i, subpos) in children.iter().enumerate() {
+                    if !matches!(self.data[*subpos].kind(), NodeKind::Comment | NodeKind::ProcessingInstruction) {
+                        continue;
+                    }
+                    if i == 0 {
+                        let text = format!("{}{}", self.data[pos].get_text().unwrap_or_default().trim(), self.crlf);
+                        self.data[pos].set_text(&text);
+                    } else {
+                        let previous = children[i - 1];
+                        self.data[previous].set_tail(&self.crlf.clone());
+                    }
+                }
+            }
         } else {
-            if !(self.data[pos].get_localname().starts_with("<") && self.data[pos].get_localname().ends_with(">"))
-            {
+            if self.data[pos].is_element() {
                 if let Some(text) = self.data[pos].get_text().as_deref() {
-                    self.data[pos].set_text(&text.trim());
+                    self.data[pos].set_text(text.trim());
                 }
             }
         }
     }
-    fn generate_index(&mut self) {
-        if self.enable_index {
-            self.index = HashMap::new();
+    /// get whether the text and the tail of each node are inside an element with xml:space="preserve"
+    fn preserved_spaces(&self) -> Vec<(bool, bool)> {
+        let mut flags: HashMap<usize, bool> = HashMap::new();
+        let mut out = Vec::with_capacity(self.data.len());
+        for node in self.data.iter() {
+            let parent = node.get_route().trim_end_matches('#').rsplit('#').next().and_then(|x| x.parse().ok());
+            let inherited = parent.and_then(|x| flags.get(&x).copied()).unwrap_or(false);
+            let flag = match node.get_attr("xml:space").as_deref() {
+                Some("preserve") => true,
+                Some("default") => false,
+                _ => inherited,
+            };
+            flags.insert(node.get_idx(), flag);
+            out.push((flag, inherited));
+        }
+        out
+    }
+    fn generate_index(&mut self) {
+        self.values = OnceLock::new();
+        if self.enable_index {
+            self.index = HashMap::new();
             for i in 0..self.data.len() {
                 self.index.insert(self.data[i].get_idx(), i);
             }
         }
     }
+    /// index of element names and attribute values, which is dropped by modifications
+    fn value_index(&self) -> &ValueIndex {
+        self.values.get_or_init(|| {
+            let mut index = ValueIndex::default();
+            for (pos, node) in self.data.iter().enumerate() {
+                if !node.is_element() {
+                    continue;
+                }
+                index.names.entry(node.get_name()).or_default().push(pos);
+                for (key, value) in node.get_attr_iter() {
+                    index.attrs.entry((key.clone(), value.clone())).or_default().push(pos);
+                }
+            }
+            index
+        })
+    }
+    fn calc_hash(&self, pos: usize) -> u64 {
+        let node = &self.data[pos];
+        if let Some(hash) = self.hashes.get(&node.get_idx()) {
+            return *hash;
+        }
+        let mut hasher = Fnv::new();
+        hasher.write_str(&node.get_tag());
+        for (key, value) in node.get_attr_iter() {
+            hasher.write_str(key);
+            hasher.write_str(value);
+        }
+        match node.get_text_ref() {
+            Some(text) => {
+                hasher.write(&[1]);
+                hasher.write_str(text);
+            }
+            None => hasher.write(&[0]),
+        }
+        for child in self.children(pos) {
+            hasher.write(&self.calc_hash(child).to_le_bytes());
+            hasher.write_str(self.data[child].get_tail_ref());
+        }
+        hasher.0
+    }
+    fn generate_hash(&mut self, pos: usize) -> u64 {
+        for child in self.children(pos) {
+            self.generate_hash(child);
+        }
+        let hash = self.calc_hash(pos);
+        self.hashes.insert(self.data[pos].get_idx(), hash);
+        hash
+    }
+    fn invalidate_hash(&mut self, pos: usize) -> Vec<usize> {
+        // the node may be renamed or get other attributes
+        self.values = OnceLock::new();
+        let mut path = Vec::new();
+        if self.enable_hash && pos < self.data.len() {
+            let mut cur = Some(pos);
+            while let Some(p) = cur {
+                self.hashes.remove(&self.data[p].get_idx());
+                path.push(p);
+                cur = self.parent(p);
+            }
+        }
+        path
+    }
+    fn update_hash(&mut self, pos: usize) {
+        for p in self.invalidate_hash(pos) {
+            let hash = self.calc_hash(p);
+            self.hashes.insert(self.data[p].get_idx(), hash);
+        }
+    }
     fn update_index(&mut self, pos: usize) {
+        self.values = OnceLock::new();
         if self.enable_index {
             for i in pos..self.data.len() {
                 if let Some(x) = self.index.get_mut(&self.data[i].get_idx()) {
@@ -980,7 +2574,29 @@ impl ETree {
         }
     }
     #[allow(dead_code)]
+    /// find the first node that matches `path` from the root node, fail if `path` is invalid
+    pub fn try_find(&self, path: &str) -> Result<Option<usize>, XPathError> {
+        self.try_find_at(path, self.root())
+    }
+    #[allow(dead_code)]
+    /// find the first node that matches `path` from specified node, fail if `path` is invalid
+    pub fn try_find_at(&self, path: &str, pos: usize) -> Result<Option<usize>, XPathError> {
+        Ok(self.try_find_at_iter(path, pos)?.next())
+    }
+    #[allow(dead_code)]
+    /// find nodes that matches `path` from the root node, fail if `path` is invalid
+    pub fn try_find_iter(&self, path: &str) -> Result<XPathIterator<'_>, XPathError> {
+        self.try_find_at_iter(path, self.root())
+    }
+    #[allow(dead_code)]
+    /// find nodes that matches `path` from specified node, fail if `path` is invalid
+    pub fn try_find_at_iter(&self, path: &str, pos: usize) -> Result<XPathIterator<'_>, XPathError> {
+        XPathIterator::try_new(self, path, pos, true)
+    }
+    #[allow(dead_code)]
     /// find the first node that matches `path` from the root node
+    ///
+    /// Panics if `path` is invalid, use `try_find` for paths which are not known in advance.
     pub fn find(&self, path: &str) -> Option<usize> {
         self.find_at(path, self.root())
     }
@@ -992,15 +2608,122 @@ impl ETree {
     }
     #[allow(dead_code)]
     /// find nodes that matches `path` from the root node
-    pub fn find_iter(&self, path: &str) -> XPathIterator {
+    pub fn find_iter(&self, path: &str) -> XPathIterator<'_> {
         self.find_at_iter(path, self.root())
     }
     #[allow(dead_code)]
     /// find nodes that matches `path` from specified node
-    pub fn find_at_iter(&self, path: &str, pos: usize) -> XPathIterator {
+    pub fn find_at_iter(&self, path: &str, pos: usize) -> XPathIterator<'_> {
         XPathIterator::new(self, path, pos, true)
     }
     #[allow(dead_code)]
+    /// find all nodes that matches `path` from the root node, in document order
+    pub fn find_all(&self, path: &str) -> Vec<usize> {
+        self.find_all_at(path, self.root())
+    }
+    #[allow(dead_code)]
+    /// find all nodes that matches `path` from specified node, in document order
+    pub fn find_all_at(&self, path: &str, pos: usize) -> Vec<usize> {
+        let mut found: Vec<usize> = self.find_at_iter(path, pos).collect();
+        found.sort_unstable();
+        found
+    }
+    #[allow(dead_code)]
+    /// find elements that match CSS `selector` in document order, panic if `selector` is invalid or not supported
+    ///
+    /// The selector is translated to XPath:
+    /// - `div`, `*`, `svg\:rect`: element by name, ":" of a prefix is escaped
+    /// - `#id`, `.class`: attribute `id`, word of attribute `class`
+    /// - `[attr]`, `[attr=v]`, `[attr~=v]`, `[attr|=v]`, `[attr^=v]`, `[attr$=v]`, `[attr*=v]`: attribute selectors,
+    ///   the value is a name or a string in quotes
+    /// - `a b`, `a > b`, `a + b`, `a ~ b`: descendant, child, next sibling and following sibling combinators
+    /// - `a, b`: elements matching either selector
+    /// - `:first-child`, `:last-child`, `:only-child`, `:nth-child(an+b)`, `:nth-last-child(an+b)` and the `-of-type`
+    ///   variants, which need an element name; positions are not supported after `+` or `~`
+    /// - `:not(simple)`, `:empty`, `:root` (in the first compound selector)
+    pub fn select(&self, selector: &str) -> Vec<usize> {
+        match self.try_select(selector) {
+            Ok(found) => found,
+            Err(e) => panic!("{}", e),
+        }
+    }
+    #[allow(dead_code)]
+    /// find elements that match CSS `selector` in document order, fail if `selector` is invalid or not supported
+    pub fn try_select(&self, selector: &str) -> Result<Vec<usize>, Error> {
+        let error = |position, message| Error::Selector {
+            selector: selector.to_string(),
+            position,
+            message,
+        };
+        let path = css::to_xpath(selector).map_err(|(position, message)| error(position, message))?;
+        let iter = self
+            .try_find_iter(&path)
+            .map_err(|_| error(0, format!("translated XPath {:?} is not supported", path)))?;
+        let mut found: Vec<usize> = iter.collect();
+        found.sort_unstable();
+        Ok(found)
+    }
+    #[allow(dead_code)]
+    /// get absolute XPath of the node of specified position, e.g. `/catalog/book[3]/title[1]`, which `find` resolves
+    /// to the node again
+    ///
+    /// Each step has the position among siblings of the same name. Comments are written as `comment()`, processing
+    /// instructions as `processing-instruction('target')` and CDATA or DOCTYPE as `node()`.
+    /// Will return None if pos is out of range
+    pub fn get_xpath_of(&self, pos: usize) -> Option<String> {
+        self.data.get(pos)?;
+        let mut steps = Vec::new();
+        let mut current = Some(pos);
+        while let Some(pos) = current {
+            let parent = self.parent(pos);
+            let siblings = match parent {
+                Some(parent) => self.children(parent),
+                None => self.roots_and_misc(),
+            };
+            let node = &self.data[pos];
+            let test = match node.kind() {
+                NodeKind::Element => node.get_name(),
+                NodeKind::Comment => "comment()".to_string(),
+                NodeKind::ProcessingInstruction => {
+                    format!("processing-instruction('{}')", node.get_pi_target().unwrap_or_default())
+                }
+                NodeKind::CData | NodeKind::DocType => "node()".to_string(),
+            };
+            // siblings are counted by name rather than by namespace, so the step doesn't depend on the prefixes
+            // registered for XPath
+            let matches = |other: &ETreeNode| match node.kind() {
+                NodeKind::Element => other.is_element() && other.get_name() == node.get_name(),
+                NodeKind::Comment => other.kind() == NodeKind::Comment,
+                NodeKind::ProcessingInstruction => other.is_pi() && other.get_pi_target() == node.get_pi_target(),
+                NodeKind::CData | NodeKind::DocType => true,
+            };
+            let index = siblings.into_iter().filter(|&x| x <= pos && matches(&self.data[x])).count();
+            steps.push(format!("{}[{}]", test, index));
+            current = parent;
+        }
+        steps.reverse();
+        Some(format!("/{}", steps.join("/")))
+    }
+    /// positions of top-level nodes including comments, processing instructions and DOCTYPE
+    fn roots_and_misc(&self) -> Vec<usize> {
+        (0..self.data.len()).filter(|&x| self.data[x].get_route() == "#").collect()
+    }
+    #[allow(dead_code)]
+    /// count nodes that matches `path` from the root node
+    pub fn count(&self, path: &str) -> usize {
+        self.find_iter(path).count()
+    }
+    #[allow(dead_code)]
+    /// sum texts of nodes that matches `path` from the root node as numbers, NaN if one is not a number
+    pub fn sum(&self, path: &str) -> f64 {
+        self.sum_nodes(self.find_iter(path))
+    }
+    fn sum_nodes<I: Iterator<Item = usize>>(&self, nodes: I) -> f64 {
+        nodes
+            .map(|x| self.data[x].get_text_ref().unwrap_or("").trim().parse::<f64>().unwrap_or(f64::NAN))
+            .sum()
+    }
+    #[allow(dead_code)]
     /// find the last node that matches `path` from the root node
     pub fn rfind(&self, path: &str) -> Option<usize> {
         self.rfind_at(path, self.root())
@@ -1013,17 +2736,23 @@ impl ETree {
     }
     #[allow(dead_code)]
     /// find nodes in reverse order that matches `path` from the root node
-    pub fn rfind_iter(&self, path: &str) -> XPathIterator {
+    pub fn rfind_iter(&self, path: &str) -> XPathIterator<'_> {
         self.rfind_at_iter(path, self.root())
     }
     #[allow(dead_code)]
     /// find nodes in reverse order that matches `path` from specified node
-    pub fn rfind_at_iter(&self, path: &str, pos: usize) -> XPathIterator {
+    pub fn rfind_at_iter(&self, path: &str, pos: usize) -> XPathIterator<'_> {
         XPathIterator::new(self, path, pos, false)
     }
 }
 
 /// transform root node into a tree
+impl std::fmt::Display for ETree {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.to_xml_string().map_err(|_| std::fmt::Error)?)
+    }
+}
+
 impl From<ETreeNode> for ETree {
     fn from(mut node: ETreeNode) -> Self {
         let mut tree = ETree {
@@ -1036,6 +2765,31 @@ impl From<ETreeNode> for ETree {
             crlf: "".to_string(),
             enable_index: false,
             index: HashMap::new(),
+            values: OnceLock::new(),
+            enable_hash: false,
+            hashes: HashMap::new(),
+            user_data: UserData::default(),
+            attr_style: AttrStyle::Inline,
+            attr_order: AttrOrder::Document,
+            escape_policy: EscapePolicy::new(),
+            quote_char: QuoteChar::Double,
+            max_width: None,
+            attrs_per_line: None,
+            write_doctype: true,
+            write_declaration: true,
+            strict_namespaces: false,
+            cdata_elements: Vec::new(),
+            output_newline: None,
+            round_trip: false,
+            source_decl: None,
+            bom: false,
+            entities: HashMap::new(),
+            references: false,
+            raw_attributes: false,
+            fragment: false,
+            head: None,
+            xpath_namespaces: HashMap::new(),
+            options: ParseOptions::default(),
         };
         node.set_idx(0);
         node.set_route("#");
@@ -1044,27 +2798,113 @@ impl From<ETreeNode> for ETree {
     }
 }
 
+/// check node against the node test of a XPath segment, prefixes in `namespaces` are matched by namespace
+pub(crate) fn node_test_matches(node: &ETreeNode, test: &str, namespaces: &HashMap<String, String>) -> bool {
+    let localname = node.get_localname();
+    let is_element = node.is_element();
+    match test {
+        "node()" => true,
+        "*" => is_element,
+        "comment()" => node.kind() == NodeKind::Comment,
+        _ => {
+            if let Some(target) = test.strip_prefix("processing-instruction(") {
+                let target = target.trim_end_matches(')').trim().trim_matches(|c| c == '\'' || c == '"');
+                node.is_pi() && (target.is_empty() || node.get_pi_target().as_deref() == Some(target))
+            } else if let Some(local) = test.strip_prefix("*:") {
+                is_element && localname == local
+            } else {
+                match test.split_once(':').and_then(|(prefix, local)| namespaces.get(prefix).map(|uri| (uri, local))) {
+                    Some((uri, "*")) => is_element && node.get_namespace() == *uri,
+                    Some((uri, local)) => is_element && node.get_namespace() == *uri && localname == local,
+                    None => match test.strip_suffix(":*") {
+                        Some(prefix) => is_element && node.get_name().split_once(':').map(|x| x.0) == Some(prefix),
+                        None => is_element && node.get_name() == test,
+                    },
+                }
+            }
+        }
+    }
+}
+
+/// value of "local-name", "namespace-uri" or "name" of `node`, the target for processing instructions and empty for
+/// other nodes which are not elements
+pub(crate) fn name_function(node: &ETreeNode, func: &str) -> String {
+    if !node.is_element() {
+        return match func {
+            "namespace-uri" => String::new(),
+            _ => node.get_pi_target().unwrap_or_default(),
+        };
+    }
+    match func {
+        "local-name" => node.get_localname(),
+        "namespace-uri" => node.get_namespace(),
+        _ => node.get_name(),
+    }
+}
+
+/// context of absolute paths, the parent of the top-level nodes
+const DOCUMENT: usize = usize::MAX;
+
 /// XPath operation
 ///
 /// # Supported syntax:
 /// ## Node query
-/// - `nodename`: the same as `//nodename`
-/// - `*`: any node
-/// - `/`: node in the children of current node
-/// - `//`: node in the descendant of current node
-/// - `.`: current node
+/// - `nodename`: `descendant-or-self::nodename` at the start of the path, the current node or its descendants, so from
+///   the root node the same as `//nodename`
+/// - `*`: any element
+/// - `prefix:nodename`: element in the namespace bound to `prefix` by `register_xpath_namespace`, otherwise the
+///   literal name
+/// - `prefix:*`: any element in the namespace bound to `prefix`, otherwise any element written with `prefix`
+/// - `*:nodename`: element with local name `nodename` in any or no namespace
+/// - `node()`: any node including comments and processing instructions
+/// - `comment()`: comment node
+/// - `processing-instruction()`: processing instruction node
+/// - `processing-instruction('target')`: processing instruction node with `target`
+/// - `text()`: texts are not nodes of the tree, so the node which directly contains text is selected, e.g.
+///   `//p/text()` selects `p` elements with text; `ETree::evaluate` returns the texts themselves
+/// - `/`: node in the children of current node, at the start of the path the top-level nodes of the document
+/// - `//`: node in the descendant of current node, at the start of the path any node of the document
+/// - `.`: current node, e.g. `.//nodename` for descendants of the node passed to `find_at`
 /// - `..`: parent node
 /// - `@attrname`
+/// - `ancestor::nodename`, `ancestor-or-self::nodename`: ancestors of current node, nearest first for predicates
+/// - `following-sibling::nodename`, `preceding-sibling::nodename`: siblings after or before current node, nearest
+///   first for predicates
+/// - `following::nodename`, `preceding::nodename`: nodes after or before current node in document order, except its
+///   descendants and ancestors, nearest first for predicates
+/// - `self::`, `parent::`, `child::`, `descendant::`, `descendant-or-self::`: the same nodes as `.`, `..`, `/` and
+///   `//` with a node test, e.g. `descendant-or-self::node()` or `self::chapter`
+/// - `path1 | path2`: nodes of both paths in document order without duplicates
 /// ## Node Predicate
 /// - `[1]`: first element
 /// - `[last()-1]`: second to last element
 /// - `[position() < 3]`: first and second element
 /// - `[@attrname]`: element with attr `attrname`
 /// - `[@*]`: element with any attr
-/// - `[@attrname='value']`: element with attr `attrname`=`value`
+/// - `[@attrname='value']`: element with attr `attrname`=`value`, strings are written in single or double quotes,
+///   `\'`, `\"` and `\\` are escapes of the quotes and the backslash
+/// - `[@attrname >= 100]`: `<`, `<=`, `>` and `>=` compare numbers, `=` and `!=` compare numbers if a side is a
+///   number and strings otherwise
 /// - `[text()='value']`: element which text is equal to `value`
 /// - `[child-tag='value']`: element which contains child `child-tag` and child tag's text is equal to `value`
 /// - `[text()='value' and child-tag='value']`: multiple condition with `and`/`or` and parenthesis
+/// - `[contains(@attrname, 'value')]`, `[starts-with(text(), 'value')]`: substring of an attr, the text (`text()` or
+///   `.`) or a child tag's text
+/// - `[matches(text(), '^[A-Z]{3}\d+$')]`: text, attr or child tag's text matches a regular expression, a pattern
+///   literal must be valid, a pattern taken from an attr or child tag matches nothing if it is invalid
+/// - `[substring-before(@date, '-')='2024']`, `[substring(text(), 1, 3)='abc']`, `[translate(., 'ABC', 'abc')='a']`:
+///   the string functions `substring`, `substring-before`, `substring-after` and `translate`
+/// - `[local-name()='svg']`, `[namespace-uri()='http://www.w3.org/2000/svg']`, `[name()='svg:rect']`: name of the
+///   element without prefix, its namespace, or the name as written
+/// - `[lower-case(text())='value']`, `[upper-case(@attrname)='VALUE']`, `[lower-case(local-name())='title']`:
+///   case-insensitive matching of values and names (functions of XPath 2.0)
+/// - `[normalize-space(text())='value']`, `[string-length(@attrname) > 3]`: compare whitespace-normalized text or
+///   length, the argument defaults to the text
+/// - `[not(@attrname)]`, `[not(child-tag)]`, `[true()]`, `[false()]`: negation and boolean literals
+/// - `[@price * @qty > 100]`, `[position() mod 2 = 1]`: arithmetic with `+`, `-`, `*`, `div` and `mod`, values are
+///   converted to numbers and NaN if they are not
+/// - `[count(child-tag) > 3]`, `[sum(child-tag) < 10]`: number of children or sum of their texts, `*` and other node
+///   tests are allowed
 /// # Search algorithm
 /// 1. `path` is split into multiple parts by consecutive "/".
 ///    - e.g. "//tag1/tag2[text()='abc']" is split into ["//tag1", "/tag2[text()='abc']"]
@@ -1076,143 +2916,382 @@ pub struct XPathIterator<'a> {
     direction: bool,
     path_list: Vec<xpath::XPathSegment>,
     todo_list: Vec<(usize, usize)>,
+    namespaces: HashMap<String, String>,
+    start: usize,
+    unions: Vec<Vec<xpath::XPathSegment>>,
+    merged: Option<std::vec::IntoIter<usize>>,
+    found: HashSet<usize>,
+    view: Option<SubtreeView<'a>>,
 }
 
 impl<'a> XPathIterator<'a> {
     #[allow(dead_code)]
     fn new(tree: &'a ETree, path: &str, pos: usize, dir: bool) -> Self {
-        let (remaining, mut path_todo) = xpath::xpath(path).unwrap();
-        debug_assert_eq!(remaining, "");
-        if path_todo[0].separator == "" {
-            if path_todo[0].node == "." {
-                path_todo.remove(0);
-            } else if path_todo[0].node == ".." {
-                path_todo[0].separator = "/".to_string();
-            } else {
-                path_todo[0].separator = "//".to_string();
+        match Self::try_new(tree, path, pos, dir) {
+            Ok(iter) => iter,
+            Err(e) => panic!("{}", e),
+        }
+    }
+    fn try_new(tree: &'a ETree, path: &str, pos: usize, dir: bool) -> Result<Self, XPathError> {
+        let mut paths = xpath::parse(path).map_err(|(position, end, message)| Error::XPath {
+            expr: path.to_string(),
+            position,
+            end,
+            message,
+        })?;
+        for path_todo in paths.iter_mut() {
+            if !path_todo[0].separator.is_empty() {
+                // an absolute path starts at the document, whose children are the top-level nodes
+                path_todo.insert(
+                    0,
+                    xpath::XPathSegment {
+                        separator: "/".to_string(),
+                        axis: String::new(),
+                        node: "/".to_string(),
+                        condition: xpath::Predictor::None,
+                    },
+                );
+            } else if path_todo[0].separator.is_empty() {
+                if !path_todo[0].axis.is_empty() {
+                    path_todo[0].separator = "/".to_string();
+                } else if path_todo[0].node == "." {
+                    path_todo.remove(0);
+                } else if path_todo[0].node == ".." {
+                    path_todo[0].separator = "/".to_string();
+                } else if path_todo[0].node == "text()" {
+                    // texts of the current node and its descendants
+                    path_todo[0].separator = "//".to_string();
+                } else {
+                    // a relative first step searches the current node and its descendants
+                    path_todo[0].separator = "/".to_string();
+                    path_todo[0].axis = "descendant-or-self".to_string();
+                }
             }
         }
-        Self {
-            tree: tree,
+        let path_todo = paths.remove(0);
+        Ok(Self {
+            tree,
             direction: dir,
             path_list: path_todo,
             todo_list: vec![(pos, 0)],
+            namespaces: tree.xpath_namespaces.clone(),
+            start: pos,
+            unions: paths,
+            merged: None,
+            found: HashSet::new(),
+            view: None,
+        })
+    }
+    /// search `path` in `view`, whose root is the top-level node of the document
+    pub(crate) fn try_new_in(view: SubtreeView<'a>, path: &str) -> Result<Self, XPathError> {
+        let mut iter = Self::try_new(view.tree(), path, view.root(), true)?;
+        iter.view = Some(view);
+        Ok(iter)
+    }
+    #[allow(dead_code)]
+    /// bind `prefix` to namespace `uri` for this query, in addition to `ETree::register_xpath_namespace`
+    pub fn with_namespace(mut self, prefix: &str, uri: &str) -> Self {
+        self.namespaces.insert(prefix.to_string(), uri.to_string());
+        self
+    }
+    /// value of "count(test)" or "sum(test)" over the children of `pos` which match the node test, or of a name
+    /// function like "local-name()" of the node at `pos`
+    fn aggregate(&self, key: &str, pos: usize) -> Option<String> {
+        let (func, test) = key.strip_suffix(')')?.split_once('(')?;
+        if test.is_empty() && matches!(func, "local-name" | "namespace-uri" | "name") {
+            return Some(name_function(self.tree.node(pos)?, func));
+        }
+        let children = self
+            .tree
+            .children(pos)
+            .into_iter()
+            .filter(|&x| node_test_matches(self.tree.node(x).unwrap(), test, &self.namespaces));
+        match func {
+            "count" => Some(children.count().to_string()),
+            "sum" => Some(self.tree.sum_nodes(children).to_string()),
+            _ => None,
+        }
+    }
+    /// value of attribute `name`, resolved by namespace if its prefix is bound
+    fn attr(&self, node: &ETreeNode, name: &str) -> Option<String> {
+        match name.split_once(':').and_then(|(prefix, local)| self.namespaces.get(prefix).map(|uri| (uri, local))) {
+            Some((uri, local)) => node.get_attr_ns(uri, local),
+            None => node.get_attr(name),
         }
     }
     fn _find(&self, path: &xpath::XPathSegment, pos: usize) -> Vec<usize> {
         let mut result: Vec<usize> = Vec::new();
-        if path.separator == "/" && path.node == "." {
+        if path.node == "/" {
+            result.push(DOCUMENT);
+        } else if path.separator == "/" && path.node == "." {
             result.push(pos);
         } else if path.separator == "/" && path.node == ".." {
-            if let Some(parent) = self.tree.parent(pos) {
+            if let Some(parent) = self.parent_of(pos) {
                 result.push(parent);
             }
+        } else if path.node == "text()" && path.axis.is_empty() {
+            // the text is owned by the context node, or by its descendants after "//"
+            let mut contexts = vec![pos];
+            if path.separator == "//" {
+                contexts.extend(self.descendant_of(pos));
+            }
+            result.extend(contexts.into_iter().filter(|&x| x != DOCUMENT && !self.tree.texts(x).is_empty()));
+        } else if !path.axis.is_empty() {
+            // after "//" the axis is applied to the node and each descendant, predicates are evaluated per context node
+            let mut contexts = vec![pos];
+            if path.separator == "//" {
+                contexts.extend(self.descendant_of(pos));
+            }
+            for context in contexts {
+                let nodes = self
+                    .indexed(path, &path.axis, context)
+                    .unwrap_or_else(|| self.axis_nodes(&path.axis, context));
+                result.extend(self.filter(path, nodes));
+            }
+            result.sort_unstable();
+            result.dedup();
         } else {
             let container = if path.separator == "//" {
-                self.tree.descendant(pos)
+                self.indexed(path, "descendant", pos).unwrap_or_else(|| self.descendant_of(pos))
             } else {
                 /* "/" */
-                self.tree.children(pos)
-            };
-            let mut container = if path.node == "*" {
-                container.clone()
-            } else {
-                container
-                    .iter()
-                    .filter(|&x| self.tree.node(*x).unwrap().get_name() == path.node)
-                    .map(|x| *x)
-                    .collect()
+                self.children_of(pos)
             };
-            if path.condition == xpath::Predictor::None {
-                result.append(&mut container);
-            } else {
-                let (c, mut a, _) = path.condition.collect();
-                if let Some(idx) = a.iter().position(|x| x == "*") {
-                    a.remove(idx);
+            result = self.filter(path, container);
+        }
+        result
+    }
+    /// positions of the nodes which can be reached, all nodes or those of the view
+    fn bounds(&self) -> Range<usize> {
+        match &self.view {
+            Some(view) => view.range(),
+            None => 0..self.tree.data.len(),
+        }
+    }
+    /// parent of `pos`, `None` for the top-level nodes
+    fn parent_of(&self, pos: usize) -> Option<usize> {
+        match &self.view {
+            Some(view) => view.parent(pos),
+            None => self.tree.parent(pos),
+        }
+    }
+    /// children of `pos`, the top-level nodes for `DOCUMENT`
+    fn children_of(&self, pos: usize) -> Vec<usize> {
+        match (&self.view, pos) {
+            (Some(view), DOCUMENT) => vec![view.root()],
+            (Some(view), _) => view.children(pos),
+            (None, DOCUMENT) => (0..self.tree.data.len()).filter(|&x| self.tree.data[x].get_route() == "#").collect(),
+            (None, _) => self.tree.children(pos),
+        }
+    }
+    /// descendants of `pos`, all nodes for `DOCUMENT`
+    fn descendant_of(&self, pos: usize) -> Vec<usize> {
+        match (&self.view, pos) {
+            (_, DOCUMENT) => self.bounds().collect(),
+            (Some(view), _) => view.descendant(pos),
+            (None, _) => self.tree.descendant(pos),
+        }
+    }
+    /// nodes on the descendant axes of `pos` which may match `path`, looked up by name or attribute value when index
+    /// feature is enabled, `None` if the index does not apply
+    fn indexed(&self, path: &xpath::XPathSegment, axis: &str, pos: usize) -> Option<Vec<usize>> {
+        if !self.tree.enable_index || !matches!(axis, "descendant" | "descendant-or-self") {
+            return None;
+        }
+        // names with a bound prefix are matched by namespace
+        let bound = |name: &str| name.split_once(':').is_some_and(|(prefix, _)| self.namespaces.contains_key(prefix));
+        let plain = |test: &str| test != "*" && !test.contains('(') && !test.starts_with("*:") && !test.ends_with(":*");
+        // a predicate on positions counts the nodes which pass the node test
+        let (_, _, functions) = path.condition.collect();
+        let positional = functions.iter().any(|x| x == "position()" || x == "last()");
+        let index = self.tree.value_index();
+        let candidates = match path.condition.attr_equals() {
+            Some((key, value)) if !positional && !bound(&key) => index.attrs.get(&(key, value)),
+            _ if plain(&path.node) && !bound(&path.node) => index.names.get(&path.node),
+            _ => return None,
+        };
+        let candidates = candidates.map(|x| x.as_slice()).unwrap_or_default();
+        if pos == DOCUMENT {
+            let bounds = self.bounds();
+            return Some(candidates.iter().copied().filter(|x| bounds.contains(x)).collect());
+        }
+        // descendants follow the node
+        let node = &self.tree.data[pos];
+        let route = format!("{}{}#", node.get_route(), node.get_idx());
+        let start = candidates.partition_point(|&x| x <= pos);
+        let mut out: Vec<usize> = candidates[start..]
+            .iter()
+            .copied()
+            .take_while(|&x| self.tree.data[x].get_route().starts_with(&route))
+            .collect();
+        if axis == "descendant-or-self" && candidates.binary_search(&pos).is_ok() {
+            out.insert(0, pos);
+        }
+        Some(out)
+    }
+    /// nodes on `axis` of `pos`, in the order of the axis (nearest first for reverse axes)
+    fn axis_nodes(&self, axis: &str, pos: usize) -> Vec<usize> {
+        let mut out = Vec::new();
+        if pos == DOCUMENT {
+            // the document has neither ancestors nor siblings, and is not a node of the tree
+            match axis {
+                "child" => out = self.children_of(pos),
+                "descendant" | "descendant-or-self" => out = self.descendant_of(pos),
+                _ => {}
+            }
+            return out;
+        }
+        match axis {
+            "ancestor" | "ancestor-or-self" => {
+                if axis == "ancestor-or-self" {
+                    out.push(pos);
                 }
-                let container_len = container.len();
-                for i in 0..container_len {
-                    let mut info = HashMap::new();
-                    if self.tree.node(container[i]).unwrap().get_attr_count() > 0 {
-                        info.insert("@*".to_string(), "true".to_string());
-                        for param in a.iter() {
-                            if let Some(v) = self.tree.node(container[i]).unwrap().get_attr(param) {
-                                info.insert(format!("@{}", param), v);
-                            }
+                let mut current = self.parent_of(pos);
+                while let Some(parent) = current {
+                    out.push(parent);
+                    current = self.parent_of(parent);
+                }
+            }
+            "self" => out.push(pos),
+            "parent" => out.extend(self.parent_of(pos)),
+            "child" => out = self.children_of(pos),
+            "descendant" => out = self.descendant_of(pos),
+            "descendant-or-self" => {
+                out.push(pos);
+                out.extend(self.descendant_of(pos));
+            }
+            "following" => {
+                // nodes after the subtree of `pos`, which are stored in document order
+                let end = pos + 1 + self.descendant_of(pos).len();
+                out.extend(end..self.bounds().end);
+            }
+            "preceding" => {
+                // nodes before `pos` except its ancestors, nearest first
+                let ancestors = self.axis_nodes("ancestor", pos);
+                out.extend((self.bounds().start..pos).rev().filter(|x| !ancestors.contains(x)));
+            }
+            "following-sibling" | "preceding-sibling" => {
+                // siblings share the route of `pos`, nodes between them have longer routes below the same parent;
+                // the root of a view has no siblings in it
+                if matches!(&self.view, Some(view) if view.root() == pos) {
+                    return out;
+                }
+                let route = self.tree.data[pos].get_route_ref();
+                let bounds = self.bounds();
+                let nodes: Box<dyn Iterator<Item = usize>> = if axis == "following-sibling" {
+                    Box::new(pos + 1..bounds.end)
+                } else {
+                    Box::new((bounds.start..pos).rev())
+                };
+                for x in nodes {
+                    let current = self.tree.data[x].get_route_ref();
+                    if current == route {
+                        out.push(x);
+                    } else if !current.starts_with(route) {
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+        out
+    }
+    /// nodes of `container` which match the node test and the predicate of `path`, in document order
+    fn filter(&self, path: &xpath::XPathSegment, container: Vec<usize>) -> Vec<usize> {
+        let mut result: Vec<usize> = Vec::new();
+        let mut container: Vec<usize> = container
+            .into_iter()
+            .filter(|&x| node_test_matches(self.tree.node(x).unwrap(), &path.node, &self.namespaces))
+            .collect();
+        if path.condition == xpath::Predictor::None {
+            result.append(&mut container);
+        } else {
+            let (c, mut a, f) = path.condition.collect();
+            if let Some(idx) = a.iter().position(|x| x == "*") {
+                a.remove(idx);
+            }
+            let container_len = container.len();
+            for (i, &cur) in container.iter().enumerate() {
+                let mut info = HashMap::new();
+                if self.tree.node(cur).unwrap().get_attr_count() > 0 {
+                    info.insert("@*".to_string(), "true".to_string());
+                    for param in a.iter() {
+                        if let Some(v) = self.attr(self.tree.node(cur).unwrap(), param) {
+                            info.insert(format!("@{}", param), v);
                         }
-                    } else {
-                        info.insert("@*".to_string(), "false".to_string());
                     }
-                    info.insert(
-                        "text()".to_string(),
-                        self.tree
-                            .node(container[i])
-                            .unwrap()
-                            .get_text()
-                            .unwrap_or("".to_string()),
-                    );
-                    info.insert("position()".to_string(), format!("{}", i + 1));
-                    info.insert("last()".to_string(), format!("{}", container_len));
-                    if c.len() > 0 {
-                        let mut subfound: Vec<Vec<usize>> = Vec::new();
-                        let mut curcomb: Vec<usize> = Vec::new();
-                        for _ in 0..c.len() {
-                            subfound.push(Vec::new());
-                            curcomb.push(0);
+                } else {
+                    info.insert("@*".to_string(), "false".to_string());
+                }
+                info.insert(
+                    "text()".to_string(),
+                    self.tree
+                        .node(cur)
+                        .unwrap()
+                        .get_text()
+                        .unwrap_or("".to_string()),
+                );
+                info.insert("position()".to_string(), format!("{}", i + 1));
+                info.insert("last()".to_string(), format!("{}", container_len));
+                for key in f.iter() {
+                    if let Some(value) = self.aggregate(key, cur) {
+                        info.insert(key.clone(), value);
+                    }
+                }
+                if !c.is_empty() {
+                    let mut subfound: Vec<Vec<usize>> = Vec::new();
+                    let mut curcomb: Vec<usize> = Vec::new();
+                    for _ in 0..c.len() {
+                        subfound.push(Vec::new());
+                        curcomb.push(0);
+                    }
+                    let subchildren = self.children_of(cur);
+                    for subi in subchildren {
+                        for subj in 0..c.len() {
+                            if self.tree.node(subi).unwrap().get_name() == c[subj] {
+                                subfound[subj].push(subi);
+                            }
                         }
-                        let subchildren = self.tree.children(container[i]);
-                        for subi in subchildren {
-                            for subj in 0..c.len() {
-                                if self.tree.node(subi).unwrap().get_name() == c[subj] {
-                                    subfound[subj].push(subi);
-                                }
+                    }
+                    // children which are missing are left out of info, so a condition on them is false
+                    let mut exit_flag = false;
+                    loop {
+                        for subj in 0..c.len() {
+                            if let Some(&sub) = subfound[subj].get(curcomb[subj]) {
+                                info.insert(c[subj].clone(), self.tree.node(sub).unwrap().get_text().unwrap_or_default());
                             }
                         }
-                        if subfound.iter().all(|ref x| x.len() > 0) {
-                            let mut exit_flag = false;
-                            loop {
-                                for subj in 0..c.len() {
-                                    info.insert(
-                                        c[subj].clone(),
-                                        self.tree
-                                            .node(subfound[subj][curcomb[subj]])
-                                            .unwrap()
-                                            .get_text()
-                                            .unwrap_or("".to_string()),
-                                    );
-                                }
-                                if eval::eval(path.condition.expr(&info).as_str()) == Ok(eval::to_value(true)) {
-                                    result.push(container[i]);
-                                    break;
-                                }
-                                let mut subi = curcomb.len() - 1;
-                                loop {
-                                    curcomb[subi] += 1;
-                                    if curcomb[subi] >= subfound[subi].len() {
-                                        curcomb[subi] = 0;
-                                        if subi > 0 {
-                                            subi -= 1;
-                                        } else {
-                                            exit_flag = true;
-                                            break;
-                                        }
-                                    } else {
-                                        break;
-                                    }
-                                }
-                                if exit_flag {
+                        if path.condition.matches(&info) {
+                            result.push(cur);
+                            break;
+                        }
+                        let mut subi = curcomb.len() - 1;
+                        loop {
+                            curcomb[subi] += 1;
+                            if curcomb[subi] >= subfound[subi].len() {
+                                curcomb[subi] = 0;
+                                if subi > 0 {
+                                    subi -= 1;
+                                } else {
+                                    exit_flag = true;
                                     break;
                                 }
+                            } else {
+                                break;
                             }
                         }
-                    } else {
-                        if eval::eval(path.condition.expr(&info).as_str()) == Ok(eval::to_value(true)) {
-                            result.push(container[i]);
+                        if exit_flag {
+                            break;
                         }
                     }
+                } else {
+                    if path.condition.matches(&info) {
+                        result.push(cur);
+                    }
                 }
             }
         }
+        result.sort_unstable();
         result
     }
 }
@@ -1220,10 +3299,36 @@ impl<'a> XPathIterator<'a> {
 impl<'a> Iterator for XPathIterator<'a> {
     type Item = usize;
     fn next(&mut self) -> Option<Self::Item> {
-        while !self.todo_list.is_empty() {
-            let item = self.todo_list.pop().unwrap();
+        if let Some(merged) = self.merged.as_mut() {
+            return merged.next();
+        } else if self.unions.is_empty() {
+            return self.next_match();
+        }
+        // results of all paths of a union are merged in document order
+        let mut found: Vec<usize> = std::iter::from_fn(|| self.next_match()).collect();
+        for path_list in std::mem::take(&mut self.unions) {
+            self.path_list = path_list;
+            self.todo_list = vec![(self.start, 0)];
+            found.extend(std::iter::from_fn(|| self.next_match()));
+        }
+        found.sort_unstable();
+        found.dedup();
+        if !self.direction {
+            found.reverse();
+        }
+        self.merged.insert(found.into_iter()).next()
+    }
+}
+
+impl<'a> XPathIterator<'a> {
+    /// next node of `path_list`
+    fn next_match(&mut self) -> Option<usize> {
+        while let Some(item) = self.todo_list.pop() {
             if item.1 >= self.path_list.len() {
-                return Some(item.0);
+                // a node is reached from several context nodes, e.g. by "//p/.."
+                if item.0 != DOCUMENT && self.found.insert(item.0) {
+                    return Some(item.0);
+                }
             } else {
                 let result = self._find(&self.path_list[item.1], item.0);
                 let rlen = result.len();
@@ -1245,19 +3350,1721 @@ impl<'a> Iterator for XPathIterator<'a> {
     }
 }
 
-#[derive(Debug)]
-pub enum WriteError {
-    IOErr(std::io::Error),
-    XMLErr(quick_xml::Error),
+/// CDATA section of `text`, which is written verbatim, "]]>" would end the section early so split it there
+fn cdata_event(text: &str) -> BytesText<'static> {
+    BytesText::from_escaped_str(text.replace("]]>", "]]]]><![CDATA[>"))
+}
+
+/// decompress gzip or zstd content if the feature is enabled, other content is returned as is
+fn decompress(content: Vec<u8>) -> std::io::Result<Vec<u8>> {
+    #[cfg(feature = "gzip")]
+    if content.starts_with(&[0x1f, 0x8b]) {
+        let mut out = Vec::new();
+        flate2::read::MultiGzDecoder::new(&content[..]).read_to_end(&mut out)?;
+        return Ok(out);
+    }
+    #[cfg(feature = "zstd")]
+    if content.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        let mut out = Vec::new();
+        ruzstd::decoding::StreamingDecoder::new(&content[..])
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?
+            .read_to_end(&mut out)?;
+        return Ok(out);
+    }
+    Ok(content)
+}
+
+/// match sibling nodes `old` and `new` by name in order, return pairs of positions
+///
+/// Common leading and trailing names are matched first. The rest is split at names which occur once on both
+/// sides (patience diff), gaps without such names are matched by longest common subsequence if they are small
+/// and greedily otherwise, so memory stays linear for long lists of siblings.
+fn match_siblings(old_data: &[ETreeNode], old: &[usize], new_data: &[ETreeNode], new: &[usize]) -> Vec<(usize, usize)> {
+    let a: Vec<String> = old.iter().map(|&x| old_data[x].get_name()).collect();
+    let b: Vec<String> = new.iter().map(|&x| new_data[x].get_name()).collect();
+    let mut out = Vec::new();
+    let mut todo = vec![(0..a.len(), 0..b.len())];
+    while let Some((mut ra, mut rb)) = todo.pop() {
+        while !ra.is_empty() && !rb.is_empty() && a[ra.start] == b[rb.start] {
+            out.push((ra.start, rb.start));
+            ra.start += 1;
+            rb.start += 1;
+        }
+        while !ra.is_empty() && !rb.is_empty() && a[ra.end - 1] == b[rb.end - 1] {
+            out.push((ra.end - 1, rb.end - 1));
+            ra.end -= 1;
+            rb.end -= 1;
+        }
+        if ra.is_empty() || rb.is_empty() {
+            continue;
+        }
+        let anchors = unique_anchors(&a[ra.clone()], &b[rb.clone()]);
+        if anchors.is_empty() {
+            match_gap(&a[ra.clone()], &b[rb.clone()], (ra.start, rb.start), &mut out);
+            continue;
+        }
+        let (mut i, mut j) = (ra.start, rb.start);
+        for (x, y) in anchors {
+            out.push((ra.start + x, rb.start + y));
+            todo.push((i..ra.start + x, j..rb.start + y));
+            i = ra.start + x + 1;
+            j = rb.start + y + 1;
+        }
+        todo.push((i..ra.end, j..rb.end));
+    }
+    out.sort_unstable();
+    out.into_iter().map(|(i, j)| (old[i], new[j])).collect()
 }
 
-impl From<std::io::Error> for WriteError {
-    fn from(value: std::io::Error) -> Self {
-        return Self::IOErr(value);
+/// pairs of positions of names which occur once in `a` and once in `b`, the longest increasing sequence of them
+fn unique_anchors(a: &[String], b: &[String]) -> Vec<(usize, usize)> {
+    // name -> (count in a, position in a, count in b, position in b)
+    let mut counts: HashMap<&str, (usize, usize, usize, usize)> = HashMap::new();
+    for (i, name) in a.iter().enumerate() {
+        let entry = counts.entry(name).or_default();
+        entry.0 += 1;
+        entry.1 = i;
     }
+    for (j, name) in b.iter().enumerate() {
+        if let Some(entry) = counts.get_mut(name.as_str()) {
+            entry.2 += 1;
+            entry.3 = j;
+        }
+    }
+    let mut pairs: Vec<(usize, usize)> =
+        counts.values().filter(|x| x.0 == 1 && x.2 == 1).map(|x| (x.1, x.3)).collect();
+    pairs.sort_unstable();
+    // patience sorting: tops[k] is the pair ending the best sequence of length k + 1, prev links the sequences
+    let mut tops: Vec<usize> = Vec::new();
+    let mut prev: Vec<Option<usize>> = vec![None; pairs.len()];
+    for k in 0..pairs.len() {
+        let len = tops.partition_point(|&t| pairs[t].1 < pairs[k].1);
+        prev[k] = len.checked_sub(1).map(|x| tops[x]);
+        if len == tops.len() {
+            tops.push(k);
+        } else {
+            tops[len] = k;
+        }
+    }
+    let mut out = Vec::with_capacity(tops.len());
+    let mut cur = tops.last().copied();
+    while let Some(k) = cur {
+        out.push(pairs[k]);
+        cur = prev[k];
+    }
+    out.reverse();
+    out
 }
-impl From<quick_xml::Error> for WriteError {
-    fn from(value: quick_xml::Error) -> Self {
-        return Self::XMLErr(value);
+
+/// match names of `a` and `b` without common unique names, `base` is the position of both slices
+fn match_gap(a: &[String], b: &[String], base: (usize, usize), out: &mut Vec<(usize, usize)>) {
+    // cells of the longest common subsequence table, larger gaps are matched greedily
+    const MAX_CELLS: usize = 1 << 20;
+    if (a.len() + 1).saturating_mul(b.len() + 1) > MAX_CELLS {
+        let mut positions: HashMap<&str, std::collections::VecDeque<usize>> = HashMap::new();
+        for (j, name) in b.iter().enumerate() {
+            positions.entry(name).or_default().push_back(j);
+        }
+        let mut next = 0;
+        for (i, name) in a.iter().enumerate() {
+            if let Some(queue) = positions.get_mut(name.as_str()) {
+                while queue.front().map(|&j| j < next).unwrap_or(false) {
+                    queue.pop_front();
+                }
+                if let Some(j) = queue.pop_front() {
+                    out.push((base.0 + i, base.1 + j));
+                    next = j + 1;
+                }
+            }
+        }
+        return;
+    }
+    // lengths[i][j]: length of the common subsequence of a[i..] and b[j..]
+    let mut lengths = vec![vec![0u32; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lengths[i][j] = if a[i] == b[j] {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i] == b[j] {
+            out.push((base.0 + i, base.1 + j));
+            i += 1;
+            j += 1;
+        } else if lengths[i + 1][j] >= lengths[i][j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+}
+
+/// detect encoding of XML bytes, return the encoding and the length of BOM
+pub(crate) fn sniff_encoding(content: &[u8]) -> (&'static Encoding, usize) {
+    if let Some(found) = Encoding::for_bom(content) {
+        return found;
+    }
+    if content.starts_with(b"<\0?\0") {
+        return (UTF_16LE, 0);
+    }
+    if content.starts_with(b"\0<\0?") {
+        return (UTF_16BE, 0);
+    }
+    let decl = Regex::new(r#"^<\?xml[^>]*?\sencoding\s*=\s*["']([A-Za-z0-9._:-]+)["']"#).unwrap();
+    let head = String::from_utf8_lossy(&content[..content.len().min(256)]);
+    let encoding = decl
+        .captures(&head)
+        .and_then(|c| Encoding::for_label(c[1].as_bytes()))
+        .unwrap_or(UTF_8);
+    // the document is already ASCII compatible if the declaration is readable
+    (if encoding == UTF_16LE || encoding == UTF_16BE { UTF_8 } else { encoding }, 0)
+}
+
+/// transcode XML bytes to UTF-8 text
+fn decode(content: &[u8]) -> Result<String, ParseError> {
+    let (encoding, bom_len) = sniff_encoding(content);
+    let bytes = &content[bom_len..];
+    let mut decoder = encoding.new_decoder_without_bom_handling();
+    let mut text = String::with_capacity(
+        decoder
+            .max_utf8_buffer_length_without_replacement(bytes.len())
+            .unwrap_or(bytes.len())
+            + 3,
+    );
+    if bom_len > 0 {
+        // keep BOM for `ETree::parse_str` to set the bom flag
+        text.push('\u{feff}');
+    }
+    let (result, read) = decoder.decode_to_string_without_replacement(bytes, &mut text, true);
+    match result {
+        DecoderResult::Malformed(bad, after) => {
+            let decoded = text.trim_start_matches('\u{feff}');
+            let mut e = ParseError::new(decoded, decoded.len(), format!("invalid {} byte sequence", encoding.name()));
+            e.offset = bom_len + read - bad as usize - after as usize;
+            Err(e)
+        }
+        _ => Ok(text),
+    }
+}
+
+/// Reader of single start tags of a stream, used by `StreamMatches` to test elements without building trees
+///
+/// Entities and ATTLIST declarations come from the prolog of the stream, start tags are decoded with its encoding.
+pub(crate) struct TagReader {
+    encoding: &'static Encoding,
+    tree: ETree,
+    state: ReadState,
+}
+
+impl TagReader {
+    /// reader for the stream with `prolog`, which is its XML declaration and DOCTYPE
+    pub(crate) fn new(prolog: &[u8]) -> Result<TagReader, ParseError> {
+        let (encoding, _) = sniff_encoding(prolog);
+        let text = decode(prolog)?;
+        let mut tree = ETree::empty(&text);
+        let mut state = ReadState::new();
+        tree.read(text.trim_start_matches('\u{feff}'), &ParseOptions::default(), &mut state, &mut None, &mut None)?;
+        Ok(TagReader { encoding, tree, state })
+    }
+    /// element of the start tag `tag`, `scope` binds the prefixes declared by its ancestors
+    pub(crate) fn read(&mut self, tag: &[u8], scope: &BTreeMap<String, String>) -> Result<ETreeNode, ParseError> {
+        let text = match self.encoding.decode_without_bom_handling_and_without_replacement(tag) {
+            Some(text) => text,
+            None => {
+                let text = String::from_utf8_lossy(tag);
+                return Err(ParseError::new(&text, 0, format!("invalid {} byte sequence", self.encoding.name())));
+            }
+        };
+        let mut reader = Reader::from_str(&text);
+        // no empty text event before the tag
+        reader.trim_text(true);
+        let mut buf = Vec::new();
+        let e = match reader.read_event(&mut buf) {
+            Ok(Event::Start(e)) | Ok(Event::Empty(e)) => e,
+            Ok(_) => return Err(ParseError::new(&text, 0, "expecting a start tag".to_string())),
+            Err(e) => return Err(parse_err(&text, &reader, e)),
+        };
+        self.state.scopes = vec![scope.iter().map(|(k, v)| (k.clone(), v.clone())).collect()];
+        let mut unescaper = dtd::Unescaper::new(&self.tree.options);
+        let (node, _) = self.tree.read_elem(&e, &reader, &text, &mut unescaper, &self.state, &mut None)?;
+        Ok(node)
+    }
+}
+
+/// State of reading XML text
+///
+/// - `status`: 0 before the first node, 1 in a start tag's text, 2 in the tail of node `closeidx`
+/// - `route`: route of children of the open element
+/// - `scopes`: namespace bindings declared by open elements
+/// - `expanded`: total bytes of expanded entities
+/// - `attlists`: attribute declarations of DOCTYPE, kept only for `AttrNormalization::Normalize`
+/// - `warnings`: warnings found so far if they are collected
+/// - `line`, `column`, `offset`: position in the document of byte `consumed` of the current chunk
+#[derive(Debug, Clone)]
+pub(crate) struct ReadState {
+    status: i32,
+    closeidx: usize,
+    route: String,
+    scopes: Vec<HashMap<String, String>>,
+    expanded: usize,
+    attlists: Vec<AttlistDecl>,
+    warnings: Option<Vec<ParseWarning>>,
+    line: usize,
+    column: usize,
+    offset: usize,
+    consumed: usize,
+}
+
+impl ReadState {
+    pub(crate) fn new() -> ReadState {
+        ReadState {
+            status: 0,
+            closeidx: 0,
+            route: "#".to_string(),
+            scopes: Vec::new(),
+            expanded: 0,
+            attlists: Vec::new(),
+            warnings: None,
+            line: 1,
+            column: 1,
+            offset: 0,
+            consumed: 0,
+        }
+    }
+    /// collect warnings, which also makes unknown entities and invalid character references recoverable
+    pub(crate) fn with_warnings(mut self) -> ReadState {
+        self.warnings = Some(Vec::new());
+        self
+    }
+    pub(crate) fn take_warnings(&mut self) -> Vec<ParseWarning> {
+        self.warnings.take().unwrap_or_default()
+    }
+    /// add warning at byte `offset` of the current chunk `data` if warnings are collected
+    fn warn(&mut self, data: &str, offset: usize, kind: ParseWarningKind) {
+        if let Some(warnings) = self.warnings.as_mut() {
+            warnings.push(ParseWarning::new(data, offset, kind));
+        }
+    }
+    /// warn about namespace `bindings` of an element which are already in scope
+    fn warn_redundant(&mut self, data: &str, offset: usize, bindings: &HashMap<String, String>) {
+        let mut redundant: Vec<(&String, &String)> = bindings
+            .iter()
+            .filter(|(prefix, uri)| self.scopes.iter().rev().find_map(|x| x.get(*prefix)) == Some(uri))
+            .collect();
+        redundant.sort();
+        for (prefix, uri) in redundant {
+            self.warn(data, offset, ParseWarningKind::RedundantNamespace(prefix.clone(), uri.clone()));
+        }
+    }
+    /// move forward to byte `offset` of the current chunk `data` and return (line, column, offset) in the document
+    fn locate(&mut self, data: &str, offset: usize) -> (usize, usize, usize) {
+        let text = &data[self.consumed..offset];
+        match text.rfind('\n') {
+            Some(i) => {
+                self.line += text.matches('\n').count();
+                self.column = text[i + 1..].chars().count() + 1;
+            }
+            None => self.column += text.chars().count(),
+        }
+        self.offset += text.len();
+        self.consumed = offset;
+        (self.line, self.column, self.offset)
+    }
+}
+
+/// fail if `value` of `what` exceeds the limit, even in recover mode
+pub(crate) fn check_limit(data: &str, reader: &Reader<&[u8]>, what: &str, value: usize, limit: Option<usize>) -> Result<(), ParseError> {
+    match limit {
+        Some(limit) if value > limit => Err(parse_err(data, reader, format!("{} exceeds limit {}", what, limit))),
+        _ => Ok(()),
+    }
+}
+
+/// keep the error if recoverable errors are collected, otherwise return it
+fn recoverable(errors: &mut Option<Vec<ParseError>>, e: ParseError) -> Result<(), ParseError> {
+    match errors {
+        Some(errors) => {
+            errors.push(e);
+            Ok(())
+        }
+        None => Err(e),
+    }
+}
+
+pub(crate) fn parse_err<E: std::fmt::Display>(data: &str, reader: &Reader<&[u8]>, e: E) -> ParseError {
+    ParseError::new(data, reader.buffer_position(), e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::Newline;
+    #[test]
+    fn test_pretty_attr_aligned() {
+        let mut tree = ETree::parse_str(r#"<beans><bean id="a" class="x.Y" scope="prototype"/><bean id="b"/></beans>"#).unwrap();
+        tree.pretty_with(&PrettyOptions::new("\n    ").with_attr_style(AttrStyle::Aligned));
+        let out = String::from_utf8(tree.write().unwrap()).unwrap();
+        assert!(out.ends_with(concat!(
+            "<beans>\n",
+            "    <bean id=\"a\"\n",
+            "          class=\"x.Y\"\n",
+            "          scope=\"prototype\"/>\n",
+            "    <bean id=\"b\"/>\n",
+            "</beans>\n",
+        )));
+    }
+    #[test]
+    fn test_attr_order() {
+        let mut tree = ETree::parse_str(r#"<?xml version="1.0"?><a z="1" b="2" m="3"/>"#).unwrap();
+        let pos = tree.root();
+        tree.node_mut(pos).unwrap().set_attr("c", "4");
+        assert_eq!(tree.to_xml_string().unwrap(), r#"<?xml version="1.0"?><a z="1" b="2" m="3" c="4"/>"#);
+        tree.set_attr_order(AttrOrder::Sorted);
+        assert_eq!(tree.to_xml_string().unwrap(), r#"<?xml version="1.0"?><a b="2" c="4" m="3" z="1"/>"#);
+        assert_eq!(tree.node(pos).unwrap().get_attr_iter().next().unwrap().0, "z");
+        let mut tree = ETree::parse_str(r#"<r xmlns:p="u"><a z="1" xmlns:q="v" q:b="2" a="3" p:c="4"/></r>"#).unwrap();
+        let node = tree.node_mut(1).unwrap();
+        node.set_attr("z", "9");
+        node.remove_attr("q:b");
+        node.set_attr("b", "5");
+        let expected = r#"<a xmlns:p="u" z="9" xmlns:q="v" a="3" p:c="4" b="5"/>"#;
+        let mut subtree = tree.subtree(1).unwrap();
+        subtree.set_write_declaration(false);
+        assert_eq!(subtree.to_xml_string().unwrap(), expected);
+    }
+    #[test]
+    fn test_escape_policy() {
+        let mut tree = ETree::parse_str(r#"<?xml version="1.0"?><a k="&apos;é&quot;">it&apos;s &gt; ü</a>"#).unwrap();
+        assert_eq!(tree.to_xml_string().unwrap(), r#"<?xml version="1.0"?><a k="&apos;é&quot;">it&apos;s &gt; ü</a>"#);
+        tree.set_escape_policy(EscapePolicy::minimal().with_non_ascii(true));
+        assert_eq!(tree.to_xml_string().unwrap(), r#"<?xml version="1.0"?><a k="'&#xE9;&quot;">it's > &#xFC;</a>"#);
+    }
+    #[test]
+    fn test_write_declaration() {
+        let mut tree = ETree::parse_str("<?xml version=\"1.0\"?>\n<!--c-->\n<a>x</a>\n").unwrap();
+        assert!(tree.get_write_declaration());
+        tree.set_write_declaration(false);
+        assert_eq!(tree.to_xml_string().unwrap(), "<!--c-->\n<a>x</a>\n");
+        tree.set_write_declaration(true);
+        assert_eq!(tree.to_xml_string().unwrap(), "<?xml version=\"1.0\"?>\n<!--c-->\n<a>x</a>\n");
+    }
+    #[test]
+    fn test_pretty_options() {
+        let content = r#"<beans><!--c--><bean id="a" class="x.Y" scope="prototype"/><bean id="bb" class="x.Z"/><?pi x?></beans>"#;
+        let mut tree = ETree::parse_str(content).unwrap();
+        let options = PrettyOptions::default().with_indent("\t").with_newline(Newline::CrLf).with_attrs_per_line(3);
+        tree.pretty_with(&options.with_indent_misc(false));
+        let out = String::from_utf8(tree.write().unwrap()).unwrap();
+        assert!(out.ends_with(concat!(
+            "<beans>\r\n",
+            "<!--c-->\r\n",
+            "\t<bean id=\"a\"\r\n",
+            "\t      class=\"x.Y\"\r\n",
+            "\t      scope=\"prototype\"/>\r\n",
+            "\t<bean id=\"bb\" class=\"x.Z\"/>\r\n",
+            "<?pi x?>\r\n",
+            "</beans>\r\n",
+        )));
+        tree.pretty_with(&PrettyOptions::new("\n  ").with_max_width(30));
+        let out = String::from_utf8(tree.write().unwrap()).unwrap();
+        assert!(out.ends_with(concat!(
+            "<beans>\n",
+            "  <!--c-->\n",
+            "  <bean id=\"a\"\n",
+            "        class=\"x.Y\"\n",
+            "        scope=\"prototype\"/>\n",
+            "  <bean id=\"bb\" class=\"x.Z\"/>\n",
+            "  <?pi x?>\n",
+            "</beans>\n",
+        )));
+    }
+    #[test]
+    fn test_pretty_space_preserve() {
+        let content = "<doc><pre xml:space=\"preserve\">  a\n  <b> x </b> </pre><p> y </p></doc>";
+        let mut tree = ETree::parse_str(content).unwrap();
+        tree.pretty("\n  ");
+        let out = String::from_utf8(tree.write().unwrap()).unwrap();
+        assert!(out.ends_with(concat!(
+            "<doc>\n",
+            "  <pre xml:space=\"preserve\">  a\n  <b> x </b> </pre>\n",
+            "  <p>y</p>\n",
+            "</doc>\n",
+        )));
+        tree.noindent();
+        let out = String::from_utf8(tree.write().unwrap()).unwrap();
+        assert!(out.ends_with("<doc><pre xml:space=\"preserve\">  a\n  <b> x </b> </pre><p>y</p></doc>"));
+    }
+    #[test]
+    fn test_subtree_hash() {
+        let content = "<root><a x=\"1\"><b>text</b></a><c/></root>";
+        let mut tree1 = ETree::parse_str(content).unwrap();
+        let mut tree2 = ETree::parse_str(content).unwrap();
+        tree1.set_enable_hash(true);
+        assert_eq!(tree1.subtree_hash(0), tree2.subtree_hash(0));
+        tree2.node_mut(2).unwrap().set_text("other");
+        assert_ne!(tree1.subtree_hash(0), tree2.subtree_hash(0));
+        assert_ne!(tree1.subtree_hash(1), tree2.subtree_hash(1));
+        assert_eq!(tree1.subtree_hash(3), tree2.subtree_hash(3));
+        tree1.node_mut(2).unwrap().set_text("other");
+        assert_eq!(tree1.subtree_hash(0), tree2.subtree_hash(0));
+        let pos = tree1.append_child_node(1, ETreeNode::new("d")).unwrap();
+        let mut fresh = tree1.clone();
+        fresh.set_enable_hash(true);
+        assert_eq!(tree1.subtree_hash(0), fresh.subtree_hash(0));
+        assert_ne!(tree1.subtree_hash(0), tree2.subtree_hash(0));
+        tree1.remove(pos);
+        tree1.set_enable_hash(false);
+        assert_eq!(tree1.subtree_hash(1), tree2.subtree_hash(1));
+        // the hash is stable, it may be persisted
+        assert_eq!(ETree::from(ETreeNode::new("c")).subtree_hash(0), Some(0x3bd7_c119_a3d6_3575));
+    }
+    #[test]
+    fn test_apply_attlist_defaults() {
+        let mut tree = ETree::parse_str(concat!(
+            "<!DOCTYPE root [\n",
+            "  <!ATTLIST a b CDATA \"x\" c CDATA #IMPLIED>\n",
+            "]>\n",
+            "<root><a/><a b=\"y\"/></root>",
+        )).unwrap();
+        assert_eq!(tree.apply_attlist_defaults(), 1);
+        let pos = tree.find("//a").unwrap();
+        assert_eq!(tree.node(pos).unwrap().get_attr("b").as_deref(), Some("x"));
+        assert!(tree.node(pos).unwrap().is_attr_defaulted("b"));
+        assert!(!tree.node(pos + 1).unwrap().is_attr_defaulted("b"));
+        let out = String::from_utf8(tree.write().unwrap()).unwrap();
+        assert!(out.ends_with("<root><a/><a b=\"y\"/></root>"));
+        tree.node_mut(pos).unwrap().set_attr("b", "x");
+        let out = String::from_utf8(tree.write().unwrap()).unwrap();
+        assert!(out.ends_with("<root><a b=\"x\"/><a b=\"y\"/></root>"));
+    }
+    #[test]
+    fn test_xsi_helpers() {
+        let tree = ETree::parse_str(concat!(
+            "<root xmlns:i=\"http://www.w3.org/2001/XMLSchema-instance\" xmlns:xs=\"http://www.w3.org/2001/XMLSchema\">",
+            "<a i:nil=\"true\"/><b i:type=\"xs:int\">1</b></root>",
+        )).unwrap();
+        assert!(tree.is_nil(1));
+        assert!(!tree.is_nil(2));
+        assert_eq!(
+            tree.resolve_xsi_type(2),
+            Some(("http://www.w3.org/2001/XMLSchema".to_string(), "int".to_string()))
+        );
+        let mut node = ETreeNode::new("a");
+        node.set_nil(true);
+        node.set_xsi_type("xs:string");
+        assert!(node.is_nil());
+        assert_eq!(node.get_attr("xsi:nil").as_deref(), Some("true"));
+        assert_eq!(node.get_xsi_type().as_deref(), Some("xs:string"));
+        node.set_nil(false);
+        assert_eq!(node.get_attr_count(), 1);
+        let out = ETree::from(node).to_xml_string().unwrap();
+        assert!(out.ends_with(&format!("<a xmlns:xsi=\"{}\" xsi:type=\"xs:string\"/>", XSI_NAMESPACE)));
+        let mut tree = ETree::parse_str(&format!("<root xmlns:i=\"{}\"><a/></root>", XSI_NAMESPACE)).unwrap();
+        assert!(tree.set_nil(1, true));
+        assert_eq!(tree.node(1).unwrap().get_attr("i:nil").as_deref(), Some("true"));
+        assert!(tree.is_nil(1));
+        assert!(tree.set_nil(1, false));
+        assert!(!tree.is_nil(1) && tree.node(1).unwrap().get_attr_count() == 0);
+        let mut tree = ETree::parse_str("<root xmlns:xsi=\"urn:x\"><a/></root>").unwrap();
+        assert!(tree.set_xsi_type(1, "t"));
+        assert_eq!(tree.node(1).unwrap().get_attr("xsi1:type").as_deref(), Some("t"));
+        assert_eq!(tree.lookup_namespace(1, "xsi1").as_deref(), Some(XSI_NAMESPACE));
+        assert!(!tree.set_nil(5, true));
+    }
+    #[test]
+    fn test_typed_values() {
+        let content = "<server port=' 8080' debug='yes'><timeout>1.5</timeout><name/></server>";
+        let mut tree = ETree::parse_str(content).unwrap();
+        let server = tree.node(0).unwrap();
+        assert_eq!(server.get_attr_as::<u16>("port").unwrap(), 8080);
+        let e = server.get_attr_as::<bool>("debug").unwrap_err();
+        assert!(e.to_string().starts_with("attribute \"debug\" of <server> has invalid value \"yes\": "));
+        let e = server.get_attr_as::<u16>("host").unwrap_err();
+        assert_eq!(e.to_string(), "attribute \"host\" of <server>: missing attribute");
+        assert_eq!(tree.node(1).unwrap().get_text_as::<f64>().unwrap(), 1.5);
+        assert_eq!(tree.node(2).unwrap().get_text_as::<String>().unwrap(), "");
+        assert!(matches!(tree.node(2).unwrap().get_text_as::<i32>(), Err(Error::Value { attr: None, .. })));
+        tree.node_mut(1).unwrap().set_text_from(&30);
+        assert_eq!(tree.node(1).unwrap().get_text_as::<u32>().unwrap(), 30);
+    }
+    #[test]
+    fn test_attr_entry() {
+        let mut node = ETreeNode::new("a");
+        node.set_attr("x", "1");
+        node.set_attr("y", "2");
+        assert!(node.has_attr("x") && !node.has_attr("z"));
+        assert_eq!(node.remove_attr("x").as_deref(), Some("1"));
+        assert_eq!(node.remove_attr("x"), None);
+        assert_eq!(node.attr_entry("y").or_insert("3").as_str(), "2");
+        node.attr_entry("z").or_insert_with(|| "4".to_string()).push('5');
+        node.attr_entry("y").and_modify(|x| *x = "6".to_string());
+        assert_eq!(node.attr_entry("w").and_modify(|x| x.clear()).get(), None);
+        let attrs = node.get_attr_iter().map(|x| format!("{}={}", x.0, x.1)).collect::<Vec<_>>();
+        assert_eq!(attrs, vec!["y=6", "z=45"]);
+        assert_eq!(node.attr_entry("z").remove().as_deref(), Some("45"));
+        assert_eq!(node.get_attr_count(), 1);
+    }
+    #[test]
+    fn test_compact() {
+        let mut tree = ETree::parse_str("<root><a><b/></a><c><d/></c></root>").unwrap();
+        tree.set_enable_index(true);
+        tree.remove(1);
+        let pos = tree.append_child_node(1, ETreeNode::new("e")).unwrap();
+        let old = tree.node(pos).unwrap().get_idx();
+        assert_eq!(old, 5);
+        let before = tree.write().unwrap();
+        let idxmap = tree.compact();
+        assert_eq!(idxmap[&old], 3);
+        assert_eq!(tree.pos(3), Some(3));
+        assert_eq!(tree.children(1), vec![2, 3]);
+        assert_eq!(tree.write().unwrap(), before);
+        assert_eq!(tree.append_child_node(0, ETreeNode::new("f")).map(|x| tree.node(x).unwrap().get_idx()), Some(4));
+    }
+    #[test]
+    fn test_value_index() {
+        let content = "<root><a id='1'><b id='2'/><a id='3' k='x'/></a><c><a id='2'/></c></root>";
+        let mut tree = ETree::parse_str(content).unwrap();
+        let paths = [
+            "//a",
+            "//*[@id='2']",
+            "//a[@id='2' and @id!='1']",
+            "//a[@k=\"x\"]",
+            "a[@id='1']",
+            "/root/c//a[@id='2']",
+            "//a[position()=2]",
+        ];
+        let expected: Vec<Vec<usize>> = paths.iter().map(|x| tree.find_iter(x).collect()).collect();
+        tree.set_enable_index(true);
+        for (path, expected) in paths.iter().zip(expected.iter()) {
+            assert_eq!(&tree.find_iter(path).collect::<Vec<_>>(), expected, "{}", path);
+        }
+        assert_eq!(tree.find_at_iter(".//a", 1).collect::<Vec<_>>(), vec![3]);
+        assert_eq!(tree.find_at_iter("a", 1).collect::<Vec<_>>(), vec![1, 3]);
+        // modifications drop the index
+        tree.node_mut(2).unwrap().set_attr("id", "4");
+        assert_eq!(tree.find_iter("//*[@id='2']").collect::<Vec<_>>(), vec![5]);
+        let pos = tree.append_child_node(4, ETreeNode::new("a")).unwrap();
+        tree.node_mut(pos).unwrap().set_attr("id", "2");
+        assert_eq!(tree.find_iter("//*[@id='2']").collect::<Vec<_>>(), vec![5, 6]);
+        tree.remove(4);
+        assert_eq!(tree.find_iter("//a").collect::<Vec<_>>(), vec![1, 3]);
+    }
+    #[test]
+    fn test_user_data() {
+        let mut tree = ETree::parse_str("<root><a/><b/></root>").unwrap();
+        assert!(tree.set_user_data(2, "b".to_string()));
+        assert!(tree.set_user_data(2, 42u32));
+        assert!(!tree.set_user_data(3, 0u32));
+        tree.append_previous_node(1, ETreeNode::new("c"));
+        assert_eq!(tree.get_user_data::<String>(3).map(|x| x.as_str()), Some("b"));
+        *tree.get_user_data_mut::<u32>(3).unwrap() += 1;
+        assert_eq!(tree.get_user_data::<u32>(3), Some(&43));
+        let copy = tree.clone();
+        *tree.get_user_data_mut::<u32>(3).unwrap() += 1;
+        assert_eq!(tree.get_user_data::<u32>(3), Some(&44));
+        assert_eq!(copy.get_user_data::<u32>(3), Some(&43));
+        fn unwind_safe<T: UnwindSafe + RefUnwindSafe>(_: &T) {}
+        unwind_safe(&copy);
+        assert!(tree.remove_user_data::<String>(3));
+        assert_eq!(tree.get_user_data::<String>(3), None);
+        assert_eq!(tree.get_user_data::<u32>(1), None);
+    }
+    #[test]
+    fn test_detach() {
+        let mut tree = ETree::parse_str("<root>\n  <a>\n    <b/>\n  </a>\n  <c/>\n</root>").unwrap();
+        tree.set_enable_index(true);
+        tree.set_user_data(2, 1u8);
+        let sub = tree.detach(1).unwrap();
+        assert_eq!(tree.pos(3), Some(1));
+        assert_eq!(tree.children(0), vec![1]);
+        assert_eq!(sub.get_user_data::<u8>(1), Some(&1));
+        assert_eq!(sub.children(0), vec![1]);
+        assert_eq!(sub.node(0).unwrap().get_route(), "#");
+        let out = String::from_utf8(tree.write().unwrap()).unwrap();
+        assert!(out.ends_with("<root>\n  <c/>\n</root>"));
+        tree.append_child_tree(0, sub);
+        assert_eq!(tree.find("//b"), Some(3));
+        assert!(tree.detach(9).is_none());
+    }
+    #[test]
+    fn test_parse_error() {
+        let e = ETree::parse_file("/nonexistent/etree.xml").unwrap_err();
+        assert!(matches!(e, Error::Io { path: Some(_), .. }));
+        assert!(e.to_string().starts_with("/nonexistent/etree.xml: "));
+        let e = ETree::parse_str("<root>\n  <a></b></root>").unwrap_err();
+        assert_eq!((e.offset, e.line, e.column), (14, 2, 8));
+        let e = ETree::parse_str("<root><a>").unwrap_err();
+        assert_eq!((e.offset, e.message.as_str()), (9, "element <a> is not closed"));
+        assert!(ETree::parse_fragment("<a/><b>").is_err());
+    }
+    #[test]
+    fn test_self_closing() {
+        let mut tree = ETree::parse_str("<root><a/><b></b><c/></root>").unwrap();
+        tree.node_mut(1).unwrap().set_text("");
+        tree.node_mut(3).unwrap().set_text("text");
+        let out = String::from_utf8(tree.write().unwrap()).unwrap();
+        assert!(out.ends_with("<root><a/><b></b><c>text</c></root>"));
+        let mut tree = ETree::parse_str("<root><a></a></root>").unwrap();
+        tree.node_mut(1).unwrap().set_self_closing(None);
+        let out = String::from_utf8(tree.write().unwrap()).unwrap();
+        assert!(out.ends_with("<root><a></a></root>"));
+    }
+    #[test]
+    fn test_xpath_namespaces() {
+        let content = concat!(
+            r#"<root xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:x="http://www.w3.org/1999/xlink">"#,
+            r#"<dc:title>a</dc:title><t:title xmlns:t="http://purl.org/dc/elements/1.1/">b</t:title>"#,
+            r#"<title>c</title><link x:href="h"/></root>"#
+        );
+        let mut tree = ETree::parse_str(content).unwrap();
+        assert_eq!(tree.find_iter("//dc:title").count(), 1);
+        assert_eq!(tree.find_iter("//dc:title").with_namespace("dc", "http://purl.org/dc/elements/1.1/").count(), 2);
+        tree.register_xpath_namespace("d", "http://purl.org/dc/elements/1.1/");
+        tree.register_xpath_namespace("xl", "http://www.w3.org/1999/xlink");
+        let found: Vec<usize> = tree.find_iter("//d:title").collect();
+        assert_eq!(found, vec![1, 2]);
+        assert_eq!(tree.find("//link[@xl:href='h']"), Some(4));
+        assert_eq!(tree.find("//d:title[text()='b']"), Some(2));
+    }
+    #[test]
+    fn test_find_namespace_wildcards() {
+        let content = concat!(
+            r#"<html xmlns="http://www.w3.org/1999/xhtml" xmlns:svg="http://www.w3.org/2000/svg">"#,
+            r#"<svg:svg id="a"><svg:rect/><g xmlns="http://www.w3.org/2000/svg" id="b"/></svg:svg><p id="c"/></html>"#
+        );
+        let mut tree = ETree::parse_str(content).unwrap();
+        assert_eq!(tree.find_iter("//svg:*").collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(tree.find_iter("//*:svg | //*:p").collect::<Vec<_>>(), vec![1, 4]);
+        tree.register_xpath_namespace("s", "http://www.w3.org/2000/svg");
+        assert_eq!(tree.find_iter("//s:*").collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert_eq!(tree.find_iter("//s:*[@id]").collect::<Vec<_>>(), vec![1, 3]);
+        assert_eq!(tree.find_iter("/*:html/*:p").collect::<Vec<_>>(), vec![4]);
+    }
+    #[test]
+    fn test_find_contains() {
+        let tree = ETree::parse_str(
+            "<list><item name='foobar'>bar baz</item><item name='baz'>x</item><item><n>a foo</n><n>bar</n></item></list>",
+        )
+        .unwrap();
+        assert_eq!(tree.find_iter("//item[contains(@name,'foo')]").collect::<Vec<_>>(), vec![1]);
+        assert_eq!(tree.find_iter("//item[starts-with(text(), 'bar')]").collect::<Vec<_>>(), vec![1]);
+        assert_eq!(tree.find_iter("//item[contains(n, 'foo')]").collect::<Vec<_>>(), vec![3]);
+        assert_eq!(tree.find_iter("//item[starts-with(n, 'b') and contains(., '')]").collect::<Vec<_>>(), vec![3]);
+        assert_eq!(tree.find_iter("//item[contains(@name, 'ba') or starts-with(.,'x')]").count(), 2);
+    }
+    #[test]
+    fn test_find_string_literals() {
+        let tree = ETree::parse_str(concat!(
+            r#"<links><a href="a[1]/b" title="it's">x|y</a><a href='say "hi"' title="]"/>"#,
+            r#"<?target 1?><a href="c\d"/><b t="it's &quot;q&quot;"/></links>"#
+        ))
+        .unwrap();
+        assert_eq!(tree.find(r#"//a[@href="a[1]/b"]"#), Some(1));
+        assert_eq!(tree.find(r#"//a[@href='a[1]/b']/../a[@title="it's"]"#), Some(1));
+        assert_eq!(tree.find(r"//a[@title='it\'s']"), Some(1));
+        assert_eq!(tree.find(r#"//a[@href='say "hi"' and @title="]"]"#), Some(2));
+        assert_eq!(tree.find(r#"//a[@href="say \"hi\""]"#), Some(2));
+        assert_eq!(tree.find(r#"//a[text()="x|y"] | //a[contains(@href, "/b")]"#), Some(1));
+        assert_eq!(tree.find(r"//a[@href='c\\d']"), Some(4));
+        assert_eq!(tree.find(r#"//processing-instruction("target")"#), Some(3));
+        assert_eq!(tree.find(r#"//b[@t="it's \"q\""]"#), Some(5));
+    }
+    #[test]
+    fn test_find_matches() {
+        let tree = ETree::parse_str("<codes><c>ABC123</c><c>AB12</c><c k='x9'>abc1</c><c>XYZ7</c></codes>").unwrap();
+        assert_eq!(tree.find_iter(r"//c[matches(text(), '^[A-Z]{3}\d+$')]").collect::<Vec<_>>(), vec![1, 4]);
+        assert_eq!(tree.find_iter("//c[matches(@k, '[0-9]$') and not(matches(., 'B'))]").collect::<Vec<_>>(), vec![3]);
+        assert!(tree.try_find("//c[matches(., '(')]").is_err());
+    }
+    #[test]
+    fn test_find_name_functions() {
+        let tree = ETree::parse_str(concat!(
+            "<doc xmlns:s='http://www.w3.org/2000/svg'><s:svg><s:rect/></s:svg>",
+            "<svg xmlns='http://www.w3.org/2000/svg'/><svg/></doc>"
+        ))
+        .unwrap();
+        assert_eq!(tree.find_iter("//*[local-name()='svg']").collect::<Vec<_>>(), vec![1, 3, 4]);
+        assert_eq!(
+            tree.find_iter("//*[namespace-uri()='http://www.w3.org/2000/svg']").collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+        assert_eq!(tree.find_iter("//*[name()='s:rect']").collect::<Vec<_>>(), vec![2]);
+        assert_eq!(tree.evaluate("local-name(//s:rect)"), crate::XPathValue::String("rect".to_string()));
+        assert_eq!(tree.evaluate_at("namespace-uri()", 3), crate::XPathValue::String("http://www.w3.org/2000/svg".to_string()));
+    }
+    #[test]
+    fn test_find_case_insensitive() {
+        let tree = ETree::parse_str("<html><Title lang='EN'>Hello</Title><title>HELLO</title><TITLE>bye</TITLE></html>").unwrap();
+        assert_eq!(tree.find_iter("//*[lower-case(local-name())='title']").collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert_eq!(tree.find_iter("//*[lower-case(text())='hello']").collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(tree.find_iter("//*[upper-case(@lang)='EN' and contains(lower-case(.), 'ell')]").collect::<Vec<_>>(), vec![1]);
+        assert_eq!(tree.evaluate("upper-case(//title)"), crate::XPathValue::String("HELLO".to_string()));
+    }
+    #[test]
+    fn test_find_string_functions() {
+        let tree = ETree::parse_str("<t><cell>\n  Total \n</cell><cell>Sub total</cell><cell k='abcd'>Total</cell></t>").unwrap();
+        assert_eq!(tree.find_iter("//cell[normalize-space(text())='Total']").collect::<Vec<_>>(), vec![1, 3]);
+        assert_eq!(tree.find_iter("//cell[normalize-space()!='Total']").collect::<Vec<_>>(), vec![2]);
+        assert_eq!(tree.find_iter("//cell[string-length(@k) >= 4]").collect::<Vec<_>>(), vec![3]);
+        assert_eq!(tree.find_iter("//cell[string-length() = 9]").collect::<Vec<_>>(), vec![2]);
+        assert_eq!(tree.find_iter("//cell[contains(normalize-space(), 'al')]").count(), 3);
+    }
+    #[test]
+    fn test_find_substring() {
+        let tree = ETree::parse_str(concat!(
+            "<log><e date='2024-01-02'>ERR:disk</e><e date='2023-12-31'>WARN:cpu</e>",
+            "<e date='2024-03-04'>err:net</e></log>"
+        ))
+        .unwrap();
+        assert_eq!(tree.find_iter("//e[substring-before(@date,'-')='2024']").collect::<Vec<_>>(), vec![1, 3]);
+        assert_eq!(tree.find_iter("//e[substring-after(., ':')='cpu']").collect::<Vec<_>>(), vec![2]);
+        assert_eq!(tree.find_iter("//e[substring(@date, 6, 2) = '12']").collect::<Vec<_>>(), vec![2]);
+        assert_eq!(tree.find_iter("//e[translate(substring-before(., ':'), 'er', 'ER')='ERR']").count(), 2);
+    }
+    #[test]
+    fn test_find_count_sum() {
+        let tree = ETree::parse_str(concat!(
+            "<orders><order><item>1</item><item>2.5</item></order>",
+            "<order><item>1</item><item>2</item><item>3</item><item>4</item><note/></order><order/></orders>"
+        ))
+        .unwrap();
+        assert_eq!(tree.find_iter("//order[count(item) > 3]").collect::<Vec<_>>(), vec![4]);
+        assert_eq!(tree.find_iter("//order[count(*) = 0]").collect::<Vec<_>>(), vec![10]);
+        assert_eq!(tree.find_iter("//order[sum(item) = 3.5]").collect::<Vec<_>>(), vec![1]);
+        assert_eq!(tree.find_iter("//order[sum(item) >= 10 or count(item)=2]").count(), 2);
+        assert_eq!(tree.count("//item"), 6);
+        assert_eq!(tree.sum("//item"), 13.5);
+        assert!(tree.sum("//order").is_nan());
+    }
+    #[test]
+    fn test_find_arithmetic() {
+        let tree = ETree::parse_str(concat!(
+            "<cart><line price='12.5' qty='10'/><line price='3' qty='2'/><line price='x' qty='1'/>",
+            "<line price='50' qty='3'/><line/></cart>"
+        ))
+        .unwrap();
+        assert_eq!(tree.find_iter("//line[@price * @qty > 100]").collect::<Vec<_>>(), vec![1, 4]);
+        assert_eq!(tree.find_iter("/cart/line[position() mod 2 = 1]").collect::<Vec<_>>(), vec![1, 3, 5]);
+        assert_eq!(tree.find_iter("/cart/line[position() = last() - 2]").collect::<Vec<_>>(), vec![3]);
+        assert_eq!(tree.find_iter("/cart/line[last() - 2]").collect::<Vec<_>>(), vec![3]);
+        assert_eq!(tree.find_iter("//line[@price * 2 != @price * 2]").collect::<Vec<_>>(), vec![3, 5]);
+        assert_eq!(tree.find_iter("//line[-@qty + 5 = (1 + 2) * (4 div 2) - 3]").collect::<Vec<_>>(), vec![2]);
+    }
+    #[test]
+    fn test_find_union() {
+        let tree = ETree::parse_str("<doc><name>a</name><book><title>t</title><name>b</name></book><title/></doc>").unwrap();
+        assert_eq!(tree.find_iter("//title | //name").collect::<Vec<_>>(), vec![1, 3, 4, 5]);
+        assert_eq!(tree.find_iter("//title|//title[text()='t']|book/name").collect::<Vec<_>>(), vec![3, 4, 5]);
+        assert_eq!(tree.rfind_iter("//title | //name").collect::<Vec<_>>(), vec![5, 4, 3, 1]);
+        assert_eq!(tree.find("//book/title | //name"), Some(1));
+        assert!(tree.try_find("//title |").is_err());
+    }
+    #[test]
+    fn test_find_ancestor() {
+        let tree =
+            ETree::parse_str("<doc><section id='a'><section><p><b/></p></section></section><section id='c'><p/></section></doc>")
+                .unwrap();
+        let b = tree.find("//b").unwrap();
+        assert_eq!(tree.find_at_iter("ancestor::section", b).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(tree.find_at_iter("ancestor::section[@id]", b).collect::<Vec<_>>(), vec![1]);
+        assert_eq!(tree.find_at_iter("ancestor::*[1]", b).collect::<Vec<_>>(), vec![3]);
+        assert_eq!(tree.find_at_iter("ancestor-or-self::*[2]", b).collect::<Vec<_>>(), vec![3]);
+        assert_eq!(tree.find_at("ancestor-or-self::b", b), Some(b));
+        assert_eq!(tree.find_iter("//p/ancestor::section[1]").collect::<Vec<_>>(), vec![2, 5]);
+        assert_eq!(tree.find_iter("//ancestor::doc").collect::<Vec<_>>(), vec![0]);
+    }
+    #[test]
+    fn test_find_siblings() {
+        let tree =
+            ETree::parse_str("<!--c--><doc><h2>Intro</h2><p>a</p><h2>Setup</h2><p>b</p><p>c</p><h2>End</h2><p>d</p></doc><?pi x?>")
+                .unwrap();
+        assert_eq!(tree.find_iter("//h2[.='Setup']/following-sibling::p[1]").collect::<Vec<_>>(), vec![5]);
+        assert_eq!(tree.find_iter("//h2[.='Setup']/following-sibling::p").collect::<Vec<_>>(), vec![5, 6, 8]);
+        assert_eq!(tree.find_iter("//h2[.='Setup']/preceding-sibling::*[1]").collect::<Vec<_>>(), vec![3]);
+        assert_eq!(tree.find_iter("//h2[.='Setup']/preceding-sibling::*").collect::<Vec<_>>(), vec![2, 3]);
+        assert_eq!(tree.find_iter("//p/preceding-sibling::h2[1]").collect::<Vec<_>>(), vec![2, 4, 7]);
+        assert_eq!(tree.find_at_iter("following-sibling::node()", 1).collect::<Vec<_>>(), vec![9]);
+        assert_eq!(tree.find_at_iter("preceding-sibling::comment()", 1).collect::<Vec<_>>(), vec![0]);
+        // many siblings, each context only walks its own parent
+        let tree = ETree::parse_str(&format!("<r>{}</r>", "<a><b/></a>".repeat(10000))).unwrap();
+        assert_eq!(tree.find_at_iter("following-sibling::a", 1).count(), 9999);
+        assert_eq!(tree.find_at_iter("preceding-sibling::a", 19999).count(), 9999);
+        assert_eq!(tree.find_at_iter("preceding-sibling::a[1]", 19999).collect::<Vec<_>>(), vec![19997]);
+        assert_eq!(tree.find_iter("//b/following-sibling::*").count(), 0);
+        assert_eq!(tree.find_iter("//b/preceding-sibling::*").count(), 0);
+    }
+    #[test]
+    fn test_find_following_preceding() {
+        let tree =
+            ETree::parse_str("<!--c--><doc><sec><h>A</h><p>1</p></sec><sec><h>B</h><p>2<b/></p></sec><note/></doc>").unwrap();
+        assert_eq!(tree.find_all_at("following::*", 3), vec![4, 5, 6, 7, 8, 9]);
+        assert_eq!(tree.find_all_at("following::p", 7), Vec::<usize>::new());
+        assert_eq!(tree.find_all_at("following::*", 7), vec![9]);
+        assert_eq!(tree.find_all_at("preceding::*", 8), vec![2, 3, 4, 6]);
+        assert_eq!(tree.find_all_at("preceding::node()", 2), vec![0]);
+        assert_eq!(tree.find_all("//b/preceding::h[1]"), vec![6]);
+        assert_eq!(tree.find_all("//h[.='A']/following::h"), vec![6]);
+        assert_eq!(tree.find_all("//note/preceding::p[last()]"), vec![4]);
+    }
+    #[test]
+    fn test_find_self_descendant_axes() {
+        let tree = ETree::parse_str("<book><chapter n='1'><p/><chapter n='2'/></chapter><appendix><p/></appendix></book>").unwrap();
+        assert_eq!(tree.find_at_iter("descendant-or-self::node()", 1).collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert_eq!(tree.find_at_iter("descendant-or-self::chapter[@n='2']", 3).collect::<Vec<_>>(), vec![3]);
+        assert_eq!(tree.find_iter("//*/self::chapter").collect::<Vec<_>>(), vec![1, 3]);
+        assert_eq!(tree.find_iter("//p/parent::appendix").collect::<Vec<_>>(), vec![4]);
+        assert_eq!(tree.find_iter("/book/child::chapter/descendant::*").collect::<Vec<_>>(), vec![2, 3]);
+        assert_eq!(tree.find_at("self::chapter", 0), None);
+        assert_eq!(tree.find_iter("/descendant-or-self::p | //appendix/self::*").collect::<Vec<_>>(), vec![2, 4, 5]);
+    }
+    #[test]
+    fn test_find_not() {
+        let tree = ETree::parse_str("<api><fn deprecated='1'/><fn><item/></fn><fn name='a'/></api>").unwrap();
+        assert_eq!(tree.find_iter("//fn[not(@deprecated)]").collect::<Vec<_>>(), vec![2, 4]);
+        assert_eq!(tree.find_iter("//fn[not(child::item)]").collect::<Vec<_>>(), vec![1, 4]);
+        assert_eq!(tree.find_iter("//fn[not(item) and not(@deprecated)]").collect::<Vec<_>>(), vec![4]);
+        assert_eq!(tree.find_iter("//fn[item or @name='a']").collect::<Vec<_>>(), vec![2, 4]);
+        assert_eq!(tree.find_iter("//fn[true()]").count(), 3);
+        assert_eq!(tree.find_iter("//fn[false()]").count(), 0);
+        assert_eq!(tree.find_iter("//fn[not(false())]").count(), 3);
+        assert_eq!(tree.find_iter("//fn[@*]").collect::<Vec<_>>(), vec![1, 4]);
+        assert_eq!(tree.find_iter("//fn[not(@*)]").collect::<Vec<_>>(), vec![2]);
+    }
+    #[test]
+    fn test_find_absolute() {
+        let tree = ETree::parse_str("<?pi?><catalog><book id='1'><title/></book><book id='2'/></catalog><!--c-->").unwrap();
+        let title = tree.find("//title").unwrap();
+        assert_eq!(tree.find_at_iter("/catalog/book", title).collect::<Vec<_>>(), vec![2, 4]);
+        assert_eq!(tree.find_at_iter("/catalog/book", 4).collect::<Vec<_>>(), vec![2, 4]);
+        assert_eq!(tree.find_at_iter("//book[@id='2']", title).collect::<Vec<_>>(), vec![4]);
+        assert_eq!(tree.find_at_iter(".//book", 1).collect::<Vec<_>>(), vec![2, 4]);
+        assert_eq!(tree.find_at_iter("book", 1).collect::<Vec<_>>(), vec![2, 4]);
+        assert_eq!(tree.find_iter("/catalog").collect::<Vec<_>>(), vec![1]);
+        assert_eq!(tree.find_iter("/node()").collect::<Vec<_>>(), vec![0, 1, 5]);
+        assert_eq!(tree.find_iter("/book").count(), 0);
+        assert_eq!(tree.find_iter("//catalog").collect::<Vec<_>>(), vec![1]);
+        assert_eq!(tree.find_iter("/descendant::book[2] | /comment()").collect::<Vec<_>>(), vec![4, 5]);
+        assert_eq!(tree.find_iter("/.").count(), 0);
+    }
+    #[test]
+    fn test_find_relational() {
+        let tree = ETree::parse_str(concat!(
+            "<log><reading value='99'>9</reading><reading value='100'>10</reading>",
+            "<reading value='1e3'>x</reading><reading value='250'>100</reading></log>"
+        ))
+        .unwrap();
+        assert_eq!(tree.find_iter("//reading[@value >= 100]").collect::<Vec<_>>(), vec![2, 4]);
+        assert_eq!(tree.find_iter("//reading[@value < '100']").collect::<Vec<_>>(), vec![1]);
+        assert_eq!(tree.find_iter("//reading[@value != 100]").collect::<Vec<_>>(), vec![1, 3, 4]);
+        assert_eq!(tree.find_iter("//reading[text() > 9]").collect::<Vec<_>>(), vec![2, 4]);
+        assert_eq!(tree.find_iter("//reading[text() <= '10' and @value > 99]").collect::<Vec<_>>(), vec![2]);
+        assert_eq!(tree.find_iter("//reading[position() <= 2]").collect::<Vec<_>>(), vec![1, 2]);
+    }
+    #[test]
+    fn test_find_relative_self() {
+        let tree = ETree::parse_str(r#"<doc id="x"><a id="x"><doc/></a>text</doc>"#).unwrap();
+        assert_eq!(tree.find_all("//*[@id='x']"), vec![0, 1]);
+        assert_eq!(tree.find_all("doc"), vec![0, 2]);
+        assert_eq!(tree.find_all("*[@id='x']"), vec![0, 1]);
+        assert_eq!(tree.find_all_at("*[@id='x']", 1), vec![1]);
+        assert_eq!(tree.find_all_at("doc[1]", 1), vec![2]);
+        assert_eq!(tree.find_all_at(".//*", 1), vec![2]);
+        assert_eq!(tree.find_all("text()"), vec![0]);
+    }
+    #[test]
+    fn test_find_all() {
+        let tree = ETree::parse_str("<r><a><b/></a><b/><a><b/><c/></a></r>").unwrap();
+        assert_eq!(tree.find_all("//b"), vec![2, 3, 5]);
+        assert_eq!(tree.find_all("//a/* | //b"), vec![2, 3, 5, 6]);
+        assert_eq!(tree.find_all("//c/ancestor-or-self::*"), vec![0, 4, 6]);
+        assert_eq!(tree.find_all_at(".//b", 4), vec![5]);
+        assert!(tree.find_all("//d").is_empty());
+    }
+    #[test]
+    fn test_select() {
+        let content = concat!(
+            "<html><body><div class='article main' id='a1'><p>1</p><p lang='en-US'>2</p><span/><p/></div>",
+            "<div class='articles'><h1/><p>3</p><!-- c --><p data-x='a.pdf'/></div></body></html>"
+        );
+        let tree = ETree::parse_str(content).unwrap();
+        let texts = |selector: &str| -> Vec<usize> { tree.select(selector) };
+        assert_eq!(texts("div.article > p:first-child"), vec![3]);
+        assert_eq!(texts("div.article p"), vec![3, 4, 6]);
+        assert_eq!(texts("#a1 > :last-child, h1 + p"), vec![6, 9]);
+        assert_eq!(texts("p:nth-of-type(2n+1)"), vec![3, 6, 9]);
+        assert_eq!(texts("div:nth-child(2) p:last-of-type"), vec![11]);
+        assert_eq!(texts("p[lang|=en], [data-x$='.pdf']"), vec![4, 11]);
+        assert_eq!(texts("p:not(:empty)"), vec![3, 4, 9]);
+        assert_eq!(texts("h1 ~ *"), vec![9, 11]);
+        assert_eq!(texts(":root > body > div:not(.main)"), vec![7]);
+        match tree.try_select("div >") {
+            Err(e @ Error::Selector { .. }) => {
+                let message = "invalid CSS selector \"div >\": unexpected end, expected a selector at offset 5";
+                assert_eq!(e.to_string(), message)
+            }
+            _ => panic!("invalid selector is accepted"),
+        }
+        // multi-byte whitespace
+        for selector in ["\u{a0}div", "div >\u{a0}p", "h1,\u{3000}span", "div\u{3000}"].iter() {
+            let _ = tree.try_select(selector);
+        }
+        assert_eq!(tree.try_select("div >\u{3000}h1").unwrap(), vec![8]);
+    }
+    #[test]
+    fn test_node_kind() {
+        let tree = ETree::parse_str("<!DOCTYPE r><r><!-- c --><![CDATA[t]]><?pi x?><a/></r>").unwrap();
+        let kinds = (0..6).map(|x| tree.node(x).unwrap().kind()).collect::<Vec<_>>();
+        let expected = [
+            NodeKind::DocType,
+            NodeKind::Element,
+            NodeKind::Comment,
+            NodeKind::CData,
+            NodeKind::ProcessingInstruction,
+            NodeKind::Element,
+        ];
+        assert_eq!(kinds, expected);
+        assert_eq!(tree.node(2).unwrap().get_name(), "<Comment>");
+        assert!(tree.node(5).unwrap().is_element() && !tree.node(4).unwrap().is_element());
+        assert_eq!(ETreeNode::new_pi("t", "d").kind(), NodeKind::ProcessingInstruction);
+        assert_eq!(tree.children_by_name(1, "<Comment>"), Vec::<usize>::new());
+        assert_eq!(tree.children_by_name(1, "a"), vec![5]);
+    }
+    #[test]
+    fn test_get_xpath_of() {
+        let content = concat!(
+            "<!-- c --><?pi x?><r><a/><x:b xmlns:x='urn:x'/>",
+            "<a><b><![CDATA[t]]></b><!-- c --></a><?pi y?><a/></r>"
+        );
+        let mut tree = ETree::parse_str(content).unwrap();
+        assert_eq!(tree.get_xpath_of(0).as_deref(), Some("/comment()[1]"));
+        assert_eq!(tree.get_xpath_of(1).as_deref(), Some("/processing-instruction('pi')[1]"));
+        assert_eq!(tree.get_xpath_of(6).as_deref(), Some("/r[1]/a[2]/b[1]"));
+        assert_eq!(tree.get_xpath_of(10).as_deref(), Some("/r[1]/a[3]"));
+        assert_eq!(tree.get_xpath_of(11), None);
+        tree.register_xpath_namespace("x", "urn:y");
+        assert_eq!(tree.get_xpath_of(4).as_deref(), Some("/r[1]/x:b[1]"));
+        tree.register_xpath_namespace("x", "urn:x");
+        for pos in 0..11 {
+            let path = tree.get_xpath_of(pos).unwrap();
+            assert_eq!(tree.find(&path), Some(pos), "{}", path);
+        }
+    }
+    #[test]
+    fn test_try_find() {
+        let tree = ETree::parse_str("<root><a k=\"1\"/><a/></root>").unwrap();
+        assert_eq!(tree.try_find("//a[@k]").unwrap(), Some(1));
+        assert_eq!(tree.try_find("//b").unwrap(), None);
+        assert_eq!(tree.try_find_iter("a").unwrap().count(), 2);
+        match tree.try_find("//a[@k='1'") {
+            Err(e @ Error::XPath { .. }) => {
+                assert_eq!(e.to_string(), "invalid XPath \"//a[@k='1'\": unterminated predicate at offset 3");
+                assert!(matches!(e, Error::XPath { position: 3, end: 10, .. }));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(tree.try_find_iter("").is_err());
+        assert!(tree.try_find_at("/a)", 0).is_err());
+    }
+    #[test]
+    fn test_find_node_tests() {
+        let tree = ETree::parse_str("<root><!-- c1 --><?php echo 1 ?><a><!-- c2 --><?xslt x?></a></root>").unwrap();
+        assert_eq!(tree.find_iter("//comment()").collect::<Vec<_>>(), vec![1, 4]);
+        assert_eq!(tree.find_iter("//processing-instruction()").collect::<Vec<_>>(), vec![2, 5]);
+        assert_eq!(tree.find_iter("//processing-instruction('php')").collect::<Vec<_>>(), vec![2]);
+        assert_eq!(tree.find_iter("./node()").collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert_eq!(tree.find_iter("./*").collect::<Vec<_>>(), vec![3]);
+        assert_eq!(tree.find_iter("//a/comment()[1]").collect::<Vec<_>>(), vec![4]);
+        let tree = ETree::parse_str("<doc><p>a<b>x</b>c</p><p><b>y</b></p><p><![CDATA[z]]></p></doc>").unwrap();
+        assert_eq!(tree.find_iter("//p/text()").collect::<Vec<_>>(), vec![1, 5]);
+        assert_eq!(tree.find_iter("//text()").collect::<Vec<_>>(), vec![1, 2, 4, 5]);
+        assert_eq!(tree.evaluate("string(//p[1]/text())"), crate::XPathValue::String("a".to_string()));
+    }
+    #[test]
+    fn test_doctype() {
+        let mut tree = ETree::parse_str(concat!(
+            "<?xml version=\"1.0\"?>\n<!DOCTYPE html PUBLIC \"-//W3C//DTD XHTML 1.0 Strict//EN\" ",
+            "\"http://www.w3.org/TR/xhtml1/DTD/xhtml1-strict.dtd\">\n<html/>"
+        )).unwrap();
+        let doctype = tree.get_doctype().unwrap();
+        assert_eq!(doctype.name, "html");
+        assert_eq!(doctype.public_id.as_deref(), Some("-//W3C//DTD XHTML 1.0 Strict//EN"));
+        assert_eq!(doctype.system_id.as_deref(), Some("http://www.w3.org/TR/xhtml1/DTD/xhtml1-strict.dtd"));
+        assert!(tree.get_write_doctype());
+        tree.set_write_doctype(false);
+        let out = String::from_utf8(tree.write().unwrap()).unwrap();
+        assert_eq!(out, "<?xml version=\"1.0\"?>\n<html/>");
+        assert!(tree.get_doctype().is_some());
+        assert!(ETree::parse_str("<root/>").unwrap().get_doctype().is_none());
+    }
+    #[test]
+    fn test_set_doctype() {
+        let mut tree = ETree::from(ETreeNode::new("html"));
+        let system_id = "http://www.w3.org/TR/xhtml1/DTD/xhtml1-strict.dtd";
+        let pos = tree.set_doctype("html", Some("-//W3C//DTD XHTML 1.0 Strict//EN"), Some(system_id), None);
+        assert_eq!(pos, 0);
+        assert_eq!(tree.root(), 1);
+        assert_eq!(
+            tree.to_xml_string().unwrap(),
+            concat!(
+                "<?xml version=\"1.0\"?><!DOCTYPE html PUBLIC \"-//W3C//DTD XHTML 1.0 Strict//EN\" ",
+                "\"http://www.w3.org/TR/xhtml1/DTD/xhtml1-strict.dtd\">\n<html/>"
+            )
+        );
+        assert_eq!(tree.set_doctype("html", None, None, Some("<!ENTITY nbsp \"&#160;\">")), 0);
+        assert_eq!(tree.get_doctype().unwrap().internal_subset.as_deref(), Some("<!ENTITY nbsp \"&#160;\">"));
+        assert_eq!(tree.get_entities()["nbsp"].as_deref(), Some("&#160;"));
+        assert!(tree.to_xml_string().unwrap().ends_with("<!DOCTYPE html [<!ENTITY nbsp \"&#160;\">]>\n<html/>"));
+    }
+    #[test]
+    fn test_cdata_elements() {
+        let content = concat!(
+            "<?xml version=\"1.0\"?><html><script>if (a &lt; b &amp;&amp; c) {}</script>",
+            "<p>a &lt; b<br/>c &gt; d</p><style> </style></html>"
+        );
+        let mut tree = ETree::parse_str(content).unwrap();
+        tree.set_cdata_elements(&["script", "p", "style"]);
+        assert_eq!(
+            tree.to_xml_string().unwrap(),
+            concat!(
+                "<?xml version=\"1.0\"?><html><script><![CDATA[if (a < b && c) {}]]></script>",
+                "<p><![CDATA[a < b]]><br/>c &gt; d</p><style> </style></html>"
+            )
+        );
+    }
+    #[test]
+    fn test_output_newline() {
+        let content = "<?xml version=\"1.0\"?>\r\n<a>\r\n  <b>x\ny</b><!--1\r2--><![CDATA[3\n4]]>\n</a>";
+        let mut tree = ETree::parse_str(content).unwrap();
+        tree.set_output_newline(Some(Newline::Lf));
+        assert_eq!(tree.to_xml_string().unwrap(), "<?xml version=\"1.0\"?>\n<a>\n  <b>x\ny</b><!--1\n2--><![CDATA[3\n4]]>\n</a>");
+        tree.set_output_newline(Some(Newline::CrLf));
+        assert_eq!(
+            tree.to_xml_string().unwrap(),
+            "<?xml version=\"1.0\"?>\r\n<a>\r\n  <b>x\r\ny</b><!--1\r\n2--><![CDATA[3\r\n4]]>\r\n</a>"
+        );
+        assert_eq!(tree.node(0).unwrap().get_text().unwrap(), "\r\n  ");
+    }
+    #[test]
+    fn test_round_trip() {
+        let content = concat!(
+            "\u{feff}<?xml version='1.0'  encoding=\"UTF-8\" ?>\r\n",
+            "<!DOCTYPE r [<!ENTITY e \"x\">]>\n",
+            "<r  a='1' b = \"it's\"\n   c=\"&#x41;&e;\">\n",
+            "  <e/><f></f><g />text \"q\" > &amp; &lt;<!-- a < b & c --><![CDATA[<x>]]>\n",
+            "  <h x=\"&quot;\" >y</h >\n",
+            "</r >\n",
+        );
+        let options = ParseOptions::new().with_round_trip(true);
+        let mut tree = ETree::parse_with(content, &options).unwrap();
+        assert_eq!(tree.to_xml_string().unwrap(), content);
+        let root = tree.root();
+        assert_eq!(tree.node(root).unwrap().get_attr("b").as_deref(), Some("it's"));
+        let h = tree.find("//h").unwrap();
+        tree.node_mut(h).unwrap().set_attr("x", "z");
+        tree.set_version("1.1");
+        let out = tree.to_xml_string().unwrap();
+        assert!(out.starts_with("\u{feff}<?xml version=\"1.1\" encoding=\"UTF-8\"?>\r\n<!DOCTYPE r"));
+        assert!(out.contains("<r  a='1' b = \"it's\"\n   c=\"&#x41;&e;\">"));
+        assert!(out.contains("<h x=\"z\">y</h >"));
+    }
+    #[test]
+    fn test_quote_char() {
+        let mut tree = ETree::parse_str(r#"<?xml version="1.0"?><a k="it's" j='"x"'/>"#).unwrap();
+        tree.set_quote_char(QuoteChar::Single);
+        assert_eq!(tree.to_xml_string().unwrap(), r#"<?xml version="1.0"?><a k='it&apos;s' j='&quot;x&quot;'/>"#);
+        tree.set_escape_policy(EscapePolicy::minimal());
+        assert_eq!(tree.to_xml_string().unwrap(), r#"<?xml version="1.0"?><a k='it&apos;s' j='"x"'/>"#);
+        let options = ParseOptions::new().with_round_trip(true);
+        let mut tree = ETree::parse_with(r#"<a k='1'/>"#, &options).unwrap();
+        tree.node_mut(0).unwrap().set_attr("j", "2");
+        assert!(tree.to_xml_string().unwrap().ends_with("<a k='1' j='2'/>"));
+    }
+    #[test]
+    fn test_canonical_digest() {
+        let a = ETree::parse_str("<?xml version=\"1.0\"?>\n<!-- x --><r b='2' a=\"1\"><e/></r>").unwrap();
+        let b = ETree::parse_str("<r a=\"1\" b=\"2\"><e></e></r>").unwrap();
+        let c = ETree::parse_str("<r a=\"1\" b=\"3\"><e></e></r>").unwrap();
+        assert_eq!(a.canonical_sha256(None), b.canonical_sha256(None));
+        assert_ne!(a.canonical_sha256(None), c.canonical_sha256(None));
+        // SHA-256 of "<e></e>"
+        let e = a.find("//e").unwrap();
+        assert_eq!(a.canonical_digest::<sha2::Sha256>(Some(e)), <sha2::Sha256 as sha2::Digest>::digest(b"<e></e>").to_vec());
+        assert_eq!(a.canonical_sha256(Some(e)), b.canonical_sha256(Some(b.find("//e").unwrap())));
+    }
+    #[test]
+    fn test_cdata_split() {
+        let mut tree = ETree::parse_str("<script><![CDATA[]]></script>").unwrap();
+        tree.node_mut(1).unwrap().set_text("if (a[b[0]]>1) { x = \"]]>\"; }");
+        let out = String::from_utf8(tree.write().unwrap()).unwrap();
+        assert!(out.ends_with(
+            "<script><![CDATA[if (a[b[0]]]]><![CDATA[>1) { x = \"]]]]><![CDATA[>\"; }]]></script>"
+        ));
+        let tree = ETree::parse_str(&out).unwrap();
+        let texts: Vec<String> = tree.children(0).iter().map(|&x| tree.node(x).unwrap().get_text().unwrap()).collect();
+        assert_eq!(texts.concat(), "if (a[b[0]]>1) { x = \"]]>\"; }");
+    }
+    #[test]
+    fn test_cdata_round_trip() {
+        let content = "<?xml version=\"1.0\"?>\n<a><![CDATA[<b> & &amp; ]]]]><![CDATA[>\t\n]]>tail</a>";
+        let tree = ETree::parse_str(content).unwrap();
+        assert!(tree.node(1).unwrap().is_cdata());
+        assert_eq!(tree.node(1).unwrap().get_text().as_deref(), Some("<b> & &amp; ]]"));
+        assert_eq!(String::from_utf8(tree.write().unwrap()).unwrap(), content);
+        let mut tree = ETree::parse_str("<a/>").unwrap();
+        tree.append_child_node(0, ETreeNode::new_cdata("x < y"));
+        assert!(String::from_utf8(tree.write().unwrap()).unwrap().contains("<![CDATA[x < y]]>"));
+    }
+    #[test]
+    fn test_parse_bytes() {
+        let mut content = vec![0xFF, 0xFE];
+        for unit in "<?xml version=\"1.0\" encoding=\"UTF-16\"?><a>\u{4e2d}</a>".encode_utf16() {
+            content.extend_from_slice(&unit.to_le_bytes());
+        }
+        let tree = ETree::parse_bytes(&content).unwrap();
+        assert_eq!(tree.node(0).unwrap().get_text().as_deref(), Some("\u{4e2d}"));
+        let tree = ETree::parse_bytes(b"<?xml version='1.0' encoding='ISO-8859-1'?><a>caf\xe9</a>").unwrap();
+        assert_eq!(tree.node(0).unwrap().get_text().as_deref(), Some("caf\u{e9}"));
+        assert_eq!(tree.get_encoding().as_deref(), Some("ISO-8859-1"));
+        let tree = ETree::parse_bytes(b"\xEF\xBB\xBF<a>x</a>").unwrap();
+        assert_eq!(tree.node(0).unwrap().get_text().as_deref(), Some("x"));
+        let e = ETree::parse_bytes(b"<a>\n caf\xe9</a>").unwrap_err();
+        assert_eq!((e.offset, e.line, e.column), (8, 2, 5));
+    }
+    #[test]
+    fn test_bom() {
+        let content = b"\xEF\xBB\xBF<?xml version=\"1.0\"?>\n<a>x</a>";
+        let mut tree = ETree::parse_bytes(content).unwrap();
+        assert!(tree.get_bom());
+        assert_eq!(tree.write().unwrap(), content.to_vec());
+        tree.set_bom(false);
+        assert_eq!(tree.write().unwrap(), content[3..].to_vec());
+        assert!(ETree::parse_str("\u{feff}<a/>").unwrap().get_bom());
+        assert!(!ETree::parse_str("<a/>").unwrap().get_bom());
+    }
+    #[test]
+    fn test_entities() {
+        let content = concat!(
+            "<!DOCTYPE doc [<!ENTITY co \"ACME &amp; Co\"><!ENTITY me \"&co;&#x21;\">]>\n",
+            "<doc name=\"&me;\">&co; &lt;3</doc>"
+        );
+        let tree = ETree::parse_str(content).unwrap();
+        let root = tree.root();
+        assert_eq!(tree.get_entities().len(), 2);
+        assert_eq!(tree.node(root).unwrap().get_attr("name").as_deref(), Some("ACME & Co!"));
+        assert_eq!(tree.node(root).unwrap().get_text().as_deref(), Some("ACME & Co <3"));
+        let out = String::from_utf8(tree.write().unwrap()).unwrap();
+        assert!(out.contains("<!ENTITY co \"ACME &amp; Co\">"));
+        let tree = ETree::parse_with(content, &ParseOptions::new().with_expand_entities(false)).unwrap();
+        assert_eq!(tree.node(tree.root()).unwrap().get_text().as_deref(), Some("&co; <3"));
+        let out = String::from_utf8(tree.write().unwrap()).unwrap();
+        assert!(out.ends_with("<doc name=\"&me;\">&co; &lt;3</doc>"));
+        assert!(ETree::parse_str("<doc>&unknown;</doc>").is_err());
+    }
+    #[test]
+    fn test_xml11() {
+        let content = "<?xml version=\"1.1\"?>\n<doc a=\"&#x7;\">&#x1;b&#x85;</doc>";
+        let tree = ETree::parse_str(content).unwrap();
+        assert_eq!(tree.get_version().as_deref(), Some("1.1"));
+        assert_eq!(tree.node(tree.root()).unwrap().get_text().as_deref(), Some("\u{1}b\u{85}"));
+        assert_eq!(String::from_utf8(tree.write().unwrap()).unwrap(), content);
+        let e = ETree::parse_str("<?xml version=\"1.0\"?>\n<doc>&#x1;</doc>").unwrap_err();
+        assert_eq!(e.message, "invalid character reference &#x1;");
+        assert!(ETree::parse_str("<?xml version=\"1.1\"?>\n<doc>&#x0;</doc>").is_err());
+    }
+    #[test]
+    fn test_preserve_references() {
+        let content = concat!(
+            "<?xml version=\"1.0\"?>\n",
+            "<!DOCTYPE doc [<!ENTITY custom \"x\">]>\n",
+            "<doc a=\"&quot;&#65;\">&amp; &#x2014; &custom; &gt;</doc>"
+        );
+        let options = ParseOptions::new().with_preserve_references(true);
+        let mut tree = ETree::parse_with(content, &options).unwrap();
+        let root = tree.root();
+        assert!(tree.get_preserve_references());
+        assert_eq!(tree.node(root).unwrap().get_text().as_deref(), Some("&amp; &#x2014; &custom; &gt;"));
+        assert_eq!(String::from_utf8(tree.write().unwrap()).unwrap(), content);
+        tree.node_mut(root).unwrap().set_text("a & b < c &amp;");
+        let out = String::from_utf8(tree.write().unwrap()).unwrap();
+        assert!(out.contains("<doc a=\"&quot;&#65;\">a &amp; b &lt; c &amp;</doc>"));
+        assert!(ETree::parse_with("<doc>&unknown;</doc>", &options).is_err());
+        assert!(ETree::parse_with("<doc>&#xD800;</doc>", &options).is_err());
+    }
+    #[test]
+    fn test_entity_limits() {
+        let mut content = "<!DOCTYPE lolz [<!ENTITY lol0 \"lol\">".to_string();
+        for i in 1..10 {
+            content += &format!("<!ENTITY lol{} \"{}\">", i, format!("&lol{};", i - 1).repeat(10));
+        }
+        content += "]><lolz>&lol9;</lolz>";
+        let e = ETree::parse_str(&content).unwrap_err();
+        assert!(e.message.contains("entity expansion exceeds"));
+        let content = "<!DOCTYPE doc [<!ENTITY xxe SYSTEM \"file:///etc/passwd\">]><doc>&xxe;</doc>";
+        let e = ETree::parse_str(content).unwrap_err();
+        assert_eq!(e.message, "external entity &xxe; is not expanded");
+    }
+    #[test]
+    fn test_parse_with() {
+        let content = "<a> x <!-- c --> y <?pi?><![CDATA[<z>]]> <b/> </a>";
+        let tree = ETree::parse_str(content).unwrap();
+        assert_eq!(tree.children(0).len(), 4);
+        let options = ParseOptions::new()
+            .with_ignore_comments(true)
+            .with_ignore_processing_instructions(true)
+            .with_coalesce_cdata(true);
+        let tree = ETree::parse_with(content, &options).unwrap();
+        assert_eq!(tree.children(0), vec![1]);
+        assert_eq!(tree.node(0).unwrap().get_text().as_deref(), Some(" x  y <z> "));
+        let tree = ETree::parse_with(content, &options.with_trim_text(true)).unwrap();
+        assert_eq!(tree.node(0).unwrap().get_text().as_deref(), Some("xy<z>"));
+        assert_eq!(tree.node(1).unwrap().get_tail(), "");
+    }
+    #[test]
+    fn test_parse_recover() {
+        let content = "<root><a x='1' y=2>text &bogus;<b>bold</a><c></d><e>";
+        assert!(ETree::parse_str(content).is_err());
+        let (tree, errors) = ETree::parse_recover(content, &ParseOptions::default());
+        let messages: Vec<String> = errors.iter().map(|x| x.message.clone()).collect();
+        assert_eq!(tree.node(1).unwrap().get_attr("x").as_deref(), Some("1"));
+        assert_eq!(tree.node(1).unwrap().get_text().as_deref(), Some("text &bogus;"));
+        assert_eq!(tree.children(1), vec![2]);
+        assert_eq!(tree.children(0), vec![1, 3]);
+        assert_eq!(tree.children(3), vec![4]);
+        assert_eq!(
+            messages[1..],
+            [
+                "unknown entity &bogus;".to_string(),
+                "element <b> is not closed".to_string(),
+                "unexpected end tag </d>".to_string(),
+                "element <e> is not closed".to_string(),
+                "element <c> is not closed".to_string(),
+                "element <root> is not closed".to_string(),
+            ]
+        );
+        let out = String::from_utf8(tree.write().unwrap()).unwrap();
+        assert!(out.ends_with("<root><a x=\"1\">text &amp;bogus;<b>bold</b></a><c><e></e></c></root>"));
+    }
+    #[test]
+    fn test_parse_limits() {
+        let content = "<a><b x='1' y='2'><c/></b><d/></a>";
+        assert!(ETree::parse_with(content, &ParseOptions::new().with_max_depth(Some(3))).is_ok());
+        let e = ETree::parse_with(content, &ParseOptions::new().with_max_depth(Some(2))).unwrap_err();
+        assert_eq!(e.message, "nesting depth exceeds limit 2");
+        let e = ETree::parse_with(content, &ParseOptions::new().with_max_attributes(Some(1))).unwrap_err();
+        assert_eq!(e.message, "attribute count exceeds limit 1");
+        let e = ETree::parse_with(content, &ParseOptions::new().with_max_nodes(Some(3))).unwrap_err();
+        assert_eq!((e.message.as_str(), e.offset), ("node count exceeds limit 3", 30));
+        let (_, errors) = ETree::parse_recover(content, &ParseOptions::new().with_max_nodes(Some(3)));
+        assert_eq!(errors.len(), 1);
+    }
+    #[test]
+    fn test_attr_ns() {
+        let content = r#"<root xmlns:l="http://www.w3.org/1999/xlink"><a l:href="x" href="y" xml:lang="en"/></root>"#;
+        let tree = ETree::parse_str(content).unwrap();
+        let node = tree.node(1).unwrap();
+        assert_eq!(node.get_attr_ns("http://www.w3.org/1999/xlink", "href").as_deref(), Some("x"));
+        assert_eq!(node.get_attr_ns(XML_NAMESPACE, "lang").as_deref(), Some("en"));
+        assert_eq!(node.get_attr_namespace("href"), None);
+        let mut node = ETreeNode::new("b");
+        node.set_attr_ns("http://www.w3.org/1999/xlink", "xlink:href", "z");
+        assert_eq!(node.get_attr_ns("http://www.w3.org/1999/xlink", "href").as_deref(), Some("z"));
+    }
+    #[test]
+    fn test_default_namespace() {
+        let content = r#"<root xmlns="urn:a"><x:b xmlns:x="urn:x"><c/></x:b><d xmlns=""/></root>"#;
+        let mut tree = ETree::parse_str(content).unwrap();
+        assert_eq!(tree.node(2).unwrap().get_default_namespace(), "urn:a");
+        assert_eq!(tree.node(3).unwrap().get_default_namespace(), "");
+        let pos = tree.append_child_node(3, ETreeNode::new("f")).unwrap();
+        assert_eq!(tree.node(pos).unwrap().get_namespace(), "");
+        let pos = tree.append_child_node(2, ETreeNode::new("e")).unwrap();
+        assert_eq!(tree.node(pos).unwrap().get_namespace(), "urn:a");
+        let mut node = ETreeNode::new("g");
+        node.set_attr("xmlns", "urn:g");
+        let pos = tree.append_next_node(1, node).unwrap();
+        assert_eq!(tree.node(pos).unwrap().get_tag(), "{urn:g}g");
+    }
+    #[test]
+    fn test_namespace_decl() {
+        let mut tree = ETree::parse_str(r#"<root xmlns:a="urn:a"><x:b x:k="1"/></root>"#).unwrap();
+        let node = tree.node_mut(1).unwrap();
+        node.add_namespace_decl("x", "urn:x");
+        node.add_namespace_decl("", "urn:d");
+        assert_eq!(node.get_tag(), "{urn:x}b");
+        assert_eq!(node.get_attr_namespace("x:k").as_deref(), Some("urn:x"));
+        let decls = vec![("x".to_string(), "urn:x".to_string()), (String::new(), "urn:d".to_string())];
+        assert_eq!(node.declared_namespaces(), decls);
+        assert_eq!(tree.lookup_namespace(1, "x").as_deref(), Some("urn:x"));
+        let pos = tree.append_child_node(1, ETreeNode::new("c")).unwrap();
+        assert_eq!(tree.node(pos).unwrap().get_namespace(), "urn:d");
+        tree.set_write_declaration(false);
+        let expected = r#"<root xmlns:a="urn:a"><x:b x:k="1" xmlns:x="urn:x" xmlns="urn:d"><c/></x:b></root>"#;
+        assert_eq!(tree.to_xml_string().unwrap(), expected);
+        let node = tree.node_mut(1).unwrap();
+        assert_eq!(node.remove_namespace_decl("").as_deref(), Some("urn:d"));
+        assert_eq!(node.get_default_namespace(), "");
+        let node = tree.node_mut(0).unwrap();
+        assert_eq!(node.remove_namespace_decl("a").as_deref(), Some("urn:a"));
+        assert_eq!(node.remove_namespace_decl("a"), None);
+        assert!(node.declared_namespaces().is_empty() && node.get_attr_count() == 0);
+    }
+    #[test]
+    fn test_deep_equal() {
+        let a = ETree::parse_str(r#"<r xmlns:p="urn:p"><p:a x="1" y="2">t</p:a><b/>tail</r>"#).unwrap();
+        let b = ETree::parse_str("<r>\n  <q:a xmlns:q='urn:p' y='2' x='1'> t </q:a>\n  <b/>tail\n</r>").unwrap();
+        assert!(a.deep_equal(0, &a, 0));
+        assert!(!a.deep_equal(0, &b, 0));
+        assert!(!a.deep_equal_with(0, &b, 0, &CompareOptions::new().with_ignore_whitespace(true)));
+        let options = CompareOptions::new().with_ignore_whitespace(true).with_ignore_attr_order(true);
+        assert!(a.deep_equal_with(0, &b, 0, &options));
+        assert!(a.deep_equal(2, &b, 2) && !a.deep_equal(1, &b, 2) && !a.deep_equal(0, &b, 3));
+        assert!(a.node(1).unwrap().content_eq(b.node(1).unwrap(), &options));
+        assert_ne!(a.node(1).unwrap(), b.node(1).unwrap());
+        let mut node = a.node(2).unwrap().clone();
+        assert_eq!(&node, b.node(2).unwrap());
+        node.set_attr("c", "");
+        assert_ne!(&node, b.node(2).unwrap());
+    }
+    #[test]
+    fn test_source_position() {
+        let content = "<?xml version=\"1.0\"?>\n<root>\n  <!-- \u{4e2d} --><a/>\n  <b>text</b>\n</root>";
+        let tree = ETree::parse_str(content).unwrap();
+        let positions: Vec<_> = (0..4)
+            .map(|x| tree.node(x).unwrap())
+            .map(|x| (x.get_line().unwrap(), x.get_column().unwrap(), x.get_offset().unwrap()))
+            .collect();
+        assert_eq!(positions, vec![(2, 1, 22), (3, 3, 31), (3, 13, 43), (4, 3, 50)]);
+        assert_eq!(ETreeNode::new("a").get_line(), None);
+    }
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_parse_async() {
+        let content = "<?xml version=\"1.0\"?>\n<root><a>text</a></root>";
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let tree = runtime.block_on(ETree::parse_async(content.as_bytes())).unwrap();
+        assert_eq!(String::from_utf8(tree.write().unwrap()).unwrap(), content);
+        assert!(runtime.block_on(ETree::parse_async(&b"<root></a>"[..])).is_err());
+    }
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_write_async() {
+        let tree = ETree::parse_str("<?xml version=\"1.0\"?>\n<root><a>text</a></root>").unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let mut out: Vec<u8> = Vec::new();
+        runtime.block_on(tree.write_async(&mut out)).unwrap();
+        assert_eq!(out, tree.write().unwrap());
+        // large documents are written in chunks
+        struct Chunks(Vec<Vec<u8>>);
+        impl tokio::io::AsyncWrite for Chunks {
+            fn poll_write(
+                mut self: std::pin::Pin<&mut Self>,
+                _: &mut std::task::Context,
+                buf: &[u8],
+            ) -> std::task::Poll<std::io::Result<usize>> {
+                self.0.push(buf.to_vec());
+                std::task::Poll::Ready(Ok(buf.len()))
+            }
+            fn poll_flush(
+                self: std::pin::Pin<&mut Self>,
+                _: &mut std::task::Context,
+            ) -> std::task::Poll<std::io::Result<()>> {
+                std::task::Poll::Ready(Ok(()))
+            }
+            fn poll_shutdown(
+                self: std::pin::Pin<&mut Self>,
+                _: &mut std::task::Context,
+            ) -> std::task::Poll<std::io::Result<()>> {
+                std::task::Poll::Ready(Ok(()))
+            }
+        }
+        let content = format!("<root>{}</root>", "<item a=\"1\">text</item>".repeat(20000));
+        let tree = ETree::parse_str(&content).unwrap();
+        let mut out = Chunks(Vec::new());
+        runtime.block_on(tree.write_async(&mut out)).unwrap();
+        assert!(out.0.len() > 1 && out.0.iter().all(|x| x.len() < 65 * 1024));
+        assert_eq!(out.0.concat(), tree.write().unwrap());
+    }
+    #[test]
+    fn test_prolog_epilog() {
+        let content = concat!(
+            "<?xml version=\"1.0\"?>\n\n",
+            "<!-- a -->\n<?pi x?>\n",
+            "<root><!-- in --></root>\n",
+            "<!-- b -->  <?end?>\n"
+        );
+        let mut tree = ETree::parse_str(content).unwrap();
+        assert_eq!(tree.prolog(), vec![0, 1]);
+        assert_eq!(tree.epilog(), vec![4, 5]);
+        assert_eq!(tree.node(5).unwrap().get_text().as_deref(), Some("end"));
+        assert_eq!(String::from_utf8(tree.write().unwrap()).unwrap(), content);
+        tree.noindent();
+        let out = String::from_utf8(tree.write().unwrap()).unwrap();
+        assert_eq!(out, "<?xml version=\"1.0\"?><!--a--><?pi x?><root><!--in--></root><!--b--><?end?>");
+        tree.pretty("\n");
+        assert!(String::from_utf8(tree.write().unwrap()).unwrap().starts_with("<?xml version=\"1.0\"?>\n<!--a-->\n"));
+    }
+    #[test]
+    fn test_parse_fragment() {
+        let content = "Hello <b>world</b>, <i>again</i>!<!-- end -->";
+        let tree = ETree::parse_fragment(content).unwrap();
+        assert!(tree.is_fragment());
+        assert_eq!(tree.roots(), vec![0, 1]);
+        assert_eq!(tree.root(), 0);
+        assert_eq!(String::from_utf8(tree.write().unwrap()).unwrap(), content);
+        let tree = ETree::parse_fragment("just text").unwrap();
+        assert!(tree.roots().is_empty());
+        assert_eq!(String::from_utf8(tree.write().unwrap()).unwrap(), "just text");
+        assert!(!ETree::parse_str("<a/>").unwrap().is_fragment());
+    }
+    #[test]
+    fn test_parse_with_handler() {
+        struct Handler {
+            events: Vec<String>,
+            abort_at: &'static str,
+        }
+        impl ParseHandler for Handler {
+            fn start_element(&mut self, tree: &ETree, pos: usize) -> Control {
+                let name = tree.node(pos).unwrap().get_name();
+                self.events.push(format!("<{}>", name));
+                match name.as_str() {
+                    "skip" => Control::Skip,
+                    x if x == self.abort_at => Control::Abort,
+                    _ => Control::Continue,
+                }
+            }
+            fn end_element(&mut self, tree: &ETree, pos: usize) -> Control {
+                self.events.push(format!("</{}>", tree.node(pos).unwrap().get_name()));
+                Control::Continue
+            }
+            fn text(&mut self, text: &str) -> Control {
+                self.events.push(text.to_string());
+                if text == "drop" {
+                    Control::Skip
+                } else {
+                    Control::Continue
+                }
+            }
+            fn comment(&mut self, _text: &str) -> Control {
+                Control::Skip
+            }
+        }
+        let content = "<root>a<skip><skip/>x</skip>b<!--c--><e/><f>drop</f>&amp;</root>";
+        let mut handler = Handler {
+            events: Vec::new(),
+            abort_at: "",
+        };
+        let tree = ETree::parse_with_handler(content, &ParseOptions::default(), &mut handler).unwrap();
+        assert_eq!(
+            handler.events,
+            vec!["<root>", "a", "<skip>", "b", "<e>", "</e>", "<f>", "drop", "</f>", "&", "</root>"]
+        );
+        let out = String::from_utf8(tree.write().unwrap()).unwrap();
+        assert!(out.ends_with("<root>ab<e/><f></f>&amp;</root>"));
+        let mut handler = Handler {
+            events: Vec::new(),
+            abort_at: "e",
+        };
+        let e = ETree::parse_with_handler(content, &ParseOptions::default(), &mut handler).unwrap_err();
+        assert_eq!(e.offset, 37);
+        assert_eq!(handler.events.last().map(|x| x.as_str()), Some("<e>"));
+    }
+    #[cfg(any(feature = "gzip", feature = "zstd"))]
+    #[test]
+    fn test_parse_compressed_file() {
+        let content = "<?xml version=\"1.0\"?><urlset><url>a</url></urlset>";
+        let mut files = Vec::new();
+        #[cfg(feature = "gzip")]
+        {
+            let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(content.as_bytes()).unwrap();
+            files.push(("sitemap.xml.gz", encoder.finish().unwrap()));
+        }
+        #[cfg(feature = "zstd")]
+        files.push((
+            "sitemap.xml.zst",
+            ruzstd::encoding::compress_to_vec(content.as_bytes(), ruzstd::encoding::CompressionLevel::Fastest),
+        ));
+        for (name, data) in files {
+            let path = std::env::temp_dir().join(format!("etree-{}-{}", std::process::id(), name));
+            fs::write(&path, data).unwrap();
+            let tree = ETree::parse_file(&path);
+            fs::remove_file(&path).ok();
+            let tree = tree.unwrap();
+            assert_eq!(tree.node(tree.find("//url").unwrap()).unwrap().get_text().as_deref(), Some("a"));
+        }
+    }
+    #[test]
+    fn test_attr_normalization() {
+        let content = concat!(
+            "<?xml version=\"1.0\"?><!DOCTYPE root [<!ATTLIST root ids IDREFS #IMPLIED><!ENTITY e \"x\ty\">]>",
+            "<root ids=\"  a\n  b \" text=\"1\r\n2\t&#10;3 &e; &amp;\"/>"
+        );
+        let tree = ETree::parse_str(content).unwrap();
+        let root = tree.node(tree.root()).unwrap();
+        assert_eq!(root.get_attr("ids").as_deref(), Some("  a\n  b "));
+        assert_eq!(root.get_attr("text").as_deref(), Some("1\r\n2\t\n3 x\ty &"));
+        let options = ParseOptions::new().with_attr_normalization(AttrNormalization::Normalize);
+        let tree = ETree::parse_with(content, &options).unwrap();
+        let root = tree.node(tree.root()).unwrap();
+        assert_eq!(root.get_attr("ids").as_deref(), Some("a b"));
+        assert_eq!(root.get_attr("text").as_deref(), Some("1 2 \n3 x y &"));
+        let options = ParseOptions::new().with_attr_normalization(AttrNormalization::Raw);
+        let tree = ETree::parse_with(content, &options).unwrap();
+        let root = tree.node(tree.root()).unwrap();
+        assert_eq!(root.get_attr("text").as_deref(), Some("1\r\n2\t&#10;3 &e; &amp;"));
+        assert!(tree.get_raw_attributes());
+        assert_eq!(String::from_utf8(tree.write().unwrap()).unwrap(), content);
+    }
+    #[test]
+    fn test_pi() {
+        let content = "<?xml version=\"1.0\"?><?xml-stylesheet href=\"a.xsl\" type='text/xsl'?><r><?tick?></r>";
+        let mut tree = ETree::parse_str(content).unwrap();
+        assert_eq!(String::from_utf8(tree.write().unwrap()).unwrap(), content);
+        let pos = tree.prolog()[0];
+        let node = tree.node_mut(pos).unwrap();
+        assert!(node.is_pi());
+        assert_eq!(node.get_pi_target().as_deref(), Some("xml-stylesheet"));
+        assert_eq!(node.get_pi_data().as_deref(), Some("href=\"a.xsl\" type='text/xsl'"));
+        assert_eq!(node.get_pi_pseudo_attr("type").as_deref(), Some("text/xsl"));
+        node.set_pi_pseudo_attr("href", "b.xsl");
+        node.set_pi_pseudo_attr("media", "print");
+        assert_eq!(node.get_text().as_deref(), Some("xml-stylesheet href=\"b.xsl\" type='text/xsl' media=\"print\""));
+        let tick = tree.find("//processing-instruction('tick')").unwrap();
+        let node = tree.node_mut(tick).unwrap();
+        assert_eq!(node.get_pi_data().as_deref(), Some(""));
+        node.set_pi_data("a < b");
+        node.set_pi_target("tock");
+        assert_eq!(node.get_text().as_deref(), Some("tock a < b"));
+        tree.append_next_node(tick, ETreeNode::new_pi("x", ""));
+        let out = String::from_utf8(tree.write().unwrap()).unwrap();
+        assert!(out.ends_with("<r><?tock a < b?><?x?></r>"));
+        assert_eq!(ETreeNode::new("a").get_pi_target(), None);
+    }
+    #[test]
+    fn test_shared_text() {
+        let blob = "QUJD".repeat(1024);
+        let content = format!("<?xml version=\"1.0\"?><root><blob>{}</blob><small>a &amp; b</small></root>", blob);
+        let options = ParseOptions::new().with_shared_text_threshold(Some(1024));
+        let tree = ETree::parse_with(&content, &options).unwrap();
+        let blob_pos = tree.find("//blob").unwrap();
+        let node = tree.node(blob_pos).unwrap();
+        assert!(node.is_text_shared());
+        assert!(!tree.node(tree.find("//small").unwrap()).unwrap().is_text_shared());
+        assert_eq!(node.get_text_ref(), Some(blob.as_str()));
+        let copy = tree.subtree(blob_pos).unwrap();
+        let shared = node.get_text_shared().unwrap();
+        assert!(Arc::ptr_eq(&shared, &copy.node(0).unwrap().get_text_shared().unwrap()));
+        assert_eq!(String::from_utf8(tree.write().unwrap()).unwrap(), content);
+        assert!(!ETree::parse_str(&content).unwrap().node(blob_pos).unwrap().is_text_shared());
+    }
+    #[test]
+    fn test_reparse() {
+        let mut tree = ETree::parse_str("<root><a>1</a><b k=\"v\"><c/></b><d/></root>").unwrap();
+        tree.set_enable_index(true);
+        let b = tree.find("//b").unwrap();
+        let b_idx = tree.node(b).unwrap().get_idx();
+        let d_idx = tree.node(tree.find("//d").unwrap()).unwrap().get_idx();
+        tree.set_user_data(b, "note");
+        tree.set_user_data(tree.find("//d").unwrap(), 1);
+        tree.reparse("<root><new/><a>2</a><b k=\"w\"><x/><c/></b><e/></root>").unwrap();
+        let b = tree.pos(b_idx).unwrap();
+        assert_eq!(tree.node(b).unwrap().get_attr("k").as_deref(), Some("w"));
+        assert_eq!(tree.get_user_data::<&str>(b), Some(&"note"));
+        assert_eq!(tree.pos(d_idx), None);
+        assert_eq!(tree.children(b).len(), 2);
+        assert_eq!(tree.parent(tree.find("//c").unwrap()), Some(b));
+        let idxs: Vec<usize> = tree.data.iter().map(|x| x.get_idx()).collect();
+        let mut unique = idxs.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), idxs.len());
+        let out = String::from_utf8(tree.write().unwrap()).unwrap();
+        assert!(out.ends_with("<root><new/><a>2</a><b k=\"w\"><x/><c/></b><e/></root>"));
+        assert!(tree.reparse("<root></x>").is_err());
+        assert_eq!(tree.pos(b_idx), Some(b));
+        // options of the first parse are kept
+        let options = ParseOptions::new().with_trim_text(true);
+        let mut tree = ETree::parse_with("<root> <a> x </a> </root>", &options).unwrap();
+        tree.reparse("<root> <a> y </a> <b/> </root>").unwrap();
+        assert_eq!(tree.node(1).unwrap().get_text().as_deref(), Some("y"));
+        // long lists of siblings
+        let items = |n: usize| (0..n).map(|x| format!("<i{}/>", x % 7)).collect::<String>();
+        let mut tree = ETree::parse_str(&format!("<root>{}</root>", items(50000))).unwrap();
+        let last = tree.node(50000).unwrap().get_idx();
+        tree.reparse(&format!("<root><new/><x/>{}<y/></root>", items(50000))).unwrap();
+        assert_eq!(tree.pos(last), Some(50002));
+        let a: Vec<ETreeNode> = ["a", "b", "c", "a", "b", "b", "a"].iter().map(|x| ETreeNode::new(x)).collect();
+        let b: Vec<ETreeNode> = ["c", "b", "a", "b", "a", "c"].iter().map(|x| ETreeNode::new(x)).collect();
+        let pairs = match_siblings(&a, &(0..7).collect::<Vec<_>>(), &b, &(0..6).collect::<Vec<_>>());
+        assert_eq!(pairs.len(), 4);
+        assert!(pairs.windows(2).all(|x| x[0].0 < x[1].0 && x[0].1 < x[1].1));
+        assert!(pairs.iter().all(|&(i, j)| a[i].get_name() == b[j].get_name()));
+    }
+    #[test]
+    fn test_write_to() {
+        let tree = ETree::parse_str("<?xml version=\"1.0\"?><root><a>1</a></root>").unwrap();
+        let mut out = Vec::new();
+        tree.write_to(&mut out).unwrap();
+        assert_eq!(out, tree.write().unwrap());
+        struct Broken;
+        impl Write for Broken {
+            fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::new(std::io::ErrorKind::BrokenPipe, "closed"))
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        assert!(matches!(tree.write_to(Broken), Err(Error::Io { .. })));
+    }
+    #[test]
+    fn test_write_file() {
+        let content = "<?xml version=\"1.0\"?>\n<!DOCTYPE root>\n<root><a>1</a></root>\n";
+        let mut tree = ETree::parse_str(content).unwrap();
+        let path = std::env::temp_dir().join(format!("etree-{}-write.xml", std::process::id()));
+        tree.write_file(&path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), content);
+        tree.set_write_doctype(false);
+        tree.write_file(&path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "<?xml version=\"1.0\"?>\n<root><a>1</a></root>\n");
+        fs::remove_file(&path).unwrap();
+        let err = tree.write_file(path.join("missing")).unwrap_err();
+        assert!(matches!(err, Error::Io { path: Some(_), .. }));
+    }
+    #[test]
+    fn test_to_xml_string() {
+        let content = "<?xml version=\"1.0\"?>\n<root>\u{4e2d} &amp; <a/></root>";
+        let tree = ETree::parse_str(content).unwrap();
+        assert_eq!(tree.to_xml_string().unwrap(), content);
+        assert_eq!(tree.to_string(), content);
+        assert_eq!(format!("{}", ETree::from(ETreeNode::new("a"))), "<?xml version=\"1.0\"?><a/>");
+    }
+    #[test]
+    fn test_outer_xml() {
+        let tree = ETree::parse_str("<root>\n  <a k=\"&amp;\">x<b/>y<!--c--></a>tail\n  <d/>\n</root>").unwrap();
+        assert_eq!(tree.outer_xml(1), "<a k=\"&amp;\">x<b/>y<!--c--></a>");
+        assert_eq!(tree.outer_xml(2), "<b/>");
+        assert_eq!(tree.outer_xml(3), "<!--c-->");
+        assert_eq!(tree.outer_xml(4), "<d/>");
+        assert_eq!(tree.outer_xml(0), "<root>\n  <a k=\"&amp;\">x<b/>y<!--c--></a>tail\n  <d/>\n</root>");
+        assert_eq!(tree.outer_xml(9), "");
+    }
+    #[test]
+    fn test_write_range() {
+        let tree = ETree::parse_str("<feed><title/><item>1</item>\n<item><b/></item>\n<item>3</item><end/></feed>").unwrap();
+        let items = tree.children_by_name(0, "item");
+        let mut buf = Vec::new();
+        tree.write_range(items[0], items[1], &mut buf).unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(), "<item>1</item>\n<item><b/></item>");
+        let mut buf = Vec::new();
+        tree.write_range(items[2], items[2], &mut buf).unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(), "<item>3</item>");
+        assert!(tree.write_range(items[1], items[0], Vec::new()).is_err());
+        assert!(tree.write_range(items[0], tree.find("//b").unwrap(), Vec::new()).is_err());
     }
 }
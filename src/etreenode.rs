@@ -1,3 +1,14 @@
+use super::error::Error;
+use super::options::CompareOptions;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// namespace of XML Schema instance attributes (`xsi:nil`, `xsi:type`)
+pub const XSI_NAMESPACE:&str = "http://www.w3.org/2001/XMLSchema-instance";
+/// namespace bound to prefix "xml" without declaration
+pub(crate) const XML_NAMESPACE:&str = "http://www.w3.org/XML/1998/namespace";
+
 /// Element tree node
 ///
 /// `etree.ETreeNode` stores information of a tree node.
@@ -7,9 +18,15 @@
 /// - `tag`: {`namespace`}+`localname`
 /// - `name`: `namespace_abbrev` + `:` + `localname`
 /// - `localname`: tag name
-/// - `text`: text between open tag and the next open tag or close tag
+/// - `default_namespace`: default namespace in scope of the node, "" if there is none
+/// - `text`: text between open tag and the next open tag or close tag, large texts are shared between clones
 /// - `tail`: text between close tag and the next open tag or close tag
-/// - `attr`: key-value pairs in the open tag
+/// - `self_closing`: whether the element was written as `<a/>` (`Some(true)`) or `<a></a>` (`Some(false)`) in the source
+/// - `attr`: key-value pairs in the open tag, in document order followed by attributes added later
+/// - `attr_namespace`: namespaces of the prefixed attributes, resolved when parsing or set by `set_attr_ns`
+/// - `defaulted`: attributes which were not written in the source but added from DTD defaults
+/// - `source`: line, column and byte offset of the node in the parsed document
+/// - `markup`: start and end tags as written in the source, reused by the writer while the node is unchanged
 /// - `idx`: id for the node for internal useage
 /// - `route`: descendant route from root to parent for internal usage (format: `#root_idx#child_idx#child_child_idx#`)
 ///
@@ -45,17 +62,83 @@
 /// - `text`: `""`
 /// - `tail`: `"\n    "`
 /// - `attr`: `[("base-package", "xxx.xxx.controller"), ]`
-///
 #[derive(Debug, Clone)]
 pub struct ETreeNode {
     idx:usize,
     ns:String,
     ns_abbrev:String,
+    default_ns:String,
     local_name:String,
     attr:Vec<(String, String)>,
-    text:Option<String>,
+    attr_ns:Vec<(String, String)>,
+    defaulted:Vec<String>,
+    text:Option<Text>,
     tail:String,
+    self_closing:Option<bool>,
     route:String,
+    source:Option<(usize, usize, usize)>,
+    markup:Option<Box<SourceMarkup>>,
+}
+
+/// Text of a node, large texts are shared between clones instead of copied
+#[derive(Debug, Clone)]
+enum Text {
+    Owned(String),
+    Shared(Arc<str>),
+}
+
+impl Text {
+    fn as_str(&self) -> &str {
+        match self {
+            Text::Owned(text) => text,
+            Text::Shared(text) => text,
+        }
+    }
+}
+
+/// Kind of a tree node
+///
+/// Nodes other than elements are stored with the sentinel local names "<Comment>", "<CData>", "<PI>" and "<DocType>",
+/// which `get_name` and `get_localname` still return for compatibility. Check `kind` instead of comparing names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NodeKind {
+    Element,
+    Comment,
+    CData,
+    ProcessingInstruction,
+    DocType,
+}
+
+impl NodeKind {
+    /// kind of a node with sentinel local name `localname`, `Element` for other names
+    pub(crate) fn of(localname:&str) -> NodeKind {
+        match localname {
+            "<Comment>" => NodeKind::Comment,
+            "<CData>" => NodeKind::CData,
+            "<PI>" => NodeKind::ProcessingInstruction,
+            "<DocType>" => NodeKind::DocType,
+            _ => NodeKind::Element,
+        }
+    }
+    /// sentinel local name of nodes of the kind, `None` for elements
+    pub(crate) fn sentinel(self) -> Option<&'static str> {
+        match self {
+            NodeKind::Element => None,
+            NodeKind::Comment => Some("<Comment>"),
+            NodeKind::CData => Some("<CData>"),
+            NodeKind::ProcessingInstruction => Some("<PI>"),
+            NodeKind::DocType => Some("<DocType>"),
+        }
+    }
+}
+
+/// start and end tags as written in the source, valid while the name and attributes are unchanged
+#[derive(Debug, Clone)]
+struct SourceMarkup {
+    start:String,
+    end:Option<String>,
+    name:String,
+    attr:Vec<(String, String)>,
 }
 
 impl ETreeNode {
@@ -65,11 +148,112 @@ impl ETreeNode {
             idx:0,
             ns:"".to_string(),
             ns_abbrev:"".to_string(),
+            default_ns:"".to_string(),
             local_name:String::from(localname),
             attr:Vec::new(),
+            attr_ns:Vec::new(),
+            defaulted:Vec::new(),
             text:None,
             tail:"".to_string(),
+            self_closing:None,
             route:"".to_string(),
+            source:None,
+            markup:None,
+        }
+    }
+    #[allow(dead_code)]
+    /// get kind of the node
+    pub fn kind(&self) -> NodeKind {
+        NodeKind::of(&self.local_name)
+    }
+    #[allow(dead_code)]
+    /// whether the node is an element
+    pub fn is_element(&self) -> bool {
+        self.kind() == NodeKind::Element
+    }
+    #[allow(dead_code)]
+    /// create CDATA section node, `text` is written verbatim except that "]]>" is split into two sections
+    pub fn new_cdata(text:&str) -> ETreeNode {
+        let mut node = ETreeNode::new(NodeKind::CData.sentinel().unwrap());
+        node.set_text(text);
+        node
+    }
+    #[allow(dead_code)]
+    /// whether the node is a CDATA section
+    pub fn is_cdata(&self) -> bool {
+        self.kind() == NodeKind::CData
+    }
+    #[allow(dead_code)]
+    /// create processing instruction node `<?target data?>`
+    pub fn new_pi(target:&str, data:&str) -> ETreeNode {
+        let mut node = ETreeNode::new(NodeKind::ProcessingInstruction.sentinel().unwrap());
+        node.set_pi(target, data);
+        node
+    }
+    #[allow(dead_code)]
+    /// whether the node is a processing instruction
+    pub fn is_pi(&self) -> bool {
+        self.kind() == NodeKind::ProcessingInstruction
+    }
+    #[allow(dead_code)]
+    /// get target of a processing instruction, `None` for other nodes
+    pub fn get_pi_target(&self) -> Option<String> {
+        self.split_pi().map(|(target, _)| target.to_string())
+    }
+    #[allow(dead_code)]
+    /// set target of a processing instruction, other nodes are not changed
+    pub fn set_pi_target(&mut self, target:&str) {
+        if let Some(data) = self.get_pi_data() {
+            self.set_pi(target, &data);
+        }
+    }
+    #[allow(dead_code)]
+    /// get data (text after the target) of a processing instruction, `None` for other nodes
+    pub fn get_pi_data(&self) -> Option<String> {
+        self.split_pi().map(|(_, data)| data.to_string())
+    }
+    #[allow(dead_code)]
+    /// set data of a processing instruction, other nodes are not changed
+    pub fn set_pi_data(&mut self, data:&str) {
+        if let Some(target) = self.get_pi_target() {
+            self.set_pi(&target, data);
+        }
+    }
+    #[allow(dead_code)]
+    /// get pseudo-attribute `key="value"` of the data of a processing instruction such as `<?xml-stylesheet?>`
+    pub fn get_pi_pseudo_attr(&self, key:&str) -> Option<String> {
+        let data = self.get_pi_data()?;
+        pseudo_attrs(&data).into_iter().find(|x| x.0 == key).map(|x| data[x.1].to_string())
+    }
+    #[allow(dead_code)]
+    /// set pseudo-attribute of a processing instruction, it is appended to the data if missing
+    pub fn set_pi_pseudo_attr(&mut self, key:&str, value:&str) {
+        if let Some(mut data) = self.get_pi_data() {
+            let quote = if value.contains('"') { '\'' } else { '"' };
+            match pseudo_attrs(&data).into_iter().find(|x| x.0 == key) {
+                Some((_, range)) => data.replace_range(range.start - 1..range.end + 1, &format!("{}{}{}", quote, value, quote)),
+                None if data.is_empty() => data = format!("{}={}{}{}", key, quote, value, quote),
+                None => data = format!("{} {}={}{}{}", data, key, quote, value, quote),
+            }
+            self.set_pi_data(&data);
+        }
+    }
+    /// target and data of a processing instruction
+    fn split_pi(&self) -> Option<(&str, &str)> {
+        if !self.is_pi() {
+            return None;
+        }
+        let text = self.get_text_ref().unwrap_or("");
+        Some(match text.find(char::is_whitespace) {
+            Some(idx) => (&text[..idx], text[idx..].trim_start()),
+            None => (text, ""),
+        })
+    }
+    fn set_pi(&mut self, target:&str, data:&str) {
+        if data.is_empty() {
+            self.set_text(target);
+        } else {
+            self.set_text(&format!("{} {}", target, data));
         }
     }
     #[allow(dead_code)]
@@ -81,6 +265,11 @@ impl ETreeNode {
         self.route.clone()
     }
     #[allow(dead_code)]
+    /// get route without copying it
+    pub fn get_route_ref(&self) -> &str {
+        &self.route
+    }
+    #[allow(dead_code)]
     pub fn get_namespace(&self) -> String {
         self.ns.clone()
     }
@@ -89,30 +278,96 @@ impl ETreeNode {
         self.ns_abbrev.clone()
     }
     #[allow(dead_code)]
+    /// get default namespace in scope of the node, "" if there is none
+    pub fn get_default_namespace(&self) -> String {
+        self.default_ns.clone()
+    }
+    #[allow(dead_code)]
     pub fn get_tag(&self) -> String {
         format!("{{{}}}{}", self.ns, self.local_name)
     }
     #[allow(dead_code)]
     pub fn get_name(&self) -> String {
-        if self.ns_abbrev == "" {
-            format!("{}", self.local_name)
+        if self.ns_abbrev.is_empty() {
+            self.local_name.to_string()
         } else {
             format!("{}:{}", self.ns_abbrev, self.local_name)
         }
     }
     #[allow(dead_code)]
     pub fn get_localname(&self) -> String {
-        format!("{}", self.local_name)
+        self.local_name.to_string()
     }
     #[allow(dead_code)]
     pub fn get_text(&self) -> Option<String> {
-        self.text.clone()
+        self.get_text_ref().map(|x| x.to_string())
+    }
+    #[allow(dead_code)]
+    /// get text without copying it
+    pub fn get_text_ref(&self) -> Option<&str> {
+        self.text.as_ref().map(|x| x.as_str())
+    }
+    #[allow(dead_code)]
+    /// parse text without surrounding whitespace, a missing text is parsed as ""
+    ///
+    /// ```
+    /// use etree::ETree;
+    ///
+    /// let tree = ETree::parse_str("<port> 8080 </port>").unwrap();
+    /// assert_eq!(tree.node(0).unwrap().get_text_as::<u16>().unwrap(), 8080);
+    /// ```
+    pub fn get_text_as<T:FromStr>(&self) -> Result<T, Error> where T::Err:Display {
+        let text = self.get_text_ref().unwrap_or("").trim();
+        text.parse().map_err(|e:T::Err| Error::Value {
+            name:self.get_name(),
+            attr:None,
+            value:Some(text.to_string()),
+            message:e.to_string(),
+        })
+    }
+    #[allow(dead_code)]
+    /// get text as a shared string, only texts set by `set_text_shared` are not copied
+    pub fn get_text_shared(&self) -> Option<Arc<str>> {
+        match &self.text {
+            Some(Text::Shared(text)) => Some(text.clone()),
+            Some(Text::Owned(text)) => Some(Arc::from(text.as_str())),
+            None => None,
+        }
+    }
+    #[allow(dead_code)]
+    /// whether the text is a shared string, see `ParseOptions::shared_text_threshold`
+    pub fn is_text_shared(&self) -> bool {
+        matches!(self.text, Some(Text::Shared(_)))
     }
     #[allow(dead_code)]
     pub fn get_tail(&self) -> String {
         self.tail.clone()
     }
     #[allow(dead_code)]
+    /// get tail without copying it
+    pub fn get_tail_ref(&self) -> &str {
+        &self.tail
+    }
+    #[allow(dead_code)]
+    pub fn get_self_closing(&self) -> Option<bool> {
+        self.self_closing
+    }
+    #[allow(dead_code)]
+    /// get 1-based line of the node in the parsed document, `None` if the node wasn't parsed
+    pub fn get_line(&self) -> Option<usize> {
+        self.source.map(|x| x.0)
+    }
+    #[allow(dead_code)]
+    /// get 1-based column (in characters) of the node in the parsed document
+    pub fn get_column(&self) -> Option<usize> {
+        self.source.map(|x| x.1)
+    }
+    #[allow(dead_code)]
+    /// get byte offset of the node in the parsed document
+    pub fn get_offset(&self) -> Option<usize> {
+        self.source.map(|x| x.2)
+    }
+    #[allow(dead_code)]
     pub fn set_idx(&mut self, idx:usize) {
         self.idx = idx;
     }
@@ -129,27 +384,227 @@ impl ETreeNode {
         self.ns_abbrev = String::from(text);
     }
     #[allow(dead_code)]
+    pub fn set_default_namespace(&mut self, text:&str) {
+        self.default_ns = String::from(text);
+    }
+    #[allow(dead_code)]
     pub fn set_text(&mut self, text:&str) {
-        self.text = Some(String::from(text));
+        self.text = Some(Text::Owned(String::from(text)));
+    }
+    #[allow(dead_code)]
+    /// set text to the formatted `value`
+    pub fn set_text_from(&mut self, value:&impl Display) {
+        self.set_text(&value.to_string());
+    }
+    #[allow(dead_code)]
+    /// set text as a shared string, clones of the node and writing the tree don't copy it
+    pub fn set_text_shared(&mut self, text:Arc<str>) {
+        self.text = Some(Text::Shared(text));
     }
     #[allow(dead_code)]
     pub fn set_tail(&mut self, text:&str) {
         self.tail = String::from(text);
     }
     #[allow(dead_code)]
+    pub fn set_self_closing(&mut self, self_closing:Option<bool>) {
+        self.self_closing = self_closing;
+    }
+    #[allow(dead_code)]
+    /// set (line, column, byte offset) of the node in the source document
+    pub fn set_source_position(&mut self, source:Option<(usize, usize, usize)>) {
+        self.source = source;
+    }
+    /// keep the start tag as written in the source
+    pub(crate) fn set_source_start(&mut self, start:&str) {
+        self.markup = Some(Box::new(SourceMarkup {
+            start:start.to_string(),
+            end:None,
+            name:self.get_name(),
+            attr:self.attr.clone(),
+        }));
+    }
+    /// keep the end tag as written in the source
+    pub(crate) fn set_source_end(&mut self, end:&str) {
+        if let Some(markup) = self.markup.as_mut() {
+            markup.end = Some(end.to_string());
+        }
+    }
+    /// get the start tag as written in the source, `None` if the name or attributes changed since
+    pub(crate) fn get_source_start(&self) -> Option<&str> {
+        self.markup.as_ref()
+            .filter(|x| x.name == self.get_name() && x.attr == self.attr)
+            .map(|x| x.start.as_str())
+    }
+    /// get the quote character of the first attribute in the start tag as written in the source
+    pub(crate) fn get_source_quote(&self) -> Option<char> {
+        let start = &self.markup.as_ref()?.start;
+        let value = start[start.find('=')? + 1..].trim_start();
+        value.chars().next().filter(|x| *x == '"' || *x == '\'')
+    }
+    /// get the end tag as written in the source, `None` if the name changed since
+    pub(crate) fn get_source_end(&self) -> Option<&str> {
+        self.markup.as_ref().filter(|x| x.name == self.get_name()).and_then(|x| x.end.as_deref())
+    }
+    #[allow(dead_code)]
     pub fn get_attr_count(&self) -> usize {
         self.attr.len()
     }
     #[allow(dead_code)]
-    pub fn get_attr_iter(&self) -> std::slice::Iter<(String, String)> {
+    /// get attributes in document order, an attribute keeps its place when its value is set
+    pub fn get_attr_iter(&self) -> std::slice::Iter<'_, (String, String)> {
         self.attr.iter()
     }
     #[allow(dead_code)]
     pub fn get_attr(&self, key:&str) -> Option<String> {
-        self.find_attr(key).and_then(|idx| Some(self.attr[idx].1.clone()))
+        self.find_attr(key).map(|idx| self.attr[idx].1.clone())
+    }
+    #[allow(dead_code)]
+    /// whether the node has attribute `key`
+    pub fn has_attr(&self, key:&str) -> bool {
+        self.find_attr(key).is_some()
+    }
+    #[allow(dead_code)]
+    /// remove attribute `key` and return its value, `None` if it is missing
+    pub fn remove_attr(&mut self, key:&str) -> Option<String> {
+        self.defaulted.retain(|x| x != key);
+        self.attr_ns.retain(|x| x.0 != key);
+        self.find_attr(key).map(|idx| self.attr.remove(idx).1)
+    }
+    #[allow(dead_code)]
+    /// get entry of attribute `key` for conditional updates
+    ///
+    /// ```
+    /// use etree::ETreeNode;
+    ///
+    /// let mut node = ETreeNode::new("a");
+    /// node.attr_entry("class").or_insert("x");
+    /// node.attr_entry("class").and_modify(|x| x.push_str(" y")).or_insert("z");
+    /// assert_eq!(node.get_attr("class").as_deref(), Some("x y"));
+    /// ```
+    pub fn attr_entry(&mut self, key:&str) -> AttrEntry<'_> {
+        AttrEntry { node:self, key:String::from(key) }
+    }
+    #[allow(dead_code)]
+    /// parse value of attribute `key` without surrounding whitespace, fail if it is missing
+    pub fn get_attr_as<T:FromStr>(&self, key:&str) -> Result<T, Error> where T::Err:Display {
+        let error = |value:Option<&str>, message:String| Error::Value {
+            name:self.get_name(),
+            attr:Some(key.to_string()),
+            value:value.map(|x| x.to_string()),
+            message,
+        };
+        match self.find_attr(key).map(|idx| self.attr[idx].1.trim()) {
+            Some(value) => value.parse().map_err(|e:T::Err| error(Some(value), e.to_string())),
+            None => Err(error(None, "missing attribute".to_string())),
+        }
+    }
+    #[allow(dead_code)]
+    /// get value of the attribute `localname` in namespace `uri`, whatever prefix the document uses
+    pub fn get_attr_ns(&self, uri:&str, localname:&str) -> Option<String> {
+        self.attr.iter()
+            .find(|(key, _)| {
+                let (prefix, name) = split_qname(key);
+                name == localname && !prefix.is_empty() && self.get_attr_namespace(key).as_deref() == Some(uri)
+            })
+            .map(|(_, value)| value.clone())
+    }
+    #[allow(dead_code)]
+    /// get namespace of a prefixed attribute, resolved when parsing or set by `set_attr_ns`
+    pub fn get_attr_namespace(&self, key:&str) -> Option<String> {
+        self.attr_ns.iter().find(|x| x.0 == key).map(|x| x.1.clone())
+    }
+    #[allow(dead_code)]
+    /// set attribute `key` ("prefix:localname") and record that its prefix is bound to `uri`
+    pub fn set_attr_ns(&mut self, uri:&str, key:&str, value:&str) -> usize {
+        self.set_attr_namespace(key, uri);
+        self.set_attr(key, value)
+    }
+    pub(crate) fn set_attr_namespace(&mut self, key:&str, uri:&str) {
+        self.attr_ns.retain(|x| x.0 != key);
+        self.attr_ns.push((String::from(key), String::from(uri)));
+    }
+    #[allow(dead_code)]
+    /// declare `prefix` ("" for default namespace) bound to `uri` on the node, written as a `xmlns` attribute
+    ///
+    /// The namespace of the node and its attributes with `prefix` is updated, other nodes keep their namespace.
+    pub fn add_namespace_decl(&mut self, prefix:&str, uri:&str) {
+        self.set_attr(&namespace_decl_key(prefix), uri);
+        if prefix.is_empty() {
+            self.default_ns = String::from(uri);
+        }
+        if self.ns_abbrev == prefix {
+            self.ns = String::from(uri);
+        }
+        let keys:Vec<String> = self.attr.iter()
+            .map(|x| x.0.clone())
+            .filter(|key| !prefix.is_empty() && split_qname(key).0 == prefix)
+            .collect();
+        for key in keys {
+            self.set_attr_namespace(&key, uri);
+        }
+    }
+    #[allow(dead_code)]
+    /// remove declaration of `prefix` ("" for default namespace) and return its namespace
+    ///
+    /// The default namespace in scope is reset like `add_namespace_decl` sets it. Namespaces of the node and its
+    /// attributes are not changed, as the prefix may still be bound by an ancestor.
+    pub fn remove_namespace_decl(&mut self, prefix:&str) -> Option<String> {
+        let removed = self.remove_attr(&namespace_decl_key(prefix));
+        if prefix.is_empty() && removed.is_some() {
+            self.default_ns = String::new();
+        }
+        removed
+    }
+    #[allow(dead_code)]
+    /// get namespaces declared on the node as (prefix, namespace) in document order, "" for default namespace
+    pub fn declared_namespaces(&self) -> Vec<(String, String)> {
+        self.attr.iter()
+            .filter_map(|(key, value)| match key.as_str() {
+                "xmlns" => Some((String::new(), value.clone())),
+                _ => key.strip_prefix("xmlns:").map(|x| (x.to_string(), value.clone())),
+            })
+            .collect()
+    }
+    #[allow(dead_code)]
+    /// compare kind, namespace, local name, attributes and text of nodes, but not their children or tail
+    ///
+    /// Prefixes are not compared, and namespace declarations are not regarded as attributes.
+    pub fn content_eq(&self, other:&ETreeNode, options:&CompareOptions) -> bool {
+        let attrs = |node:&ETreeNode| {
+            let mut attrs:Vec<(String, String, String)> = node.attr.iter()
+                .filter(|(key, _)| key != "xmlns" && !key.starts_with("xmlns:"))
+                .map(|(key, value)| {
+                    let (prefix, name) = split_qname(key);
+                    let ns = node.get_attr_namespace(key).unwrap_or_else(|| prefix.to_string());
+                    (ns, name.to_string(), value.clone())
+                })
+                .collect();
+            if options.ignore_attr_order {
+                attrs.sort();
+            }
+            attrs
+        };
+        let text = |node:&ETreeNode| options.significant(node.get_text_ref().unwrap_or("")).to_string();
+        self.kind() == other.kind()
+            && self.ns == other.ns
+            && self.local_name == other.local_name
+            && text(self) == text(other)
+            && attrs(self) == attrs(other)
+    }
+    #[allow(dead_code)]
+    /// whether the attribute is supplied by the default value in DTD rather than the document
+    pub fn is_attr_defaulted(&self, key:&str) -> bool {
+        self.defaulted.iter().any(|x| x == key)
+    }
+    pub(crate) fn set_default_attr(&mut self, key:&str, value:&str) {
+        if self.find_attr(key).is_none() {
+            self.attr.push((String::from(key), String::from(value)));
+            self.defaulted.push(String::from(key));
+        }
     }
     #[allow(dead_code)]
     pub fn set_attr(&mut self, key:&str, value:&str) -> usize {
+        self.defaulted.retain(|x| x != key);
         if let Some(idx) = self.find_attr(key) {
             self.attr[idx].1 = String::from(value);
             idx
@@ -158,13 +613,137 @@ impl ETreeNode {
             self.attr.len()
         }
     }
-    fn find_attr(&self, key:&str) -> Option<usize> {
-        for i in 0..self.attr.len() {
-            if self.attr[i].0 == key {
-                return Some(i);
+    #[allow(dead_code)]
+    /// whether `xsi:nil` is true
+    pub fn is_nil(&self) -> bool {
+        self.get_attr(&format!("{}:nil", self.xsi_prefix()))
+            .map(|x| x.trim() == "true" || x.trim() == "1")
+            .unwrap_or(false)
+    }
+    #[allow(dead_code)]
+    /// set `xsi:nil="true"` or remove `xsi:nil`
+    ///
+    /// `write()` declares the prefix if no ancestor does, use `ETree::set_nil` to reuse a prefix bound in scope.
+    pub fn set_nil(&mut self, nil:bool) {
+        let key = format!("{}:nil", self.xsi_prefix());
+        if nil {
+            self.set_attr_ns(XSI_NAMESPACE, &key, "true");
+        } else {
+            self.remove_attr(&key);
+        }
+    }
+    #[allow(dead_code)]
+    /// get QName in `xsi:type`
+    pub fn get_xsi_type(&self) -> Option<String> {
+        self.get_attr(&format!("{}:type", self.xsi_prefix())).map(|x| x.trim().to_string())
+    }
+    #[allow(dead_code)]
+    /// set QName in `xsi:type`, prefix of `qname` should be bound to the type's namespace
+    ///
+    /// `write()` declares the prefix if no ancestor does, use `ETree::set_xsi_type` to reuse a prefix bound in scope.
+    pub fn set_xsi_type(&mut self, qname:&str) {
+        let key = format!("{}:type", self.xsi_prefix());
+        self.set_attr_ns(XSI_NAMESPACE, &key, qname);
+    }
+    /// prefix bound to XML Schema instance namespace on this node, or "xsi"
+    fn xsi_prefix(&self) -> String {
+        for (key, value) in self.attr.iter() {
+            if let Some(prefix) = key.strip_prefix("xmlns:") {
+                if value == XSI_NAMESPACE {
+                    return prefix.to_string();
+                }
             }
         }
-        return None;
+        "xsi".to_string()
+    }
+    fn find_attr(&self, key:&str) -> Option<usize> {
+        self.attr.iter().position(|item| item.0 == key)
+    }
+}
+
+/// Attribute of a node which may be missing, created by `ETreeNode::attr_entry`
+///
+/// A new attribute is appended after the existing ones, like `set_attr`.
+#[derive(Debug)]
+pub struct AttrEntry<'a> {
+    node:&'a mut ETreeNode,
+    key:String,
+}
+
+impl<'a> AttrEntry<'a> {
+    #[allow(dead_code)]
+    /// get name of the attribute
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+    #[allow(dead_code)]
+    /// get value of the attribute, `None` if it is missing
+    pub fn get(&self) -> Option<&str> {
+        self.node.find_attr(&self.key).map(|idx| self.node.attr[idx].1.as_str())
+    }
+    #[allow(dead_code)]
+    /// update the value if the attribute exists, it is no longer regarded as defaulted
+    pub fn and_modify<F:FnOnce(&mut String)>(self, f:F) -> AttrEntry<'a> {
+        if let Some(idx) = self.node.find_attr(&self.key) {
+            let key = &self.key;
+            self.node.defaulted.retain(|x| x != key);
+            f(&mut self.node.attr[idx].1);
+        }
+        self
+    }
+    #[allow(dead_code)]
+    /// insert `value` if the attribute is missing, and get the value
+    pub fn or_insert(self, value:&str) -> &'a mut String {
+        self.or_insert_with(|| String::from(value))
+    }
+    #[allow(dead_code)]
+    /// insert the result of `f` if the attribute is missing, and get the value
+    pub fn or_insert_with<F:FnOnce() -> String>(self, f:F) -> &'a mut String {
+        let idx = match self.node.find_attr(&self.key) {
+            Some(idx) => idx,
+            None => {
+                self.node.attr.push((self.key, f()));
+                self.node.attr.len() - 1
+            }
+        };
+        &mut self.node.attr[idx].1
+    }
+    #[allow(dead_code)]
+    /// remove the attribute and return its value
+    pub fn remove(self) -> Option<String> {
+        self.node.remove_attr(&self.key)
+    }
+}
+
+/// pseudo-attributes `key="value"` or `key='value'` of PI data, with the byte range of each value
+fn pseudo_attrs(data:&str) -> Vec<(String, std::ops::Range<usize>)> {
+    let re = regex::Regex::new(r#"([^\s=]+)\s*=\s*(?:"([^"]*)"|'([^']*)')"#).unwrap();
+    re.captures_iter(data)
+        .filter_map(|c| Some((c[1].to_string(), c.get(2).or_else(|| c.get(3))?.range())))
+        .collect()
+}
+
+/// attribute declaring `prefix`
+fn namespace_decl_key(prefix:&str) -> String {
+    if prefix.is_empty() {
+        String::from("xmlns")
+    } else {
+        format!("xmlns:{}", prefix)
+    }
+}
+
+/// split "prefix:localname" into prefix and localname
+fn split_qname(qname:&str) -> (&str, &str) {
+    match qname.find(':') {
+        Some(idx) => (&qname[..idx], &qname[idx + 1..]),
+        None => ("", qname),
+    }
+}
+
+/// nodes are equal when `content_eq` with default `CompareOptions` is true
+impl PartialEq for ETreeNode {
+    fn eq(&self, other:&ETreeNode) -> bool {
+        self.content_eq(other, &CompareOptions::new())
     }
 }
 
@@ -175,6 +754,6 @@ impl std::fmt::Display for ETreeNode {
         for item in self.attr.iter() {
             attrs.push(format!("{}=\"{}\"", &item.0, &item.1));
         }
-        write!(f, "{}]={:?}", attrs.join(" "), self.text)
+        write!(f, "{}]={:?}", attrs.join(" "), self.get_text_ref())
     }
 }
@@ -0,0 +1,351 @@
+use super::dtd;
+use super::error::ParseError;
+use super::etree::{check_limit, parse_err};
+use super::etreenode::NodeKind;
+use super::options::ParseOptions;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+/// Node of `ETreeRef`
+///
+/// Names, attributes and text borrow from the source text unless entities had to be expanded.
+/// Comments, CDATA, processing instructions and DOCTYPE are leaf nodes told apart by `kind()`, their names
+/// are "<Comment>", "<CData>", "<PI>" and "<DocType>" like in `ETree`.
+#[derive(Debug, Clone)]
+pub struct ETreeNodeRef<'a> {
+    kind: NodeKind,
+    name: Cow<'a, str>,
+    attrs: Vec<(Cow<'a, str>, Cow<'a, str>)>,
+    text: Option<Cow<'a, str>>,
+    tail: Option<Cow<'a, str>>,
+    parent: Option<usize>,
+    end: usize,
+}
+
+impl<'a> ETreeNodeRef<'a> {
+    #[allow(dead_code)]
+    /// get kind of the node
+    pub fn kind(&self) -> NodeKind {
+        self.kind
+    }
+    #[allow(dead_code)]
+    pub fn is_element(&self) -> bool {
+        self.kind == NodeKind::Element
+    }
+    #[allow(dead_code)]
+    /// get qualified name such as "prefix:localname"
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+    #[allow(dead_code)]
+    pub fn get_localname(&self) -> &str {
+        match self.name.find(':') {
+            Some(i) => &self.name[i + 1..],
+            None => &self.name,
+        }
+    }
+    #[allow(dead_code)]
+    pub fn get_prefix(&self) -> &str {
+        match self.name.find(':') {
+            Some(i) => &self.name[..i],
+            None => "",
+        }
+    }
+    #[allow(dead_code)]
+    pub fn get_text(&self) -> Option<&str> {
+        self.text.as_deref()
+    }
+    #[allow(dead_code)]
+    pub fn get_tail(&self) -> &str {
+        self.tail.as_deref().unwrap_or("")
+    }
+    #[allow(dead_code)]
+    pub fn get_attr_count(&self) -> usize {
+        self.attrs.len()
+    }
+    #[allow(dead_code)]
+    pub fn get_attr_iter(&self) -> std::slice::Iter<'_, (Cow<'a, str>, Cow<'a, str>)> {
+        self.attrs.iter()
+    }
+    #[allow(dead_code)]
+    pub fn get_attr(&self, key: &str) -> Option<&str> {
+        self.attrs.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_ref())
+    }
+}
+
+/// Element tree borrowing from the source text
+///
+/// A read-only variant of `ETree` for very large documents: names, attributes and text are slices of the source
+/// instead of owned strings, so parsing allocates little more than the node list. Namespaces are not resolved.
+///
+/// ```
+/// use etree::ETreeRef;
+///
+/// let content = r#"<root><item id="1">a</item><item id="2">b &amp; c</item></root>"#;
+/// let tree = ETreeRef::parse_str(content).unwrap();
+/// let texts: Vec<&str> = tree.children(tree.root()).into_iter().filter_map(|x| tree.node(x)?.get_text()).collect();
+/// assert_eq!(texts, vec!["a", "b & c"]);
+/// ```
+#[derive(Debug, Clone)]
+pub struct ETreeRef<'a> {
+    data: Vec<ETreeNodeRef<'a>>,
+}
+
+impl<'a> ETreeRef<'a> {
+    #[allow(dead_code)]
+    pub fn parse_str(content: &'a str) -> Result<ETreeRef<'a>, ParseError> {
+        ETreeRef::parse_with(content, &ParseOptions::default())
+    }
+    #[allow(dead_code)]
+    /// parse XML text with options, `max_*` limits and the `ignore_*` and `trim_text` flags are supported
+    pub fn parse_with(content: &'a str, options: &ParseOptions) -> Result<ETreeRef<'a>, ParseError> {
+        let data = content.strip_prefix('\u{feff}').unwrap_or(content);
+        let mut out = ETreeRef { data: Vec::new() };
+        let mut reader = Reader::from_str(data);
+        reader.check_end_names(false);
+        let mut buf = Vec::new();
+        let mut entities = HashMap::new();
+        let mut unescaper = dtd::Unescaper::new(options);
+        let mut open: Vec<usize> = Vec::new();
+        // the node whose tail receives text after a closed node
+        let mut closed: Option<usize> = None;
+        loop {
+            let start = reader.buffer_position();
+            buf.clear();
+            match reader.read_event(&mut buf) {
+                Ok(Event::Start(ref e)) => {
+                    let node = out.read_elem(e, &reader, data, start + 1, &mut unescaper, &entities, &open)?;
+                    out.data.push(node);
+                    open.push(out.data.len() - 1);
+                    closed = None;
+                }
+                Ok(Event::Empty(ref e)) => {
+                    let node = out.read_elem(e, &reader, data, start + 1, &mut unescaper, &entities, &open)?;
+                    out.data.push(node);
+                    closed = Some(out.data.len() - 1);
+                }
+                Ok(Event::End(ref e)) => {
+                    let name = reader.decode(e.name()).map_err(|x| parse_err(data, &reader, x))?;
+                    match open.pop() {
+                        Some(x) if out.data[x].name == name => {
+                            out.data[x].end = out.data.len();
+                            closed = Some(x);
+                        }
+                        Some(x) => {
+                            let message = format!("expecting </{}> but found </{}>", out.data[x].name, name);
+                            return Err(ParseError::new(data, start, message));
+                        }
+                        None => {
+                            let message = format!("unexpected end tag </{}>", name);
+                            return Err(ParseError::new(data, start, message));
+                        }
+                    }
+                }
+                Ok(Event::Text(e)) => {
+                    let raw = slice(data, &reader, start, &e, &e)?;
+                    let text = unescape(raw, &mut unescaper, &entities).map_err(|x| parse_err(data, &reader, x))?;
+                    let text = if options.trim_text { trim(text) } else { text };
+                    out.push_text(&open, closed, text);
+                }
+                Ok(Event::Comment(_)) if options.ignore_comments => {}
+                Ok(Event::PI(_)) if options.ignore_processing_instructions => {}
+                // content of leaf nodes follows "<!--", "<![CDATA[", "<?" or "<!DOCTYPE"
+                Ok(Event::Comment(e)) => {
+                    closed = Some(out.push_leaf(NodeKind::Comment, slice(data, &reader, start + 4, &e, &e)?, &open));
+                }
+                Ok(Event::CData(e)) => {
+                    closed = Some(out.push_leaf(NodeKind::CData, slice(data, &reader, start + 9, &e, &e)?, &open));
+                }
+                Ok(Event::PI(e)) => {
+                    let text = slice(data, &reader, start + 2, &e, &e)?;
+                    closed = Some(out.push_leaf(NodeKind::ProcessingInstruction, text, &open));
+                }
+                Ok(Event::DocType(e)) => {
+                    let text = slice(data, &reader, start + 9, &e, &e)?;
+                    entities = dtd::entities(text);
+                    closed = Some(out.push_leaf(NodeKind::DocType, text, &open));
+                }
+                Ok(Event::Decl(_)) => {}
+                Ok(Event::Eof) => break,
+                Err(e) => return Err(parse_err(data, &reader, e)),
+            }
+            check_limit(data, &reader, "node count", out.data.len(), options.max_nodes)?;
+        }
+        if let Some(&x) = open.last() {
+            let message = format!("element <{}> is not closed", out.data[x].name);
+            return Err(ParseError::new(data, data.len(), message));
+        }
+        Ok(out)
+    }
+    #[allow(dead_code)]
+    /// get number of nodes
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+    #[allow(dead_code)]
+    /// get position of root element
+    pub fn root(&self) -> usize {
+        self.data
+            .iter()
+            .position(|x| x.parent.is_none() && x.is_element())
+            .unwrap_or(self.data.len())
+    }
+    #[allow(dead_code)]
+    pub fn node(&self, pos: usize) -> Option<&ETreeNodeRef<'a>> {
+        self.data.get(pos)
+    }
+    #[allow(dead_code)]
+    /// get position of parent node
+    pub fn parent(&self, pos: usize) -> Option<usize> {
+        self.data.get(pos).and_then(|x| x.parent)
+    }
+    #[allow(dead_code)]
+    /// get positions of children node
+    pub fn children(&self, pos: usize) -> Vec<usize> {
+        let mut out = Vec::new();
+        if let Some(node) = self.data.get(pos) {
+            let mut child = pos + 1;
+            while child < node.end {
+                out.push(child);
+                child = self.data[child].end;
+            }
+        }
+        out
+    }
+    #[allow(dead_code)]
+    /// get positions of descendant nodes
+    pub fn descendant(&self, pos: usize) -> Vec<usize> {
+        match self.data.get(pos) {
+            Some(node) => (pos + 1..node.end).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// build element node from a start tag, `base` is the position of the tag content in `data`
+    #[allow(clippy::too_many_arguments)]
+    fn read_elem(
+        &self,
+        e: &BytesStart,
+        reader: &Reader<&[u8]>,
+        data: &'a str,
+        base: usize,
+        unescaper: &mut dtd::Unescaper,
+        entities: &HashMap<String, Option<String>>,
+        open: &[usize],
+    ) -> Result<ETreeNodeRef<'a>, ParseError> {
+        check_limit(data, reader, "nesting depth", open.len() + 1, unescaper.options().max_depth)?;
+        let name = slice(data, reader, base, e, e.name())?;
+        let mut attrs = Vec::new();
+        for (i, attr) in e.attributes().enumerate() {
+            check_limit(data, reader, "attribute count", i + 1, unescaper.options().max_attributes)?;
+            let attr = attr.map_err(|x| parse_err(data, reader, x))?;
+            let key = slice(data, reader, base, e, attr.key)?;
+            let raw = slice(data, reader, base, e, &attr.value)?;
+            let value = unescape(raw, unescaper, entities).map_err(|x| parse_err(data, reader, x))?;
+            attrs.push((Cow::Borrowed(key), value));
+        }
+        Ok(ETreeNodeRef {
+            kind: NodeKind::Element,
+            name: Cow::Borrowed(name),
+            attrs,
+            text: None,
+            tail: None,
+            parent: open.last().copied(),
+            end: self.data.len() + 1,
+        })
+    }
+    /// add a comment, CDATA, PI or DOCTYPE node and return its position
+    fn push_leaf(&mut self, kind: NodeKind, text: &'a str, open: &[usize]) -> usize {
+        self.data.push(ETreeNodeRef {
+            kind,
+            name: Cow::Borrowed(kind.sentinel().unwrap_or_default()),
+            attrs: Vec::new(),
+            text: Some(Cow::Borrowed(text)),
+            tail: None,
+            parent: open.last().copied(),
+            end: self.data.len() + 1,
+        });
+        self.data.len() - 1
+    }
+    /// append text to the open element or the tail of the last closed node, text outside the root is dropped
+    fn push_text(&mut self, open: &[usize], closed: Option<usize>, text: Cow<'a, str>) {
+        let slot = match (closed, open.last()) {
+            (Some(x), _) => &mut self.data[x].tail,
+            (None, Some(&x)) => &mut self.data[x].text,
+            (None, None) => return,
+        };
+        *slot = match slot.take() {
+            Some(prev) => Some(Cow::Owned(prev.into_owned() + &text)),
+            None => Some(text),
+        };
+    }
+}
+
+/// borrow `part` of the event content `whole` from `data`, `base` is the position of `whole` in `data`
+fn slice<'a>(data: &'a str, reader: &Reader<&[u8]>, base: usize, whole: &[u8], part: &[u8]) -> Result<&'a str, ParseError> {
+    let range = (part.as_ptr() as usize)
+        .checked_sub(whole.as_ptr() as usize)
+        .filter(|x| x + part.len() <= whole.len())
+        .map(|x| base + x..base + x + part.len());
+    match range.and_then(|x| data.get(x)) {
+        Some(x) if x.as_bytes() == part => Ok(x),
+        _ => Err(parse_err(data, reader, "content is not a slice of the source")),
+    }
+}
+
+/// expand references, text without "&" stays borrowed
+fn unescape<'a>(
+    raw: &'a str,
+    unescaper: &mut dtd::Unescaper,
+    entities: &HashMap<String, Option<String>>,
+) -> Result<Cow<'a, str>, String> {
+    if raw.contains('&') {
+        unescaper.unescape(raw, entities).map(Cow::Owned)
+    } else {
+        Ok(Cow::Borrowed(raw))
+    }
+}
+
+fn trim(text: Cow<'_, str>) -> Cow<'_, str> {
+    match text {
+        Cow::Borrowed(x) => Cow::Borrowed(x.trim()),
+        Cow::Owned(x) => Cow::Owned(x.trim().to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_etreeref() {
+        let content = "<?xml version=\"1.0\"?>\n<root a=\"1\" b=\"&lt;\">\n  <x:item>text</x:item><!-- c -->tail<![CDATA[<d>]]>\n  <e/>\n</root>";
+        let tree = ETreeRef::parse_str(content).unwrap();
+        let range = content.as_ptr() as usize..content.as_ptr() as usize + content.len();
+        let root = tree.root();
+        assert_eq!(tree.node(root).unwrap().get_name(), "root");
+        assert_eq!(tree.node(root).unwrap().get_attr("b"), Some("<"));
+        assert!(range.contains(&(tree.node(root).unwrap().get_attr("a").unwrap().as_ptr() as usize)));
+        let children = tree.children(root);
+        assert_eq!(children, vec![1, 2, 3, 4]);
+        let item = tree.node(1).unwrap();
+        assert_eq!((item.get_prefix(), item.get_localname(), item.get_text()), ("x", "item", Some("text")));
+        assert!(range.contains(&(item.get_text().unwrap().as_ptr() as usize)));
+        assert_eq!(tree.node(2).unwrap().get_name(), "<Comment>");
+        assert_eq!(tree.node(2).unwrap().kind(), NodeKind::Comment);
+        assert!(tree.node(1).unwrap().is_element());
+        assert_eq!(tree.node(2).unwrap().get_text(), Some(" c "));
+        assert_eq!(tree.node(2).unwrap().get_tail(), "tail");
+        assert_eq!(tree.node(3).unwrap().get_text(), Some("<d>"));
+        assert_eq!(tree.parent(4), Some(root));
+        assert_eq!(tree.descendant(root).len(), 4);
+        let e = ETreeRef::parse_str("<root>\n  <a></b></root>").unwrap_err();
+        assert_eq!((e.offset, e.line, e.column), (12, 2, 6));
+        assert!(ETreeRef::parse_str("<root>").is_err());
+    }
+}
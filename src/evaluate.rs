@@ -0,0 +1,302 @@
+use super::error::{Error, XPathError};
+use super::etree::{name_function, ETree};
+use super::etreenode::NodeKind;
+use super::xpath::{self, Expr};
+
+/// Result of `ETree::evaluate`
+///
+/// - `NodeSet`: positions of the selected nodes in document order
+/// - `String`: string value, e.g. of `string()` or an attribute path like `/cfg/@version`
+/// - `Number`: number value, NaN if a value is not a number
+/// - `Boolean`: boolean value, e.g. of `boolean()` or a comparison
+#[derive(Debug, Clone, PartialEq)]
+pub enum XPathValue {
+    NodeSet(Vec<usize>),
+    String(String),
+    Number(f64),
+    Boolean(bool),
+}
+
+/// intermediate value, attributes and texts are kept as their values since they are not nodes of the tree
+enum Value {
+    Nodes(Vec<usize>),
+    Attrs(Vec<String>),
+    Str(String),
+    Num(f64),
+    Bool(bool),
+}
+
+impl ETree {
+    #[allow(dead_code)]
+    /// evaluate XPath expression `expr` from the root node
+    ///
+    /// Panics if `expr` is invalid, use `try_evaluate` for expressions which are not known in advance.
+    pub fn evaluate(&self, expr: &str) -> XPathValue {
+        self.evaluate_at(expr, self.root())
+    }
+    #[allow(dead_code)]
+    /// evaluate XPath expression `expr` from specified node
+    pub fn evaluate_at(&self, expr: &str, pos: usize) -> XPathValue {
+        match self.try_evaluate_at(expr, pos) {
+            Ok(value) => value,
+            Err(e) => panic!("{}", e),
+        }
+    }
+    #[allow(dead_code)]
+    /// evaluate XPath expression `expr` from the root node, fail if `expr` is invalid
+    pub fn try_evaluate(&self, expr: &str) -> Result<XPathValue, XPathError> {
+        self.try_evaluate_at(expr, self.root())
+    }
+    #[allow(dead_code)]
+    /// evaluate XPath expression `expr` from specified node, fail if `expr` is invalid
+    pub fn try_evaluate_at(&self, expr: &str, pos: usize) -> Result<XPathValue, XPathError> {
+        let parsed = xpath::parse_expr(expr).map_err(|(position, end, message)| Error::XPath {
+            expr: expr.to_string(),
+            position,
+            end,
+            message,
+        })?;
+        Ok(match self.eval(&parsed, pos)? {
+            Value::Nodes(nodes) => XPathValue::NodeSet(nodes),
+            // an attribute or a text is not a node of the tree, its string value is returned like `string()`
+            Value::Attrs(values) => XPathValue::String(values.into_iter().next().unwrap_or_default()),
+            Value::Str(value) => XPathValue::String(value),
+            Value::Num(value) => XPathValue::Number(value),
+            Value::Bool(value) => XPathValue::Boolean(value),
+        })
+    }
+    fn eval(&self, expr: &Expr, pos: usize) -> Result<Value, XPathError> {
+        Ok(match expr {
+            Expr::Path(path) => Value::Nodes(self.try_find_at_iter(path, pos)?.collect()),
+            Expr::Attribute(path, name) => Value::Attrs(
+                self.try_find_at_iter(path, pos)?
+                    .filter_map(|x| self.node(x).unwrap().get_attr(name))
+                    .collect(),
+            ),
+            Expr::Text(path) => {
+                let mut texts = Vec::new();
+                for x in self.try_find_at_iter(path, pos)? {
+                    texts.extend(self.texts(x));
+                }
+                Value::Attrs(texts)
+            }
+            Expr::Literal(text) => Value::Str(text.clone()),
+            Expr::Number(text) => Value::Num(xpath::to_number(text)),
+            Expr::Negate(inner) => Value::Num(-self.number(&self.eval(inner, pos)?)),
+            Expr::Binary(op, left, right) => {
+                let left = self.eval(left, pos)?;
+                match op.as_str() {
+                    // the right side is not evaluated if the left side decides
+                    "or" => Value::Bool(self.boolean(&left) || self.boolean(&self.eval(right, pos)?)),
+                    "and" => Value::Bool(self.boolean(&left) && self.boolean(&self.eval(right, pos)?)),
+                    "+" | "-" | "*" | "div" | "mod" => {
+                        let (left, right) = (self.number(&left), self.number(&self.eval(right, pos)?));
+                        Value::Num(match op.as_str() {
+                            "+" => left + right,
+                            "-" => left - right,
+                            "*" => left * right,
+                            "div" => left / right,
+                            _ => left % right,
+                        })
+                    }
+                    _ => Value::Bool(self.compare(&left, op, &self.eval(right, pos)?)),
+                }
+            }
+            Expr::Function(name, args) => self.call(name, args, pos)?,
+        })
+    }
+    fn call(&self, name: &str, args: &[Expr], pos: usize) -> Result<Value, XPathError> {
+        let args = args.iter().map(|x| self.eval(x, pos)).collect::<Result<Vec<_>, _>>()?;
+        // string functions default to the string value of the context node
+        let mut strings: Vec<String> = args.iter().map(|x| self.string(x)).collect();
+        if strings.is_empty() {
+            strings.push(self.string_value(pos));
+        }
+        Ok(match name {
+            "count" => Value::Num(match args.first() {
+                Some(Value::Nodes(nodes)) => nodes.len() as f64,
+                Some(Value::Attrs(values)) => values.len() as f64,
+                _ => f64::NAN,
+            }),
+            "sum" => Value::Num(self.items(args.first()).iter().map(|x| xpath::to_number(x)).sum()),
+            "string" => Value::Str(strings.swap_remove(0)),
+            "number" => Value::Num(match args.first() {
+                Some(value) => self.number(value),
+                None => xpath::to_number(&self.string_value(pos)),
+            }),
+            "boolean" => Value::Bool(args.first().map(|x| self.boolean(x)).unwrap_or(false)),
+            "not" => Value::Bool(!args.first().map(|x| self.boolean(x)).unwrap_or(false)),
+            "true" => Value::Bool(true),
+            "false" => Value::Bool(false),
+            "concat" => Value::Str(strings.concat()),
+            "local-name" | "namespace-uri" | "name" => {
+                // name of the first node of the argument, or of the context node
+                let node = match args.first() {
+                    Some(Value::Nodes(nodes)) => nodes.first().copied(),
+                    Some(_) => None,
+                    None => Some(pos),
+                };
+                Value::Str(node.and_then(|x| self.node(x)).map(|x| name_function(x, name)).unwrap_or_default())
+            }
+            _ => match xpath::string_function(name, &strings) {
+                Some(value) if name == "string-length" => Value::Num(xpath::to_number(&value)),
+                Some(value) => Value::Str(value),
+                None => Value::Bool(xpath::boolean_function(name, &strings)),
+            },
+        })
+    }
+    /// texts directly in the node at `pos`: its text, CDATA sections and tails of children
+    pub(crate) fn texts(&self, pos: usize) -> Vec<String> {
+        let mut out = Vec::new();
+        let node = match self.node(pos) {
+            Some(node) if node.is_element() => node,
+            _ => return out,
+        };
+        out.extend(node.get_text().filter(|x| !x.is_empty()));
+        for x in self.children(pos) {
+            let child = self.node(x).unwrap();
+            if child.is_cdata() {
+                out.extend(child.get_text().filter(|x| !x.is_empty()));
+            }
+            out.extend(Some(child.get_tail()).filter(|x| !x.is_empty()));
+        }
+        out
+    }
+    /// texts of all text nodes in the node at `pos`, only the own text for comments and processing instructions
+    fn string_value(&self, pos: usize) -> String {
+        let node = match self.node(pos) {
+            Some(node) => node,
+            None => return String::new(),
+        };
+        let mut out = node.get_text().unwrap_or_default();
+        for x in self.descendant(pos) {
+            let child = self.node(x).unwrap();
+            if matches!(child.kind(), NodeKind::Element | NodeKind::CData) {
+                out.push_str(child.get_text_ref().unwrap_or(""));
+            }
+            out.push_str(child.get_tail_ref());
+        }
+        out
+    }
+    /// string values of a node set or attributes, empty for other values
+    fn items(&self, value: Option<&Value>) -> Vec<String> {
+        match value {
+            Some(Value::Nodes(nodes)) => nodes.iter().map(|&x| self.string_value(x)).collect(),
+            Some(Value::Attrs(values)) => values.clone(),
+            _ => Vec::new(),
+        }
+    }
+    fn string(&self, value: &Value) -> String {
+        match value {
+            Value::Nodes(_) | Value::Attrs(_) => self.items(Some(value)).into_iter().next().unwrap_or_default(),
+            Value::Str(value) => value.clone(),
+            Value::Num(value) => xpath::format_number(*value),
+            Value::Bool(value) => value.to_string(),
+        }
+    }
+    fn number(&self, value: &Value) -> f64 {
+        match value {
+            Value::Num(value) => *value,
+            Value::Bool(value) => *value as u8 as f64,
+            _ => xpath::to_number(&self.string(value)),
+        }
+    }
+    fn boolean(&self, value: &Value) -> bool {
+        match value {
+            Value::Nodes(nodes) => !nodes.is_empty(),
+            Value::Attrs(values) => !values.is_empty(),
+            Value::Str(value) => !value.is_empty(),
+            Value::Num(value) => *value != 0.0 && !value.is_nan(),
+            Value::Bool(value) => *value,
+        }
+    }
+    /// compare values with `op`, a node set matches if one of its nodes does
+    fn compare(&self, left: &Value, op: &str, right: &Value) -> bool {
+        let is_set = |x: &Value| matches!(x, Value::Nodes(_) | Value::Attrs(_));
+        match (is_set(left), is_set(right)) {
+            (true, true) => {
+                let right = self.items(Some(right));
+                self.items(Some(left))
+                    .into_iter()
+                    .any(|l| right.iter().any(|r| self.compare(&Value::Str(l.clone()), op, &Value::Str(r.clone()))))
+            }
+            (true, false) | (false, true) if matches!(left, Value::Bool(_)) || matches!(right, Value::Bool(_)) => {
+                compare_atoms(
+                    &Value::Bool(self.boolean(left)),
+                    op,
+                    &Value::Bool(self.boolean(right)),
+                )
+            }
+            (true, false) => self.items(Some(left)).into_iter().any(|l| compare_atoms(&Value::Str(l), op, right)),
+            (false, true) => self.items(Some(right)).into_iter().any(|r| compare_atoms(left, op, &Value::Str(r))),
+            (false, false) => compare_atoms(left, op, right),
+        }
+    }
+}
+
+/// compare values which are not node sets, booleans before numbers before strings like XPath 1.0
+fn compare_atoms(left: &Value, op: &str, right: &Value) -> bool {
+    let number = |x: &Value| match x {
+        Value::Num(value) => *value,
+        Value::Bool(value) => *value as u8 as f64,
+        Value::Str(value) => xpath::to_number(value),
+        _ => f64::NAN,
+    };
+    let boolean = |x: &Value| match x {
+        Value::Num(value) => *value != 0.0 && !value.is_nan(),
+        Value::Str(value) => !value.is_empty(),
+        Value::Bool(value) => *value,
+        _ => false,
+    };
+    let either = |f: fn(&Value) -> bool| f(left) || f(right);
+    if xpath::is_relational(op) {
+        xpath::compare_numbers(number(left), op, number(right))
+    } else if either(|x| matches!(x, Value::Bool(_))) {
+        (boolean(left) == boolean(right)) == (op == "==")
+    } else if either(|x| matches!(x, Value::Num(_))) {
+        xpath::compare_numbers(number(left), op, number(right))
+    } else {
+        match (left, right) {
+            (Value::Str(l), Value::Str(r)) => (l == r) == (op == "=="),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_evaluate() {
+        let tree = ETree::parse_str(concat!(
+            "<cfg version=\"1.2\"><item id=\"a\">3</item><item id=\"b\">4<!-- x --><b>5</b></item>",
+            "<name> x  y </name></cfg>"
+        ))
+        .unwrap();
+        assert_eq!(tree.evaluate("count(//item)"), XPathValue::Number(2.0));
+        assert_eq!(tree.evaluate("string(/cfg/@version)"), XPathValue::String("1.2".to_string()));
+        assert_eq!(tree.evaluate("/cfg/@version"), XPathValue::String("1.2".to_string()));
+        assert_eq!(tree.evaluate("boolean(//err)"), XPathValue::Boolean(false));
+        assert_eq!(tree.evaluate("boolean(//item)"), XPathValue::Boolean(true));
+        assert_eq!(tree.evaluate("//item"), XPathValue::NodeSet(vec![1, 2]));
+        assert_eq!(tree.evaluate("string(//item[2])"), XPathValue::String("45".to_string()));
+        assert_eq!(tree.evaluate("sum(//item) div 2"), XPathValue::Number(24.0));
+        assert_eq!(tree.evaluate("count(//@id) * 10 - 1"), XPathValue::Number(19.0));
+        assert_eq!(tree.evaluate("normalize-space(//name)"), XPathValue::String("x y".to_string()));
+        assert_eq!(tree.evaluate("concat(//item/@id, '-', 1 + 1)"), XPathValue::String("a-2".to_string()));
+        assert_eq!(tree.evaluate("//item = '3' and //item/@id = 'b'"), XPathValue::Boolean(true));
+        assert_eq!(tree.evaluate("//item > 4 or not(//b)"), XPathValue::Boolean(true));
+        assert_eq!(tree.evaluate("//item = 7"), XPathValue::Boolean(false));
+        assert_eq!(tree.evaluate("//err = false()"), XPathValue::Boolean(true));
+        assert_eq!(tree.evaluate_at("string(@id)", 2), XPathValue::String("b".to_string()));
+        assert_eq!(tree.evaluate_at("number(.) * 2", 1), XPathValue::Number(6.0));
+        assert!(matches!(tree.evaluate("number('x')"), XPathValue::Number(x) if x.is_nan()));
+        assert_eq!(tree.evaluate(r"matches(/cfg/@version, '^\d+\.\d+$')"), XPathValue::Boolean(true));
+        assert_eq!(tree.evaluate("substring-after(/cfg/@version, '.')"), XPathValue::String("2".to_string()));
+        assert_eq!(tree.evaluate("string-length(translate(//name, ' ', ''))"), XPathValue::Number(2.0));
+        assert!(tree.try_evaluate("substring('abc')").is_err());
+        assert_eq!(tree.evaluate("sum(//item/text())"), XPathValue::Number(7.0));
+        assert_eq!(tree.evaluate("count(//text())"), XPathValue::Number(4.0));
+        assert!(matches!(tree.try_evaluate("count(//item"), Err(Error::XPath { .. })));
+    }
+}
@@ -0,0 +1,74 @@
+use super::etree::ETree;
+
+/// What the parser does after a `ParseHandler` callback
+///
+/// - `Continue`: keep parsing normally
+/// - `Skip`: leave out the reported node, for `start_element` its whole subtree is skipped without further callbacks
+/// - `Abort`: stop parsing, `ETree::parse_with_handler` returns an error at the current position
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Control {
+    #[default]
+    Continue,
+    Skip,
+    Abort,
+}
+
+/// Callbacks receiving events while `ETree::parse_with_handler` builds the tree
+///
+/// Element callbacks get the tree built so far and the position of the element, so the name and attributes
+/// can be inspected with `tree.node(pos)`. All methods continue by default.
+///
+/// ```
+/// use etree::{Control, ETree, ParseHandler, ParseOptions};
+///
+/// struct SkipScripts(usize);
+///
+/// impl ParseHandler for SkipScripts {
+///     fn start_element(&mut self, tree: &ETree, pos: usize) -> Control {
+///         self.0 += 1;
+///         match tree.node(pos).map(|x| x.get_name()).as_deref() {
+///             Some("script") => Control::Skip,
+///             _ => Control::Continue,
+///         }
+///     }
+/// }
+///
+/// let mut handler = SkipScripts(0);
+/// let tree = ETree::parse_with_handler(
+///     "<html><script><a/></script><p/></html>",
+///     &ParseOptions::default(),
+///     &mut handler,
+/// )
+/// .unwrap();
+/// assert_eq!(handler.0, 3);
+/// assert_eq!(tree.descendant(tree.root()).len(), 1);
+/// ```
+pub trait ParseHandler {
+    /// element at `pos` was opened, its attributes are read but children are not
+    fn start_element(&mut self, _tree: &ETree, _pos: usize) -> Control {
+        Control::Continue
+    }
+    /// element at `pos` was closed, `Skip` is the same as `Continue`
+    fn end_element(&mut self, _tree: &ETree, _pos: usize) -> Control {
+        Control::Continue
+    }
+    /// text or CDATA content was read, entities are already expanded
+    fn text(&mut self, _text: &str) -> Control {
+        Control::Continue
+    }
+    /// comment was read
+    fn comment(&mut self, _text: &str) -> Control {
+        Control::Continue
+    }
+}
+
+/// call `f` with the handler if there is one
+pub(crate) fn notify<F: FnOnce(&mut dyn ParseHandler) -> Control>(
+    handler: &mut Option<&mut dyn ParseHandler>,
+    f: F,
+) -> Control {
+    match handler {
+        Some(handler) => f(&mut **handler),
+        None => Control::Continue,
+    }
+}
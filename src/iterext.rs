@@ -0,0 +1,112 @@
+use super::etree::ETree;
+
+#[derive(Debug, Clone)]
+enum NodeTest {
+    Tag(String),
+    Namespace(String),
+    Attr(String),
+}
+
+/// Iterator adaptor which keeps positions of nodes passing a test
+#[derive(Debug, Clone)]
+pub struct NodeFilter<'a, I> {
+    iter: I,
+    tree: &'a ETree,
+    test: NodeTest,
+}
+
+impl<'a, I: Iterator<Item = usize>> Iterator for NodeFilter<'a, I> {
+    type Item = usize;
+    fn next(&mut self) -> Option<Self::Item> {
+        for pos in self.iter.by_ref() {
+            let node = match self.tree.node(pos) {
+                Some(node) => node,
+                None => continue,
+            };
+            let matched = match &self.test {
+                NodeTest::Tag(tag) => &node.get_name() == tag || &node.get_tag() == tag,
+                NodeTest::Namespace(ns) => &node.get_namespace() == ns,
+                NodeTest::Attr(key) => node.get_attr(key).is_some(),
+            };
+            if matched {
+                return Some(pos);
+            }
+        }
+        None
+    }
+}
+
+/// Iterator adaptor which maps positions of nodes to their text
+#[derive(Debug, Clone)]
+pub struct NodeTexts<'a, I> {
+    iter: I,
+    tree: &'a ETree,
+}
+
+impl<'a, I: Iterator<Item = usize>> Iterator for NodeTexts<'a, I> {
+    type Item = String;
+    fn next(&mut self) -> Option<Self::Item> {
+        let tree = self.tree;
+        self.iter
+            .find_map(|pos| tree.node(pos))
+            .map(|node| node.get_text().unwrap_or_default())
+    }
+}
+
+/// Adaptors for iterators of node positions
+///
+/// ```
+/// use etree::{ETree, TreeIterExt};
+///
+/// let tree = ETree::parse_str(r#"<root><item id="1">a</item><other id="2"/><item>b</item></root>"#).unwrap();
+/// let texts: Vec<String> = tree.find_iter("//*").with_tag(&tree, "item").with_attr(&tree, "id").texts(&tree).collect();
+/// assert_eq!(texts, vec!["a".to_string()]);
+/// ```
+pub trait TreeIterExt: Iterator<Item = usize> + Sized {
+    /// keep nodes whose name (`prefix:localname`) or tag (`{namespace}localname`) is `tag`
+    fn with_tag<'a>(self, tree: &'a ETree, tag: &str) -> NodeFilter<'a, Self> {
+        NodeFilter {
+            iter: self,
+            tree,
+            test: NodeTest::Tag(tag.to_string()),
+        }
+    }
+    /// keep nodes in namespace `uri`
+    fn in_namespace<'a>(self, tree: &'a ETree, uri: &str) -> NodeFilter<'a, Self> {
+        NodeFilter {
+            iter: self,
+            tree,
+            test: NodeTest::Namespace(uri.to_string()),
+        }
+    }
+    /// keep nodes with attribute `key`
+    fn with_attr<'a>(self, tree: &'a ETree, key: &str) -> NodeFilter<'a, Self> {
+        NodeFilter {
+            iter: self,
+            tree,
+            test: NodeTest::Attr(key.to_string()),
+        }
+    }
+    /// map nodes to their text
+    fn texts(self, tree: &ETree) -> NodeTexts<'_, Self> {
+        NodeTexts { iter: self, tree }
+    }
+}
+
+impl<I: Iterator<Item = usize>> TreeIterExt for I {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_tree_iter_ext() {
+        let tree = ETree::parse_str(r#"<root xmlns:x="urn:x"><x:item id="1">a</x:item><item>b</item><x:other/></root>"#).unwrap();
+        let found: Vec<usize> = tree.children(0).into_iter().in_namespace(&tree, "urn:x").collect();
+        assert_eq!(found, vec![1, 3]);
+        let found: Vec<usize> = tree.children(0).into_iter().with_tag(&tree, "{urn:x}item").collect();
+        assert_eq!(found, vec![1]);
+        let texts: Vec<String> = tree.children(0).into_iter().with_tag(&tree, "item").texts(&tree).collect();
+        assert_eq!(texts, vec!["b".to_string()]);
+        assert_eq!(tree.children(0).into_iter().with_attr(&tree, "id").count(), 1);
+    }
+}
@@ -2,9 +2,44 @@
 //!
 //! `etree` is a DOM library for XML files.
 
+mod builder;
+mod canonical;
+mod css;
+mod dtd;
+mod error;
 mod etree;
 mod etreenode;
+mod etreeref;
+mod evaluate;
+mod handler;
+mod iterext;
+mod namespace;
+mod options;
+mod schema;
+mod stream;
+mod view;
+mod warning;
+mod wellformed;
+mod writer;
 mod xpath;
 
-pub use self::etree::{ETree, WriteError, XPathIterator};
-pub use self::etreenode::ETreeNode;
+pub use self::builder::{Documents, TreeBuilder};
+pub use self::dtd::{AttlistDecl, AttrDefault, Doctype, EntityDecl, NotationDecl};
+pub use self::error::{Error, ParseError, WriteError, XPathError};
+pub use self::etree::{ETree, XPathIterator};
+pub use self::etreenode::{AttrEntry, ETreeNode, NodeKind, XSI_NAMESPACE};
+pub use self::etreeref::{ETreeNodeRef, ETreeRef};
+pub use self::evaluate::XPathValue;
+pub use self::handler::{Control, ParseHandler};
+pub use self::iterext::{NodeFilter, NodeTexts, TreeIterExt};
+pub use self::namespace::{NamespaceError, NamespaceErrorKind};
+pub use self::options::{
+    AttrNormalization, AttrOrder, AttrStyle, CompareOptions, IndentOptions, Newline, ParseOptions, PrettyOptions,
+    QuoteChar,
+};
+pub use self::schema::{ElementRule, Occurs, Schema, ValidationError, ValidationErrorKind};
+pub use self::stream::StreamMatches;
+pub use self::view::SubtreeView;
+pub use self::warning::{ParseWarning, ParseWarningKind};
+pub use self::wellformed::{WellFormednessError, WellFormednessErrorKind};
+pub use self::writer::{EscapePolicy, XmlWriter};
@@ -0,0 +1,227 @@
+use super::etree::ETree;
+use super::etreenode::XML_NAMESPACE;
+use std::collections::HashMap;
+
+/// namespace declarations (prefix, namespace) to add to start tags, keyed by position
+pub(crate) type Declarations = HashMap<usize, Vec<(String, String)>>;
+
+/// Reason of a namespace error
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NamespaceErrorKind {
+    /// prefix of the element name is not declared
+    UnboundElementPrefix(String),
+    /// prefix of the attribute name is not declared (prefix, attribute name)
+    UnboundAttrPrefix(String, String),
+    /// prefix of the element name is bound to another namespace than the node's namespace (prefix, declared, node)
+    MismatchedNamespace(String, String, String),
+}
+
+/// Namespace error located at the node of position `pos`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NamespaceError {
+    pub pos: usize,
+    pub kind: NamespaceErrorKind,
+}
+
+impl std::fmt::Display for NamespaceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "[{}]: ", self.pos)?;
+        match &self.kind {
+            NamespaceErrorKind::UnboundElementPrefix(prefix) => write!(f, "element prefix \"{}\" is not declared", prefix),
+            NamespaceErrorKind::UnboundAttrPrefix(prefix, attr) => {
+                write!(f, "prefix \"{}\" of attribute \"{}\" is not declared", prefix, attr)
+            }
+            NamespaceErrorKind::MismatchedNamespace(prefix, declared, node) => write!(
+                f,
+                "prefix \"{}\" is bound to \"{}\" but the node is in \"{}\"",
+                prefix, declared, node
+            ),
+        }
+    }
+}
+
+impl std::error::Error for NamespaceError {}
+
+impl ETree {
+    #[allow(dead_code)]
+    /// check that every prefix used by elements and attributes has an in-scope declaration
+    pub fn check_namespaces(&self) -> Result<(), Vec<NamespaceError>> {
+        let (errors, _) = self.walk_namespaces(false);
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+    /// get declarations to add to start tags so that every prefix is bound when writing, keyed by position
+    ///
+    /// A prefix without declaration is declared on the node using it if the namespace of the node or attribute is
+    /// known, otherwise it is left as it is, or is an error if `strict` is true.
+    pub(crate) fn missing_declarations(&self, strict: bool) -> Result<Declarations, Vec<NamespaceError>> {
+        let (errors, fixes) = self.walk_namespaces(true);
+        if !strict {
+            return Ok(fixes);
+        }
+        let errors: Vec<NamespaceError> = errors
+            .into_iter()
+            .filter(|x| !matches!(x.kind, NamespaceErrorKind::MismatchedNamespace(..)))
+            .collect();
+        if errors.is_empty() {
+            Ok(fixes)
+        } else {
+            Err(errors)
+        }
+    }
+    /// check prefixes in document order, unbound prefixes with known namespace are declared if `fix` is true
+    fn walk_namespaces(&self, fix: bool) -> (Vec<NamespaceError>, Declarations) {
+        let mut errors = Vec::new();
+        let mut fixes: Declarations = HashMap::new();
+        // (route of children, bindings declared by the node)
+        let mut scopes: Vec<(String, HashMap<String, String>)> = Vec::new();
+        let mut pos = 0;
+        while let Some(node) = self.node(pos) {
+            let route = node.get_route();
+            while scopes.last().map(|x| !route.starts_with(&x.0)).unwrap_or(false) {
+                scopes.pop();
+            }
+            if !node.is_element() {
+                pos += 1;
+                continue;
+            }
+            let mut bindings = HashMap::new();
+            for (key, value) in node.get_attr_iter() {
+                if key == "xmlns" {
+                    bindings.insert("".to_string(), value.clone());
+                } else if let Some(prefix) = key.strip_prefix("xmlns:") {
+                    bindings.insert(prefix.to_string(), value.clone());
+                }
+            }
+            let lookup = |bindings: &HashMap<String, String>, prefix: &str| -> Option<String> {
+                if prefix == "xml" {
+                    return Some(XML_NAMESPACE.to_string());
+                }
+                bindings
+                    .get(prefix)
+                    .or_else(|| scopes.iter().rev().find_map(|x| x.1.get(prefix)))
+                    .filter(|x| !x.is_empty())
+                    .cloned()
+            };
+            let mut declare = |bindings: &mut HashMap<String, String>, prefix: &str, ns: Option<String>| -> bool {
+                match ns.filter(|x| fix && !x.is_empty()) {
+                    Some(ns) => {
+                        bindings.insert(prefix.to_string(), ns.clone());
+                        fixes.entry(pos).or_default().push((prefix.to_string(), ns));
+                        true
+                    }
+                    None => false,
+                }
+            };
+            let prefix = node.get_namespace_abbrev();
+            if !prefix.is_empty() {
+                match lookup(&bindings, &prefix) {
+                    None if declare(&mut bindings, &prefix, Some(node.get_namespace())) => {}
+                    None => errors.push(NamespaceError {
+                        pos,
+                        kind: NamespaceErrorKind::UnboundElementPrefix(prefix.clone()),
+                    }),
+                    Some(ns) => {
+                        if !node.get_namespace().is_empty() && ns != node.get_namespace() {
+                            errors.push(NamespaceError {
+                                pos,
+                                kind: NamespaceErrorKind::MismatchedNamespace(prefix.clone(), ns, node.get_namespace()),
+                            });
+                        }
+                    }
+                }
+            }
+            for (key, _) in node.get_attr_iter() {
+                if let Some(i) = key.find(':') {
+                    let prefix = &key[..i];
+                    if prefix != "xmlns"
+                        && lookup(&bindings, prefix).is_none()
+                        && !declare(&mut bindings, prefix, node.get_attr_namespace(key))
+                    {
+                        errors.push(NamespaceError {
+                            pos,
+                            kind: NamespaceErrorKind::UnboundAttrPrefix(prefix.to_string(), key.clone()),
+                        });
+                    }
+                }
+            }
+            scopes.push((format!("{}{}#", route, node.get_idx()), bindings));
+            pos += 1;
+        }
+        (errors, fixes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ETreeNode, Error};
+    #[test]
+    fn test_check_namespaces() {
+        let mut tree = ETree::parse_str(r#"<a:root xmlns:a="urn:a"><a:x xml:lang="en"/><b/></a:root>"#).unwrap();
+        assert_eq!(tree.check_namespaces(), Ok(()));
+        let mut node = ETreeNode::new("y");
+        node.set_namespace_abbrev("c");
+        node.set_attr("d:attr", "1");
+        let pos = tree.append_child_node(2, node).unwrap();
+        let mut node = ETreeNode::new("z");
+        node.set_namespace_abbrev("a");
+        node.set_namespace("urn:other");
+        tree.append_child_node(0, node);
+        assert_eq!(
+            tree.check_namespaces(),
+            Err(vec![
+                NamespaceError {
+                    pos,
+                    kind: NamespaceErrorKind::UnboundElementPrefix("c".to_string()),
+                },
+                NamespaceError {
+                    pos,
+                    kind: NamespaceErrorKind::UnboundAttrPrefix("d".to_string(), "d:attr".to_string()),
+                },
+                NamespaceError {
+                    pos: pos + 1,
+                    kind: NamespaceErrorKind::MismatchedNamespace(
+                        "a".to_string(),
+                        "urn:a".to_string(),
+                        "urn:other".to_string()
+                    ),
+                },
+            ])
+        );
+    }
+    #[test]
+    fn test_write_unbound_prefix() {
+        let mut tree = ETree::parse_str(r#"<?xml version="1.0"?><root><a/></root>"#).unwrap();
+        let mut node = ETreeNode::new("x");
+        node.set_namespace_abbrev("p");
+        node.set_namespace("urn:p");
+        node.set_attr_ns("urn:q", "q:k", "1");
+        let pos = tree.append_child_node(1, node).unwrap();
+        let mut node = ETreeNode::new("y");
+        node.set_namespace_abbrev("p");
+        node.set_namespace("urn:p");
+        tree.append_child_node(pos, node).unwrap();
+        assert_eq!(
+            tree.to_xml_string().unwrap(),
+            r#"<?xml version="1.0"?><root><a><p:x xmlns:p="urn:p" xmlns:q="urn:q" q:k="1"><p:y/></p:x></a></root>"#
+        );
+        let mut node = ETreeNode::new("z");
+        node.set_namespace_abbrev("u");
+        tree.append_child_node(0, node).unwrap();
+        assert!(tree.to_xml_string().unwrap().ends_with("<u:z/></root>"));
+        tree.set_strict_namespaces(true);
+        match tree.to_xml_string() {
+            Err(Error::Namespace(errors)) => {
+                assert_eq!(errors.len(), 1);
+                assert_eq!(errors[0].kind, NamespaceErrorKind::UnboundElementPrefix("u".to_string()));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        let content = r#"<root><fb:like href="x"/></root>"#;
+        assert!(ETree::parse_str(content).unwrap().to_xml_string().unwrap().ends_with(content));
+    }
+}
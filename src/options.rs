@@ -0,0 +1,345 @@
+use super::writer::split_indent;
+
+/// Layout of attributes inside a start tag
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AttrStyle {
+    /// all attributes on the same line as the tag name
+    #[default]
+    Inline,
+    /// each attribute after the first on its own line, aligned under the first attribute
+    ///
+    /// ```xml
+    /// <bean id="xxx"
+    ///       class="xxx.xxx.xxx.Xxx"
+    ///       scope="prototype"/>
+    /// ```
+    Aligned,
+}
+
+/// Order of attributes inside a start tag when writing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AttrOrder {
+    /// the order of the source document, attributes added later follow
+    #[default]
+    Document,
+    /// sorted by name
+    Sorted,
+}
+
+/// Quote character around attribute values when writing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QuoteChar {
+    /// `key="value"`
+    #[default]
+    Double,
+    /// `key='value'`
+    Single,
+}
+
+/// Treatment of attribute values when parsing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AttrNormalization {
+    /// references are replaced, whitespace characters are kept as written
+    #[default]
+    Unescape,
+    /// attribute-value normalization of the XML specification
+    ///
+    /// Literal tabs, newlines and carriage returns become spaces (character references such as `&#10;` are kept),
+    /// values of attributes declared with a type other than CDATA in the DOCTYPE are trimmed and runs of spaces
+    /// collapsed to one.
+    Normalize,
+    /// values are kept exactly as written, references included, and `write()` outputs them unchanged
+    Raw,
+}
+
+/// Line break written by `ETree::pretty_with`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Newline {
+    /// "\n"
+    #[default]
+    Lf,
+    /// "\r\n"
+    CrLf,
+    /// "\r"
+    Cr,
+}
+
+impl Newline {
+    #[allow(dead_code)]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Newline::Lf => "\n",
+            Newline::CrLf => "\r\n",
+            Newline::Cr => "\r",
+        }
+    }
+}
+
+/// Pretty-printing options
+///
+/// Options used by `ETree::pretty_with`.
+///
+/// - `indent`: indent unit repeated for each level, such as two spaces or a tab
+/// - `newline`: line break between nodes
+/// - `attr_style`: layout of attributes in start tags
+/// - `max_width`: start tags longer than this number of characters, counting their indent, get one attribute per
+///   line aligned under the first attribute
+/// - `attrs_per_line`: start tags with at least this number of attributes get one attribute per line
+/// - `indent_misc`: indent comments and processing instructions like elements, otherwise they start at column 0
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrettyOptions {
+    pub indent: String,
+    pub newline: Newline,
+    pub attr_style: AttrStyle,
+    pub max_width: Option<usize>,
+    pub attrs_per_line: Option<usize>,
+    pub indent_misc: bool,
+}
+
+/// Indent options
+///
+/// Kept for compatibility, see `PrettyOptions`.
+pub type IndentOptions = PrettyOptions;
+
+impl PrettyOptions {
+    #[allow(dead_code)]
+    /// `indent` is a newline followed by the indent unit, the same as the argument of `ETree::pretty`
+    pub fn new(indent: &str) -> PrettyOptions {
+        let (crlf, indent) = split_indent(indent);
+        let newline = match crlf.as_str() {
+            "\r\n" => Newline::CrLf,
+            "\r" => Newline::Cr,
+            _ => Newline::Lf,
+        };
+        PrettyOptions {
+            indent,
+            newline,
+            attr_style: AttrStyle::Inline,
+            max_width: None,
+            attrs_per_line: None,
+            indent_misc: true,
+        }
+    }
+    #[allow(dead_code)]
+    pub fn with_indent(mut self, indent: &str) -> PrettyOptions {
+        self.indent = indent.to_string();
+        self
+    }
+    #[allow(dead_code)]
+    pub fn with_newline(mut self, newline: Newline) -> PrettyOptions {
+        self.newline = newline;
+        self
+    }
+    #[allow(dead_code)]
+    pub fn with_attr_style(mut self, attr_style: AttrStyle) -> PrettyOptions {
+        self.attr_style = attr_style;
+        self
+    }
+    #[allow(dead_code)]
+    pub fn with_max_width(mut self, max_width: usize) -> PrettyOptions {
+        self.max_width = Some(max_width);
+        self
+    }
+    #[allow(dead_code)]
+    pub fn with_attrs_per_line(mut self, attrs_per_line: usize) -> PrettyOptions {
+        self.attrs_per_line = Some(attrs_per_line);
+        self
+    }
+    #[allow(dead_code)]
+    pub fn with_indent_misc(mut self, indent_misc: bool) -> PrettyOptions {
+        self.indent_misc = indent_misc;
+        self
+    }
+}
+
+impl Default for PrettyOptions {
+    fn default() -> Self {
+        PrettyOptions::new("\n  ")
+    }
+}
+
+/// Parse options
+///
+/// Options used by `ETree::parse_with`.
+///
+/// - `expand_entities`: replace references to entities declared in the internal subset of DOCTYPE by their value,
+///   otherwise references such as `&foo;` are kept as text and `write()` outputs them unescaped
+/// - `max_entity_depth`: maximum nesting of entity references inside entity values
+/// - `max_entity_expansion`: maximum total bytes of entity values expanded in a document
+/// - `attr_normalization`: how attribute values are unescaped and normalized, see `AttrNormalization`
+/// - `preserve_references`: keep character and entity references such as `&amp;` or `&#x2014;` verbatim in texts
+///   and attribute values, `write()` then outputs them exactly as authored
+/// - `round_trip`: keep the XML declaration, start and end tags and comments as written, so `write()` reproduces
+///   the source byte for byte as long as nodes are not changed; implies `preserve_references` and
+///   `AttrNormalization::Raw`, and texts are escaped by `EscapePolicy::minimal()`
+/// - `shared_text_threshold`: store texts of elements with at least this many bytes as shared strings,
+///   so cloning nodes or trees doesn't copy them (see `ETreeNode::get_text_shared`), `None` to never share
+/// - `trim_text`: remove leading and trailing whitespaces of texts and tails
+/// - `ignore_comments`: don't create nodes for comments
+/// - `ignore_processing_instructions`: don't create nodes for processing instructions
+/// - `coalesce_cdata`: merge CDATA sections into the surrounding text instead of creating nodes
+/// - `max_depth`: maximum nesting depth of elements, `None` for no limit
+/// - `max_attributes`: maximum count of attributes of an element, `None` for no limit
+/// - `max_nodes`: maximum count of nodes in the tree, `None` for no limit
+///
+/// Exceeding `max_depth`, `max_attributes` or `max_nodes` aborts parsing, even by `ETree::parse_recover`.
+///
+/// External entities (`<!ENTITY foo SYSTEM "file">`) are never fetched, a reference to them is an error
+/// unless `expand_entities` is false. Together with the limits above this makes parsing untrusted documents safe
+/// against XXE and "billion laughs" attacks by default.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseOptions {
+    pub expand_entities: bool,
+    pub max_entity_depth: usize,
+    pub max_entity_expansion: usize,
+    pub attr_normalization: AttrNormalization,
+    pub preserve_references: bool,
+    pub round_trip: bool,
+    pub shared_text_threshold: Option<usize>,
+    pub trim_text: bool,
+    pub ignore_comments: bool,
+    pub ignore_processing_instructions: bool,
+    pub coalesce_cdata: bool,
+    pub max_depth: Option<usize>,
+    pub max_attributes: Option<usize>,
+    pub max_nodes: Option<usize>,
+}
+
+impl ParseOptions {
+    #[allow(dead_code)]
+    pub fn new() -> ParseOptions {
+        ParseOptions {
+            expand_entities: true,
+            max_entity_depth: 16,
+            max_entity_expansion: 1 << 20,
+            attr_normalization: AttrNormalization::Unescape,
+            preserve_references: false,
+            round_trip: false,
+            shared_text_threshold: None,
+            trim_text: false,
+            ignore_comments: false,
+            ignore_processing_instructions: false,
+            coalesce_cdata: false,
+            max_depth: None,
+            max_attributes: None,
+            max_nodes: None,
+        }
+    }
+    #[allow(dead_code)]
+    pub fn with_expand_entities(mut self, expand_entities: bool) -> ParseOptions {
+        self.expand_entities = expand_entities;
+        self
+    }
+    #[allow(dead_code)]
+    pub fn with_max_entity_depth(mut self, max_entity_depth: usize) -> ParseOptions {
+        self.max_entity_depth = max_entity_depth;
+        self
+    }
+    #[allow(dead_code)]
+    pub fn with_max_entity_expansion(mut self, max_entity_expansion: usize) -> ParseOptions {
+        self.max_entity_expansion = max_entity_expansion;
+        self
+    }
+    #[allow(dead_code)]
+    pub fn with_attr_normalization(mut self, attr_normalization: AttrNormalization) -> ParseOptions {
+        self.attr_normalization = attr_normalization;
+        self
+    }
+    #[allow(dead_code)]
+    pub fn with_preserve_references(mut self, preserve_references: bool) -> ParseOptions {
+        self.preserve_references = preserve_references;
+        self
+    }
+    #[allow(dead_code)]
+    pub fn with_round_trip(mut self, round_trip: bool) -> ParseOptions {
+        self.round_trip = round_trip;
+        self
+    }
+    #[allow(dead_code)]
+    pub fn with_shared_text_threshold(mut self, shared_text_threshold: Option<usize>) -> ParseOptions {
+        self.shared_text_threshold = shared_text_threshold;
+        self
+    }
+    #[allow(dead_code)]
+    pub fn with_trim_text(mut self, trim_text: bool) -> ParseOptions {
+        self.trim_text = trim_text;
+        self
+    }
+    #[allow(dead_code)]
+    pub fn with_ignore_comments(mut self, ignore_comments: bool) -> ParseOptions {
+        self.ignore_comments = ignore_comments;
+        self
+    }
+    #[allow(dead_code)]
+    pub fn with_ignore_processing_instructions(mut self, ignore_processing_instructions: bool) -> ParseOptions {
+        self.ignore_processing_instructions = ignore_processing_instructions;
+        self
+    }
+    #[allow(dead_code)]
+    pub fn with_coalesce_cdata(mut self, coalesce_cdata: bool) -> ParseOptions {
+        self.coalesce_cdata = coalesce_cdata;
+        self
+    }
+    #[allow(dead_code)]
+    pub fn with_max_depth(mut self, max_depth: Option<usize>) -> ParseOptions {
+        self.max_depth = max_depth;
+        self
+    }
+    #[allow(dead_code)]
+    pub fn with_max_attributes(mut self, max_attributes: Option<usize>) -> ParseOptions {
+        self.max_attributes = max_attributes;
+        self
+    }
+    #[allow(dead_code)]
+    pub fn with_max_nodes(mut self, max_nodes: Option<usize>) -> ParseOptions {
+        self.max_nodes = max_nodes;
+        self
+    }
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        ParseOptions::new()
+    }
+}
+
+/// Compare options
+///
+/// Options used by `ETreeNode::content_eq` and `ETree::deep_equal_with`.
+///
+/// - `ignore_attr_order`: attributes are equal in any order, otherwise they must be in the same order
+/// - `ignore_whitespace`: leading and trailing whitespaces of texts and tails are ignored, so are texts and tails
+///   which are only whitespace such as indentation
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct CompareOptions {
+    pub ignore_attr_order: bool,
+    pub ignore_whitespace: bool,
+}
+
+impl CompareOptions {
+    #[allow(dead_code)]
+    pub fn new() -> CompareOptions {
+        CompareOptions {
+            ignore_attr_order: false,
+            ignore_whitespace: false,
+        }
+    }
+    #[allow(dead_code)]
+    pub fn with_ignore_attr_order(mut self, ignore_attr_order: bool) -> CompareOptions {
+        self.ignore_attr_order = ignore_attr_order;
+        self
+    }
+    #[allow(dead_code)]
+    pub fn with_ignore_whitespace(mut self, ignore_whitespace: bool) -> CompareOptions {
+        self.ignore_whitespace = ignore_whitespace;
+        self
+    }
+    /// text as compared
+    pub(crate) fn significant<'a>(&self, text: &'a str) -> &'a str {
+        if self.ignore_whitespace {
+            text.trim()
+        } else {
+            text
+        }
+    }
+}
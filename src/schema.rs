@@ -0,0 +1,256 @@
+use super::etree::ETree;
+use regex::Regex;
+use std::collections::HashMap;
+
+/// Occurrence of a child element
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Occurs {
+    /// exactly once
+    One,
+    /// zero or one time (`?`)
+    Optional,
+    /// one or more times (`+`)
+    OneOrMore,
+    /// any times (`*`)
+    ZeroOrMore,
+}
+
+impl Occurs {
+    fn check(&self, count: usize) -> Result<(), (usize, Option<usize>)> {
+        let (min, max) = match self {
+            Occurs::One => (1, Some(1)),
+            Occurs::Optional => (0, Some(1)),
+            Occurs::OneOrMore => (1, None),
+            Occurs::ZeroOrMore => (0, None),
+        };
+        if count < min || max.map(|m| count > m).unwrap_or(false) {
+            Err((min, max))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Structural rule for one element
+///
+/// Elements are matched by name (`prefix:localname`), the same as `ETree::children_by_name`.
+/// Child elements which are not declared by `child` are reported as unexpected,
+/// and attributes which are not declared are allowed.
+#[derive(Debug, Clone)]
+pub struct ElementRule {
+    name: String,
+    children: Vec<(String, Occurs)>,
+    attrs: Vec<(String, bool)>,
+    text: Option<Regex>,
+}
+
+impl ElementRule {
+    #[allow(dead_code)]
+    pub fn new(name: &str) -> ElementRule {
+        ElementRule {
+            name: name.to_string(),
+            children: Vec::new(),
+            attrs: Vec::new(),
+            text: None,
+        }
+    }
+    #[allow(dead_code)]
+    /// declare a child element
+    pub fn child(mut self, name: &str, occurs: Occurs) -> ElementRule {
+        self.children.push((name.to_string(), occurs));
+        self
+    }
+    #[allow(dead_code)]
+    /// declare a required attribute
+    pub fn required_attr(mut self, name: &str) -> ElementRule {
+        self.attrs.push((name.to_string(), true));
+        self
+    }
+    #[allow(dead_code)]
+    /// declare an optional attribute
+    pub fn optional_attr(mut self, name: &str) -> ElementRule {
+        self.attrs.push((name.to_string(), false));
+        self
+    }
+    #[allow(dead_code)]
+    /// text of the element must match `pattern`
+    ///
+    /// # Panics
+    /// Panics if `pattern` is not a valid regular expression
+    pub fn text_matches(mut self, pattern: &str) -> ElementRule {
+        self.text = Some(Regex::new(pattern).unwrap());
+        self
+    }
+}
+
+/// Structural schema
+///
+/// ```
+/// use etree::{ETree, ElementRule, Occurs, Schema};
+///
+/// let schema = Schema::new()
+///     .root("config")
+///     .element(ElementRule::new("config").child("server", Occurs::OneOrMore).child("log", Occurs::Optional))
+///     .element(ElementRule::new("server").required_attr("host").text_matches(r"^\d+$"));
+/// let tree = ETree::parse_str(r#"<config><server host="a">80</server></config>"#).unwrap();
+/// assert!(tree.validate_with(&schema).is_ok());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Schema {
+    root: Option<String>,
+    elements: HashMap<String, ElementRule>,
+}
+
+impl Schema {
+    #[allow(dead_code)]
+    pub fn new() -> Schema {
+        Schema::default()
+    }
+    #[allow(dead_code)]
+    /// name of the root element
+    pub fn root(mut self, name: &str) -> Schema {
+        self.root = Some(name.to_string());
+        self
+    }
+    #[allow(dead_code)]
+    /// add rule for an element, elements without rule are not checked
+    pub fn element(mut self, rule: ElementRule) -> Schema {
+        self.elements.insert(rule.name.clone(), rule);
+        self
+    }
+}
+
+/// Reason of a validation error
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationErrorKind {
+    /// root element has another name
+    UnexpectedRoot(String),
+    /// child element is not declared
+    UnexpectedChild(String),
+    /// child element occurs too few or too many times (name, count)
+    ChildCount(String, usize),
+    /// required attribute is missing
+    MissingAttr(String),
+    /// text doesn't match the pattern
+    TextMismatch(String),
+}
+
+/// Validation error located at the node of position `pos`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationError {
+    pub pos: usize,
+    pub name: String,
+    pub kind: ValidationErrorKind,
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "<{}>[{}]: ", self.name, self.pos)?;
+        match &self.kind {
+            ValidationErrorKind::UnexpectedRoot(expected) => write!(f, "root element should be <{}>", expected),
+            ValidationErrorKind::UnexpectedChild(child) => write!(f, "unexpected child <{}>", child),
+            ValidationErrorKind::ChildCount(child, count) => write!(f, "child <{}> occurs {} times", child, count),
+            ValidationErrorKind::MissingAttr(attr) => write!(f, "missing attribute \"{}\"", attr),
+            ValidationErrorKind::TextMismatch(text) => write!(f, "text {:?} doesn't match", text),
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+impl ETree {
+    #[allow(dead_code)]
+    /// validate the tree structure against `schema` and return all errors
+    pub fn validate_with(&self, schema: &Schema) -> Result<(), Vec<ValidationError>> {
+        let mut errors = Vec::new();
+        let root = self.root();
+        let node = match self.node(root) {
+            Some(node) => node,
+            None => return Ok(()),
+        };
+        if let Some(expected) = &schema.root {
+            if &node.get_name() != expected {
+                errors.push(ValidationError {
+                    pos: root,
+                    name: node.get_name(),
+                    kind: ValidationErrorKind::UnexpectedRoot(expected.clone()),
+                });
+            }
+        }
+        for pos in std::iter::once(root).chain(self.descendant(root)) {
+            let node = self.node(pos).unwrap();
+            let rule = match schema.elements.get(&node.get_name()) {
+                Some(rule) => rule,
+                None => continue,
+            };
+            let mut error = |kind| {
+                errors.push(ValidationError {
+                    pos,
+                    name: node.get_name(),
+                    kind,
+                })
+            };
+            for (attr, required) in rule.attrs.iter() {
+                if *required && node.get_attr(attr).is_none() {
+                    error(ValidationErrorKind::MissingAttr(attr.clone()));
+                }
+            }
+            if let Some(pattern) = &rule.text {
+                let text = node.get_text().unwrap_or_default();
+                if !pattern.is_match(&text) {
+                    error(ValidationErrorKind::TextMismatch(text));
+                }
+            }
+            let mut counts: HashMap<String, usize> = HashMap::new();
+            for child in self.children(pos) {
+                let child = self.node(child).unwrap();
+                if !child.is_element() {
+                    continue;
+                }
+                let name = child.get_name();
+                if rule.children.iter().any(|x| x.0 == name) {
+                    *counts.entry(name).or_insert(0) += 1;
+                } else {
+                    error(ValidationErrorKind::UnexpectedChild(name));
+                }
+            }
+            for (child, occurs) in rule.children.iter() {
+                let count = counts.get(child).copied().unwrap_or(0);
+                if occurs.check(count).is_err() {
+                    error(ValidationErrorKind::ChildCount(child.clone(), count));
+                }
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_validate_with() {
+        let schema = Schema::new()
+            .root("config")
+            .element(ElementRule::new("config").child("server", Occurs::OneOrMore).child("log", Occurs::Optional))
+            .element(ElementRule::new("server").required_attr("host").text_matches(r"^\d+$"));
+        let tree = ETree::parse_str(r#"<config><server host="a">80</server><log/><!-- x --></config>"#).unwrap();
+        assert_eq!(tree.validate_with(&schema), Ok(()));
+        let tree = ETree::parse_str(r#"<config><server>http</server><log/><log/><misc/></config>"#).unwrap();
+        let errors = tree.validate_with(&schema).unwrap_err();
+        assert_eq!(
+            errors.iter().map(|x| (x.pos, x.kind.clone())).collect::<Vec<_>>(),
+            vec![
+                (0, ValidationErrorKind::UnexpectedChild("misc".to_string())),
+                (0, ValidationErrorKind::ChildCount("log".to_string(), 2)),
+                (1, ValidationErrorKind::MissingAttr("host".to_string())),
+                (1, ValidationErrorKind::TextMismatch("http".to_string())),
+            ]
+        );
+        assert_eq!(errors[2].to_string(), "<server>[1]: missing attribute \"host\"");
+    }
+}
@@ -0,0 +1,458 @@
+use super::builder::{markup_end, TreeBuilder};
+use super::error::{Error, ParseError, XPathError};
+use super::etree::{name_function, node_test_matches, ETree, TagReader};
+use super::etreenode::ETreeNode;
+use super::xpath::{self, Predictor, XPathSegment};
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+use std::rc::Rc;
+
+/// element wrapping a match, it declares the namespaces in scope of the ancestors
+const WRAPPER: &str = "etree-stream";
+
+/// namespaces in scope by prefix, "" for the default namespace
+type Scope = BTreeMap<String, String>;
+
+/// Iterator over subtrees matching a XPath while a stream is read, created by `ETree::stream_find`
+///
+/// Only the open elements and the current match are kept, so documents larger than memory can be searched.
+/// The path is restricted to what is known when a start tag is read:
+///
+/// - steps with `/`, `//`, `child::` or `descendant::` and node tests of elements (names, `*`, `prefix:*`,
+///   `*:name` and `node()`), a relative path is searched like `//`
+/// - predicates on attributes and names (`local-name()`, `namespace-uri()`, `name()`), positions like `[2]` or
+///   `[position() < 3]` only after `/`; texts, children and `last()` are not known yet
+///
+/// A match nested in another match is part of its subtree and is not yielded separately. Each match is parsed with
+/// the XML declaration and DOCTYPE of the stream, the namespaces declared by its ancestors are added to its root
+/// element. Positions of errors and nodes are relative to the start of the match. The stream must use an encoding
+/// compatible with ASCII, such as UTF-8 or ISO-8859-1.
+///
+/// ```
+/// use etree::ETree;
+///
+/// let stream = "<export><record id='1'/><archive><record id='2'/></archive><record id='3'><v>c</v></record></export>";
+/// let records: Vec<ETree> = ETree::stream_find(stream.as_bytes(), "/export/record[@id != '1']")
+///     .unwrap()
+///     .collect::<Result<_, _>>()
+///     .unwrap();
+/// assert_eq!(records.len(), 1);
+/// assert_eq!(records[0].node(0).unwrap().get_attr("id").as_deref(), Some("3"));
+/// assert_eq!(records[0].find("/record/v"), Some(1));
+/// ```
+pub struct StreamMatches<R> {
+    reader: R,
+    paths: Vec<Vec<XPathSegment>>,
+    namespaces: HashMap<String, String>,
+    buf: Vec<u8>,
+    eof: bool,
+    // length of scanned part of `buf`
+    scanned: usize,
+    declaration: Vec<u8>,
+    doctype: Vec<u8>,
+    // reader of start tags, created with the prolog at the first start tag
+    tags: Option<TagReader>,
+    // open elements which may contain a match, the first frame is the document
+    stack: Vec<Frame>,
+    // depth in a subtree which cannot contain a match
+    skip: usize,
+    // start of the match in `buf`, depth in the match and the namespaces in scope
+    capture: Option<(usize, usize, Rc<Scope>)>,
+}
+
+/// open element with the steps its children are tested against
+struct Frame {
+    // (path, step) to test
+    states: Vec<(usize, usize)>,
+    // number of children which passed the node test of a step, for positions
+    counts: HashMap<(usize, usize), usize>,
+    declarations: Rc<Scope>,
+}
+
+impl<R: Read> StreamMatches<R> {
+    pub(crate) fn new(reader: R, path: &str) -> Result<StreamMatches<R>, XPathError> {
+        let error = |position, end, message| Error::XPath {
+            expr: path.to_string(),
+            position,
+            end,
+            message,
+        };
+        let mut paths = xpath::parse(path).map_err(|(position, end, message)| error(position, end, message))?;
+        for segments in paths.iter_mut() {
+            if segments[0].separator.is_empty() {
+                // the context is the document
+                segments[0].separator = if segments[0].axis.is_empty() { "//" } else { "/" }.to_string();
+            }
+            for segment in segments.iter() {
+                if let Some(message) = unsupported(segment) {
+                    let position = path.find(segment.node.as_str()).unwrap_or(0);
+                    return Err(error(position, position + segment.node.len(), message));
+                }
+            }
+        }
+        let states = (0..paths.len()).map(|x| (x, 0)).collect();
+        Ok(StreamMatches {
+            reader,
+            paths,
+            namespaces: HashMap::new(),
+            buf: Vec::new(),
+            eof: false,
+            scanned: 0,
+            declaration: Vec::new(),
+            doctype: Vec::new(),
+            tags: None,
+            stack: vec![Frame {
+                states,
+                counts: HashMap::new(),
+                declarations: Rc::new(BTreeMap::new()),
+            }],
+            skip: 0,
+            capture: None,
+        })
+    }
+    #[allow(dead_code)]
+    /// bind `prefix` to namespace `uri` for node tests and attributes of the path
+    pub fn with_namespace(mut self, prefix: &str, uri: &str) -> Self {
+        self.namespaces.insert(prefix.to_string(), uri.to_string());
+        self
+    }
+    /// drop the bytes which are done with and read the next chunk
+    fn fill(&mut self) -> std::io::Result<()> {
+        let done = self.capture.as_ref().map(|x| x.0).unwrap_or(self.scanned);
+        self.buf.drain(..done);
+        self.scanned -= done;
+        if let Some(capture) = self.capture.as_mut() {
+            capture.0 = 0;
+        }
+        let mut chunk = [0; 64 * 1024];
+        loop {
+            match self.reader.read(&mut chunk) {
+                Ok(0) => self.eof = true,
+                Ok(len) => self.buf.extend_from_slice(&chunk[..len]),
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+            return Ok(());
+        }
+    }
+    /// stop at an error
+    fn fail(&mut self, e: Error) -> Option<Result<ETree, Error>> {
+        self.eof = true;
+        self.buf.clear();
+        self.scanned = 0;
+        self.capture = None;
+        Some(Err(e))
+    }
+    /// handle the markup from `start` to `end` of `buf`, return the match it completes
+    fn markup(&mut self, start: usize, end: usize) -> Option<Result<ETree, Error>> {
+        let markup = &self.buf[start..end];
+        let closing = markup.starts_with(b"</");
+        let opening = !closing && !markup.starts_with(b"<!") && !markup.starts_with(b"<?");
+        let empty = markup.ends_with(b"/>");
+        let change = |depth: &mut usize| {
+            if closing {
+                *depth -= 1;
+            } else if opening && !empty {
+                *depth += 1;
+            }
+        };
+        if let Some((from, depth, _)) = self.capture.as_mut() {
+            change(depth);
+            if *depth > 0 {
+                return None;
+            }
+            let (from, declarations) = (*from, self.capture.take().unwrap().2);
+            return Some(self.parse_match(from, end, &declarations));
+        }
+        if self.skip > 0 {
+            change(&mut self.skip);
+            return None;
+        }
+        if closing {
+            if self.stack.len() > 1 {
+                self.stack.pop();
+            }
+            return None;
+        }
+        if !opening {
+            // the prolog is needed to decode and to expand entities
+            let prolog = self.stack.len() == 1;
+            if prolog && markup.starts_with(b"<?xml") && markup.get(5).is_some_and(u8::is_ascii_whitespace) {
+                self.declaration = markup.to_vec();
+            } else if prolog && markup.starts_with(b"<!DOCTYPE") {
+                self.doctype = markup.to_vec();
+            }
+            return None;
+        }
+        let declarations = self.stack.last().unwrap().declarations.clone();
+        let node = match self.read_tag(start, end, &declarations) {
+            Ok(node) => node,
+            Err(e) => return self.fail(e),
+        };
+        let mut states = Vec::new();
+        let mut matched = false;
+        let parent = self.stack.last_mut().unwrap();
+        for &(p, k) in parent.states.iter() {
+            let segment = &self.paths[p][k];
+            if segment.separator == "//" || segment.axis == "descendant" {
+                states.push((p, k));
+            }
+            if !node_test_matches(&node, &segment.node, &self.namespaces) {
+                continue;
+            }
+            let position = parent.counts.entry((p, k)).or_insert(0);
+            *position += 1;
+            if predicate_matches(&segment.condition, &node, *position, &self.namespaces) {
+                if k + 1 == self.paths[p].len() {
+                    matched = true;
+                } else {
+                    states.push((p, k + 1));
+                }
+            }
+        }
+        let declarations = scope(&node, declarations);
+        if matched && empty {
+            return Some(self.parse_match(start, end, &declarations));
+        } else if matched {
+            self.capture = Some((start, 1, declarations));
+        } else if empty {
+            return None;
+        } else if states.is_empty() {
+            self.skip = 1;
+        } else {
+            states.sort_unstable();
+            states.dedup();
+            self.stack.push(Frame {
+                states,
+                counts: HashMap::new(),
+                declarations,
+            });
+        }
+        None
+    }
+    /// parse `body` in the wrapper with the prolog of the stream, errors are located in `body`
+    fn parse(&self, body: &[u8], declarations: &Scope) -> Result<ETree, Error> {
+        let mut prefix = self.declaration.clone();
+        prefix.extend_from_slice(&self.doctype);
+        prefix.extend_from_slice(format!("<{}", WRAPPER).as_bytes());
+        for (prefix_name, uri) in declarations.iter() {
+            let uri = uri.replace('&', "&amp;").replace('<', "&lt;").replace('"', "&quot;");
+            match prefix_name.as_str() {
+                "" => prefix.extend_from_slice(format!(" xmlns=\"{}\"", uri).as_bytes()),
+                _ => prefix.extend_from_slice(format!(" xmlns:{}=\"{}\"", prefix_name, uri).as_bytes()),
+            }
+        }
+        prefix.extend_from_slice(b">\n");
+        let mut builder = TreeBuilder::new();
+        let result = builder
+            .feed(&prefix)
+            .and_then(|_| builder.feed(body))
+            .and_then(|_| builder.feed(format!("</{}>", WRAPPER).as_bytes()));
+        let mut e = match result.and_then(|_| builder.finish()) {
+            Ok(tree) => return Ok(tree),
+            Err(e) => e,
+        };
+        let lines = prefix.iter().filter(|&&c| c == b'\n').count();
+        if e.line > lines {
+            e.line -= lines;
+            e.offset = e.offset.saturating_sub(prefix.len());
+        }
+        Err(e.into())
+    }
+    /// element of the start tag from `start` to `end` of `buf`, errors are located in the tag
+    fn read_tag(&mut self, start: usize, end: usize, declarations: &Scope) -> Result<ETreeNode, Error> {
+        if self.tags.is_none() {
+            let prolog = [self.declaration.as_slice(), self.doctype.as_slice()].concat();
+            self.tags = Some(TagReader::new(&prolog)?);
+        }
+        Ok(self.tags.as_mut().unwrap().read(&self.buf[start..end], declarations)?)
+    }
+    /// subtree of the match from `start` to `end` of `buf`
+    fn parse_match(&self, start: usize, end: usize, declarations: &Scope) -> Result<ETree, Error> {
+        let tree = self.parse(&self.buf[start..end], declarations)?;
+        let mut out = tree.subtree(tree.children(tree.root())[0]).unwrap();
+        let root = out.node_mut(0).unwrap();
+        for (prefix, uri) in declarations.iter() {
+            let key = if prefix.is_empty() { "xmlns".to_string() } else { format!("xmlns:{}", prefix) };
+            if root.get_attr(&key).is_none() {
+                root.set_attr(&key, uri);
+            }
+        }
+        Ok(out)
+    }
+    /// end of the stream, a match which is not closed is an error
+    fn finish(&mut self) -> Option<Result<ETree, Error>> {
+        let (from, _, _) = self.capture.take()?;
+        let text = String::from_utf8_lossy(&self.buf[from..]).into_owned();
+        let e = ParseError::new(&text, text.len(), "unexpected end of stream in a match".to_string());
+        self.fail(e.into())
+    }
+}
+
+impl<R: Read> Iterator for StreamMatches<R> {
+    type Item = Result<ETree, Error>;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let rest = &self.buf[self.scanned..];
+            self.scanned += rest.iter().position(|&c| c == b'<').unwrap_or(rest.len());
+            let start = self.scanned;
+            match markup_end(&self.buf[start..]) {
+                Some(len) => {
+                    self.scanned = start + len;
+                    if let Some(result) = self.markup(start, start + len) {
+                        return Some(result);
+                    }
+                }
+                None if self.eof => return self.finish(),
+                None => {
+                    if let Err(e) = self.fill() {
+                        return self.fail(e.into());
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// reason why `segment` cannot be matched at the start tag
+fn unsupported(segment: &XPathSegment) -> Option<String> {
+    if !matches!(segment.axis.as_str(), "" | "child" | "descendant") {
+        return Some(format!("axis {:?} is not supported in streaming", segment.axis));
+    }
+    if matches!(segment.node.as_str(), "." | ".." | "text()" | "comment()")
+        || segment.node.starts_with("processing-instruction(")
+    {
+        return Some(format!("node test {:?} is not supported in streaming", segment.node));
+    }
+    let (children, _, functions) = segment.condition.collect();
+    let known = |x: &String| matches!(x.as_str(), "position()" | "local-name()" | "namespace-uri()" | "name()");
+    if !children.is_empty() || !functions.iter().all(known) {
+        return Some("predicates on texts, children or last() are not supported in streaming".to_string());
+    }
+    if (segment.separator == "//" || segment.axis == "descendant") && positional(&segment.condition) {
+        return Some("positions after \"//\" are not supported in streaming".to_string());
+    }
+    None
+}
+
+/// whether the predicate depends on the position
+fn positional(condition: &Predictor) -> bool {
+    match condition {
+        Predictor::IndexDecimal(_) | Predictor::IndexExpr(_, _) => true,
+        Predictor::And(left, right) | Predictor::Or(left, right) => positional(left) || positional(right),
+        Predictor::Not(inner) => positional(inner),
+        _ => condition.collect().2.iter().any(|x| x == "position()"),
+    }
+}
+
+/// check the predicate against the attributes and the name of `node`, which is the child at `position`
+fn predicate_matches(
+    condition: &Predictor,
+    node: &ETreeNode,
+    position: usize,
+    namespaces: &HashMap<String, String>,
+) -> bool {
+    if *condition == Predictor::None {
+        return true;
+    }
+    let (_, attrs, functions) = condition.collect();
+    let mut info = HashMap::new();
+    info.insert("@*".to_string(), (node.get_attr_count() > 0).to_string());
+    for name in attrs.iter().filter(|x| *x != "*") {
+        let bound = name.split_once(':').and_then(|(prefix, local)| namespaces.get(prefix).map(|uri| (uri, local)));
+        let value = match bound {
+            Some((uri, local)) => node.get_attr_ns(uri, local),
+            None => node.get_attr(name),
+        };
+        if let Some(value) = value {
+            info.insert(format!("@{}", name), value);
+        }
+    }
+    for function in functions.iter().filter(|x| *x != "position()") {
+        info.insert(function.clone(), name_function(node, function.trim_end_matches("()")));
+    }
+    info.insert("position()".to_string(), position.to_string());
+    condition.matches(&info)
+}
+
+/// namespaces in scope of `node`, whose parent has `declarations`
+fn scope(node: &ETreeNode, declarations: Rc<Scope>) -> Rc<Scope> {
+    let own: Vec<(String, String)> = node
+        .get_attr_iter()
+        .filter_map(|(key, value)| match key.strip_prefix("xmlns") {
+            Some("") => Some((String::new(), value.clone())),
+            Some(prefix) => prefix.strip_prefix(':').map(|x| (x.to_string(), value.clone())),
+            None => None,
+        })
+        .collect();
+    if own.is_empty() {
+        return declarations;
+    }
+    let mut scope = (*declarations).clone();
+    scope.extend(own);
+    Rc::new(scope)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn names(stream: &str, path: &str) -> Vec<String> {
+        StreamMatches::new(stream.as_bytes(), path)
+            .unwrap()
+            .map(|x| {
+                let tree = x.unwrap();
+                let node = tree.node(0).unwrap();
+                node.get_attr("id").map(|id| format!("{}{}", node.get_name(), id)).unwrap_or_else(|| node.get_name())
+            })
+            .collect()
+    }
+    #[test]
+    fn test_stream_find() {
+        let stream = concat!(
+            "<?xml version=\"1.0\"?>\n<!DOCTYPE r [<!ENTITY e 'entity'>]>\n",
+            "<r xmlns:x='urn:x'><a id='1'><a id='2'/></a><b><a id='3' k='&e;'/><x:c id='4'>&e;</x:c></b>",
+            "<a id='5'/></r>"
+        );
+        assert_eq!(names(stream, "//a"), vec!["a1", "a3", "a5"]);
+        assert_eq!(names(stream, "a[@k='entity']"), vec!["a3"]);
+        assert_eq!(names(stream, "/r/a[2]"), vec!["a5"]);
+        assert_eq!(names(stream, "/r/*[position() > 1]"), vec!["b", "a5"]);
+        assert_eq!(names(stream, "/r/b/descendant::*[local-name()='c'] | /r/a[1]/a"), vec!["a2", "x:c4"]);
+        let mut matches = StreamMatches::new(stream.as_bytes(), "//y:c").unwrap().with_namespace("y", "urn:x");
+        let tree = matches.next().unwrap().unwrap();
+        assert_eq!(tree.node(0).unwrap().get_namespace(), "urn:x");
+        assert_eq!(tree.node(0).unwrap().get_text().as_deref(), Some("entity"));
+        let written = String::from_utf8(tree.write().unwrap()).unwrap();
+        assert!(written.contains("<x:c id=\"4\" xmlns:x=\"urn:x\">entity</x:c>"));
+        assert!(matches.next().is_none());
+        // chunks of one byte
+        struct Slow<'a>(&'a [u8]);
+        impl Read for Slow<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let len = self.0.len().min(buf.len()).min(1);
+                buf[..len].copy_from_slice(&self.0[..len]);
+                self.0 = &self.0[len..];
+                Ok(len)
+            }
+        }
+        assert_eq!(StreamMatches::new(Slow(stream.as_bytes()), "//*[@id]").unwrap().count(), 4);
+        // start tags are decoded with the encoding of the stream
+        let stream = b"<?xml version=\"1.0\" encoding=\"ISO-8859-1\"?><r><a n='\xe9'/><a n='e'/></r>";
+        assert_eq!(StreamMatches::new(&stream[..], "//a[@n='\u{e9}']").unwrap().count(), 1);
+    }
+    #[test]
+    fn test_stream_find_error() {
+        for (path, position) in [("//a/..", 4), ("//a[text()='x']", 2), ("//a[1]", 2), ("/r/ancestor::a", 3)] {
+            match StreamMatches::new(&b"<r/>"[..], path) {
+                Err(Error::XPath { position: p, .. }) => assert_eq!(p, position, "{}", path),
+                _ => panic!("{} is supported", path),
+            }
+        }
+        let results: Vec<_> = StreamMatches::new(&b"<r><a><b></a><a>"[..], "/r/a").unwrap().collect();
+        assert_eq!(results.len(), 1);
+        assert!(matches!(&results[0], Err(Error::Parse { source, .. }) if source.offset == 13));
+        let results: Vec<_> = StreamMatches::new(&b"<r><a/><a>"[..], "/r/a").unwrap().collect();
+        assert_eq!(results.len(), 2);
+        assert!(matches!(&results[1], Err(Error::Parse { source, .. }) if source.offset == 3));
+    }
+}
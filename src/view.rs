@@ -0,0 +1,183 @@
+use super::error::XPathError;
+use super::etree::{ETree, XPathIterator};
+use super::etreenode::ETreeNode;
+use std::ops::Range;
+use std::sync::Arc;
+
+/// Subtree of an `ETree` rooted at one node, created by `ETree::view`
+///
+/// The end of the subtree and the parent of each node are computed once, so navigation and XPath do not scan routes
+/// of the whole tree. Positions are those of the tree, nodes outside the subtree are not reached: the root has no
+/// parent or siblings, and for XPath the root is the top-level node of the document, e.g. `/item` selects the root
+/// if it is an `item` element and `//item` includes the root.
+///
+/// ```
+/// use etree::ETree;
+///
+/// let tree = ETree::parse_str("<root><a><b/><b/></a><b/></root>").unwrap();
+/// let view = tree.view(tree.find("//a").unwrap()).unwrap();
+/// assert_eq!(view.find_iter("//b").collect::<Vec<_>>(), vec![2, 3]);
+/// assert_eq!(view.children(view.root()), vec![2, 3]);
+/// assert_eq!(view.parent(view.root()), None);
+/// ```
+#[derive(Debug, Clone)]
+pub struct SubtreeView<'a> {
+    tree: &'a ETree,
+    start: usize,
+    // end of the subtree of each node
+    ends: Arc<[usize]>,
+    // parent of each node, `None` for the root
+    parents: Arc<[Option<usize>]>,
+}
+
+impl<'a> SubtreeView<'a> {
+    pub(crate) fn new(tree: &'a ETree, pos: usize) -> Option<SubtreeView<'a>> {
+        tree.node(pos)?;
+        let end = pos + 1 + tree.descendant(pos).len();
+        let mut ends = vec![end; end - pos];
+        let mut parents = vec![None; end - pos];
+        // open nodes with their depth, which is the number of ancestors in the route
+        let mut open: Vec<(usize, usize)> = Vec::new();
+        for i in pos..end {
+            let depth = tree.node(i).unwrap().get_route().matches('#').count();
+            while let Some(&(last, _)) = open.last().filter(|x| x.1 >= depth) {
+                ends[last - pos] = i;
+                open.pop();
+            }
+            parents[i - pos] = open.last().map(|x| x.0);
+            open.push((i, depth));
+        }
+        Some(SubtreeView {
+            tree,
+            start: pos,
+            ends: ends.into(),
+            parents: parents.into(),
+        })
+    }
+    #[allow(dead_code)]
+    /// get the tree of the subtree
+    pub fn tree(&self) -> &'a ETree {
+        self.tree
+    }
+    #[allow(dead_code)]
+    /// get position of the root of the subtree
+    pub fn root(&self) -> usize {
+        self.start
+    }
+    #[allow(dead_code)]
+    /// get positions of the nodes in document order
+    pub fn range(&self) -> Range<usize> {
+        self.start..self.start + self.ends.len()
+    }
+    #[allow(dead_code)]
+    /// get number of nodes including the root
+    pub fn len(&self) -> usize {
+        self.ends.len()
+    }
+    #[allow(dead_code)]
+    /// always false, the root is in the subtree
+    pub fn is_empty(&self) -> bool {
+        self.ends.is_empty()
+    }
+    #[allow(dead_code)]
+    /// get whether the node of specified position is in the subtree
+    pub fn contains(&self, pos: usize) -> bool {
+        self.range().contains(&pos)
+    }
+    #[allow(dead_code)]
+    /// get node by position, None outside the subtree
+    pub fn node(&self, pos: usize) -> Option<&'a ETreeNode> {
+        self.tree.node(pos).filter(|_| self.contains(pos))
+    }
+    #[allow(dead_code)]
+    /// get position of parent node, None for the root
+    pub fn parent(&self, pos: usize) -> Option<usize> {
+        self.parents.get(pos.checked_sub(self.start)?).copied().flatten()
+    }
+    #[allow(dead_code)]
+    /// get positions of children node
+    pub fn children(&self, pos: usize) -> Vec<usize> {
+        let mut out = Vec::new();
+        if self.contains(pos) {
+            let mut child = pos + 1;
+            while child < self.ends[pos - self.start] {
+                out.push(child);
+                child = self.ends[child - self.start];
+            }
+        }
+        out
+    }
+    #[allow(dead_code)]
+    /// get positions of descendant node
+    pub fn descendant(&self, pos: usize) -> Vec<usize> {
+        match self.contains(pos) {
+            true => (pos + 1..self.ends[pos - self.start]).collect(),
+            false => Vec::new(),
+        }
+    }
+    #[allow(dead_code)]
+    /// get position of previous sibling node, None for the root
+    pub fn previous(&self, pos: usize) -> Option<usize> {
+        let siblings = self.children(self.parent(pos)?);
+        let idx = siblings.iter().position(|&x| x == pos)?;
+        idx.checked_sub(1).map(|x| siblings[x])
+    }
+    #[allow(dead_code)]
+    /// get position of next sibling node, None for the root
+    pub fn next(&self, pos: usize) -> Option<usize> {
+        let parent = self.parent(pos)?;
+        let next = self.ends[pos - self.start];
+        Some(next).filter(|&x| x < self.ends[parent - self.start])
+    }
+    #[allow(dead_code)]
+    /// find nodes that match `path` in the subtree, fail if `path` is invalid
+    pub fn try_find_iter(&self, path: &str) -> Result<XPathIterator<'a>, XPathError> {
+        XPathIterator::try_new_in(self.clone(), path)
+    }
+    #[allow(dead_code)]
+    /// find the first node that matches `path` in the subtree
+    pub fn find(&self, path: &str) -> Option<usize> {
+        self.find_iter(path).next()
+    }
+    #[allow(dead_code)]
+    /// find nodes that match `path` in the subtree, panic if `path` is invalid
+    pub fn find_iter(&self, path: &str) -> XPathIterator<'a> {
+        match self.try_find_iter(path) {
+            Ok(iter) => iter,
+            Err(e) => panic!("{}", e),
+        }
+    }
+    #[allow(dead_code)]
+    /// clone the subtree into a new tree
+    pub fn to_tree(&self) -> ETree {
+        self.tree.subtree(self.start).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_view() {
+        let tree = ETree::parse_str("<r><x/><a><b><c/></b><!-- d --><b id='2'/></a><b/></r>").unwrap();
+        let view = tree.view(2).unwrap();
+        assert_eq!(view.range(), 2..7);
+        assert_eq!(view.children(2), vec![3, 5, 6]);
+        assert_eq!(view.descendant(3), vec![4]);
+        assert_eq!(view.parent(4), Some(3));
+        assert_eq!((view.parent(2), view.parent(1)), (None, None));
+        assert_eq!((view.next(3), view.next(6), view.next(2)), (Some(5), None, None));
+        assert_eq!((view.previous(5), view.previous(3)), (Some(3), None));
+        assert!(view.node(7).is_none());
+        assert_eq!(view.find_iter("//b").collect::<Vec<_>>(), vec![3, 6]);
+        assert_eq!(view.find_iter("/a/b[@id]").collect::<Vec<_>>(), vec![6]);
+        assert_eq!(view.find_iter("b").collect::<Vec<_>>(), vec![3, 6]);
+        assert_eq!(view.find_iter("//c/ancestor::*").collect::<Vec<_>>(), vec![2, 3]);
+        assert_eq!(view.find_iter("//c/following::node()").collect::<Vec<_>>(), vec![5, 6]);
+        assert_eq!(view.find_iter("//b[last()]/preceding::*").collect::<Vec<_>>(), vec![3, 4]);
+        assert_eq!(view.find("/a/.."), None);
+        assert_eq!(view.find("//a/following-sibling::*"), None);
+        assert_eq!(view.to_tree().write().unwrap(), tree.subtree(2).unwrap().write().unwrap());
+        assert!(tree.view(8).is_none());
+    }
+}
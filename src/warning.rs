@@ -0,0 +1,105 @@
+use super::error::ParseError;
+use super::etree::{ETree, ReadState};
+use super::options::ParseOptions;
+
+/// Reason of a parse warning
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseWarningKind {
+    /// an element declares a namespace prefix already bound to the same URI by an ancestor (prefix, URI),
+    /// the default namespace has an empty prefix
+    RedundantNamespace(String, String),
+    /// reference to an undeclared entity, kept as text (name)
+    UnknownEntity(String),
+    /// character reference to a character not allowed in the document, replaced by U+FFFD (reference)
+    InvalidCharacter(String),
+}
+
+/// Warning of parsing located by byte `offset` and 1-based `line` and `column` (in characters) of the markup
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseWarning {
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+    pub kind: ParseWarningKind,
+}
+
+impl ParseWarning {
+    /// locate byte `offset` in `content`
+    pub(crate) fn new(content: &str, offset: usize, kind: ParseWarningKind) -> ParseWarning {
+        let at = ParseError::new(content, offset, String::new());
+        ParseWarning {
+            offset: at.offset,
+            line: at.line,
+            column: at.column,
+            kind,
+        }
+    }
+}
+
+impl std::fmt::Display for ParseWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "line {}, column {} (byte {}): ", self.line, self.column, self.offset)?;
+        match &self.kind {
+            ParseWarningKind::RedundantNamespace(prefix, uri) if prefix.is_empty() => {
+                write!(f, "default namespace \"{}\" is already declared", uri)
+            }
+            ParseWarningKind::RedundantNamespace(prefix, uri) => {
+                write!(f, "prefix \"{}\" is already bound to \"{}\"", prefix, uri)
+            }
+            ParseWarningKind::UnknownEntity(name) => write!(f, "unknown entity &{}; is kept as text", name),
+            ParseWarningKind::InvalidCharacter(reference) => {
+                write!(f, "invalid character reference {} is replaced by U+FFFD", reference)
+            }
+        }
+    }
+}
+
+impl ETree {
+    #[allow(dead_code)]
+    /// parse XML text and report suspicious constructs which don't fail the parse
+    ///
+    /// Unknown entity references and invalid character references, which are errors for `parse_str`,
+    /// become warnings: the former are kept as text, the latter replaced by U+FFFD.
+    pub fn parse_str_with_warnings(content: &str) -> Result<(ETree, Vec<ParseWarning>), ParseError> {
+        let mut out = ETree::empty(content);
+        let mut state = ReadState::new().with_warnings();
+        let content = content.strip_prefix('\u{feff}').unwrap_or(content);
+        out.read(content, &ParseOptions::default(), &mut state, &mut None, &mut None)?;
+        out.read_end(content, &state, &mut None)?;
+        out.detect_indent();
+        Ok((out, state.take_warnings()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_parse_warnings() {
+        let content = concat!(
+            "<root xmlns=\"urn:a\" xmlns:x=\"urn:x\">\n",
+            "  <x:a xmlns:x=\"urn:x\" k=\"&bogus;\">&#1;</x:a>\n",
+            "  <b xmlns=\"urn:a\" xmlns:x=\"urn:y\"/>\n",
+            "</root>"
+        );
+        assert!(ETree::parse_str(content).is_err());
+        let (tree, warnings) = ETree::parse_str_with_warnings(content).unwrap();
+        let kinds: Vec<ParseWarningKind> = warnings.iter().map(|x| x.kind.clone()).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                ParseWarningKind::RedundantNamespace("x".to_string(), "urn:x".to_string()),
+                ParseWarningKind::UnknownEntity("bogus".to_string()),
+                ParseWarningKind::InvalidCharacter("&#1;".to_string()),
+                ParseWarningKind::RedundantNamespace("".to_string(), "urn:a".to_string()),
+            ]
+        );
+        assert_eq!((warnings[0].line, warnings[0].column), (2, 3));
+        assert_eq!((warnings[2].line, warnings[2].column), (2, 36));
+        assert_eq!(warnings[1].to_string(), "line 2, column 3 (byte 39): unknown entity &bogus; is kept as text");
+        let a = tree.node(1).unwrap();
+        assert_eq!(a.get_attr("k").as_deref(), Some("&bogus;"));
+        assert_eq!(a.get_text().as_deref(), Some("\u{fffd}"));
+        assert!(ETree::parse_str_with_warnings("<a></b>").is_err());
+    }
+}
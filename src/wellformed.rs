@@ -0,0 +1,169 @@
+use super::error::ParseError;
+use super::etree::ETree;
+use super::namespace::NamespaceErrorKind;
+use super::options::ParseOptions;
+
+/// Reason of a well-formedness error
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WellFormednessErrorKind {
+    /// the markup is broken, e.g. mismatched or unclosed tags (message of the parser)
+    Malformed(String),
+    /// the document has no root element
+    NoRoot,
+    /// the document has more than one root element (name of the extra element)
+    MultipleRoots(String),
+    /// a character which is not allowed by the XML version of the document
+    IllegalCharacter(char),
+    /// prefix of an element or attribute name is not declared (prefix, name)
+    UnboundPrefix(String, String),
+}
+
+/// Well-formedness error located by byte `offset` and 1-based `line` and `column` (in characters)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WellFormednessError {
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+    pub kind: WellFormednessErrorKind,
+}
+
+impl std::fmt::Display for WellFormednessError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "line {}, column {} (byte {}): ", self.line, self.column, self.offset)?;
+        match &self.kind {
+            WellFormednessErrorKind::Malformed(message) => write!(f, "{}", message),
+            WellFormednessErrorKind::NoRoot => write!(f, "no root element"),
+            WellFormednessErrorKind::MultipleRoots(name) => write!(f, "extra root element <{}>", name),
+            WellFormednessErrorKind::IllegalCharacter(c) => write!(f, "illegal character U+{:04X}", *c as u32),
+            WellFormednessErrorKind::UnboundPrefix(prefix, name) => {
+                write!(f, "prefix \"{}\" of \"{}\" is not declared", prefix, name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for WellFormednessError {}
+
+impl From<ParseError> for WellFormednessError {
+    fn from(value: ParseError) -> Self {
+        WellFormednessError {
+            offset: value.offset,
+            line: value.line,
+            column: value.column,
+            kind: WellFormednessErrorKind::Malformed(value.message),
+        }
+    }
+}
+
+impl ETree {
+    #[allow(dead_code)]
+    /// parse XML text and check well-formedness, all violations are reported instead of the first one
+    ///
+    /// Besides the errors of `parse_recover`, the document must have a single root element, only characters
+    /// allowed by its XML version and declared namespace prefixes.
+    pub fn parse_strict(content: &str) -> Result<ETree, Vec<WellFormednessError>> {
+        let (tree, errors) = ETree::parse_recover(content, &ParseOptions::default());
+        let mut errors: Vec<WellFormednessError> = errors.into_iter().map(WellFormednessError::from).collect();
+        let content = content.strip_prefix('\u{feff}').unwrap_or(content);
+        errors.extend(illegal_characters(content, tree.get_version().as_deref() == Some("1.1")));
+        let at = |pos: usize, kind: WellFormednessErrorKind| {
+            let node = tree.node(pos);
+            WellFormednessError {
+                offset: node.and_then(|x| x.get_offset()).unwrap_or(0),
+                line: node.and_then(|x| x.get_line()).unwrap_or(1),
+                column: node.and_then(|x| x.get_column()).unwrap_or(1),
+                kind,
+            }
+        };
+        let roots = tree.roots();
+        if roots.is_empty() {
+            let end = ParseError::new(content, content.len(), String::new());
+            errors.push(WellFormednessError {
+                offset: end.offset,
+                line: end.line,
+                column: end.column,
+                kind: WellFormednessErrorKind::NoRoot,
+            });
+        }
+        for &pos in roots.iter().skip(1) {
+            let name = tree.node(pos).map(|x| x.get_name()).unwrap_or_default();
+            errors.push(at(pos, WellFormednessErrorKind::MultipleRoots(name)));
+        }
+        if let Err(found) = tree.check_namespaces() {
+            for e in found {
+                let kind = match e.kind {
+                    NamespaceErrorKind::UnboundElementPrefix(prefix) => {
+                        let name = tree.node(e.pos).map(|x| x.get_name()).unwrap_or_default();
+                        WellFormednessErrorKind::UnboundPrefix(prefix, name)
+                    }
+                    NamespaceErrorKind::UnboundAttrPrefix(prefix, name) => {
+                        WellFormednessErrorKind::UnboundPrefix(prefix, name)
+                    }
+                    // namespaces of parsed nodes always match their prefixes
+                    NamespaceErrorKind::MismatchedNamespace(..) => continue,
+                };
+                errors.push(at(e.pos, kind));
+            }
+        }
+        if errors.is_empty() {
+            Ok(tree)
+        } else {
+            errors.sort_by_key(|x| x.offset);
+            Err(errors)
+        }
+    }
+}
+
+/// find characters which can't appear literally in a document, XML 1.1 allows control characters only as references
+fn illegal_characters(content: &str, xml11: bool) -> Vec<WellFormednessError> {
+    let mut out = Vec::new();
+    let (mut line, mut column) = (1, 1);
+    for (offset, c) in content.char_indices() {
+        let legal = match c {
+            '\t' | '\n' | '\r' => true,
+            '\u{0}'..='\u{1f}' | '\u{fffe}' | '\u{ffff}' => false,
+            '\u{85}' => true,
+            '\u{7f}'..='\u{9f}' => !xml11,
+            _ => true,
+        };
+        if !legal {
+            out.push(WellFormednessError {
+                offset,
+                line,
+                column,
+                kind: WellFormednessErrorKind::IllegalCharacter(c),
+            });
+        }
+        if c == '\n' {
+            line += 1;
+            column = 1;
+        } else {
+            column += 1;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_parse_strict() {
+        assert!(ETree::parse_strict("<?xml version=\"1.0\"?>\n<a:root xmlns:a=\"urn:a\"><a:x/></a:root>").is_ok());
+        let errors = ETree::parse_strict("<root>\n  <a x:y=\"1\">\u{1}</b>\n</root>\n<extra/>").unwrap_err();
+        let found: Vec<_> = errors.iter().map(|x| (x.line, x.column, x.kind.clone())).collect();
+        assert_eq!(
+            found,
+            vec![
+                (2, 3, WellFormednessErrorKind::UnboundPrefix("x".to_string(), "x:y".to_string())),
+                (2, 14, WellFormednessErrorKind::IllegalCharacter('\u{1}')),
+                (2, 15, WellFormednessErrorKind::Malformed("unexpected end tag </b>".to_string())),
+                (3, 1, WellFormednessErrorKind::Malformed("element <a> is not closed".to_string())),
+                (4, 1, WellFormednessErrorKind::MultipleRoots("extra".to_string())),
+            ]
+        );
+        assert_eq!(errors[1].to_string(), "line 2, column 14 (byte 20): illegal character U+0001");
+        let errors = ETree::parse_strict("<!-- only a comment -->").unwrap_err();
+        assert_eq!(errors[0].kind, WellFormednessErrorKind::NoRoot);
+    }
+}
@@ -0,0 +1,517 @@
+use super::error::Error;
+use super::options::{AttrStyle, Newline, QuoteChar};
+use quick_xml::events::attributes::Attribute;
+use quick_xml::events::{BytesDecl, BytesEnd, BytesStart, BytesText, Event};
+use quick_xml::Writer;
+use std::borrow::Cow;
+use std::io::Write;
+
+/// split indent such as "\n  " into newline and indent unit
+pub(crate) fn split_indent(indent: &str) -> (String, String) {
+    let lines: Vec<&str> = indent.lines().collect();
+    let crlf = if lines.len() >= 2 && !lines[lines.len() - 1].is_empty() {
+        if indent.contains("\r\n") {
+            "\r\n"
+        } else if indent.contains('\n') {
+            "\n"
+        } else {
+            "\r"
+        }
+    } else {
+        "\n"
+    };
+    (crlf.to_string(), lines.last().unwrap_or(&"").to_string())
+}
+
+/// replace "\r\n", "\r" and "\n" in `text` by `newline`, text already in that style is not copied
+pub(crate) fn normalize_newlines(text: &str, newline: Newline) -> Cow<'_, str> {
+    let target = newline.as_str();
+    let normalized = match newline {
+        Newline::Lf => !text.contains('\r'),
+        Newline::Cr => !text.contains('\n'),
+        Newline::CrLf => text.match_indices(['\r', '\n']).all(|(i, c)| match c {
+            "\r" => text[i + 1..].starts_with('\n'),
+            _ => text[..i].ends_with('\r'),
+        }),
+    };
+    if normalized {
+        return Cow::Borrowed(text);
+    }
+    Cow::Owned(text.replace("\r\n", "\n").replace('\r', "\n").replace('\n', target))
+}
+
+/// check whether `text` starts with a character or entity reference such as "&amp;" or "&#x2014;"
+fn is_reference(text: &str) -> bool {
+    let name = match text.strip_prefix('&').and_then(|x| x.split(';').next()) {
+        Some(name) if text.len() > name.len() + 1 && !name.is_empty() => name,
+        _ => return false,
+    };
+    if let Some(hex) = name.strip_prefix("#x") {
+        !hex.is_empty() && hex.chars().all(|c| c.is_ascii_hexdigit())
+    } else if let Some(dec) = name.strip_prefix('#') {
+        !dec.is_empty() && dec.chars().all(|c| c.is_ascii_digit())
+    } else {
+        !name.starts_with(|c: char| c.is_ascii_digit() || c == '-' || c == '.')
+            && name.chars().all(|c| c.is_alphanumeric() || "_:-.".contains(c))
+    }
+}
+
+/// Escaping policy of texts and attribute values
+///
+/// "<" and "&" are always escaped, so are the quote character in attribute values and ">" after "]]".
+///
+/// - `apos`: write "'" as `&apos;`
+/// - `quot`: write '"' in texts as `&quot;`
+/// - `gt`: write ">" as `&gt;`
+/// - `non_ascii`: write characters outside ASCII as character references
+/// - `hex_references`: write character references in hexadecimal (`&#xA0;`) rather than decimal (`&#160;`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EscapePolicy {
+    pub apos: bool,
+    pub quot: bool,
+    pub gt: bool,
+    pub non_ascii: bool,
+    pub hex_references: bool,
+}
+
+impl EscapePolicy {
+    #[allow(dead_code)]
+    pub fn new() -> EscapePolicy {
+        EscapePolicy {
+            apos: true,
+            quot: true,
+            gt: true,
+            non_ascii: false,
+            hex_references: true,
+        }
+    }
+    #[allow(dead_code)]
+    /// escape only what XML requires
+    pub fn minimal() -> EscapePolicy {
+        EscapePolicy {
+            apos: false,
+            quot: false,
+            gt: false,
+            ..EscapePolicy::new()
+        }
+    }
+    #[allow(dead_code)]
+    pub fn with_apos(mut self, apos: bool) -> EscapePolicy {
+        self.apos = apos;
+        self
+    }
+    #[allow(dead_code)]
+    pub fn with_quot(mut self, quot: bool) -> EscapePolicy {
+        self.quot = quot;
+        self
+    }
+    #[allow(dead_code)]
+    pub fn with_gt(mut self, gt: bool) -> EscapePolicy {
+        self.gt = gt;
+        self
+    }
+    #[allow(dead_code)]
+    pub fn with_non_ascii(mut self, non_ascii: bool) -> EscapePolicy {
+        self.non_ascii = non_ascii;
+        self
+    }
+    #[allow(dead_code)]
+    pub fn with_hex_references(mut self, hex_references: bool) -> EscapePolicy {
+        self.hex_references = hex_references;
+        self
+    }
+}
+
+impl Default for EscapePolicy {
+    fn default() -> Self {
+        EscapePolicy::new()
+    }
+}
+
+/// Escaping of texts and attribute values written by `ETree::write`
+///
+/// - `keep_references`: keep "&" which starts a reference, see `ParseOptions::preserve_references`
+/// - `xml11`: write control characters as character references, XML 1.1 doesn't allow them literally
+/// - `attr`: the text is an attribute value delimited by `quote`
+/// - `quote`: quote character around attribute values
+/// - `policy`: optional escapes, see `EscapePolicy`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Escape {
+    pub keep_references: bool,
+    pub xml11: bool,
+    pub attr: bool,
+    pub quote: QuoteChar,
+    pub policy: EscapePolicy,
+}
+
+impl Escape {
+    /// escape text like quick-xml with the exceptions above, text without special characters is not copied
+    pub(crate) fn escape<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        if !text.char_indices().any(|(i, c)| self.is_special(text, i, c)) {
+            return Cow::Borrowed(text);
+        }
+        let mut out = String::with_capacity(text.len());
+        for (i, c) in text.char_indices() {
+            match c {
+                _ if !self.is_special(text, i, c) => out.push(c),
+                '<' => out.push_str("&lt;"),
+                '>' => out.push_str("&gt;"),
+                '\'' => out.push_str("&apos;"),
+                '"' => out.push_str("&quot;"),
+                '&' => out.push_str("&amp;"),
+                c if self.policy.hex_references => out.push_str(&format!("&#x{:X};", c as u32)),
+                c => out.push_str(&format!("&#{};", c as u32)),
+            }
+        }
+        Cow::Owned(out)
+    }
+    /// whether `c` at byte `i` of `text` is replaced by `escape`
+    fn is_special(&self, text: &str, i: usize, c: char) -> bool {
+        match c {
+            '<' => true,
+            '>' => self.policy.gt || text[..i].ends_with("]]"),
+            '\'' => self.policy.apos || (self.attr && self.quote == QuoteChar::Single),
+            '"' => self.policy.quot || (self.attr && self.quote == QuoteChar::Double),
+            '&' => !(self.keep_references && is_reference(&text[i..])),
+            '\u{1}'..='\u{8}' | '\u{b}' | '\u{c}' | '\u{e}'..='\u{1f}' | '\u{7f}'..='\u{9f}' => {
+                self.xml11 || (self.policy.non_ascii && c > '\u{7f}')
+            }
+            _ => self.policy.non_ascii && !c.is_ascii(),
+        }
+    }
+}
+
+/// build start tag, `margin` is the whitespace before "<" on its line, used by `AttrStyle::Aligned`
+///
+/// Attribute values are escaped by `escape`.
+pub(crate) fn start_tag<'a, I>(
+    name: &str,
+    attrs: I,
+    attr_style: AttrStyle,
+    margin: &str,
+    crlf: &str,
+    escape: Escape,
+) -> BytesStart<'static>
+where
+    I: Iterator<Item = &'a (String, String)>,
+{
+    let crlf = if crlf.is_empty() { "\n" } else { crlf };
+    let separator = format!("{}{}{}", crlf, margin, " ".repeat(name.chars().count() + 2));
+    let mut buf = name.to_string().into_bytes();
+    let attrs: Vec<_> = attrs.collect();
+    let aligned = attr_style == AttrStyle::Aligned && attrs.len() > 1;
+    for (i, attr) in attrs.into_iter().enumerate() {
+        let value = escape.escape(&attr.1);
+        let attr = Attribute::from((attr.0.as_bytes(), value.as_bytes()));
+        if i > 0 && aligned {
+            buf.extend_from_slice(separator.as_bytes());
+        } else {
+            buf.push(b' ');
+        }
+        let quote = match escape.quote {
+            QuoteChar::Double => b'"',
+            QuoteChar::Single => b'\'',
+        };
+        buf.extend_from_slice(attr.key);
+        buf.push(b'=');
+        buf.push(quote);
+        buf.extend_from_slice(&attr.value);
+        buf.push(quote);
+    }
+    BytesStart::owned(buf, name.len())
+}
+
+struct OpenElement {
+    name: String,
+    has_child: bool,
+    has_text: bool,
+}
+
+/// Event-based streaming XML writer
+///
+/// Events are written to the inner `io::Write` as soon as possible, so the document never exists in memory.
+/// Text and attribute values are escaped automatically.
+///
+/// ```
+/// use etree::XmlWriter;
+///
+/// let mut writer = XmlWriter::new(Vec::new()).with_indent("\n  ");
+/// writer.start_element("ROOT").unwrap();
+/// writer.start_element("CHILD").unwrap().attr("id", "1").unwrap().text("a & b").unwrap();
+/// writer.end_element().unwrap();
+/// let out = writer.finish().unwrap();
+/// assert_eq!(String::from_utf8(out).unwrap(), "<ROOT>\n  <CHILD id=\"1\">a &amp; b</CHILD>\n</ROOT>");
+/// ```
+pub struct XmlWriter<W: Write> {
+    writer: Writer<W>,
+    crlf: String,
+    indent: String,
+    attr_style: AttrStyle,
+    escape_policy: EscapePolicy,
+    quote_char: QuoteChar,
+    stack: Vec<OpenElement>,
+    pending: Option<(String, Vec<(String, String)>)>,
+    started: bool,
+}
+
+impl<W: Write> XmlWriter<W> {
+    #[allow(dead_code)]
+    pub fn new(inner: W) -> XmlWriter<W> {
+        XmlWriter {
+            writer: Writer::new(inner),
+            crlf: "".to_string(),
+            indent: "".to_string(),
+            attr_style: AttrStyle::Inline,
+            escape_policy: EscapePolicy::new(),
+            quote_char: QuoteChar::Double,
+            stack: Vec::new(),
+            pending: None,
+            started: false,
+        }
+    }
+    #[allow(dead_code)]
+    /// indent nested elements, `indent` has the same format as the argument of `ETree::pretty`
+    pub fn with_indent(mut self, indent: &str) -> XmlWriter<W> {
+        let (crlf, indent) = split_indent(indent);
+        self.crlf = crlf;
+        self.indent = indent;
+        self
+    }
+    #[allow(dead_code)]
+    /// set layout of attributes in start tags
+    pub fn with_attr_style(mut self, attr_style: AttrStyle) -> XmlWriter<W> {
+        self.attr_style = attr_style;
+        self
+    }
+    #[allow(dead_code)]
+    /// set optional escapes of texts and attribute values
+    pub fn with_escape_policy(mut self, escape_policy: EscapePolicy) -> XmlWriter<W> {
+        self.escape_policy = escape_policy;
+        self
+    }
+    #[allow(dead_code)]
+    /// set quote character around attribute values
+    pub fn with_quote_char(mut self, quote_char: QuoteChar) -> XmlWriter<W> {
+        self.quote_char = quote_char;
+        self
+    }
+    #[allow(dead_code)]
+    /// write XML declaration, must be the first event
+    pub fn declaration(&mut self, version: &str, encoding: Option<&str>, standalone: Option<&str>) -> Result<&mut Self, Error> {
+        if self.started {
+            return Err(Error::InvalidState("declaration must be the first event"));
+        }
+        let elem = BytesDecl::new(version.as_bytes(), encoding.map(|x| x.as_bytes()), standalone.map(|x| x.as_bytes()));
+        self.writer.write_event(Event::Decl(elem))?;
+        self.started = true;
+        Ok(self)
+    }
+    #[allow(dead_code)]
+    /// open an element, attributes can be added until the next event
+    pub fn start_element(&mut self, name: &str) -> Result<&mut Self, Error> {
+        self.flush_pending(false)?;
+        self.write_newline()?;
+        self.pending = Some((name.to_string(), Vec::new()));
+        Ok(self)
+    }
+    #[allow(dead_code)]
+    /// add an attribute to the element just opened
+    pub fn attr(&mut self, key: &str, value: &str) -> Result<&mut Self, Error> {
+        match self.pending.as_mut() {
+            Some((_, attrs)) => {
+                attrs.push((key.to_string(), value.to_string()));
+                Ok(self)
+            }
+            None => Err(Error::InvalidState("attribute must follow start_element")),
+        }
+    }
+    #[allow(dead_code)]
+    /// write text of the current element
+    pub fn text(&mut self, text: &str) -> Result<&mut Self, Error> {
+        self.flush_pending(false)?;
+        if let Some(parent) = self.stack.last_mut() {
+            parent.has_text = true;
+        }
+        let escape = Escape {
+            policy: self.escape_policy,
+            ..Escape::default()
+        };
+        let elem = BytesText::from_escaped_str(escape.escape(text));
+        self.writer.write_event(Event::Text(elem))?;
+        Ok(self)
+    }
+    #[allow(dead_code)]
+    /// write a comment, `text` is written as it is and must not contain "--" or end with "-"
+    pub fn comment(&mut self, text: &str) -> Result<&mut Self, Error> {
+        if text.contains("--") || text.ends_with('-') {
+            return Err(Error::InvalidState("comment must not contain \"--\" or end with \"-\""));
+        }
+        self.flush_pending(false)?;
+        self.write_newline()?;
+        let elem = BytesText::from_escaped_str(text);
+        self.writer.write_event(Event::Comment(elem))?;
+        Ok(self)
+    }
+    #[allow(dead_code)]
+    /// close the current element
+    pub fn end_element(&mut self) -> Result<&mut Self, Error> {
+        if self.flush_pending(true)? {
+            return Ok(self);
+        }
+        match self.stack.pop() {
+            Some(elem) => {
+                if elem.has_child && !elem.has_text && !self.indent.is_empty() {
+                    let newline = format!("{}{}", self.crlf, self.indent.repeat(self.stack.len()));
+                    self.writer.write(newline.as_bytes())?;
+                }
+                self.writer.write_event(Event::End(BytesEnd::owned(elem.name.into_bytes())))?;
+                Ok(self)
+            }
+            None => Err(Error::InvalidState("no element to close")),
+        }
+    }
+    #[allow(dead_code)]
+    /// close all open elements and return the inner writer
+    pub fn finish(mut self) -> Result<W, Error> {
+        while self.pending.is_some() || !self.stack.is_empty() {
+            self.end_element()?;
+        }
+        let mut inner = self.writer.into_inner();
+        inner.flush()?;
+        Ok(inner)
+    }
+
+    /// write pending start tag, return true if written as an empty element
+    fn flush_pending(&mut self, empty: bool) -> Result<bool, Error> {
+        if let Some((name, attrs)) = self.pending.take() {
+            let margin = self.indent.repeat(self.stack.len());
+            let escape = Escape {
+                attr: true,
+                quote: self.quote_char,
+                policy: self.escape_policy,
+                ..Escape::default()
+            };
+            let elem = start_tag(&name, attrs.iter(), self.attr_style, &margin, &self.crlf, escape);
+            if empty {
+                self.writer.write_event(Event::Empty(elem))?;
+            } else {
+                self.writer.write_event(Event::Start(elem))?;
+                self.stack.push(OpenElement {
+                    name,
+                    has_child: false,
+                    has_text: false,
+                });
+            }
+            Ok(empty)
+        } else {
+            Ok(false)
+        }
+    }
+    fn write_newline(&mut self) -> Result<(), Error> {
+        let mixed = match self.stack.last_mut() {
+            Some(parent) => {
+                parent.has_child = true;
+                parent.has_text
+            }
+            None => false,
+        };
+        if self.started && !mixed && !self.indent.is_empty() {
+            let newline = format!("{}{}", self.crlf, self.indent.repeat(self.stack.len()));
+            self.writer.write(newline.as_bytes())?;
+        } else if self.started && self.stack.is_empty() && !self.crlf.is_empty() {
+            self.writer.write(self.crlf.as_bytes())?;
+        }
+        self.started = true;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_split_indent() {
+        assert_eq!(split_indent("\n  "), ("\n".to_string(), "  ".to_string()));
+        assert_eq!(split_indent("\r\n\t"), ("\r\n".to_string(), "\t".to_string()));
+        assert_eq!(split_indent("  "), ("\n".to_string(), "  ".to_string()));
+    }
+    #[test]
+    fn test_normalize_newlines() {
+        assert!(matches!(normalize_newlines("a\nb", Newline::Lf), Cow::Borrowed(_)));
+        assert!(matches!(normalize_newlines("a\r\nb", Newline::CrLf), Cow::Borrowed(_)));
+        assert_eq!(normalize_newlines("a\r\nb\rc\nd", Newline::Lf), "a\nb\nc\nd");
+        assert_eq!(normalize_newlines("a\r\nb\rc\nd", Newline::CrLf), "a\r\nb\r\nc\r\nd");
+        assert_eq!(normalize_newlines("a\r\nb\n", Newline::Cr), "a\rb\r");
+    }
+    #[test]
+    fn test_escape() {
+        let escape = Escape {
+            keep_references: true,
+            ..Escape::default()
+        };
+        assert_eq!(
+            escape.escape("&amp; &#x2014; &#8212; &foo; & &#; &1a; <"),
+            "&amp; &#x2014; &#8212; &foo; &amp; &amp;#; &amp;1a; &lt;"
+        );
+        assert_eq!(Escape::default().escape("&amp; \u{1}\t"), "&amp;amp; \u{1}\t");
+        assert!(matches!(Escape::default().escape("plain \u{1}"), Cow::Borrowed(_)));
+        let escape = Escape {
+            xml11: true,
+            ..Escape::default()
+        };
+        assert_eq!(escape.escape("a\u{1}\u{85}\t\n"), "a&#x1;&#x85;\t\n");
+    }
+    #[test]
+    fn test_escape_policy() {
+        let policy = EscapePolicy::minimal().with_non_ascii(true).with_hex_references(false);
+        let escape = Escape {
+            policy,
+            ..Escape::default()
+        };
+        assert_eq!(escape.escape("'a' \"b\" > ]]> é"), "'a' \"b\" > ]]&gt; &#233;");
+        let escape = Escape {
+            attr: true,
+            ..escape
+        };
+        assert_eq!(escape.escape("'a' \"b\""), "'a' &quot;b&quot;");
+        let mut writer = XmlWriter::new(Vec::new()).with_escape_policy(EscapePolicy::new().with_apos(false));
+        writer.start_element("a").unwrap().attr("k", "it's").unwrap().text("it's <").unwrap();
+        let out = writer.finish().unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "<a k=\"it's\">it's &lt;</a>");
+        let mut writer = XmlWriter::new(Vec::new())
+            .with_escape_policy(EscapePolicy::minimal())
+            .with_quote_char(QuoteChar::Single);
+        writer.start_element("a").unwrap().attr("k", "it's \"x\"").unwrap();
+        let out = writer.finish().unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "<a k='it&apos;s \"x\"'/>");
+    }
+    #[test]
+    fn test_xml_writer() {
+        let mut writer = XmlWriter::new(Vec::new()).with_indent("\n  ").with_attr_style(AttrStyle::Aligned);
+        writer.declaration("1.0", Some("UTF-8"), None).unwrap();
+        writer.start_element("ROOT").unwrap();
+        writer.comment(" generated <a> & ").unwrap();
+        writer.start_element("A").unwrap().attr("x", "1").unwrap().attr("y", "<2>").unwrap();
+        writer.end_element().unwrap();
+        writer.start_element("B").unwrap().text("mixed ").unwrap();
+        writer.start_element("C").unwrap().text("c").unwrap();
+        let out = writer.finish().unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            concat!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+                "<ROOT>\n",
+                "  <!-- generated <a> & -->\n",
+                "  <A x=\"1\"\n",
+                "     y=\"&lt;2&gt;\"/>\n",
+                "  <B>mixed <C>c</C></B>\n",
+                "</ROOT>",
+            )
+        );
+        let mut writer = XmlWriter::new(Vec::new());
+        assert!(writer.end_element().is_err());
+        assert!(writer.attr("a", "b").is_err());
+        assert!(writer.comment("a -- b").is_err());
+        assert!(writer.comment("a-").is_err());
+    }
+}
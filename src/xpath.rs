@@ -2,6 +2,9 @@
 ///
 /// Grammar rules:
 /// ```text
+/// union:
+///     xpath
+///     union | xpath
 /// xpath:
 ///     element
 ///     separator element
@@ -10,14 +13,38 @@
 ///     //
 ///     /
 /// element:
+///     axis :: step
+///     step
+/// axis:
+///     ancestor-or-self
+///     ancestor
+///     following-sibling
+///     preceding-sibling
+///     following
+///     preceding
+///     descendant-or-self
+///     descendant
+///     child
+///     parent
+///     self
+/// step:
 ///     ..
 ///     .
 ///     @name
-///     name [ conditions_or ]
-///     name [ index ]
+///     node_test [ conditions_or ]
+///     node_test [ index ]
 ///     * [ conditions_or ]
 ///     * [ index ]
 ///     *
+///     node_test
+/// node_test:
+///     comment()
+///     text()
+///     processing-instruction()
+///     processing-instruction( string )
+///     node()
+///     prefix:*
+///     *:localname
 ///     name
 /// conditions_or:
 ///     conditions_and or conditions_and
@@ -26,16 +53,71 @@
 ///     condition and condition
 ///     condition
 /// condition:
+///     function ( operand , operand )
+///     not ( conditions_or )
+///     true()
+///     false()
 ///     name operator string
 ///     @name operator string
 ///     text() operator string
 ///     position() operator decimal
+///     operand operator operand
 ///     name
+///     child :: name
 ///     @name
 ///     @*
 ///     ( condition )
 ///     ( conditions_and )
 ///     ( conditions_or )
+/// function:
+///     contains
+///     starts-with
+///     matches
+/// value_function:
+///     normalize-space ( operand )
+///     normalize-space ( )
+///     string-length ( operand )
+///     string-length ( )
+///     substring ( operand , operand )
+///     substring ( operand , operand , operand )
+///     substring-before ( operand , operand )
+///     substring-after ( operand , operand )
+///     translate ( operand , operand , operand )
+///     lower-case ( operand )
+///     upper-case ( operand )
+/// aggregate:
+///     count ( node_test )
+///     sum ( node_test )
+/// operand:
+///     term
+///     operand + term
+///     operand - term
+/// term:
+///     unary
+///     term * unary
+///     term div unary
+///     term mod unary
+/// unary:
+///     primary
+///     - unary
+/// primary:
+///     value_function
+///     aggregate
+///     text()
+///     .
+///     @name
+///     position()
+///     last()
+///     local-name()
+///     namespace-uri()
+///     name()
+///     name
+///     string
+///     number
+///     ( operand )
+/// number:
+///     decimal
+///     decimal . decimal
 /// index:
 ///     decimal
 ///     last() - decimal
@@ -48,21 +130,78 @@
 ///     !=
 ///     =
 /// ```
+///
+/// Expressions evaluated by `ETree::evaluate`:
+/// ```text
+/// expr:
+///     expr_and
+///     expr or expr_and
+/// expr_and:
+///     expr_compare
+///     expr_and and expr_compare
+/// expr_compare:
+///     expr_sum
+///     expr_compare operator expr_sum
+/// expr_sum:
+///     expr_term
+///     expr_sum + expr_term
+///     expr_sum - expr_term
+/// expr_term:
+///     expr_unary
+///     expr_term * expr_unary
+///     expr_term div expr_unary
+///     expr_term mod expr_unary
+/// expr_unary:
+///     expr_primary
+///     - expr_unary
+/// expr_primary:
+///     number
+///     string
+///     ( expr )
+///     expr_function ( expr , ... )
+///     union
+/// expr_function:
+///     count
+///     sum
+///     string
+///     number
+///     boolean
+///     not
+///     true
+///     false
+///     concat
+///     contains
+///     starts-with
+///     matches
+///     normalize-space
+///     string-length
+///     substring
+///     substring-before
+///     substring-after
+///     translate
+///     local-name
+///     namespace-uri
+///     name
+///     lower-case
+///     upper-case
+/// ```
 use std::collections::{HashSet, HashMap};
 use nom::{
     IResult,
     bytes::complete::{tag, escaped},
-    character::complete::{one_of, none_of, char, anychar, space0, space1, alpha1, alphanumeric1, digit1},
+    character::complete::{none_of, char, anychar, space0, space1, alpha1, alphanumeric1, digit1},
     branch::alt,
-    sequence::{pair, tuple, delimited},
-    multi::{many0, many0_count},
-    combinator::{recognize, opt, map, value},
+    sequence::{pair, tuple, delimited, preceded},
+    multi::{many0, many0_count, separated_list0},
+    combinator::{recognize, opt, map, map_opt, value, verify},
 };
 
 #[allow(dead_code)]
 #[derive(Debug, PartialEq)]
 pub struct XPathSegment {
     pub separator: String,
+    /// axis before "::", empty for the abbreviated child or descendant step
+    pub axis: String,
     pub node: String,
     pub condition: Predictor,
 }
@@ -75,9 +214,239 @@ pub enum Predictor {
     Condition(String, Option<String>, Option<String>),
     IndexDecimal(String),
     IndexExpr(String, String),
+    Function(String, Vec<Operand>),
+    Compare(Operand, String, Operand),
+    Not(Box<Predictor>),
     None,
 }
 
+/// argument of a function in a predicate
+#[allow(dead_code)]
+#[derive(Debug, PartialEq)]
+pub enum Operand {
+    /// value looked up like the left side of `Predictor::Condition`, e.g. "@id", "text()" or a child name
+    Key(String),
+    /// string literal without quotes
+    Literal(String),
+    /// number literal
+    Number(String),
+    /// function returning a string or a number, arguments default to "text()"
+    ///
+    /// `count` and `sum` take the node test of children as `Operand::Key`.
+    Function(String, Vec<Operand>),
+    /// arithmetic operation "+", "-", "*", "div" or "mod" on numbers
+    Binary(String, Box<Operand>, Box<Operand>),
+    /// negated number
+    Negate(Box<Operand>),
+    /// string literal of the pattern of `matches`, compiled when the predicate is parsed
+    Pattern(Pattern),
+}
+
+/// compiled regular expression, equal to another one with the same source
+#[derive(Debug)]
+pub struct Pattern(regex::Regex);
+
+impl PartialEq for Pattern {
+    fn eq(&self, other:&Pattern) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl Operand {
+    /// value of the operand, `None` if the node has no such attribute or child
+    fn value(&self, info:&HashMap<String, String>) -> Option<String> {
+        match self {
+            Operand::Key(key) => info.get(key).cloned(),
+            Operand::Literal(text) | Operand::Number(text) => Some(text.clone()),
+            Operand::Pattern(pattern) => Some(pattern.0.as_str().to_string()),
+            Operand::Function(_, _) if self.aggregate().is_some() => info.get(&self.aggregate().unwrap()).cloned(),
+            Operand::Binary(op, left, right) => {
+                let (left, right) = (left.number(info), right.number(info));
+                let result = match op.as_str() {
+                    "+" => left + right,
+                    "-" => left - right,
+                    "*" => left * right,
+                    "div" => left / right,
+                    _ => left % right,
+                };
+                Some(format_number(result))
+            },
+            Operand::Negate(operand) => Some(format_number(-operand.number(info))),
+            Operand::Function(name, args) => {
+                // a missing attribute or child is an empty string
+                let mut args:Vec<String> = args.iter().map(|x| x.value(info).unwrap_or_default()).collect();
+                if args.is_empty() {
+                    args.push(info.get("text()").cloned().unwrap_or_default());
+                }
+                string_function(name, &args)
+            },
+        }
+    }
+    /// value converted to a number, NaN if it is missing or not a number
+    fn number(&self, info:&HashMap<String, String>) -> f64 {
+        self.value(info).map(|x| to_number(&x)).unwrap_or(f64::NAN)
+    }
+    /// whether the value is compared as a number
+    fn is_number(&self) -> bool {
+        match self {
+            Operand::Number(_) | Operand::Binary(_, _, _) | Operand::Negate(_) => true,
+            Operand::Key(key) => key == "position()" || key == "last()",
+            Operand::Function(name, _) => matches!(name.as_str(), "string-length" | "count" | "sum"),
+            _ => false,
+        }
+    }
+    /// key of `count` or `sum` over children, e.g. "count(item)", which is looked up like a function
+    fn aggregate(&self) -> Option<String> {
+        match self {
+            Operand::Function(name, args) if name == "count" || name == "sum" => match args.first() {
+                Some(Operand::Key(test)) => Some(format!("{}({})", name, test)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+    fn collect(&self, child:&mut HashSet<String>, attr:&mut HashSet<String>, func:&mut HashSet<String>) {
+        match self {
+            Operand::Key(key) => collect_key(key, child, attr, func),
+            Operand::Function(_, _) if self.aggregate().is_some() => {
+                func.insert(self.aggregate().unwrap());
+            },
+            Operand::Function(_, args) if args.is_empty() => collect_key("text()", child, attr, func),
+            Operand::Function(_, args) => {
+                for arg in args.iter() {
+                    arg.collect(child, attr, func);
+                }
+            },
+            Operand::Binary(_, left, right) => {
+                left.collect(child, attr, func);
+                right.collect(child, attr, func);
+            },
+            Operand::Negate(operand) => operand.collect(child, attr, func),
+            _ => {}
+        }
+    }
+}
+
+/// string converted to a number like XPath `number()`, NaN if it is not a number
+pub(crate) fn to_number(value:&str) -> f64 {
+    let value = value.trim();
+    // results of arithmetic are written by `format_number`
+    match value {
+        "Infinity" => return f64::INFINITY,
+        "-Infinity" => return f64::NEG_INFINITY,
+        _ => {}
+    }
+    // Rust also parses "inf", "NaN" and exponents, which are not XPath numbers
+    if value.is_empty() || !value.chars().all(|c| c.is_ascii_digit() || c == '.' || c == '-') {
+        return f64::NAN;
+    }
+    value.parse::<f64>().unwrap_or(f64::NAN)
+}
+
+/// range of the number of arguments of function `name`
+pub(crate) fn arity(name:&str) -> std::ops::RangeInclusive<usize> {
+    match name {
+        "true" | "false" => 0..=0,
+        "string" | "number" | "normalize-space" | "string-length" | "local-name" | "namespace-uri" | "name" => 0..=1,
+        "count" | "sum" | "boolean" | "not" | "lower-case" | "upper-case" => 1..=1,
+        "substring" => 2..=3,
+        "translate" => 3..=3,
+        "concat" => 2..=usize::MAX,
+        _ => 2..=2,
+    }
+}
+
+/// result of string function `name` on `args`, `None` if `name` is not a string function
+pub(crate) fn string_function(name:&str, args:&[String]) -> Option<String> {
+    let arg = |i:usize| args.get(i).map(|x| x.as_str()).unwrap_or("");
+    Some(match name {
+        "normalize-space" => arg(0).split_whitespace().collect::<Vec<_>>().join(" "),
+        "string-length" => arg(0).chars().count().to_string(),
+        "substring" => {
+            // positions start at 1 and are rounded, characters from `start` to before `start + length` are taken
+            let round = |x:f64| (x + 0.5).floor();
+            let start = round(to_number(arg(1)));
+            let end = args.get(2).map(|x| start + round(to_number(x))).unwrap_or(f64::INFINITY);
+            arg(0)
+                .chars()
+                .enumerate()
+                .filter(|(i, _)| (*i + 1) as f64 >= start && ((*i + 1) as f64) < end)
+                .map(|x| x.1)
+                .collect()
+        },
+        "lower-case" => arg(0).to_lowercase(),
+        "upper-case" => arg(0).to_uppercase(),
+        "substring-before" => arg(0).split_once(arg(1)).map(|x| x.0.to_string()).unwrap_or_default(),
+        "substring-after" => arg(0).split_once(arg(1)).map(|x| x.1.to_string()).unwrap_or_default(),
+        "translate" => {
+            // characters of the second argument are replaced by the character at the same position of the third,
+            // or removed if the third is shorter
+            let from:Vec<char> = arg(1).chars().collect();
+            let to:Vec<char> = arg(2).chars().collect();
+            arg(0)
+                .chars()
+                .filter_map(|c| match from.iter().position(|&x| x == c) {
+                    Some(i) => to.get(i).copied(),
+                    None => Some(c),
+                })
+                .collect()
+        },
+        _ => return None,
+    })
+}
+
+/// result of boolean function `name` on `args`
+pub(crate) fn boolean_function(name:&str, args:&[String]) -> bool {
+    match name {
+        "contains" => args[0].contains(args[1].as_str()),
+        "starts-with" => args[0].starts_with(args[1].as_str()),
+        "matches" => regex::Regex::new(&args[1]).map(|x| x.is_match(&args[0])).unwrap_or(false),
+        "true" => true,
+        _ => false,
+    }
+}
+
+/// whether `op` compares order, which converts both sides to numbers
+pub(crate) fn is_relational(op:&str) -> bool {
+    matches!(op, "<" | "<=" | ">" | ">=")
+}
+
+/// compare numbers with `op`, NaN is not equal to anything, even NaN
+pub(crate) fn compare_numbers(left:f64, op:&str, right:f64) -> bool {
+    match op {
+        "==" => left == right,
+        "!=" => left != right,
+        "<" => left < right,
+        "<=" => left <= right,
+        ">" => left > right,
+        _ => left >= right,
+    }
+}
+
+/// number as a string, integers are written without fraction
+pub(crate) fn format_number(value:f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value.is_infinite() {
+        if value > 0.0 { "Infinity".to_string() } else { "-Infinity".to_string() }
+    } else if value == 0.0 {
+        "0".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+/// sort `key` of predicates into child names, attribute names and functions
+fn collect_key(key:&str, child:&mut HashSet<String>, attr:&mut HashSet<String>, func:&mut HashSet<String>) {
+    if key.starts_with("@") {
+        attr.insert(key.get(1..).unwrap().to_string());
+    } else if key.ends_with("()") {
+        func.insert(key.to_string());
+    } else {
+        child.insert(key.to_string());
+    }
+}
+
 impl Predictor {
     #[allow(dead_code)]
     pub fn collect(&self) -> (Vec<String>, Vec<String>, Vec<String>) {
@@ -95,6 +464,12 @@ impl Predictor {
                 attr.extend(a2);
                 func.extend(f2);
             },
+            Predictor::Not(ref inner) => {
+                let (c1, a1, f1) = inner.collect();
+                child.extend(c1);
+                attr.extend(a1);
+                func.extend(f1);
+            },
             Predictor::Or(ref left, ref right) => {
                 let (c1, a1, f1) = left.collect();
                 child.extend(c1);
@@ -106,14 +481,17 @@ impl Predictor {
                 func.extend(f2);
             },
             Predictor::Condition(ref left, _, _) => {
-                if left.starts_with("@") {
-                    attr.insert(left.get(1..).unwrap().to_string());
-                } else if left.ends_with("()") {
-                    func.insert(left.to_string());
-                } else {
-                    child.insert(left.to_string());
+                collect_key(left, &mut child, &mut attr, &mut func);
+            },
+            Predictor::Function(_, ref args) => {
+                for arg in args.iter() {
+                    arg.collect(&mut child, &mut attr, &mut func);
                 }
             },
+            Predictor::Compare(ref left, _, ref right) => {
+                left.collect(&mut child, &mut attr, &mut func);
+                right.collect(&mut child, &mut attr, &mut func);
+            },
             Predictor::IndexExpr(_, _) => {
                 func.insert("last()".to_string());
             },
@@ -127,42 +505,71 @@ impl Predictor {
         func.sort();
         (child, attr, func)
     }
+    /// attribute name and value of a condition `@attrname='value'` which must hold for the predicate to be true
     #[allow(dead_code)]
-    pub fn expr(&self, info:&HashMap<String, String>) -> String {
+    pub fn attr_equals(&self) -> Option<(String, String)> {
         match self {
-            Predictor::And(ref left, ref right) => {
-                format!("({}) && ({})", left.expr(info), right.expr(info))
+            Predictor::And(ref left, ref right) => left.attr_equals().or_else(|| right.attr_equals()),
+            Predictor::Condition(ref left, Some(ref op), Some(ref right)) if op == "==" && right.starts_with('\'') => {
+                let name = left.strip_prefix('@').filter(|x| *x != "*")?;
+                Some((name.to_string(), literal(right).map(|x| x.1).unwrap_or_else(|_| right.clone())))
             },
-            Predictor::Or(ref left, ref right) => {
-                format!("({}) || ({})", left.expr(info), right.expr(info))
-            },
-            Predictor::Condition(ref left, ref op, ref right) => {
-                if info.contains_key(left) {
-                    if op.is_none() || right.is_none() {
-                        "true".to_string()
+            _ => None,
+        }
+    }
+    /// whether the node described by `info` satisfies the predicate, a missing attribute or child is false
+    #[allow(dead_code)]
+    pub fn matches(&self, info:&HashMap<String, String>) -> bool {
+        match self {
+            Predictor::And(ref left, ref right) => left.matches(info) && right.matches(info),
+            Predictor::Or(ref left, ref right) => left.matches(info) || right.matches(info),
+            Predictor::Not(ref inner) => !inner.matches(info),
+            Predictor::Condition(ref left, ref op, ref right) => match (info.get(left), op, right) {
+                (None, _, _) => false,
+                // "@*" is looked up as whether the node has attributes
+                (Some(value), _, _) if left == "@*" => value == "true",
+                (Some(_), None, _) | (Some(_), _, None) => true,
+                (Some(value), Some(op), Some(right)) => {
+                    let literal = literal(right).map(|x| x.1).unwrap_or_else(|_| right.clone());
+                    if is_relational(op) || !right.starts_with('\'') {
+                        // compared as numbers, a quoted string is converted as well
+                        compare_numbers(to_number(value), op, to_number(&literal))
                     } else {
-                        format!("'{}' {} {}", escape_info(info.get(left).unwrap()).unwrap().1, op.as_ref().unwrap(), right.as_ref().unwrap())
+                        (*value == literal) == (op == "==")
                     }
-                } else {
-                    "false".to_string()
-                }
+                },
             },
             Predictor::IndexDecimal(ref left) => {
                 debug_assert!(info.contains_key("position()"));
-                format!("{} == {}", info.get("position()").unwrap(), left)
+                info.get("position()").map(|x| to_number(x)) == Some(to_number(left))
             },
             Predictor::IndexExpr(ref left, ref right) => {
                 debug_assert!(info.contains_key("position()"));
                 debug_assert!(info.contains_key("last()"));
-                if right == "" {
-                    format!("{} == {}", info.get("position()").unwrap(), info.get(left).unwrap())
-                } else {
-                    format!("{} == {} - {}", info.get("position()").unwrap(), info.get(left).unwrap(), right)
+                let position = info.get("position()").map(|x| to_number(x)).unwrap_or(f64::NAN);
+                let last = info.get(left).map(|x| to_number(x)).unwrap_or(f64::NAN);
+                let offset = if right.is_empty() { 0.0 } else { to_number(right) };
+                position == last - offset
+            },
+            Predictor::Function(ref name, ref args) => match args.as_slice() {
+                [text, Operand::Pattern(pattern)] => pattern.0.is_match(&text.value(info).unwrap_or_default()),
+                _ => {
+                    // a missing attribute or child is an empty string
+                    let args:Vec<String> = args.iter().map(|x| x.value(info).unwrap_or_default()).collect();
+                    boolean_function(name, &args)
+                },
+            },
+            Predictor::Compare(ref left, ref op, ref right) => {
+                match (left.value(info), right.value(info)) {
+                    (Some(l), Some(r)) if left.is_number() || right.is_number() || is_relational(op) => {
+                        // both sides are compared as numbers, a value which is not a number matches nothing
+                        compare_numbers(to_number(&l), op, to_number(&r))
+                    },
+                    (Some(l), Some(r)) => (l == r) == (op == "=="),
+                    _ => false,
                 }
             },
-            _ => {
-                "true".to_string()
-            }
+            Predictor::None => true,
         }
     }
 }
@@ -180,6 +587,10 @@ fn decimal(input:&str) -> IResult<&str, &str> {
     digit1(input)
 }
 
+fn number(input:&str) -> IResult<&str, &str> {
+    recognize(pair(digit1, opt(pair(char('.'), digit1))))(input)
+}
+
 fn name(input:&str) -> IResult<&str, &str> {
     recognize(pair(
             alt((alpha1, tag("_"), tag(":"))),
@@ -187,6 +598,14 @@ fn name(input:&str) -> IResult<&str, &str> {
     ))(input)
 }
 
+/// name without prefix
+fn ncname(input:&str) -> IResult<&str, &str> {
+    recognize(pair(
+            alt((alpha1, tag("_"))),
+            many0_count(alt((alphanumeric1, tag("_"), tag("-"), tag(".")))),
+    ))(input)
+}
+
 fn separator(input:&str) -> IResult<&str, &str> {
     alt((
             tag("//"),
@@ -205,14 +624,132 @@ fn operator(input:&str) -> IResult<&str, &str> {
     ))(input)
 }
 
+/// string literal in single or double quotes, the other quote, brackets and slashes need no escape
 fn string(input:&str) -> IResult<&str, &str> {
-    recognize(delimited(
-            tag("'"),
-            many0_count(escaped(none_of("'\\"), '\\', one_of(r#"\'"#))),
-            tag("'"),
+    alt((
+            recognize(delimited(
+                    tag("'"),
+                    many0_count(escaped(none_of("'\\"), '\\', anychar)),
+                    tag("'"),
+            )),
+            recognize(delimited(
+                    tag("\""),
+                    many0_count(escaped(none_of("\"\\"), '\\', anychar)),
+                    tag("\""),
+            )),
+    ))(input)
+}
+
+/// content of a string literal, other escapes than "\\", "\'" and "\"" are kept, e.g. "\d" of a regular expression
+fn literal(input:&str) -> IResult<&str, String> {
+    map(string, |t| {
+        let mut out = String::new();
+        let mut chars = t[1..t.len() - 1].chars();
+        while let Some(c) = chars.next() {
+            match (c, chars.clone().next()) {
+                ('\\', Some(next)) if next == '\\' || next == '\'' || next == '"' => out.extend(chars.next()),
+                (c, _) => out.push(c),
+            }
+        }
+        out
+    })(input)
+}
+
+/// string literal rewritten in single quotes, which is how `Predictor::Condition` keeps a string apart from a number
+fn quoted(input:&str) -> IResult<&str, String> {
+    map(literal, |t| format!("'{}'", escape_info(&t).unwrap().1))(input)
+}
+
+fn value_function(input:&str) -> IResult<&str, Operand> {
+    map(
+        verify(
+            tuple((
+                alt((
+                    tag("normalize-space"),
+                    tag("string-length"),
+                    tag("substring-before"),
+                    tag("substring-after"),
+                    tag("substring"),
+                    tag("translate"),
+                    tag("lower-case"),
+                    tag("upper-case"),
+                )),
+                space0, tag("("), space0, separated_list0(tuple((space0, tag(","), space0)), operand), space0, tag(")"),
+            )),
+            |t| arity(t.0).contains(&t.4.len())
+        ),
+        |t| Operand::Function(t.0.to_string(), t.4)
+    )(input)
+}
+
+fn aggregate(input:&str) -> IResult<&str, Operand> {
+    map(
+        tuple((alt((tag("count"), tag("sum"))), space0, tag("("), space0, alt((tag("*"), node_test)), space0, tag(")"))),
+        |t| Operand::Function(t.0.to_string(), vec![Operand::Key(t.4.to_string())])
+    )(input)
+}
+
+fn primary(input:&str) -> IResult<&str, Operand> {
+    alt((
+            value_function,
+            aggregate,
+            map(tag("text()"), |t:&str| Operand::Key(t.to_string())),
+            map(tag("."), |_| Operand::Key("text()".to_string())),
+            map(recognize(pair(tag("@"), name)), |t| Operand::Key(t.to_string())),
+            map(
+                alt((tag("position()"), tag("last()"), tag("local-name()"), tag("namespace-uri()"), tag("name()"))),
+                |t:&str| Operand::Key(t.to_string())
+            ),
+            map(name, |t| Operand::Key(t.to_string())),
+            map(literal, Operand::Literal),
+            map(number, |t| Operand::Number(t.to_string())),
+            map(tuple((tag("("), space0, operand, space0, tag(")"))), |t| t.2),
     ))(input)
 }
 
+fn unary(input:&str) -> IResult<&str, Operand> {
+    alt((
+            map(pair(pair(tag("-"), space0), unary), |t| Operand::Negate(Box::new(t.1))),
+            primary,
+    ))(input)
+}
+
+/// fold `first` and following (operator, operand) pairs from left to right
+fn fold_binary(first:Operand, rest:Vec<(&str, Operand)>) -> Operand {
+    rest.into_iter().fold(first, |left, (op, right)| Operand::Binary(op.to_string(), Box::new(left), Box::new(right)))
+}
+
+fn term(input:&str) -> IResult<&str, Operand> {
+    map(
+        pair(unary, many0(pair(delimited(space0, alt((tag("*"), tag("div"), tag("mod"))), space0), unary))),
+        |t| fold_binary(t.0, t.1)
+    )(input)
+}
+
+fn operand(input:&str) -> IResult<&str, Operand> {
+    map(
+        pair(term, many0(pair(delimited(space0, alt((tag("+"), tag("-"))), space0), term))),
+        |t| fold_binary(t.0, t.1)
+    )(input)
+}
+
+fn function(input:&str) -> IResult<&str, Predictor> {
+    map_opt(
+        tuple((
+            alt((tag("contains"), tag("starts-with"), tag("matches"))),
+            space0, tag("("), space0, operand, space0, tag(","), space0, operand, space0, tag(")"),
+        )),
+        |t| {
+            let pattern = match (t.0, t.8) {
+                // a pattern which is known in advance must be a valid regular expression, and is compiled once
+                ("matches", Operand::Literal(pattern)) => Operand::Pattern(Pattern(regex::Regex::new(&pattern).ok()?)),
+                (_, operand) => operand,
+            };
+            Some(Predictor::Function(t.0.to_string(), vec![t.4, pattern]))
+        }
+    )(input)
+}
+
 fn index(input:&str) -> IResult<&str, Predictor> {
     alt((
             map(decimal, |t| Predictor::IndexDecimal(t.to_string())),
@@ -223,10 +760,15 @@ fn index(input:&str) -> IResult<&str, Predictor> {
 
 fn condition(input:&str) -> IResult<&str, Predictor> {
     alt((
-            map(tuple((name, space0, operator, space0, string)), |t| Predictor::Condition(t.0.to_string(), Some(t.2.to_string()), Some(t.4.to_string()))),
-            map(tuple((tag("@"), name, space0, operator, space0, string)), |t| Predictor::Condition(format!("@{}", t.1), Some(t.3.to_string()), Some(t.5.to_string()))),
-            map(tuple((tag("text()"), space0, operator, space0, string)), |t| Predictor::Condition(t.0.to_string(), Some(t.2.to_string()), Some(t.4.to_string()))),
+            function,
+            map(tuple((tag("not"), space0, tag("("), space0, conditions_or, space0, tag(")"))), |t| Predictor::Not(Box::new(t.4))),
+            map(alt((tag("true()"), tag("false()"))), |t:&str| Predictor::Function(t.trim_end_matches("()").to_string(), Vec::new())),
+            map(tuple((name, space0, operator, space0, quoted)), |t| Predictor::Condition(t.0.to_string(), Some(t.2.to_string()), Some(t.4))),
+            map(tuple((tag("@"), name, space0, operator, space0, quoted)), |t| Predictor::Condition(format!("@{}", t.1), Some(t.3.to_string()), Some(t.5))),
+            map(tuple((tag("text()"), space0, operator, space0, quoted)), |t| Predictor::Condition(t.0.to_string(), Some(t.2.to_string()), Some(t.4))),
             map(tuple((tag("position()"), space0, operator, space0, decimal)), |t| Predictor::Condition(t.0.to_string(), Some(t.2.to_string()), Some(t.4.to_string()))),
+            map(tuple((operand, space0, operator, space0, operand)), |t| Predictor::Compare(t.0, t.2.to_string(), t.4)),
+            map(pair(tag("child::"), name), |t| Predictor::Condition(t.1.to_string(), None, None)),
             map(name, |t| Predictor::Condition(t.to_string(), None, None)),
             map(pair(tag("@"), name), |t| Predictor::Condition(format!("{}{}", t.0, t.1), None, None)),
             map(tag("@*"), |t:&str| Predictor::Condition(t.to_string(), None, None)),
@@ -250,50 +792,97 @@ fn conditions_or(input:&str) -> IResult<&str, Predictor> {
     ))(input)
 }
 
+fn node_test(input:&str) -> IResult<&str, &str> {
+    alt((
+            tag("comment()"),
+            tag("text()"),
+            recognize(tuple((tag("processing-instruction("), space0, opt(string), space0, tag(")")))),
+            tag("node()"),
+            recognize(pair(ncname, tag(":*"))),
+            recognize(pair(tag("*:"), ncname)),
+            name,
+    ))(input)
+}
+
+fn axis(input:&str) -> IResult<&str, &str> {
+    alt((
+            tag("ancestor-or-self"),
+            tag("ancestor"),
+            tag("following-sibling"),
+            tag("preceding-sibling"),
+            tag("following"),
+            tag("preceding"),
+            tag("descendant-or-self"),
+            tag("descendant"),
+            tag("child"),
+            tag("parent"),
+            tag("self"),
+    ))(input)
+}
+
 fn element(input:&str) -> IResult<&str, XPathSegment> {
+    alt((
+            map(tuple((axis, tag("::"), step)), |mut t| {
+                t.2.axis = t.0.to_string();
+                t.2
+            }),
+            step,
+    ))(input)
+}
+
+fn step(input:&str) -> IResult<&str, XPathSegment> {
     alt((
             map(tag(".."), |t:&str| XPathSegment {
                 separator: "".to_string(),
+                axis: "".to_string(),
                 node: t.to_string(),
                 condition: Predictor::None,
             }),
             map(tag("."), |t:&str| XPathSegment {
                 separator: "".to_string(),
+                axis: "".to_string(),
                 node: t.to_string(),
                 condition: Predictor::None,
             }),
             map(recognize(pair(tag("@"), name)), |t| XPathSegment {
                 separator: "".to_string(),
+                axis: "".to_string(),
                 node: "*".to_string(),
                 condition: Predictor::Condition(t.to_string(), None, None),
             }),
-            map(tuple((name, tag("["), space0, conditions_or, space0, tag("]"))), |t| XPathSegment {
+            map(tuple((node_test, tag("["), space0, conditions_or, space0, tag("]"))), |t| XPathSegment {
                 separator: "".to_string(),
+                axis: "".to_string(),
                 node: t.0.to_string(),
                 condition: t.3,
             }),
-            map(tuple((name, tag("["), space0, index, space0, tag("]"))), |t| XPathSegment {
+            map(tuple((node_test, tag("["), space0, index, space0, tag("]"))), |t| XPathSegment {
                 separator: "".to_string(),
+                axis: "".to_string(),
                 node: t.0.to_string(),
                 condition: t.3,
             }),
             map(tuple((tag("*["), space0, conditions_or, space0, tag("]"))), |t| XPathSegment {
                 separator: "".to_string(),
+                axis: "".to_string(),
                 node: "*".to_string(),
                 condition: t.2,
             }),
             map(tuple((tag("*["), space0, index, space0, tag("]"))), |t| XPathSegment {
                 separator: "".to_string(),
+                axis: "".to_string(),
                 node: "*".to_string(),
                 condition: t.2,
             }),
-            map(tag("*"), |t:&str| XPathSegment {
+            map(node_test, |t| XPathSegment {
                 separator: "".to_string(),
+                axis: "".to_string(),
                 node: t.to_string(),
                 condition: Predictor::None,
             }),
-            map(name, |t| XPathSegment {
+            map(tag("*"), |t:&str| XPathSegment {
                 separator: "".to_string(),
+                axis: "".to_string(),
                 node: t.to_string(),
                 condition: Predictor::None,
             }),
@@ -315,6 +904,274 @@ pub fn xpath(input:&str) -> IResult<&str, Vec<XPathSegment>> {
     Ok((remaining, segments))
 }
 
+/// invalid part of an expression: start and end byte and the reason
+pub type SyntaxError = (usize, usize, String);
+
+/// byte length of the bracketed part at the start of `input` up to the closing bracket, quoted strings are skipped;
+/// `Err` with the offset of an unterminated string or `None` if the bracket is not closed
+fn closing(input:&str) -> Result<Option<usize>, usize> {
+    let mut depth = 0;
+    let mut chars = input.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '[' | '(' => depth += 1,
+            ']' | ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Ok(Some(i + 1));
+                }
+            },
+            '\'' | '"' => {
+                let mut closed = false;
+                while let Some((_, next)) = chars.next() {
+                    if next == '\\' {
+                        chars.next();
+                    } else if next == c {
+                        closed = true;
+                        break;
+                    }
+                }
+                if !closed {
+                    return Err(i);
+                }
+            },
+            _ => {},
+        }
+    }
+    Ok(None)
+}
+
+/// explain why parsing stopped at byte `pos` of `input`, `expected` is what may follow a complete part
+fn diagnose(input:&str, pos:usize, expected:&str) -> SyntaxError {
+    let rest = &input[pos..];
+    let bracket = match rest.chars().next() {
+        None => return (pos, pos, format!("unexpected end, expected {}", expected)),
+        Some('[') => "predicate",
+        Some('(') => "parenthesis",
+        Some(c) if c == '\'' || c == '"' => match closing(rest) {
+            Err(_) => return (pos, input.len(), "unterminated string literal".to_string()),
+            Ok(_) => return (pos, pos + 1, format!("unexpected string literal, expected {}", expected)),
+        },
+        Some(c) => return (pos, pos + c.len_utf8(), format!("unexpected {:?}, expected {}", c, expected)),
+    };
+    match closing(rest) {
+        Ok(Some(len)) => (pos, pos + len, format!("invalid {}", bracket)),
+        Ok(None) => (pos, input.len(), format!("unterminated {}", bracket)),
+        Err(quote) => (pos + quote, input.len(), "unterminated string literal".to_string()),
+    }
+}
+
+/// position of the error of nom in `input`
+fn error_position(input:&str, e:nom::Err<nom::error::Error<&str>>) -> usize {
+    match e {
+        nom::Err::Error(e) | nom::Err::Failure(e) => input.len() - e.input.len(),
+        nom::Err::Incomplete(_) => input.len(),
+    }
+}
+
+/// parse the whole `input` into paths separated by "|", return the invalid part and the reason if it is not valid
+pub fn parse(input:&str) -> Result<Vec<Vec<XPathSegment>>, SyntaxError> {
+    let mut paths = Vec::new();
+    let mut rest = input;
+    loop {
+        let start = input.len() - rest.len();
+        let (remaining, segments) = match xpath(rest) {
+            Ok(result) => result,
+            Err(e) => {
+                let pos = error_position(input, e);
+                return Err(diagnose(input, pos, "a step"));
+            },
+        };
+        if segments.is_empty() {
+            return match rest.chars().next() {
+                None | Some('|') => Err((start, start, "expected a path".to_string())),
+                _ => Err(diagnose(input, start, "a path")),
+            };
+        }
+        // a name may contain ":", so an unknown axis is read as a name
+        for segment in segments.iter() {
+            if let Some((axis, _)) = segment.node.split_once("::") {
+                let pos = start + rest.find(segment.node.as_str()).unwrap_or(0);
+                return Err((pos, pos + axis.len(), format!("unknown axis {:?}", axis)));
+            }
+        }
+        paths.push(segments);
+        match tuple((space0::<&str, ()>, char('|'), space0))(remaining) {
+            Ok((next, _)) => rest = next,
+            Err(_) if remaining.is_empty() => return Ok(paths),
+            Err(_) => return Err(diagnose(input, input.len() - remaining.len(), "'/', '//' or '|'")),
+        }
+    }
+}
+
+/// expression evaluated by `ETree::evaluate`
+#[allow(dead_code)]
+#[derive(Debug, PartialEq)]
+pub enum Expr {
+    /// path or union of paths selecting nodes
+    Path(String),
+    /// attribute `name` of the nodes selected by the path, e.g. "/cfg/@version"
+    Attribute(String, String),
+    /// texts directly in the nodes selected by the path, e.g. "//p/text()"
+    Text(String),
+    /// string literal without quotes
+    Literal(String),
+    /// number literal
+    Number(String),
+    /// function call with its arguments
+    Function(String, Vec<Expr>),
+    /// "or", "and", comparison or arithmetic operation
+    Binary(String, Box<Expr>, Box<Expr>),
+    /// negated number
+    Negate(Box<Expr>),
+}
+
+/// fold `first` and following (operator, operand) pairs from left to right
+fn fold_expr(first:Expr, rest:Vec<(&str, Expr)>) -> Expr {
+    rest.into_iter().fold(first, |left, (op, right)| Expr::Binary(op.to_string(), Box::new(left), Box::new(right)))
+}
+
+fn expr_function(input:&str) -> IResult<&str, &str> {
+    alt((
+            alt((
+                    tag("count"),
+                    tag("sum"),
+                    tag("string-length"),
+                    tag("string"),
+                    tag("number"),
+                    tag("boolean"),
+                    tag("not"),
+                    tag("true"),
+                    tag("false"),
+                    tag("local-name"),
+                    tag("namespace-uri"),
+                    tag("name"),
+            )),
+            alt((
+                    tag("concat"),
+                    tag("contains"),
+                    tag("starts-with"),
+                    tag("matches"),
+                    tag("normalize-space"),
+                    tag("substring-before"),
+                    tag("substring-after"),
+                    tag("substring"),
+                    tag("translate"),
+                    tag("lower-case"),
+                    tag("upper-case"),
+            )),
+    ))(input)
+}
+
+fn path_expr(input:&str) -> IResult<&str, Expr> {
+    let (remaining, segments) = verify(xpath, |t:&Vec<XPathSegment>| !t.is_empty())(input)?;
+    let (rest, unions) = many0_count(preceded(
+        tuple((space0, char('|'), space0)),
+        verify(xpath, |t:&Vec<XPathSegment>| !t.is_empty()),
+    ))(remaining)?;
+    let text = &input[..input.len() - remaining.len()];
+    if unions > 0 {
+        return Ok((rest, Expr::Path(input[..input.len() - rest.len()].to_string())));
+    }
+    // a trailing "@name" or "text()" step selects the attribute or the texts of the nodes before it
+    match segments.last() {
+        Some(XPathSegment { axis, node, condition: Predictor::Condition(key, None, None), .. })
+            if axis.is_empty() && node == "*" && key.starts_with('@') && text.ends_with(key.as_str()) => {
+            Ok((rest, Expr::Attribute(owner_path(&text[..text.len() - key.len()]), key[1..].to_string())))
+        },
+        Some(XPathSegment { axis, node, condition: Predictor::None, .. })
+            if axis.is_empty() && node == "text()" && text.ends_with("text()") => {
+            Ok((rest, Expr::Text(owner_path(&text[..text.len() - "text()".len()]))))
+        },
+        _ => Ok((rest, Expr::Path(text.to_string()))),
+    }
+}
+
+/// path of the elements owning attributes or texts, `path` is the expression before the "@name" or "text()" step
+fn owner_path(path:&str) -> String {
+    if path.ends_with("//") {
+        format!("{}descendant-or-self::*", path)
+    } else if path.is_empty() {
+        ".".to_string()
+    } else {
+        path.trim_end_matches('/').to_string()
+    }
+}
+
+fn expr_primary(input:&str) -> IResult<&str, Expr> {
+    alt((
+            map(number, |t| Expr::Number(t.to_string())),
+            map(literal, Expr::Literal),
+            map(tuple((tag("("), space0, expr, space0, tag(")"))), |t| t.2),
+            map(
+                verify(
+                    tuple((
+                        expr_function, space0, tag("("), space0,
+                        separated_list0(tuple((space0, tag(","), space0)), expr),
+                        space0, tag(")"),
+                    )),
+                    |t| arity(t.0).contains(&t.4.len())
+                ),
+                |t| Expr::Function(t.0.to_string(), t.4)
+            ),
+            path_expr,
+    ))(input)
+}
+
+fn expr_unary(input:&str) -> IResult<&str, Expr> {
+    alt((
+            map(pair(pair(tag("-"), space0), expr_unary), |t| Expr::Negate(Box::new(t.1))),
+            expr_primary,
+    ))(input)
+}
+
+fn expr_term(input:&str) -> IResult<&str, Expr> {
+    map(
+        pair(expr_unary, many0(pair(delimited(space0, alt((tag("*"), tag("div"), tag("mod"))), space0), expr_unary))),
+        |t| fold_expr(t.0, t.1)
+    )(input)
+}
+
+fn expr_sum(input:&str) -> IResult<&str, Expr> {
+    map(
+        pair(expr_term, many0(pair(delimited(space0, alt((tag("+"), tag("-"))), space0), expr_term))),
+        |t| fold_expr(t.0, t.1)
+    )(input)
+}
+
+fn expr_compare(input:&str) -> IResult<&str, Expr> {
+    map(
+        pair(expr_sum, many0(pair(delimited(space0, operator, space0), expr_sum))),
+        |t| fold_expr(t.0, t.1)
+    )(input)
+}
+
+fn expr_and(input:&str) -> IResult<&str, Expr> {
+    map(
+        pair(expr_compare, many0(pair(delimited(space1, tag("and"), space1), expr_compare))),
+        |t| fold_expr(t.0, t.1)
+    )(input)
+}
+
+fn expr(input:&str) -> IResult<&str, Expr> {
+    map(
+        pair(expr_and, many0(pair(delimited(space1, tag("or"), space1), expr_and))),
+        |t| fold_expr(t.0, t.1)
+    )(input)
+}
+
+/// parse the whole `input` into an expression, return the invalid part and the reason if it is not valid
+pub fn parse_expr(input:&str) -> Result<Expr, SyntaxError> {
+    match delimited(space0, expr, space0)(input) {
+        Ok(("", result)) => Ok(result),
+        Ok((remaining, _)) => Err(diagnose(input, input.len() - remaining.len(), "an operator")),
+        Err(e) => {
+            let pos = error_position(input, e);
+            Err(diagnose(input, pos, "an expression"))
+        },
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -347,6 +1204,9 @@ mod tests {
     fn test_string() {
         assert_eq!(string("'ab''"), Ok(("'", "'ab'")));
         assert_eq!(string(r"'ab\'''"), Ok(("'", r"'ab\''")));
+        assert_eq!(string(r#""it's \"a[1]/b\"""#), Ok(("", r#""it's \"a[1]/b\"""#)));
+        assert_eq!(literal(r#""it's \"x\"""#), Ok(("", r#"it's "x""#.to_string())));
+        assert_eq!(quoted(r#""it's""#), Ok(("", r"'it\'s'".to_string())));
     }
     #[test]
     fn test_index() {
@@ -362,10 +1222,159 @@ mod tests {
         assert_eq!(condition("@attr"), Ok(("", Predictor::Condition("@attr".to_string(), None, None))));
         assert_eq!(condition("@attr  = 'aa'"), Ok(("", Predictor::Condition("@attr".to_string(), Some("==".to_string()), Some("'aa'".to_string())))));
         assert_eq!(condition("text()!= 'aa'"), Ok(("", Predictor::Condition("text()".to_string(), Some("!=".to_string()), Some("'aa'".to_string())))));
+        assert_eq!(condition("@href=\"a[1]/b\""), Ok(("", Predictor::Condition("@href".to_string(), Some("==".to_string()), Some("'a[1]/b'".to_string())))));
         assert_eq!(condition("position()>= 7a"), Ok(("a", Predictor::Condition("position()".to_string(), Some(">=".to_string()), Some("7".to_string())))));
         assert_eq!(condition("(position()>= 7 )a"), Ok(("a", Predictor::Condition("position()".to_string(), Some(">=".to_string()), Some("7".to_string())))));
     }
     #[test]
+    fn test_function() {
+        assert_eq!(condition("contains(@name, 'fo\\'o')"), Ok(("", Predictor::Function("contains".to_string(), vec![
+                Operand::Key("@name".to_string()),
+                Operand::Literal("fo'o".to_string()),
+        ]))));
+        assert_eq!(condition("starts-with(.,'a')]"), Ok(("]", Predictor::Function("starts-with".to_string(), vec![
+                Operand::Key("text()".to_string()),
+                Operand::Literal("a".to_string()),
+        ]))));
+        let pred = Predictor::Function("contains".to_string(), vec![Operand::Key("@k".to_string()), Operand::Literal("b".to_string())]);
+        let mut info = HashMap::new();
+        assert!(!pred.matches(&info));
+        info.insert("@k".to_string(), "abc".to_string());
+        assert!(pred.matches(&info));
+        assert_eq!(pred.collect(), (Vec::new(), vec!["k".to_string()], Vec::new()));
+    }
+    #[test]
+    fn test_matches() {
+        assert_eq!(condition(r"matches(text(), '^[A-Z]{3}\d+$')"), Ok(("", Predictor::Function("matches".to_string(), vec![
+                Operand::Key("text()".to_string()),
+                Operand::Pattern(Pattern(regex::Regex::new(r"^[A-Z]{3}\d+$").unwrap())),
+        ]))));
+        assert_eq!(literal(r"'a\'b\\c\.'"), Ok(("", r"a'b\c\.".to_string())));
+        assert!(function("matches(., '[a-')").is_err());
+        let pred = Predictor::Function("matches".to_string(), vec![Operand::Key("text()".to_string()), Operand::Key("@re".to_string())]);
+        let mut info = HashMap::new();
+        info.insert("text()".to_string(), "ABC12".to_string());
+        info.insert("@re".to_string(), "^[A-Z]+\\d+$".to_string());
+        assert!(pred.matches(&info));
+        info.insert("@re".to_string(), "(".to_string());
+        assert!(!pred.matches(&info));
+    }
+    #[test]
+    fn test_value_function() {
+        assert_eq!(condition("normalize-space( )='a'"), Ok(("", Predictor::Compare(
+                Operand::Function("normalize-space".to_string(), Vec::new()),
+                "==".to_string(),
+                Operand::Literal("a".to_string()),
+        ))));
+        let (_, pred) = condition("string-length(normalize-space(@k)) > 3").unwrap();
+        let mut info = HashMap::new();
+        assert!(!pred.matches(&info));
+        info.insert("@k".to_string(), "  a  b ".to_string());
+        assert!(!pred.matches(&info));
+        assert_eq!(pred.collect(), (Vec::new(), vec!["k".to_string()], Vec::new()));
+    }
+    #[test]
+    fn test_name_function() {
+        assert_eq!(condition("local-name()='svg'"), Ok(("", Predictor::Compare(
+                Operand::Key("local-name()".to_string()),
+                "==".to_string(),
+                Operand::Literal("svg".to_string()),
+        ))));
+        let (_, pred) = conditions_or("namespace-uri() = 'urn:a' and name()!='a:b'").unwrap();
+        assert_eq!(pred.collect(), (Vec::new(), Vec::new(), vec!["name()".to_string(), "namespace-uri()".to_string()]));
+        assert_eq!(parse_expr("local-name(//a)"), Ok(Expr::Function("local-name".to_string(), vec![Expr::Path("//a".to_string())])));
+        assert_eq!(parse_expr("name"), Ok(Expr::Path("name".to_string())));
+    }
+    #[test]
+    fn test_string_function() {
+        let call = |name:&str, args:&[&str]| string_function(name, &args.iter().map(|x| x.to_string()).collect::<Vec<_>>());
+        assert_eq!(call("substring", &["12345", "2", "3"]), Some("234".to_string()));
+        assert_eq!(call("substring", &["12345", "1.5", "2.6"]), Some("234".to_string()));
+        assert_eq!(call("substring", &["12345", "0", "3"]), Some("12".to_string()));
+        assert_eq!(call("substring", &["12345", "3"]), Some("345".to_string()));
+        assert_eq!(call("substring", &["12345", "x", "3"]), Some("".to_string()));
+        assert_eq!(call("substring-before", &["2024-05-01", "-"]), Some("2024".to_string()));
+        assert_eq!(call("substring-after", &["2024-05-01", "-"]), Some("05-01".to_string()));
+        assert_eq!(call("substring-after", &["2024", "/"]), Some("".to_string()));
+        assert_eq!(call("translate", &["bar", "abc", "ABC"]), Some("BAr".to_string()));
+        assert_eq!(call("translate", &["--aaa--", "abc-", "ABC"]), Some("AAA".to_string()));
+        assert_eq!(call("lower-case", &["Title ÄB"]), Some("title äb".to_string()));
+        assert_eq!(call("upper-case", &["Title"]), Some("TITLE".to_string()));
+        assert_eq!(call("contains", &["a", "b"]), None);
+        assert!(value_function("substring(@k)").is_err());
+        assert!(value_function("translate(., 'a', 'b')").is_ok());
+        let (_, pred) = condition("substring-before(@date,'-')='2024'").unwrap();
+        let mut info = HashMap::new();
+        info.insert("@date".to_string(), "2024-05-01".to_string());
+        assert!(pred.matches(&info));
+    }
+    #[test]
+    fn test_aggregate() {
+        let (remaining, pred) = condition("count(item) > 3").unwrap();
+        assert_eq!(remaining, "");
+        assert_eq!(pred.collect(), (Vec::new(), Vec::new(), vec!["count(item)".to_string()]));
+        let mut info = HashMap::new();
+        info.insert("count(item)".to_string(), "4".to_string());
+        assert!(pred.matches(&info));
+        let (_, pred) = condition("sum(*)= count(a)").unwrap();
+        assert_eq!(pred.collect(), (Vec::new(), Vec::new(), vec!["count(a)".to_string(), "sum(*)".to_string()]));
+    }
+    #[test]
+    fn test_arithmetic() {
+        assert_eq!(operand("@a - -2 * 3 mod 4)"), Ok((")", Operand::Binary(
+                "-".to_string(),
+                Box::new(Operand::Key("@a".to_string())),
+                Box::new(Operand::Binary(
+                        "mod".to_string(),
+                        Box::new(Operand::Binary(
+                                "*".to_string(),
+                                Box::new(Operand::Negate(Box::new(Operand::Number("2".to_string())))),
+                                Box::new(Operand::Number("3".to_string())),
+                        )),
+                        Box::new(Operand::Number("4".to_string())),
+                )),
+        ))));
+        let mut info = HashMap::new();
+        info.insert("@price".to_string(), "12.5".to_string());
+        info.insert("@qty".to_string(), "8".to_string());
+        info.insert("position()".to_string(), "3".to_string());
+        let (_, pred) = condition("@price * @qty > 100").unwrap();
+        assert!(!pred.matches(&info));
+        let (_, pred) = condition("position() mod 2 = 1").unwrap();
+        assert!(pred.matches(&info));
+        let (_, pred) = condition("(@price + @missing) != 1").unwrap();
+        assert!(pred.matches(&info));
+        let (_, pred) = condition("@price div 0 = 1 div 0").unwrap();
+        assert!(pred.matches(&info));
+    }
+    #[test]
+    fn test_not() {
+        assert_eq!(condition("not( child::item )"), Ok(("", Predictor::Not(Box::new(
+                Predictor::Condition("item".to_string(), None, None),
+        )))));
+        let (_, pred) = condition("not(@a and true()) or false()").unwrap();
+        assert!(pred.matches(&HashMap::new()));
+        let (_, pred) = conditions_or("not(@a and true()) or false()").unwrap();
+        assert!(pred.matches(&HashMap::new()));
+    }
+    #[test]
+    fn test_relational() {
+        let mut info = HashMap::new();
+        info.insert("@value".to_string(), " 120 ".to_string());
+        info.insert("text()".to_string(), "abc".to_string());
+        info.insert("position()".to_string(), "2".to_string());
+        let expr = |x:&str| conditions_or(x).unwrap().1.matches(&info);
+        assert!(expr("@value >= 100"));
+        assert!(expr("@value >= '100'"));
+        assert!(!expr("@value < '99.5'"));
+        assert!(!expr("@value != 120"));
+        assert!(!expr("text() > 1 or text() <= 1"));
+        assert!(expr("text() != 1"));
+        assert!(expr("position() = 2"));
+        assert!(!expr("position() < 2"));
+        assert!(!expr("@missing < 2"));
+    }
+    #[test]
     fn test_conditions_or() {
         assert_eq!(conditions_or("@attr  = 'aa'"), Ok(("", Predictor::Condition("@attr".to_string(), Some("==".to_string()), Some("'aa'".to_string())))));
         assert_eq!(conditions_or("text()!= 'aa'"), Ok(("", Predictor::Condition("text()".to_string(), Some("!=".to_string()), Some("'aa'".to_string())))));
@@ -386,6 +1395,7 @@ mod tests {
         assert_eq!(xpath("@id"), Ok(("", vec![
                     XPathSegment {
                         separator:"".to_string(),
+                        axis:"".to_string(),
                         node:"*".to_string(),
                         condition:Predictor::Condition("@id".to_string(), None, None)
                     },
@@ -393,6 +1403,7 @@ mod tests {
         assert_eq!(xpath("//NODE[@oid and @attrcatref='abc']"), Ok(("", vec![
                     XPathSegment {
                         separator:"//".to_string(),
+                        axis:"".to_string(),
                         node:"NODE".to_string(),
                         condition:Predictor::And(
                             Box::new(Predictor::Condition("@oid".to_string(), None, None)),
@@ -403,22 +1414,72 @@ mod tests {
         assert_eq!(xpath(".//NAME/TUV"), Ok(("", vec![
                     XPathSegment {
                         separator:"".to_string(),
+                        axis:"".to_string(),
                         node:".".to_string(),
                         condition:Predictor::None
                     },
                     XPathSegment {
                         separator:"//".to_string(),
+                        axis:"".to_string(),
                         node:"NAME".to_string(),
                         condition:Predictor::None
                     },
                     XPathSegment {
                         separator:"/".to_string(),
+                        axis:"".to_string(),
                         node:"TUV".to_string(),
                         condition:Predictor::None
                     },
         ])));
     }
     #[test]
+    fn test_axis() {
+        assert_eq!(xpath("ancestor::section[@id]/ancestor-or-self::*"), Ok(("", vec![
+                    XPathSegment {
+                        separator:"".to_string(),
+                        axis:"ancestor".to_string(),
+                        node:"section".to_string(),
+                        condition:Predictor::Condition("@id".to_string(), None, None)
+                    },
+                    XPathSegment {
+                        separator:"/".to_string(),
+                        axis:"ancestor-or-self".to_string(),
+                        node:"*".to_string(),
+                        condition:Predictor::None
+                    },
+        ])));
+    }
+    #[test]
+    fn test_axis_names() {
+        let (_, segs) = xpath("descendant-or-self::node()/child::a/descendant::b/parent::*/self::c").unwrap();
+        let axes:Vec<&str> = segs.iter().map(|x| x.axis.as_str()).collect();
+        assert_eq!(axes, vec!["descendant-or-self", "child", "descendant", "parent", "self"]);
+        let (_, segs) = xpath("following::a/preceding::b/following-sibling::c").unwrap();
+        let axes:Vec<&str> = segs.iter().map(|x| x.axis.as_str()).collect();
+        assert_eq!(axes, vec!["following", "preceding", "following-sibling"]);
+    }
+    #[test]
+    fn test_node_test() {
+        assert_eq!(node_test("comment()[1]"), Ok(("[1]", "comment()")));
+        assert_eq!(node_test("processing-instruction( 'php' )/"), Ok(("/", "processing-instruction( 'php' )")));
+        assert_eq!(node_test("processing-instruction()"), Ok(("", "processing-instruction()")));
+        assert_eq!(node_test("node()"), Ok(("", "node()")));
+        assert_eq!(node_test("text()"), Ok(("", "text()")));
+        assert_eq!(node_test("svg:*/"), Ok(("/", "svg:*")));
+        assert_eq!(node_test("*:id["), Ok(("[", "*:id")));
+        assert_eq!(step("*:id").map(|x| x.1.node), Ok("*:id".to_string()));
+        assert_eq!(step("*").map(|x| x.1.node), Ok("*".to_string()));
+        assert_eq!(node_test("nodes"), Ok(("", "nodes")));
+        assert_eq!(xpath("//comment()"), Ok(("", vec![
+                    XPathSegment {
+                        separator:"//".to_string(),
+                        axis:"".to_string(),
+                        node:"comment()".to_string(),
+                        condition:Predictor::None
+                    },
+        ])));
+    }
+    #[test]
     fn test_predictor_expr() {
         let (remaining, segs) = xpath(".//NAME[text()='aa' and (@id='bb' or @gid)]").unwrap();
         assert_eq!(remaining, "");
@@ -431,7 +1492,62 @@ mod tests {
         let mut info = HashMap::new();
         info.insert("text()".to_string(), "aaa".to_string());
         info.insert("@id".to_string(), "123".to_string());
-        assert_eq!(segs[1].condition.expr(&info), "('aaa' == 'aa') && (('123' == 'bb') || (false))")
+        assert!(!segs[1].condition.matches(&info));
+        info.insert("text()".to_string(), "aa".to_string());
+        assert!(!segs[1].condition.matches(&info));
+        info.insert("@gid".to_string(), "".to_string());
+        assert!(segs[1].condition.matches(&info));
+        let (_, pred) = conditions_or("@* and position() = last() - 1").unwrap();
+        info.insert("@*".to_string(), "false".to_string());
+        info.insert("position()".to_string(), "2".to_string());
+        info.insert("last()".to_string(), "3".to_string());
+        assert!(!pred.matches(&info));
+        info.insert("@*".to_string(), "true".to_string());
+        assert!(pred.matches(&info));
+    }
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("//a/b").map(|x| x[0].len()), Ok(2));
+        assert_eq!(parse("//a | b/c|d").map(|x| x.iter().map(|x| x.len()).collect::<Vec<_>>()), Ok(vec![1, 2, 1]));
+        assert_eq!(parse("a |"), Err((3, 3, "expected a path".to_string())));
+        assert_eq!(parse(""), Err((0, 0, "expected a path".to_string())));
+        assert_eq!(parse("a[@k='v'"), Err((1, 8, "unterminated predicate".to_string())));
+        assert_eq!(parse("a/b]"), Err((3, 4, "unexpected ']', expected '/', '//' or '|'".to_string())));
+        assert_eq!(parse("//item[@id='x]"), Err((11, 14, "unterminated string literal".to_string())));
+        assert_eq!(parse("//a[@k=]/b"), Err((3, 8, "invalid predicate".to_string())));
+        assert_eq!(parse("//a[b[1]"), Err((3, 8, "unterminated predicate".to_string())));
+        assert_eq!(parse("a/ancestr::b"), Err((2, 9, "unknown axis \"ancestr\"".to_string())));
+        assert_eq!(parse("a/"), Err((1, 2, "unexpected '/', expected '/', '//' or '|'".to_string())));
+        assert_eq!(parse("]a"), Err((0, 1, "unexpected ']', expected a path".to_string())));
+    }
+    #[test]
+    fn test_parse_expr() {
+        assert_eq!(parse_expr("count(//item)"), Ok(Expr::Function("count".to_string(), vec![Expr::Path("//item".to_string())])));
+        assert_eq!(parse_expr("/cfg/@version"), Ok(Expr::Attribute("/cfg".to_string(), "version".to_string())));
+        assert_eq!(parse_expr("@id"), Ok(Expr::Attribute(".".to_string(), "id".to_string())));
+        assert_eq!(parse_expr("//@id"), Ok(Expr::Attribute("//descendant-or-self::*".to_string(), "id".to_string())));
+        assert_eq!(parse_expr("//a[@id] | //b"), Ok(Expr::Path("//a[@id] | //b".to_string())));
+        assert_eq!(parse_expr("//p/text()"), Ok(Expr::Text("//p".to_string())));
+        assert_eq!(parse_expr("text()"), Ok(Expr::Text(".".to_string())));
+        assert_eq!(
+            parse_expr("1 + 2 * 3"),
+            Ok(Expr::Binary(
+                "+".to_string(),
+                Box::new(Expr::Number("1".to_string())),
+                Box::new(Expr::Binary("*".to_string(), Box::new(Expr::Number("2".to_string())), Box::new(Expr::Number("3".to_string())))),
+            ))
+        );
+        assert_eq!(
+            parse_expr("//a = 'x' or true()"),
+            Ok(Expr::Binary(
+                "or".to_string(),
+                Box::new(Expr::Binary("==".to_string(), Box::new(Expr::Path("//a".to_string())), Box::new(Expr::Literal("x".to_string())))),
+                Box::new(Expr::Function("true".to_string(), Vec::new())),
+            ))
+        );
+        assert_eq!(parse_expr("count(//a"), Err((5, 9, "unterminated parenthesis".to_string())));
+        assert_eq!(parse_expr("1 +"), Err((2, 3, "unexpected '+', expected an operator".to_string())));
+        assert_eq!(parse_expr(""), Err((0, 0, "unexpected end, expected an expression".to_string())));
     }
     #[test]
     fn test_escape_info() {